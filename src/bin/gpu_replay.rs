@@ -0,0 +1,34 @@
+use std::{fs::File, io::BufWriter, path::Path};
+
+use shiranuhi::core::gpu::{png, trace::{Replayer, Trace}, vram_view::VramView};
+use spdlog::prelude::*;
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 && args.len() != 4 {
+        eprintln!("Usage: {} <trace file> <output directory> [resolution scale]", args[0]);
+        std::process::exit(1);
+    }
+
+    let trace = Trace::open(&args[1]).unwrap();
+    info!("Replaying {} GPU writes from {}", trace.events.len(), args[1]);
+
+    let output = Path::new(&args[2]);
+    std::fs::create_dir_all(output).unwrap();
+
+    let mut replayer = Replayer::new(trace);
+    if let Some(scale) = args.get(3) {
+        replayer.gpu_mut().set_resolution_scale(scale.parse().unwrap());
+    }
+    let mut frame_number = 0;
+    while let Some(frame) = replayer.next_frame() {
+        let path = output.join(format!("frame_{:05}.png", frame_number));
+        let writer = BufWriter::new(File::create(path).unwrap());
+        png::encode_rgb(writer, frame.width, frame.height, &frame.pixels).unwrap();
+
+        info!("Frame {} at cycle {}: {}x{}", frame_number, frame.cycle, frame.width, frame.height);
+        frame_number += 1;
+    }
+
+    replayer.gpu().export_vram_png(output.join("vram.png"), VramView::Direct15).unwrap();
+}
\ No newline at end of file
@@ -1,5 +1,7 @@
 use std::{fs::File, io::Read, path::Path};
 
+use super::region::Region;
+
 pub struct Bios {
     data: Box<[u8; 512 * 1024]>
 }
@@ -38,4 +40,22 @@ impl Bios {
     pub fn load8(&self, address: u32) -> u8 {
         self.data[address as usize]
     }
+
+    /// Read from the version string, which ends in A, E or J. Only the very first Japanese
+    /// BIOS has no version string, it still carries the copyright
+    pub fn region(&self) -> Option<Region> {
+        let find = |needle: &[u8]| self.data.windows(needle.len()).position(|window| window == needle);
+
+        let Some(start) = find(b"System ROM Version") else {
+            return find(b"Sony Computer Entertainment Inc.").map(|_| Region::Japan);
+        };
+        let end = self.data[start..].iter().position(|&byte| byte == 0).map_or(self.data.len(), |length| start + length);
+
+        match self.data[start..end].trim_ascii_end().last() {
+            Some(b'J') => Some(Region::Japan),
+            Some(b'A') => Some(Region::NorthAmerica),
+            Some(b'E') => Some(Region::Europe),
+            _ => None
+        }
+    }
 }
\ No newline at end of file
@@ -1,13 +1,29 @@
 use super::{
     bios::Bios,
-    devices::ram::Ram
+    cdrom::{self, disc::Disc, Cdrom},
+    devices::{
+        dma::{Disconnected, Dma, Port, Step},
+        interrupts::{Interrupt, InterruptController},
+        ram::Ram,
+        sio::{self, Sio},
+        timers::Timers
+    },
+    gpu::Gpu,
+    precision::PreciseWord,
+    scheduler::{Event, Scheduler}
 };
 use spdlog::prelude::*;
 
 const RAM_RANGE: Range = Range(0x00000000, 2 * 1024 * 1024);
 const EXPANSION_1_RANGE: Range = Range(0x1F000000, 512 * 1024); // 512KB i think
 const MEMORY_CONTROL_RANGE: Range = Range(0x1F801000, 36);
+const SIO0_RANGE: Range = Range(0x1F801040, 16);
 const RAM_SIZE_RANGE: Range = Range(0x1F801060, 4);
+const INTERRUPT_CONTROL_RANGE: Range = Range(0x1F801070, 8);
+const DMA_RANGE: Range = Range(0x1F801080, 128);
+const TIMERS_RANGE: Range = Range(0x1F801100, 48);
+const CDROM_RANGE: Range = Range(0x1F801800, 4);
+const GPU_RANGE: Range = Range(0x1F801810, 8);
 const SPU_RANGE: Range = Range(0x1F801C00, 640);
 const EXPANSION_2_RANGE: Range = Range(0x1F802000, 66);
 const BIOS_RANGE: Range = Range(0x1FC00000, 512 * 1024);
@@ -16,21 +32,243 @@ const CACHE_CONTROL_RANGE: Range = Range(0xFFFE0130, 4);
 pub struct Bus {
     bios: Bios,
     ram: Ram,
-    cache_control: CacheControl
+    interrupts: InterruptController,
+    dma: Dma,
+    timers: Timers,
+    sio: Sio,
+    cdrom: Cdrom,
+    gpu: Gpu,
+    cache_control: CacheControl,
+    scheduler: Scheduler,
+
+    stall_cycles: u64,
+    // 44.1 kHz stereo output not taken yet, None while nobody listens
+    audio: Option<Vec<(i16, i16)>>
 }
 
 impl Bus {
     pub fn new(bios: Bios) -> Self {
-        Self { bios, ram: Ram::new(), cache_control: CacheControl(0) }
+        let mut bus = Self {
+            bios,
+            ram: Ram::new(),
+            interrupts: InterruptController::new(),
+            dma: Dma::new(),
+            timers: Timers::new(),
+            sio: Sio::new(),
+            cdrom: Cdrom::new(),
+            gpu: Gpu::new(),
+            cache_control: CacheControl(0),
+            scheduler: Scheduler::new(),
+
+            stall_cycles: 0,
+            audio: None
+        };
+
+        let region = bus.bios.region();
+        bus.cdrom.set_console_region(region);
+
+        let delay = bus.gpu.cycles_to_hblank();
+        bus.scheduler.schedule(Event::HBlankStart, delay);
+        bus.update_timers();
+
+        bus
     }
 
-    pub fn load32(&self, address: u32) -> u32 {        
+    /// Cycles the CPU spent waiting on the bus since the last call
+    pub fn take_stall_cycles(&mut self) -> u64 {
+        std::mem::take(&mut self.stall_cycles)
+    }
+
+    pub fn interrupt_pending(&self) -> bool {
+        self.interrupts.pending()
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.gpu.tick(cycles);
+
+        let time = self.scheduler.now() + cycles;
+        while let Some(event) = self.scheduler.pop_until(time) {
+            match event {
+                Event::DmaChunk(port) => self.dma_step(port),
+                Event::DmaComplete(port) => {
+                    self.dma.finish(port);
+                    self.update_dma_interrupt();
+
+                    // Finishing can unblock a lower priority channel
+                    self.run_dma();
+                }
+                Event::HBlankStart => {
+                    self.timers.set_hblank(true, self.scheduler.now());
+
+                    let delay = self.gpu.start_hblank();
+                    self.scheduler.schedule(Event::HBlankEnd, delay);
+                    self.update_timers();
+                }
+                Event::HBlankEnd => {
+                    let now = self.scheduler.now();
+                    let was_vblank = self.gpu.in_vblank();
+
+                    self.gpu.next_scanline();
+                    self.timers.set_hblank(false, now);
+
+                    let vblank = self.gpu.in_vblank();
+                    if vblank != was_vblank {
+                        if vblank {
+                            self.interrupts.request(Interrupt::VBlank);
+                            self.sio.vblank();
+                        }
+                        self.timers.set_vblank(vblank, now);
+                    }
+
+                    let delay = self.gpu.cycles_to_hblank();
+                    self.scheduler.schedule(Event::HBlankStart, delay);
+                    self.update_timers();
+                }
+                Event::Timers => {
+                    self.timers.sync(self.scheduler.now());
+                    self.update_timers();
+                }
+                Event::SioTransfer => {
+                    if self.sio.finish_transfer() {
+                        self.scheduler.schedule(Event::SioAck, sio::ACK_DELAY);
+                    }
+                    self.update_sio();
+                }
+                Event::SioAck => {
+                    self.sio.set_ack(true);
+                    self.scheduler.schedule(Event::SioAckEnd, sio::ACK_LENGTH);
+                    self.update_sio();
+                }
+                Event::SioAckEnd => self.sio.set_ack(false),
+                Event::Cdrom(timer) => {
+                    self.cdrom.handle_timer(timer);
+                    self.update_cdrom();
+                }
+                Event::AudioSample => {
+                    // There's no SPU yet, so the output is just the CD input
+                    let sample = self.cdrom.audio_sample();
+                    if let Some(audio) = &mut self.audio {
+                        audio.push(sample);
+                    }
+                    self.scheduler.schedule(Event::AudioSample, cdrom::AUDIO_SAMPLE_CYCLES);
+                }
+            }
+        }
+        self.scheduler.advance_to(time);
+    }
+
+    pub fn sio(&self) -> &Sio {
+        &self.sio
+    }
+
+    pub fn sio_mut(&mut self) -> &mut Sio {
+        &mut self.sio
+    }
+
+    pub fn cdrom(&self) -> &Cdrom {
+        &self.cdrom
+    }
+
+    pub fn cdrom_mut(&mut self) -> &mut Cdrom {
+        &mut self.cdrom
+    }
+
+    /// Starts or stops collecting the audio output for `take_audio`
+    pub fn set_audio_capture(&mut self, enabled: bool) {
+        if enabled == self.audio.is_some() {
+            return;
+        }
+
+        self.scheduler.cancel(Event::AudioSample);
+        self.audio = enabled.then(Vec::new);
+        if enabled {
+            self.scheduler.schedule(Event::AudioSample, cdrom::AUDIO_SAMPLE_CYCLES);
+        }
+    }
+
+    /// The audio output since the last call, nothing unless capture is on
+    pub fn take_audio(&mut self) -> Vec<(i16, i16)> {
+        self.audio.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /// Puts a disc into the CD-ROM drive, or takes it out with None
+    pub fn set_disc(&mut self, disc: Option<Box<dyn Disc>>) {
+        self.cdrom.set_disc(disc);
+        self.update_cdrom();
+    }
+
+    pub fn set_cdrom_lid(&mut self, open: bool) {
+        match open {
+            true => self.cdrom.open_lid(),
+            false => self.cdrom.close_lid()
+        }
+        self.update_cdrom();
+    }
+
+    /// Starts or stops carrying sub-pixel GTE vertices through RAM and DMA to the GPU
+    pub fn set_precise_geometry(&mut self, enabled: bool) {
+        self.ram.set_precision_tracking(enabled);
+        self.gpu.set_precise_geometry(enabled);
+    }
+
+    /// Hands over the precise vertex of the word about to be stored at `address`
+    pub fn store_precise(&mut self, address: u32, precise: Option<PreciseWord>) {
+        let address = get_masked_address(address);
+
+        if let Some(offset) = RAM_RANGE.contains(address) {
+            self.ram.store_precise(offset, precise);
+        } else if GPU_RANGE.contains(address) == Some(0) {
+            self.gpu.set_next_precise(precise.map(|precise| precise.vertex));
+        }
+    }
+
+    /// The precise vertex behind the word at `address`, only RAM keeps them
+    pub fn load_precise(&self, address: u32) -> Option<PreciseWord> {
+        RAM_RANGE.contains(get_masked_address(address)).and_then(|offset| self.ram.load_precise(offset))
+    }
+
+    /// Copies `data` straight into RAM, for loading executables
+    pub fn write_ram(&mut self, address: u32, data: &[u8]) {
+        for (address, &byte) in (address..).zip(data) {
+            self.ram.store8(address, byte);
+        }
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    pub fn gpu_mut(&mut self) -> &mut Gpu {
+        &mut self.gpu
+    }
+
+    pub fn load32(&mut self, address: u32) -> u32 {        
         let address = get_masked_address(address);
         
         if let Some(offset) = RAM_RANGE.contains(address) {
             return self.ram.load32(offset);
         }
 
+        if let Some(offset) = INTERRUPT_CONTROL_RANGE.contains(address) {
+            return self.interrupts.load32(offset);
+        }
+
+        if let Some(offset) = DMA_RANGE.contains(address) {
+            return self.dma.load32(offset);
+        }
+
+        if let Some(offset) = SIO0_RANGE.contains(address) {
+            return self.sio.load32(offset);
+        }
+
+        if let Some(offset) = TIMERS_RANGE.contains(address) {
+            return self.timers.load32(offset, self.scheduler.now());
+        }
+
+        if let Some(offset) = GPU_RANGE.contains(address) {
+            return self.gpu.load32(offset);
+        }
+
         if let Some(offset) = BIOS_RANGE.contains(address) {
             return self.bios.load32(offset);
         }
@@ -42,13 +280,29 @@ impl Bus {
         panic!("INVALID LOAD32 ADDRESS: 0x{:08X}", address);
     }
 
-    pub fn load16(&self, address: u32) -> u16 {        
+    pub fn load16(&mut self, address: u32) -> u16 {        
         let address = get_masked_address(address);
 
+        if let Some(offset) = INTERRUPT_CONTROL_RANGE.contains(address) {
+            return (self.interrupts.load32(offset & !0x3) >> ((offset & 2) * 8)) as u16;
+        }
+
+        if let Some(offset) = SIO0_RANGE.contains(address) {
+            return self.sio.load32(offset) as u16;
+        }
+
+        if let Some(offset) = CDROM_RANGE.contains(address) {
+            return self.cdrom.load16(offset);
+        }
+
+        if let Some(offset) = TIMERS_RANGE.contains(address) {
+            return self.timers.load16(offset, self.scheduler.now());
+        }
+
         panic!("INVALID LOAD16 ADDRESS: 0x{:08X}", address);
     }
 
-    pub fn load8(&self, address: u32) -> u8 {        
+    pub fn load8(&mut self, address: u32) -> u8 {        
         let address = get_masked_address(address);
 
         if let Some(offset) = RAM_RANGE.contains(address) {
@@ -60,6 +314,14 @@ impl Bus {
             return 0xFF;
         }
 
+        if let Some(offset) = SIO0_RANGE.contains(address) {
+            return self.sio.load32(offset) as u8;
+        }
+
+        if let Some(offset) = CDROM_RANGE.contains(address) {
+            return self.cdrom.load8(offset);
+        }
+
         if let Some(offset) = BIOS_RANGE.contains(address) {
             return self.bios.load8(offset);
         }
@@ -75,6 +337,38 @@ impl Bus {
             return;
         }
 
+        if let Some(offset) = INTERRUPT_CONTROL_RANGE.contains(address) {
+            self.interrupts.store32(offset, value);
+            return;
+        }
+
+        if let Some(offset) = DMA_RANGE.contains(address) {
+            self.dma.store32(offset, value);
+            self.run_dma();
+            return;
+        }
+
+        if let Some(offset) = SIO0_RANGE.contains(address) {
+            self.sio.store32(offset, value);
+            self.update_sio();
+            return;
+        }
+
+        if let Some(offset) = TIMERS_RANGE.contains(address) {
+            self.timers.store32(offset, value, self.scheduler.now());
+            self.update_timers();
+            return;
+        }
+
+        if let Some(offset) = GPU_RANGE.contains(address) {
+            self.gpu.store32(offset, value);
+
+            // GP1(08h) can change the dot clock under timer 0
+            self.timers.set_dot_divider(self.gpu.dot_divider(), self.scheduler.now());
+            self.update_timers();
+            return;
+        }
+
         if let Some(_offset) = MEMORY_CONTROL_RANGE.contains(address) {
             warn!("[MEM_CONTROL] Unhandled store32 at [0x{:08X}]: 0x{:08X}", address, value);
             return;
@@ -96,6 +390,26 @@ impl Bus {
     pub fn store16(&mut self, address: u32, value: u16) {
         let address = get_masked_address(address);
 
+        if let Some(offset) = INTERRUPT_CONTROL_RANGE.contains(address) {
+            // Keep the other half as it is, zeroes would acknowledge every pending interrupt
+            let shift = (offset & 2) * 8;
+            let word = self.interrupts.load32(offset & !0x3);
+            self.interrupts.store32(offset & !0x3, (word & !(0xFFFF << shift)) | ((value as u32) << shift));
+            return;
+        }
+
+        if let Some(offset) = SIO0_RANGE.contains(address) {
+            self.sio.store32(offset, value as u32);
+            self.update_sio();
+            return;
+        }
+
+        if let Some(offset) = TIMERS_RANGE.contains(address) {
+            self.timers.store16(offset, value, self.scheduler.now());
+            self.update_timers();
+            return;
+        }
+
         if let Some(_offset) = SPU_RANGE.contains(address) {
             warn!("[SPU] Unhandled store16 at [0x{:08X}]: 0x{:04X}", address, value);
             return;
@@ -107,6 +421,18 @@ impl Bus {
     pub fn store8(&mut self, address: u32, value: u8) {
         let address = get_masked_address(address);
 
+        if let Some(offset) = SIO0_RANGE.contains(address) {
+            self.sio.store32(offset, value as u32);
+            self.update_sio();
+            return;
+        }
+
+        if let Some(offset) = CDROM_RANGE.contains(address) {
+            self.cdrom.store8(offset, value);
+            self.update_cdrom();
+            return;
+        }
+
         if let Some(_offset) = EXPANSION_2_RANGE.contains(address) {
             warn!("[EXP2] Unhandled store8 at [0x{:08X}]: 0x{:02X}", address, value);
             return;
@@ -116,6 +442,95 @@ impl Bus {
     }
 }
 
+impl Bus {
+    fn run_dma(&mut self) {
+        // Channels stopped by software mid-transfer don't get their remaining chunks
+        for port in Port::ALL {
+            if !self.dma.is_running(port) {
+                self.scheduler.cancel(Event::DmaChunk(port));
+                self.scheduler.cancel(Event::DmaComplete(port));
+            }
+        }
+
+        while let Some(port) = self.dma.active_channel() {
+            self.dma_step(port);
+        }
+
+        self.update_dma_interrupt();
+    }
+
+    fn dma_step(&mut self, port: Port) {
+        let step = match port {
+            Port::Gpu => self.dma.step(port, &mut self.ram, &mut self.gpu),
+            Port::CdRom => self.dma.step(port, &mut self.ram, &mut self.cdrom),
+            // TODO: Connect the remaining devices once they exist
+            _ => self.dma.step(port, &mut self.ram, &mut Disconnected(port))
+        };
+
+        // The CPU is halted while DMA owns the bus, it only gets to run in the windows between
+        // chunks of chopped and sliced transfers
+        match step {
+            Step::Continue { cycles, cpu_window } => {
+                self.stall_cycles += cycles;
+                self.scheduler.schedule(Event::DmaChunk(port), cycles + cpu_window);
+            }
+            Step::Done { cycles } => {
+                self.stall_cycles += cycles;
+                self.scheduler.schedule(Event::DmaComplete(port), cycles);
+            }
+        }
+    }
+
+    fn update_timers(&mut self) {
+        const INTERRUPTS: [Interrupt; 3] = [Interrupt::Timer0, Interrupt::Timer1, Interrupt::Timer2];
+
+        for (index, interrupt) in INTERRUPTS.into_iter().enumerate() {
+            if self.timers.take_interrupt(index) {
+                self.interrupts.request(interrupt);
+            }
+        }
+
+        // Only the next target or overflow hit needs waking up for, the rest is counted lazily
+        self.scheduler.cancel(Event::Timers);
+        if let Some(delay) = self.timers.next_event() {
+            self.scheduler.schedule(Event::Timers, delay);
+        }
+    }
+
+    fn update_sio(&mut self) {
+        // A reset drops the byte in flight along with its completion
+        if !self.sio.is_transferring() {
+            self.scheduler.cancel(Event::SioTransfer);
+        }
+        if let Some(cycles) = self.sio.take_transfer_start() {
+            self.scheduler.schedule(Event::SioTransfer, cycles);
+        }
+
+        if self.sio.take_interrupt() {
+            self.interrupts.request(Interrupt::Controller);
+        }
+    }
+
+    fn update_cdrom(&mut self) {
+        for (timer, delay) in self.cdrom.take_timer_requests() {
+            self.scheduler.cancel(Event::Cdrom(timer));
+            if let Some(delay) = delay {
+                self.scheduler.schedule(Event::Cdrom(timer), delay);
+            }
+        }
+
+        if self.cdrom.take_interrupt() {
+            self.interrupts.request(Interrupt::CdRom);
+        }
+    }
+
+    fn update_dma_interrupt(&mut self) {
+        if self.dma.take_interrupt() {
+            self.interrupts.request(Interrupt::Dma);
+        }
+    }
+}
+
 pub struct Range(u32, u32);
 impl Range {
     pub fn contains(self, address: u32) -> Option<u32> {
@@ -130,6 +545,7 @@ impl Range {
 }
 
 struct CacheControl(u32);
+#[allow(dead_code)]
 impl CacheControl {
     pub fn icache_enabled(&self) -> bool {
         self.0 & 0x800 != 0
@@ -149,4 +565,92 @@ const REGION_MASKS: [u32; 8] = [
 
 fn get_masked_address(address: u32) -> u32 {
     address & REGION_MASKS[(address >> 29) as usize]
-} 
\ No newline at end of file
+} 
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn bus() -> Bus {
+        let path = std::env::temp_dir().join(format!("shiranuhi-bus-{}.bin", std::process::id()));
+        fs::write(&path, vec![0; 512 * 1024]).unwrap();
+        let bios = Bios::new(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        Bus::new(bios)
+    }
+
+    #[test]
+    fn captures_audio_at_44100_hz() {
+        let mut bus = bus();
+        bus.tick(cdrom::AUDIO_SAMPLE_CYCLES * 100);
+        assert!(bus.take_audio().is_empty());
+
+        bus.set_audio_capture(true);
+        for _ in 0..10 {
+            bus.tick(cdrom::AUDIO_SAMPLE_CYCLES * 4410);
+        }
+        let audio = bus.take_audio();
+        assert_eq!(audio.len(), 44100);
+        assert!(audio.iter().all(|&sample| sample == (0, 0)));
+        assert!(bus.take_audio().is_empty());
+
+        // Turning it on again doesn't add a second sample clock
+        bus.set_audio_capture(true);
+        bus.tick(cdrom::AUDIO_SAMPLE_CYCLES * 10);
+        assert_eq!(bus.take_audio().len(), 10);
+
+        bus.set_audio_capture(false);
+        bus.tick(cdrom::AUDIO_SAMPLE_CYCLES * 10);
+        assert!(bus.take_audio().is_empty());
+    }
+
+    #[test]
+    fn carries_precise_vertices_to_the_gpu() {
+        use crate::core::{gpu::VRAM_WIDTH, precision::PreciseVertex};
+
+        let precise = |word: u32, x: f32, y: f32| Some(PreciseWord { word, vertex: PreciseVertex { x, y, z: 0.0 } });
+        let packet = [
+            (0x200000FF, None),
+            (0x00000000, precise(0x00000000, 0.9, 0.9)),
+            (0x00000008, precise(0x00000008, 8.9, 0.9)),
+            (0x00080000, precise(0x00080000, 0.9, 8.9))
+        ];
+
+        for enabled in [false, true] {
+            let mut bus = bus();
+            bus.set_precise_geometry(enabled);
+            for word in [0xE3000000, 0xE4000000 | (511 << 10) | 1023, 0xE5000000] {
+                bus.gpu_mut().gp0(word);
+            }
+
+            // A single node linked list at 0x1000
+            bus.store32(0x1000, 0x04FFFFFF);
+            for (address, (word, precise)) in (0x1004..).step_by(4).zip(packet) {
+                bus.store_precise(address, precise);
+                bus.store32(address, word);
+            }
+            assert_eq!(bus.load_precise(0x8000100C), precise(0x00000008, 8.9, 0.9).filter(|_| enabled));
+
+            bus.store32(0x1F8010F0, 0x800);
+            bus.store32(0x1F8010A0, 0x1000);
+            bus.store32(0x1F8010A8, 0x01000401);
+
+            // The whole triangle moves by 0.9 pixels, so its top left corner drops out
+            let vram = bus.gpu().vram();
+            assert_eq!(vram[0] == 0x001F, !enabled, "enabled: {}", enabled);
+            assert_eq!(vram[VRAM_WIDTH + 1], 0x001F);
+        }
+
+        // Anything else stored over a word leaves its precise vertex behind
+        let mut bus = bus();
+        bus.set_precise_geometry(true);
+        bus.store_precise(0x2000, precise(0x00010001, 1.5, 1.5));
+        bus.store32(0x2000, 0x00010001);
+        assert!(bus.load_precise(0x2000).is_some());
+        bus.store32(0x2000, 0x00010002);
+        assert_eq!(bus.load_precise(0x2000), None);
+    }
+}
\ No newline at end of file
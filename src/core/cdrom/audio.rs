@@ -0,0 +1,311 @@
+use std::collections::VecDeque;
+
+use bitfield_struct::bitfield;
+
+use super::disc::SECTOR_SIZE;
+
+/// Sound groups in the data of a Form 2 sector
+const SOUND_GROUPS: usize = 18;
+const SOUND_GROUP_SIZE: usize = 128;
+const SAMPLES_PER_UNIT: usize = 28;
+
+// Prediction filters, applied to the two previous samples
+const FILTER_POSITIVE: [i32; 4] = [0, 60, 115, 98];
+const FILTER_NEGATIVE: [i32; 4] = [0, 0, -52, -55];
+
+/// Byte 2 of a Mode 2 subheader
+#[bitfield(u8)]
+pub struct Submode {
+    pub end_of_record: bool,
+    pub video: bool,
+    pub audio: bool,
+    pub data: bool,
+    pub trigger: bool,
+    pub form2: bool,
+    pub real_time: bool,
+    pub end_of_file: bool
+}
+
+/// Byte 3 of a Mode 2 subheader, how an XA-ADPCM sector is encoded
+#[bitfield(u8)]
+pub struct Coding {
+    #[bits(2)]
+    pub channels: u8,
+    #[bits(2)]
+    pub rate: u8,
+    #[bits(2)]
+    pub bits: u8,
+    pub emphasis: bool,
+    __: bool
+}
+
+/// Turns 37.8 or 18.9 kHz samples into 44.1 kHz ones by linear interpolation, the hardware
+/// uses a zigzag FIR which isn't reproduced here
+#[derive(Debug, Default)]
+struct Resampler {
+    previous: (i16, i16),
+    // Position of the next output sample between `previous` and the incoming one, in 1/7ths
+    phase: u32
+}
+
+impl Resampler {
+    /// `step` is the input rate in 1/7ths of 44.1 kHz, 6 for 37.8 kHz and 3 for 18.9 kHz
+    fn push(&mut self, sample: (i16, i16), step: u32, output: &mut VecDeque<(i16, i16)>) {
+        let lerp = |from: i16, to: i16, phase: u32| (from as i32 + (to as i32 - from as i32) * phase as i32 / 7) as i16;
+
+        while self.phase < 7 {
+            output.push_back((lerp(self.previous.0, sample.0, self.phase), lerp(self.previous.1, sample.1, self.phase)));
+            self.phase += step;
+        }
+        self.phase -= 7;
+        self.previous = sample;
+    }
+}
+
+/// Decodes XA-ADPCM sectors, keeping the filter history between them
+#[derive(Debug, Default)]
+pub struct XaDecoder {
+    // Last two samples of the left (or mono) and right channels
+    history: [(i32, i32); 2],
+    resampler: Resampler
+}
+
+impl XaDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    /// Decodes a real-time audio sector, pushing its samples resampled to 44.1 kHz stereo
+    pub fn decode_sector(&mut self, sector: &[u8; SECTOR_SIZE], output: &mut VecDeque<(i16, i16)>) {
+        let coding = Coding::from(sector[19]);
+        let stereo = coding.channels() == 1;
+        let eight_bit = coding.bits() == 1;
+        let step = if coding.rate() == 1 { 3 } else { 6 };
+
+        let units = if eight_bit { 4 } else { 8 };
+        let mut samples = [Vec::with_capacity(SOUND_GROUPS * 8 * SAMPLES_PER_UNIT), Vec::new()];
+
+        for group in sector[24..24 + SOUND_GROUPS * SOUND_GROUP_SIZE].chunks_exact(SOUND_GROUP_SIZE) {
+            for unit in 0..units {
+                // Stereo sectors alternate between left and right units
+                let channel = if stereo { unit % 2 } else { 0 };
+                self.decode_unit(group, unit, eight_bit, channel, &mut samples[channel]);
+            }
+        }
+
+        let [left, right] = samples;
+        if stereo {
+            for (&left, &right) in left.iter().zip(&right) {
+                self.resampler.push((left, right), step, output);
+            }
+        } else {
+            for &sample in &left {
+                self.resampler.push((sample, sample), step, output);
+            }
+        }
+    }
+
+    fn decode_unit(&mut self, group: &[u8], unit: usize, eight_bit: bool, channel: usize, output: &mut Vec<i16>) {
+        let header = group[4 + unit];
+        let filter = ((header >> 4) & 3) as usize;
+        // Ranges past 12 behave like 9
+        let range = match header & 0xF {
+            range @ 0..=12 => range,
+            _ => 9
+        };
+
+        let (mut old, mut older) = self.history[channel];
+        for sample in 0..SAMPLES_PER_UNIT {
+            let word = &group[16 + sample * 4..];
+            // Place the encoded bits at the top of a halfword, then shift them down by the range
+            let encoded = match eight_bit {
+                true => ((word[unit] as u16) << 8) as i16,
+                false => (((word[unit / 2] >> ((unit % 2) * 4)) as u16) << 12) as i16
+            };
+
+            let predicted = (old * FILTER_POSITIVE[filter] + older * FILTER_NEGATIVE[filter] + 32) >> 6;
+            let decoded = ((encoded >> range) as i32 + predicted).clamp(i16::MIN as i32, i16::MAX as i32);
+
+            output.push(decoded as i16);
+            older = old;
+            old = decoded;
+        }
+        self.history[channel] = (old, older);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (stereo, 18.9 kHz, 8-bit, samples out at 44.1 kHz)
+    const SECTOR_LENGTHS: [(bool, bool, bool, usize); 8] = [
+        (false, false, false, 4704),
+        (false, true, false, 9408),
+        (true, false, false, 2352),
+        (true, true, false, 4704),
+        (false, false, true, 2352),
+        (false, true, true, 4704),
+        (true, false, true, 1176),
+        (true, true, true, 2352)
+    ];
+
+    fn coding(stereo: bool, low_rate: bool, eight_bit: bool) -> u8 {
+        Coding::new().with_channels(stereo as u8).with_rate(low_rate as u8).with_bits(eight_bit as u8).into()
+    }
+
+    // A sound group with `headers` for its units and `data(sample, unit)` for the encoded bits
+    fn group(headers: &[u8], data: impl Fn(usize, usize) -> u8, eight_bit: bool) -> [u8; SOUND_GROUP_SIZE] {
+        let mut group = [0; SOUND_GROUP_SIZE];
+        for (unit, &header) in headers.iter().enumerate() {
+            group[4 + unit] = header;
+        }
+        for sample in 0..SAMPLES_PER_UNIT {
+            let word = &mut group[16 + sample * 4..16 + sample * 4 + 4];
+            for unit in 0..headers.len() {
+                match eight_bit {
+                    true => word[unit] = data(sample, unit),
+                    false => word[unit / 2] |= (data(sample, unit) & 0xF) << ((unit % 2) * 4)
+                }
+            }
+        }
+        group
+    }
+
+    fn decode(decoder: &mut XaDecoder, group: &[u8], unit: usize, eight_bit: bool) -> Vec<i16> {
+        let mut samples = Vec::new();
+        decoder.decode_unit(group, unit, eight_bit, 0, &mut samples);
+        samples
+    }
+
+    fn sector(coding: u8, group: &[u8; SOUND_GROUP_SIZE]) -> [u8; SECTOR_SIZE] {
+        let mut sector = [0; SECTOR_SIZE];
+        sector[15] = 2;
+        sector[18] = Submode::new().with_audio(true).with_real_time(true).with_form2(true).into();
+        sector[19] = coding;
+        for chunk in sector[24..24 + SOUND_GROUPS * SOUND_GROUP_SIZE].chunks_exact_mut(SOUND_GROUP_SIZE) {
+            chunk.copy_from_slice(group);
+        }
+        sector
+    }
+
+    #[test]
+    fn four_bit_units() {
+        // Filter 0 with range 12 gives back the nibbles, sign extended
+        let nibbles = group(&[0x00, 0x0C], |sample, _| sample as u8, false);
+        let expected: Vec<i16> = (0..SAMPLES_PER_UNIT).map(|sample| (((sample as u8) << 4) as i8 >> 4) as i16).collect();
+        assert_eq!(decode(&mut XaDecoder::new(), &nibbles, 1, false), expected);
+
+        // Range 0 puts a nibble at the top of the sample, filter 1 decays it by 60/64
+        let decay = group(&[0x10], |sample, _| (sample == 0) as u8, false);
+        assert_eq!(decode(&mut XaDecoder::new(), &decay, 0, false)[..6], [4096, 3840, 3600, 3375, 3164, 2966]);
+
+        // Ranges past 12 shift like 9
+        let large_range = group(&[0x0F], |_, _| 1, false);
+        assert!(decode(&mut XaDecoder::new(), &large_range, 0, false).iter().all(|&sample| sample == 8));
+    }
+
+    #[test]
+    fn filters_use_history() {
+        let silence = group(&[0x2C, 0x3C, 0x30], |sample, unit| if unit == 2 && sample == 0 { 0xF } else { 0 }, false);
+
+        let mut decoder = XaDecoder::new();
+        decoder.history[0] = (1000, 500);
+        assert_eq!(decode(&mut decoder, &silence, 0, false)[..4], [1391, 1687, 1901, 2045]);
+
+        decoder.history[0] = (1000, 500);
+        assert_eq!(decode(&mut decoder, &silence, 1, false)[..4], [1102, 828, 321, -220]);
+
+        // The next unit of the channel picks up from the last two samples
+        let mut decoder = XaDecoder::new();
+        let samples = decode(&mut decoder, &silence, 2, false);
+        assert_eq!(samples[..4], [-4096, -6272, -6084, -3926]);
+        assert_eq!(decoder.history[0], (samples[27] as i32, samples[26] as i32));
+    }
+
+    #[test]
+    fn eight_bit_units() {
+        let bytes = group(&[0x08, 0x00, 0x10, 0x00], |sample, unit| [sample as u8 * 9, 0x40, 0x7F, 0x80][unit], true);
+
+        let expected: Vec<i16> = (0..SAMPLES_PER_UNIT).map(|sample| (sample as u8 * 9) as i8 as i16).collect();
+        assert_eq!(decode(&mut XaDecoder::new(), &bytes, 0, true), expected);
+        assert!(decode(&mut XaDecoder::new(), &bytes, 1, true).iter().all(|&sample| sample == 0x4000));
+        assert!(decode(&mut XaDecoder::new(), &bytes, 3, true).iter().all(|&sample| sample == -0x8000));
+
+        // Prediction on top of a full scale sample clamps
+        let mut decoder = XaDecoder::new();
+        decoder.history[0] = (32000, 0);
+        assert_eq!(decode(&mut decoder, &bytes, 2, true)[0], 32767);
+    }
+
+    #[test]
+    fn resampler_ratios() {
+        let mut output = VecDeque::new();
+        let mut resampler = Resampler::default();
+        resampler.push((700, -700), 6, &mut output);
+        resampler.push((0, 0), 6, &mut output);
+        assert_eq!(output, [(0, 0), (600, -600), (200, -200)]);
+
+        output.clear();
+        let mut resampler = Resampler::default();
+        resampler.push((700, -700), 3, &mut output);
+        assert_eq!(output, [(0, 0), (300, -300), (600, -600)]);
+
+        // 37.8 kHz gives 7 samples for every 6 and 18.9 kHz 7 for every 3
+        for (step, inputs) in [(6, 6), (3, 3)] {
+            output.clear();
+            let mut resampler = Resampler::default();
+            for _ in 0..inputs * 10 {
+                resampler.push((0, 0), step, &mut output);
+            }
+            assert_eq!(output.len(), 70, "step {}", step);
+            assert_eq!(resampler.phase, 0, "step {}", step);
+        }
+    }
+
+    #[test]
+    fn sector_lengths() {
+        let silence = [0; SOUND_GROUP_SIZE];
+        for (stereo, low_rate, eight_bit, length) in SECTOR_LENGTHS {
+            let mut output = VecDeque::new();
+            XaDecoder::new().decode_sector(&sector(coding(stereo, low_rate, eight_bit), &silence), &mut output);
+            assert_eq!(output.len(), length, "stereo: {}, 18.9 kHz: {}, 8-bit: {}", stereo, low_rate, eight_bit);
+        }
+    }
+
+    #[test]
+    fn stereo_interleaves_units() {
+        // Even units are left, odd units right, all at full scale with opposite signs
+        for eight_bit in [false, true] {
+            let units = if eight_bit { 4 } else { 8 };
+            let headers = [0x00; 8];
+            let value = |unit: usize| match (eight_bit, unit % 2) {
+                (false, 0) => 0x1,
+                (false, _) => 0xF,
+                (true, 0) => 0x10,
+                (true, _) => 0xF0
+            };
+            let group = group(&headers[..units], |_, unit| value(unit), eight_bit);
+
+            let mut output = VecDeque::new();
+            XaDecoder::new().decode_sector(&sector(coding(true, false, eight_bit), &group), &mut output);
+            assert_eq!(output[0], (0, 0));
+            assert_eq!(output[1], (3510, -3510));
+            assert!(output.iter().skip(2).all(|&sample| sample == (4096, -4096)), "8-bit: {}", eight_bit);
+        }
+
+        // Mono plays every unit on both sides in order
+        let group = group(&[0x0C; 8], |_, unit| unit as u8, false);
+        let mut output = VecDeque::new();
+        XaDecoder::new().decode_sector(&sector(coding(false, true, false), &group), &mut output);
+        assert!(output.iter().all(|&(left, right)| left == right));
+        // At 18.9 kHz output n sits 3n/7 samples in, so unit 1's first sample is fully reached at 68
+        assert_eq!(output[67], (0, 0));
+        assert_eq!(output[68], (1, 1));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,70 @@
+use std::collections::VecDeque;
+
+/// Keeps the most recently used decompressed hunks of an image
+#[derive(Debug)]
+pub struct HunkCache {
+    capacity: usize,
+    // Most recently used first
+    hunks: VecDeque<(u32, Box<[u8]>)>
+}
+
+impl HunkCache {
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, hunks: VecDeque::with_capacity(capacity) }
+    }
+
+    pub fn contains(&self, index: u32) -> bool {
+        self.hunks.iter().any(|(hunk, _)| *hunk == index)
+    }
+
+    /// Marks the hunk as just used
+    pub fn get(&mut self, index: u32) -> Option<&[u8]> {
+        let position = self.hunks.iter().position(|(hunk, _)| *hunk == index)?;
+        let entry = self.hunks.remove(position)?;
+        self.hunks.push_front(entry);
+        self.hunks.front().map(|(_, data)| &data[..])
+    }
+
+    /// Evicts the least recently used hunk if the cache is full
+    pub fn insert(&mut self, index: u32, data: Box<[u8]>) -> &[u8] {
+        if self.hunks.len() == self.capacity {
+            self.hunks.pop_back();
+        }
+        self.hunks.push_front((index, data));
+        &self.hunks[0].1
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hunk(value: u8) -> Box<[u8]> {
+        vec![value; 4].into_boxed_slice()
+    }
+
+    #[test]
+    fn evicts_least_recently_used() {
+        let mut cache = HunkCache::new(3);
+        assert_eq!(cache.insert(1, hunk(1)), [1; 4]);
+        cache.insert(2, hunk(2));
+        cache.insert(3, hunk(3));
+
+        // Reading 1 makes 2 the oldest
+        assert_eq!(cache.get(1), Some(&[1; 4][..]));
+        cache.insert(4, hunk(4));
+        assert!(!cache.contains(2));
+        assert!(cache.contains(1) && cache.contains(3) && cache.contains(4));
+
+        cache.insert(5, hunk(5));
+        assert!(!cache.contains(3));
+        cache.get(4);
+        cache.insert(6, hunk(6));
+        assert!(!cache.contains(1));
+        assert_eq!(cache.get(2), None);
+
+        let order: Vec<u32> = cache.hunks.iter().map(|(index, _)| *index).collect();
+        assert_eq!(order, [6, 4, 5]);
+    }
+}
\ No newline at end of file
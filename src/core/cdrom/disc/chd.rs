@@ -0,0 +1,731 @@
+use std::{collections::HashSet, fs::File, io::{self, Cursor, Read, Seek, SeekFrom}, path::Path};
+
+use spdlog::prelude::*;
+
+use super::{
+    super::super::region::Region,
+    Disc, Msf, PREGAP_SECTORS, SECTOR_SIZE, Track, TrackKind,
+    cache::HunkCache,
+    layout::{self, Layout, Location}
+};
+
+const MAGIC: &[u8; 8] = b"MComprHD";
+const HEADER_SIZE: usize = 124;
+
+/// A sector followed by its 96 bytes of subchannel data
+const FRAME_SIZE: usize = SECTOR_SIZE + SUBCODE_SIZE;
+const SUBCODE_SIZE: usize = 96;
+/// Every track is padded to a multiple of this many frames
+const TRACK_PADDING: u32 = 4;
+
+const CACHE_HUNKS: usize = 16;
+
+const fn tag(name: &[u8; 4]) -> u32 {
+    u32::from_be_bytes(*name)
+}
+
+const CODEC_CD_ZLIB: u32 = tag(b"cdzl");
+const CODEC_CD_LZMA: u32 = tag(b"cdlz");
+const CODEC_CD_FLAC: u32 = tag(b"cdfl");
+const CODEC_CD_ZSTD: u32 = tag(b"cdzs");
+
+const METADATA_TRACK: u32 = tag(b"CHTR");
+const METADATA_TRACK_2: u32 = tag(b"CHT2");
+
+// How a hunk is stored, as found in the map
+const COMPRESSION_TYPE_3: u8 = 3;
+const COMPRESSION_NONE: u8 = 4;
+const COMPRESSION_SELF: u8 = 5;
+const COMPRESSION_PARENT: u8 = 6;
+// Only used while decoding the map
+const COMPRESSION_RLE_SMALL: u8 = 7;
+const COMPRESSION_RLE_LARGE: u8 = 8;
+const COMPRESSION_SELF_0: u8 = 9;
+const COMPRESSION_SELF_1: u8 = 10;
+const COMPRESSION_PARENT_SELF: u8 = 11;
+const COMPRESSION_PARENT_0: u8 = 12;
+const COMPRESSION_PARENT_1: u8 = 13;
+
+#[derive(Debug, Clone, Copy)]
+struct MapEntry {
+    compression: u8,
+    length: u32,
+    // Byte offset in the file, or the source hunk for COMPRESSION_SELF
+    offset: u64,
+    crc: u16
+}
+
+/// A CHD v5 image of a CD, hunks are decompressed when first read
+#[derive(Debug)]
+pub struct Chd {
+    file: File,
+    codecs: [u32; 4],
+    hunk_bytes: u32,
+    map: Vec<MapEntry>,
+    cache: HunkCache,
+    layout: Layout,
+    region: Option<Region>
+}
+
+impl Chd {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let mut file = File::open(path)?;
+
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact(&mut header)?;
+        if &header[0..8] != MAGIC {
+            return Err(invalid("not a CHD file"));
+        }
+        let version = be32(&header[12..]);
+        if version != 5 {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("CHD version {} isn't supported", version)));
+        }
+        if header[104..124].iter().any(|&byte| byte != 0) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "CHDs with a parent aren't supported"));
+        }
+
+        let codecs = [be32(&header[16..]), be32(&header[20..]), be32(&header[24..]), be32(&header[28..])];
+        let logical_bytes = be64(&header[32..]);
+        let map_offset = be64(&header[40..]);
+        let metadata_offset = be64(&header[48..]);
+        let hunk_bytes = be32(&header[56..]);
+        let unit_bytes = be32(&header[60..]);
+
+        if unit_bytes as usize != FRAME_SIZE || hunk_bytes == 0 || !(hunk_bytes as usize).is_multiple_of(FRAME_SIZE) {
+            return Err(invalid("CHD isn't a CD image"));
+        }
+        if let Some(&codec) = codecs.iter().find(|&&codec| codec != 0 && ![CODEC_CD_ZLIB, CODEC_CD_LZMA, CODEC_CD_FLAC, CODEC_CD_ZSTD].contains(&codec)) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, format!("CHD codec {} isn't supported", String::from_utf8_lossy(&codec.to_be_bytes()))));
+        }
+
+        let hunk_count = logical_bytes.div_ceil(hunk_bytes as u64) as u32;
+        let map = match codecs[0] {
+            0 => read_raw_map(&mut file, map_offset, hunk_count, hunk_bytes)?,
+            _ => read_map(&mut file, map_offset, hunk_count, hunk_bytes, unit_bytes)?
+        };
+        let layout = read_tracks(&mut file, metadata_offset)?;
+
+        let mut chd = Self { file, codecs, hunk_bytes, map, cache: HunkCache::new(CACHE_HUNKS), layout, region: None };
+        chd.region = super::detect_region(&mut chd);
+        Ok(chd)
+    }
+
+    fn hunk(&mut self, index: u32) -> Result<&[u8], io::Error> {
+        if !self.cache.contains(index) {
+            let data = self.decompress_hunk(index)?;
+            return Ok(self.cache.insert(index, data));
+        }
+        Ok(self.cache.get(index).unwrap())
+    }
+
+    fn decompress_hunk(&mut self, index: u32) -> Result<Box<[u8]>, io::Error> {
+        let entry = *self.map.get(index as usize).ok_or_else(|| invalid("hunk past the end of the CHD"))?;
+        let mut data = vec![0; self.hunk_bytes as usize].into_boxed_slice();
+
+        match entry.compression {
+            0..=COMPRESSION_TYPE_3 => {
+                let mut compressed = vec![0; entry.length as usize];
+                self.file.seek(SeekFrom::Start(entry.offset))?;
+                self.file.read_exact(&mut compressed)?;
+                decompress_cd(self.codecs[entry.compression as usize], &compressed, &mut data)?;
+            }
+            // A zero offset in an uncompressed map is a hunk which was never written
+            COMPRESSION_NONE if entry.offset == 0 => return Ok(data),
+            COMPRESSION_NONE => {
+                self.file.seek(SeekFrom::Start(entry.offset))?;
+                self.file.read_exact(&mut data)?;
+            }
+            // Always refers to an earlier hunk, so this can't loop
+            COMPRESSION_SELF if entry.offset < index as u64 => {
+                data.copy_from_slice(self.hunk(entry.offset as u32)?);
+                return Ok(data);
+            }
+            _ => return Err(invalid("invalid hunk reference in the CHD map"))
+        }
+
+        if crc16(&data) != entry.crc {
+            return Err(invalid("hunk CRC mismatch"));
+        }
+        Ok(data)
+    }
+}
+
+impl Disc for Chd {
+    fn tracks(&self) -> &[Track] {
+        self.layout.tracks()
+    }
+
+    fn lead_out(&self) -> u32 {
+        self.layout.lead_out()
+    }
+
+    fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    fn read_sector(&mut self, lba: u32, buffer: &mut [u8; SECTOR_SIZE]) -> bool {
+        let (kind, position) = match self.layout.locate(lba) {
+            None => return false,
+            Some(Location::Gap(kind)) => {
+                layout::write_gap(lba, kind, buffer);
+                return true;
+            }
+            Some(Location::Stored { kind, position, .. }) => (kind, position)
+        };
+
+        let hunk_bytes = self.hunk_bytes as u64;
+        let offset = (position % hunk_bytes) as usize;
+        match self.hunk((position / hunk_bytes) as u32) {
+            Ok(hunk) => buffer.copy_from_slice(&hunk[offset..offset + SECTOR_SIZE]),
+            Err(error) => {
+                error!("[CHD] Failed to read sector {}: {}", Msf::from_lba(lba), error);
+                return false;
+            }
+        }
+
+        // CD audio is stored big endian
+        if kind == TrackKind::Audio {
+            for sample in buffer.chunks_exact_mut(2) {
+                sample.swap(0, 1);
+            }
+        }
+        true
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("CHD: {}", message))
+}
+
+fn be32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn be64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes[..8].try_into().unwrap())
+}
+
+// Maps without compression are just the hunk offsets divided by the hunk size
+fn read_raw_map(file: &mut File, offset: u64, hunk_count: u32, hunk_bytes: u32) -> Result<Vec<MapEntry>, io::Error> {
+    let mut raw = vec![0; hunk_count as usize * 4];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut raw)?;
+
+    Ok(raw
+        .chunks_exact(4)
+        .map(|entry| MapEntry { compression: COMPRESSION_NONE, length: hunk_bytes, offset: be32(entry) as u64 * hunk_bytes as u64, crc: 0 })
+        .collect())
+}
+
+// Compressed maps store Huffman coded compression types followed by lengths, offsets and CRCs
+fn read_map(file: &mut File, offset: u64, hunk_count: u32, hunk_bytes: u32, unit_bytes: u32) -> Result<Vec<MapEntry>, io::Error> {
+    let mut header = [0; 16];
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(&mut header)?;
+
+    let compressed_length = be32(&header[0..]) as usize;
+    let mut first_offset = [0; 8];
+    first_offset[2..].copy_from_slice(&header[4..10]);
+    let map_crc = u16::from_be_bytes([header[10], header[11]]);
+    let (length_bits, self_bits, parent_bits) = (header[12] as u32, header[13] as u32, header[14] as u32);
+
+    let mut compressed = vec![0; compressed_length];
+    file.read_exact(&mut compressed)?;
+    let mut bits = BitReader::new(&compressed);
+
+    let decoder = Huffman::read(&mut bits)?;
+    let mut compression = Vec::with_capacity(hunk_count as usize);
+    let mut last = 0;
+    let mut repeat = 0;
+    for _ in 0..hunk_count {
+        if repeat > 0 {
+            repeat -= 1;
+            compression.push(last);
+            continue;
+        }
+
+        match decoder.decode(&mut bits) {
+            COMPRESSION_RLE_SMALL => repeat = 2 + decoder.decode(&mut bits) as u32,
+            COMPRESSION_RLE_LARGE => repeat = 2 + 16 + ((decoder.decode(&mut bits) as u32) << 4) + decoder.decode(&mut bits) as u32,
+            value => last = value
+        }
+        compression.push(last);
+    }
+
+    let mut map = Vec::with_capacity(hunk_count as usize);
+    let mut raw = Vec::with_capacity(hunk_count as usize * 12);
+    let mut position = u64::from_be_bytes(first_offset);
+    let (mut last_self, mut last_parent) = (0, 0);
+    for (index, compression) in compression.into_iter().enumerate() {
+        let mut entry = MapEntry { compression, length: 0, offset: position, crc: 0 };
+
+        match compression {
+            0..=COMPRESSION_TYPE_3 => {
+                entry.length = bits.read(length_bits) as u32;
+                entry.crc = bits.read(16) as u16;
+                position += entry.length as u64;
+            }
+            COMPRESSION_NONE => {
+                entry.length = hunk_bytes;
+                entry.crc = bits.read(16) as u16;
+                position += entry.length as u64;
+            }
+            COMPRESSION_SELF => {
+                last_self = bits.read(self_bits);
+                entry.offset = last_self;
+            }
+            COMPRESSION_PARENT => {
+                last_parent = bits.read(parent_bits);
+                entry.offset = last_parent;
+            }
+            COMPRESSION_SELF_0 | COMPRESSION_SELF_1 => {
+                if compression == COMPRESSION_SELF_1 {
+                    last_self += 1;
+                }
+                entry.compression = COMPRESSION_SELF;
+                entry.offset = last_self;
+            }
+            COMPRESSION_PARENT_SELF => {
+                last_parent = index as u64 * hunk_bytes as u64 / unit_bytes as u64;
+                entry.compression = COMPRESSION_PARENT;
+                entry.offset = last_parent;
+            }
+            COMPRESSION_PARENT_0 | COMPRESSION_PARENT_1 => {
+                if compression == COMPRESSION_PARENT_1 {
+                    last_parent += (hunk_bytes / unit_bytes) as u64;
+                }
+                entry.compression = COMPRESSION_PARENT;
+                entry.offset = last_parent;
+            }
+            _ => return Err(invalid("invalid compression type in the map"))
+        }
+
+        raw.push(entry.compression);
+        raw.extend_from_slice(&entry.length.to_be_bytes()[1..]);
+        raw.extend_from_slice(&entry.offset.to_be_bytes()[2..]);
+        raw.extend_from_slice(&entry.crc.to_be_bytes());
+        map.push(entry);
+    }
+
+    if crc16(&raw) != map_crc {
+        return Err(invalid("map CRC mismatch"));
+    }
+    Ok(map)
+}
+
+// The TOC is kept as text metadata, one entry per track
+fn read_tracks(file: &mut File, mut offset: u64) -> Result<Layout, io::Error> {
+    let mut layout = Layout::new();
+    let mut position = 0;
+    let mut frame = 0;
+    // Entries are chained by offsets from the file, which could point back at an earlier one
+    let mut visited = HashSet::new();
+
+    while offset != 0 {
+        if !visited.insert(offset) {
+            return Err(invalid("metadata loop"));
+        }
+
+        let mut header = [0; 16];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut header)?;
+
+        let tag = be32(&header[0..]);
+        let length = be32(&header[4..]) & 0xFFFFFF;
+        offset = be64(&header[8..]);
+        if tag != METADATA_TRACK && tag != METADATA_TRACK_2 {
+            continue;
+        }
+
+        let mut text = vec![0; length as usize];
+        file.read_exact(&mut text)?;
+        let text = String::from_utf8_lossy(&text);
+        let field = |name: &str| {
+            text.split_whitespace()
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix(':'))
+                .map(|value| value.trim_end_matches('\0'))
+        };
+        let number = |name: &str| field(name).map_or(Ok(0), |value| value.parse::<u32>().map_err(|_| invalid("invalid track metadata")));
+
+        let kind = match field("TYPE") {
+            Some("MODE1_RAW") => TrackKind::Mode1,
+            Some("MODE2_RAW") => TrackKind::Mode2,
+            Some("AUDIO") => TrackKind::Audio,
+            Some(kind) => return Err(io::Error::new(io::ErrorKind::Unsupported, format!("CHD track type {} isn't supported", kind))),
+            None => return Err(invalid("track without a type"))
+        };
+        let track = number("TRACK")?;
+        let frames = number("FRAMES")?;
+        let pregap = number("PREGAP")?;
+        // A pregap type starting with V means the pregap is stored along with the track
+        let stored_pregap = if field("PGTYPE").is_some_and(|kind| kind.starts_with('V')) { pregap.min(frames) } else { 0 };
+
+        if track == 1 {
+            position = PREGAP_SECTORS.saturating_sub(pregap);
+        }
+        if pregap > stored_pregap {
+            layout.add_gap(position, pregap - stored_pregap, kind);
+            position += pregap - stored_pregap;
+        }
+        layout.add_stored(position, frames, kind, 0, frame as u64 * FRAME_SIZE as u64, FRAME_SIZE);
+        layout.add_track(track as u8, kind, position + stored_pregap, pregap);
+
+        position += frames;
+        frame += frames.next_multiple_of(TRACK_PADDING);
+    }
+
+    layout.finish(position)
+}
+
+// CD codecs compress sector data and subcode separately, sectors whose sync and ECC could be
+// regenerated have them stripped
+fn decompress_cd(codec: u32, source: &[u8], hunk: &mut [u8]) -> Result<(), io::Error> {
+    let frames = hunk.len() / FRAME_SIZE;
+    let mut sectors = vec![0; frames * SECTOR_SIZE];
+    let mut subcode = vec![0; frames * SUBCODE_SIZE];
+
+    let mut ecc = &[][..];
+    match codec {
+        CODEC_CD_FLAC => {
+            let length = decode_flac(source, &mut sectors)?;
+            inflate(&source[length..], &mut subcode)?;
+        }
+        _ => {
+            let ecc_bytes = frames.div_ceil(8);
+            let length_bytes = if hunk.len() < 65536 { 2 } else { 3 };
+            let header_bytes = ecc_bytes + length_bytes;
+            if source.len() < header_bytes {
+                return Err(invalid("truncated hunk"));
+            }
+            ecc = &source[..ecc_bytes];
+
+            let base_length = source[ecc_bytes..header_bytes].iter().fold(0, |length, &byte| (length << 8) | byte as usize);
+            let base = source.get(header_bytes..header_bytes + base_length).ok_or_else(|| invalid("truncated hunk"))?;
+            let rest = &source[header_bytes + base_length..];
+
+            match codec {
+                CODEC_CD_ZLIB => {
+                    inflate(base, &mut sectors)?;
+                    inflate(rest, &mut subcode)?;
+                }
+                CODEC_CD_LZMA => {
+                    decode_lzma(base, &mut sectors)?;
+                    inflate(rest, &mut subcode)?;
+                }
+                CODEC_CD_ZSTD => {
+                    decode_zstd(base, &mut sectors)?;
+                    decode_zstd(rest, &mut subcode)?;
+                }
+                _ => return Err(invalid("hunk uses an unknown codec"))
+            }
+        }
+    }
+
+    for (index, frame) in hunk.chunks_exact_mut(FRAME_SIZE).enumerate() {
+        let sector: &mut [u8; SECTOR_SIZE] = (&mut frame[..SECTOR_SIZE]).try_into().unwrap();
+        sector.copy_from_slice(&sectors[index * SECTOR_SIZE..][..SECTOR_SIZE]);
+        if ecc.get(index / 8).is_some_and(|&bits| bits & (1 << (index % 8)) != 0) {
+            sector[..12].copy_from_slice(&[0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0]);
+            generate_ecc(sector);
+        }
+        frame[SECTOR_SIZE..].copy_from_slice(&subcode[index * SUBCODE_SIZE..][..SUBCODE_SIZE]);
+    }
+
+    Ok(())
+}
+
+// Raw deflate, without a zlib header
+fn inflate(source: &[u8], output: &mut [u8]) -> Result<(), io::Error> {
+    match miniz_oxide::inflate::decompress_slice_iter_to_slice(output, std::iter::once(source), false, true) {
+        Ok(length) if length == output.len() => Ok(()),
+        Ok(_) => Err(invalid("deflate data ended early")),
+        Err(status) => Err(invalid(&format!("deflate error {:?}", status)))
+    }
+}
+
+// Raw LZMA without a header, the properties are always the ones of level 9
+fn decode_lzma(source: &[u8], output: &mut [u8]) -> Result<(), io::Error> {
+    use lzma_rs::decompress::raw::{LzmaDecoder, LzmaParams, LzmaProperties};
+
+    let properties = LzmaProperties { lc: 3, lp: 0, pb: 2 };
+    // The whole hunk fits in the window, which is all the dictionary size is for
+    let params = LzmaParams::new(properties, output.len().max(4096) as u32, Some(output.len() as u64));
+    let mut decoder = LzmaDecoder::new(params, None).map_err(|error| invalid(&format!("LZMA error {}", error)))?;
+
+    let length = output.len();
+    let mut writer = &mut output[..];
+    decoder.decompress(&mut &source[..], &mut writer).map_err(|error| invalid(&format!("LZMA error {}", error)))?;
+    match writer.is_empty() {
+        true => Ok(()),
+        false => Err(invalid(&format!("LZMA data ended after {} of {} bytes", length - writer.len(), length)))
+    }
+}
+
+fn decode_zstd(source: &[u8], output: &mut [u8]) -> Result<(), io::Error> {
+    let mut decoder = ruzstd::decoding::StreamingDecoder::new(source).map_err(|error| invalid(&format!("zstd error {}", error)))?;
+    decoder.read_exact(output)
+}
+
+// FLAC frames without a stream header, 16-bit stereo written back big endian, returns how much
+// of the source the frames took up
+fn decode_flac(source: &[u8], output: &mut [u8]) -> Result<usize, io::Error> {
+    let mut reader = claxon::frame::FrameReader::new(Cursor::new(source));
+    let mut buffer = Vec::new();
+
+    let mut samples = output.chunks_exact_mut(4);
+    let mut remaining = samples.len();
+    while remaining > 0 {
+        let block = reader
+            .read_next_or_eof(buffer)
+            .map_err(|error| invalid(&format!("FLAC error {}", error)))?
+            .ok_or_else(|| invalid("FLAC data ended early"))?;
+
+        for ((left, right), sample) in block.stereo_samples().zip(samples.by_ref()) {
+            sample[0..2].copy_from_slice(&(left as i16).to_be_bytes());
+            sample[2..4].copy_from_slice(&(right as i16).to_be_bytes());
+            remaining -= 1;
+        }
+        buffer = block.into_buffer();
+    }
+
+    Ok(reader.into_inner().position() as usize)
+}
+
+// Reads bits starting from the most significant one, past the end everything reads as zero
+struct BitReader<'a> {
+    data: &'a [u8],
+    position: usize
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, position: 0 }
+    }
+
+    fn peek(&self, count: u32) -> u64 {
+        (0..count as usize).fold(0, |value, bit| {
+            let position = self.position + bit;
+            let byte = self.data.get(position / 8).copied().unwrap_or(0);
+            (value << 1) | ((byte >> (7 - position % 8)) & 1) as u64
+        })
+    }
+
+    fn read(&mut self, count: u32) -> u64 {
+        let value = self.peek(count);
+        self.position += count as usize;
+        value
+    }
+}
+
+// Canonical Huffman decoder for the 16 map symbols, codes are at most 8 bits long
+struct Huffman {
+    // (symbol, code length) for every 8-bit prefix
+    lookup: [(u8, u8); 256]
+}
+
+impl Huffman {
+    const CODES: usize = 16;
+    const MAX_BITS: u32 = 8;
+
+    // The code lengths are stored run-length encoded, 4 bits each
+    fn read(bits: &mut BitReader) -> Result<Self, io::Error> {
+        let mut lengths = [0u8; Self::CODES];
+        let mut code = 0;
+        while code < Self::CODES {
+            let mut length = bits.read(4) as u8;
+            let mut count = 1;
+            if length == 1 {
+                length = bits.read(4) as u8;
+                if length != 1 {
+                    count = bits.read(4) as usize + 3;
+                }
+            }
+            if code + count > Self::CODES {
+                return Err(invalid("invalid Huffman tree in the map"));
+            }
+            lengths[code..code + count].fill(length);
+            code += count;
+        }
+
+        let mut histogram = [0u32; 33];
+        for &length in &lengths {
+            if length as u32 > Self::MAX_BITS {
+                return Err(invalid("invalid Huffman tree in the map"));
+            }
+            histogram[length as usize] += 1;
+        }
+        let mut start = 0;
+        for length in (1..=32).rev() {
+            let next = (start + histogram[length]) >> 1;
+            if length != 1 && next * 2 != start + histogram[length] {
+                return Err(invalid("invalid Huffman tree in the map"));
+            }
+            histogram[length] = start;
+            start = next;
+        }
+
+        let mut lookup = [(0, 0); 256];
+        for (symbol, &length) in lengths.iter().enumerate() {
+            if length == 0 {
+                continue;
+            }
+            let code = histogram[length as usize];
+            histogram[length as usize] += 1;
+
+            let shift = Self::MAX_BITS - length as u32;
+            let first = (code << shift) as usize;
+            lookup[first..first + (1 << shift)].fill((symbol as u8, length));
+        }
+
+        Ok(Self { lookup })
+    }
+
+    fn decode(&self, bits: &mut BitReader) -> u8 {
+        let (symbol, length) = self.lookup[bits.peek(Self::MAX_BITS) as usize];
+        bits.position += length as usize;
+        symbol
+    }
+}
+
+// CRC-16/CCITT, used for the map and every hunk
+fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0xFFFF, |crc, &byte| {
+        (0..8).fold(crc ^ ((byte as u16) << 8), |crc, _| match crc & 0x8000 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x1021
+        })
+    })
+}
+
+// Multiplication by x in GF(2^8) and its inverse applied to (x ^ 1)
+const ECC_TABLES: ([u8; 256], [u8; 256]) = {
+    let mut forward = [0; 256];
+    let mut backward = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let j = (i << 1) ^ if i & 0x80 != 0 { 0x11D } else { 0 };
+        forward[i] = j as u8;
+        backward[i ^ j] = i as u8;
+        i += 1;
+    }
+    (forward, backward)
+};
+
+// Regenerates the P and Q parity of a Mode 1 sector
+fn generate_ecc(sector: &mut [u8; SECTOR_SIZE]) {
+    compute_ecc_block(sector, 86, 24, 2, 86, 0x81C);
+    compute_ecc_block(sector, 52, 43, 86, 88, 0x8C8);
+}
+
+fn compute_ecc_block(sector: &mut [u8; SECTOR_SIZE], major_count: usize, minor_count: usize, major_mult: usize, minor_inc: usize, destination: usize) {
+    let (forward, backward) = &ECC_TABLES;
+    let size = major_count * minor_count;
+
+    for major in 0..major_count {
+        let mut index = (major >> 1) * major_mult + (major & 1);
+        let (mut a, mut b) = (0u8, 0u8);
+        for _ in 0..minor_count {
+            let value = sector[0xC + index];
+            index += minor_inc;
+            if index >= size {
+                index -= size;
+            }
+            a ^= value;
+            b ^= value;
+            a = forward[a as usize];
+        }
+        a = backward[(forward[a as usize] ^ b) as usize];
+        sector[destination + major] = a;
+        sector[destination + major + major_count] = a ^ b;
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn temp_file(name: &str, data: &[u8]) -> File {
+        let path = std::env::temp_dir().join(format!("shiranuhi-chd-{}-{}", std::process::id(), name));
+        fs::write(&path, data).unwrap();
+        let file = File::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        file
+    }
+
+    fn metadata(tag: &[u8; 4], text: &str, next: u64) -> Vec<u8> {
+        let mut entry = tag.to_vec();
+        entry.extend((text.len() as u32).to_be_bytes());
+        entry.extend(next.to_be_bytes());
+        entry.extend(text.as_bytes());
+        entry
+    }
+
+    #[test]
+    fn self_references_copy_earlier_hunks() {
+        let hunk_bytes = FRAME_SIZE as u32;
+        let mut data = vec![0; 16];
+        data.extend((0..FRAME_SIZE).map(|byte| byte as u8));
+        let crc = crc16(&data[16..]);
+
+        let none = |offset| MapEntry { compression: COMPRESSION_NONE, length: hunk_bytes, offset, crc };
+        let reference = |hunk| MapEntry { compression: COMPRESSION_SELF, length: 0, offset: hunk, crc: 0 };
+        let map = vec![none(16), reference(0), reference(1), reference(3), reference(7), none(0)];
+
+        let mut chd = Chd {
+            file: temp_file("self", &data),
+            codecs: [0; 4],
+            hunk_bytes,
+            map,
+            cache: HunkCache::new(2),
+            layout: Layout::new(),
+            region: None
+        };
+
+        let expected = chd.hunk(0).unwrap().to_vec();
+        assert_eq!(expected, data[16..]);
+        assert_eq!(chd.hunk(1).unwrap(), expected);
+        // A chain of references ends at the stored hunk
+        assert_eq!(chd.hunk(2).unwrap(), expected);
+        // References to the hunk itself or later ones would never resolve
+        assert!(chd.hunk(3).is_err());
+        assert!(chd.hunk(4).is_err());
+        assert!(chd.hunk(5).unwrap().iter().all(|&byte| byte == 0));
+        assert!(chd.hunk(6).is_err());
+    }
+
+    #[test]
+    fn reads_track_metadata() {
+        let mut data = vec![0; 8];
+        data.extend(metadata(b"CHT2", "TRACK:1 TYPE:MODE2_RAW SUBTYPE:NONE FRAMES:1000 PREGAP:0 PGTYPE:MODE2_RAW\0", 0));
+        let second_offset = data.len() as u64;
+        data.extend(metadata(b"CHT2", "TRACK:2 TYPE:AUDIO SUBTYPE:NONE FRAMES:300 PREGAP:150 PGTYPE:VAUDIO\0", 0));
+        data[16..24].copy_from_slice(&second_offset.to_be_bytes());
+
+        let layout = read_tracks(&mut temp_file("tracks", &data), 8).unwrap();
+        assert_eq!(layout.tracks(), [
+            Track { number: 1, kind: TrackKind::Mode2, start: 150, pregap: 150, length: 1000 },
+            Track { number: 2, kind: TrackKind::Audio, start: 1300, pregap: 150, length: 150 }
+        ]);
+        assert_eq!(layout.lead_out(), 1450);
+        // Track 2 starts on a 4 frame boundary of the image, its pregap is stored
+        assert_eq!(layout.locate(1150), Some(Location::Stored {
+            kind: TrackKind::Audio, file: 0, position: 1000 * FRAME_SIZE as u64, sector_size: FRAME_SIZE
+        }));
+    }
+
+    #[test]
+    fn rejects_metadata_loops() {
+        let mut data = vec![0; 8];
+        data.extend(metadata(b"CHT2", "TRACK:1 TYPE:MODE2_RAW FRAMES:1000", 0));
+        let second_offset = data.len() as u64;
+        data.extend(metadata(b"CHT2", "TRACK:2 TYPE:AUDIO FRAMES:300", 8));
+        data[16..24].copy_from_slice(&second_offset.to_be_bytes());
+
+        let error = read_tracks(&mut temp_file("loop", &data), 8).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("metadata loop"), "{}", error);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,225 @@
+use std::{io, path::{Path, PathBuf}};
+
+use super::{Msf, TrackKind};
+
+/// A FILE entry of a CUE sheet and the tracks stored in it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CueFile {
+    pub path: PathBuf,
+    pub tracks: Vec<CueTrack>
+}
+
+/// Positions are in sectors from the start of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CueTrack {
+    pub number: u8,
+    pub kind: TrackKind,
+    pub sector_size: usize,
+    /// PREGAP, silence which isn't stored in the file
+    pub pregap: u32,
+    pub index0: Option<u32>,
+    pub index1: u32
+}
+
+/// Parses a CUE sheet, FILE paths are taken relative to `directory`
+pub fn parse(text: &str, directory: &Path) -> Result<Vec<CueFile>, io::Error> {
+    let mut files: Vec<CueFile> = Vec::new();
+    // INDEX 01 is mandatory, it's checked once the track is over
+    let mut index1_seen = true;
+
+    for (number, line) in text.lines().enumerate() {
+        let error = |message: &str| invalid(format!("line {}: {}", number + 1, message));
+
+        let tokens = tokenize(line);
+        let Some(command) = tokens.first() else {
+            continue;
+        };
+
+        match command.to_ascii_uppercase().as_str() {
+            "FILE" => {
+                let name = tokens.get(1).ok_or_else(|| error("FILE without a name"))?;
+                if let Some(kind) = tokens.get(2) && !kind.eq_ignore_ascii_case("BINARY") {
+                    return Err(error(&format!("unsupported file type {}", kind)));
+                }
+
+                files.push(CueFile { path: directory.join(name), tracks: Vec::new() });
+            }
+            "TRACK" => {
+                if !index1_seen {
+                    return Err(error("previous track has no INDEX 01"));
+                }
+                let file = files.last_mut().ok_or_else(|| error("TRACK before FILE"))?;
+
+                let number = tokens
+                    .get(1)
+                    .and_then(|number| number.parse().ok())
+                    .ok_or_else(|| error("invalid track number"))?;
+                let (kind, sector_size) = match tokens.get(2).map(|mode| mode.to_ascii_uppercase()).as_deref() {
+                    Some("MODE1/2352") => (TrackKind::Mode1, 2352),
+                    Some("MODE2/2352") => (TrackKind::Mode2, 2352),
+                    Some("MODE1/2048") => (TrackKind::Mode1, 2048),
+                    Some("AUDIO") => (TrackKind::Audio, 2352),
+                    _ => return Err(error("unsupported track mode"))
+                };
+
+                file.tracks.push(CueTrack { number, kind, sector_size, pregap: 0, index0: None, index1: 0 });
+                index1_seen = false;
+            }
+            "INDEX" | "PREGAP" => {
+                let track = files
+                    .last_mut()
+                    .and_then(|file| file.tracks.last_mut())
+                    .ok_or_else(|| error("index outside of a track"))?;
+
+                let (argument, time) = match command.to_ascii_uppercase().as_str() {
+                    "PREGAP" => (None, tokens.get(1)),
+                    _ => (tokens.get(1), tokens.get(2))
+                };
+                let sectors = time
+                    .and_then(|time| parse_msf(time))
+                    .ok_or_else(|| error("invalid time"))?
+                    .to_lba();
+
+                match argument.map(|index| index.parse::<u8>()) {
+                    None => track.pregap = sectors,
+                    Some(Ok(0)) => track.index0 = Some(sectors),
+                    Some(Ok(1)) => {
+                        track.index1 = sectors;
+                        index1_seen = true;
+                    }
+                    // Subindices past 01 don't change where anything is
+                    Some(Ok(_)) => {}
+                    Some(Err(_)) => return Err(error("invalid index number"))
+                }
+            }
+            // Metadata which doesn't affect the layout
+            "REM" | "CATALOG" | "CDTEXTFILE" | "FLAGS" | "ISRC" | "PERFORMER" | "SONGWRITER" | "TITLE" | "POSTGAP" => {}
+            _ => return Err(error(&format!("unknown command {}", command)))
+        }
+    }
+
+    if !index1_seen {
+        return Err(invalid("last track has no INDEX 01".to_string()));
+    }
+    if files.is_empty() {
+        return Err(invalid("no tracks".to_string()));
+    }
+    if let Some(file) = files.iter().find(|file| file.tracks.is_empty()) {
+        return Err(invalid(format!("{} has no tracks", file.path.display())));
+    }
+
+    Ok(files)
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("CUE sheet: {}", message))
+}
+
+// Splits on whitespace, keeping quoted strings together
+fn tokenize(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+
+    let mut chars = line.trim().chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        let token = if c == '"' {
+            chars.next();
+            chars.by_ref().take_while(|&c| c != '"').collect()
+        } else {
+            let mut token = String::new();
+            while let Some(&c) = chars.peek() && !c.is_whitespace() {
+                token.push(c);
+                chars.next();
+            }
+            token
+        };
+        tokens.push(token);
+    }
+
+    tokens
+}
+
+// CUE times are mm:ss:ff in decimal
+fn parse_msf(time: &str) -> Option<Msf> {
+    let mut parts = time.split(':').map(|part| part.parse::<u8>().ok());
+    let msf = Msf { minute: parts.next()??, second: parts.next()??, frame: parts.next()?? };
+
+    (parts.next().is_none() && msf.second < 60 && msf.frame < 75).then_some(msf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(number: u8, kind: TrackKind, pregap: u32, index0: Option<u32>, index1: u32) -> CueTrack {
+        let sector_size = if kind == TrackKind::Mode1 { 2048 } else { 2352 };
+        CueTrack { number, kind, sector_size, pregap, index0, index1 }
+    }
+
+    fn file(name: &str, tracks: Vec<CueTrack>) -> CueFile {
+        CueFile { path: Path::new("/games").join(name), tracks }
+    }
+
+    #[test]
+    fn parses_sheets() {
+        let sheets = [
+            (
+                "single track",
+                "FILE \"Game (USA).bin\" BINARY\n  TRACK 01 MODE2/2352\n    INDEX 01 00:00:00\n",
+                vec![file("Game (USA).bin", vec![track(1, TrackKind::Mode2, 0, None, 0)])]
+            ),
+            (
+                "index 00 and pregap",
+                "REM comment\r\nFILE game.bin BINARY\r\n  TRACK 01 MODE1/2048\r\n    INDEX 01 00:00:00\r\n  \
+                 TRACK 02 AUDIO\r\n    PREGAP 00:02:00\r\n    INDEX 00 01:00:00\r\n    INDEX 01 01:00:74\r\n    INDEX 02 01:01:00\r\n",
+                vec![file("game.bin", vec![
+                    track(1, TrackKind::Mode1, 0, None, 0),
+                    track(2, TrackKind::Audio, 150, Some(4500), 4574)
+                ])]
+            ),
+            (
+                "multiple files",
+                "FILE \"a.bin\" BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:00:00\n\
+                 FILE \"b.bin\" BINARY\nTRACK 02 AUDIO\nINDEX 00 00:00:00\nINDEX 01 00:02:00\n\
+                 FILE \"c.bin\" BINARY\nTRACK 03 AUDIO\nFLAGS DCP\nINDEX 01 00:00:00\n",
+                vec![
+                    file("a.bin", vec![track(1, TrackKind::Mode2, 0, None, 0)]),
+                    file("b.bin", vec![track(2, TrackKind::Audio, 0, Some(0), 150)]),
+                    file("c.bin", vec![track(3, TrackKind::Audio, 0, None, 0)])
+                ]
+            )
+        ];
+
+        for (name, sheet, expected) in sheets {
+            let files = parse(sheet, Path::new("/games")).unwrap_or_else(|error| panic!("{}: {}", name, error));
+            assert_eq!(files, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn rejects_invalid_sheets() {
+        let sheets = [
+            ("TRACK 01 MODE2/2352\nINDEX 01 00:00:00\n", "TRACK before FILE"),
+            ("FILE a.bin BINARY\nTRACK 01 MODE2/2352\n", "no INDEX 01"),
+            ("FILE a.bin BINARY\nTRACK 01 MODE2/2352\nTRACK 02 AUDIO\nINDEX 01 00:00:00\n", "no INDEX 01"),
+            ("FILE a.bin WAVE\nTRACK 01 AUDIO\nINDEX 01 00:00:00\n", "unsupported file type"),
+            ("FILE a.bin BINARY\nTRACK 01 CDG\nINDEX 01 00:00:00\n", "unsupported track mode"),
+            ("FILE a.bin BINARY\nTRACK 01 AUDIO\nINDEX 01 00:60:00\n", "invalid time"),
+            ("FILE a.bin BINARY\nTRACK 01 AUDIO\nINDEX 01 00:00:75\n", "invalid time"),
+            ("FILE a.bin BINARY\nINDEX 01 00:00:00\n", "index outside of a track"),
+            ("FILE a.bin BINARY\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nFILE b.bin BINARY\n", "has no tracks"),
+            ("FILE a.bin BINARY\nTRACK 01 AUDIO\nINDEX 01 00:00:00\nSESSION 2\n", "unknown command"),
+            ("REM nothing\n", "no tracks")
+        ];
+
+        for (sheet, message) in sheets {
+            let error = parse(sheet, Path::new("")).expect_err(sheet);
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", sheet);
+            assert!(error.to_string().contains(message), "{}: {}", sheet, error);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,211 @@
+use std::{fs::{self, File}, io::{self, Read, Seek, SeekFrom}, path::Path};
+
+use spdlog::prelude::*;
+
+use super::{
+    super::super::region::Region,
+    Disc, Msf, PREGAP_SECTORS, SECTOR_SIZE, Track, TrackKind, cue,
+    layout::{self, Layout, Location}
+};
+
+/// Size of a cooked sector holding only user data
+const DATA_SIZE: usize = 2048;
+
+/// A disc stored as sector dumps in one or more files, BIN/CUE or ISO
+#[derive(Debug)]
+pub struct Image {
+    files: Vec<File>,
+    layout: Layout,
+    region: Option<Region>
+}
+
+impl Image {
+    /// Opens a CUE sheet along with the files it refers to
+    pub fn open_cue(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)?;
+        let cue = cue::parse(&text, path.parent().unwrap_or(Path::new("")))?;
+
+        let mut files = Vec::new();
+        let mut layout = Layout::new();
+        // Track 1's INDEX 01 always lands on 00:02:00
+        let first = &cue[0].tracks[0];
+        let mut position = PREGAP_SECTORS.saturating_sub(first.index1 + first.pregap);
+
+        for cue_file in &cue {
+            let file = File::open(&cue_file.path)
+                .map_err(|error| io::Error::new(error.kind(), format!("{}: {}", cue_file.path.display(), error)))?;
+            let file_size = file.metadata()?.len();
+            let file_index = files.len();
+            files.push(file);
+
+            let mut offset = 0;
+            for (index, track) in cue_file.tracks.iter().enumerate() {
+                let first_sector = track.index0.unwrap_or(track.index1);
+                let next_sector = match cue_file.tracks.get(index + 1) {
+                    Some(next) => next.index0.unwrap_or(next.index1),
+                    None => first_sector + (file_size.saturating_sub(offset) / track.sector_size as u64) as u32
+                };
+                if next_sector < first_sector || track.index1 < first_sector {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, format!("CUE sheet: track {} is out of order", track.number)));
+                }
+
+                if track.pregap > 0 {
+                    layout.add_gap(position + first_sector, track.pregap, track.kind);
+                    position += track.pregap;
+                }
+                layout.add_stored(position + first_sector, next_sector - first_sector, track.kind, file_index, offset, track.sector_size);
+                layout.add_track(track.number, track.kind, position + track.index1, track.pregap + track.index1 - first_sector);
+
+                offset += (next_sector - first_sector) as u64 * track.sector_size as u64;
+                if cue_file.tracks.get(index + 1).is_none() {
+                    position += next_sector;
+                }
+            }
+        }
+
+        Self::new(files, layout.finish(position)?)
+    }
+
+    /// Opens a plain ISO, a single data track of 2048 byte sectors
+    pub fn open_iso(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let file = File::open(path)?;
+        let length = (file.metadata()?.len() / DATA_SIZE as u64) as u32;
+
+        let mut layout = Layout::new();
+        layout.add_stored(PREGAP_SECTORS, length, TrackKind::Mode2, 0, 0, DATA_SIZE);
+        layout.add_track(1, TrackKind::Mode2, PREGAP_SECTORS, 0);
+
+        Self::new(vec![file], layout.finish(PREGAP_SECTORS + length)?)
+    }
+
+    fn new(files: Vec<File>, layout: Layout) -> Result<Self, io::Error> {
+        let mut image = Self { files, layout, region: None };
+        image.region = super::detect_region(&mut image);
+        Ok(image)
+    }
+}
+
+impl Disc for Image {
+    fn tracks(&self) -> &[Track] {
+        self.layout.tracks()
+    }
+
+    fn lead_out(&self) -> u32 {
+        self.layout.lead_out()
+    }
+
+    fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    fn read_sector(&mut self, lba: u32, buffer: &mut [u8; SECTOR_SIZE]) -> bool {
+        let (file, position, sector_size) = match self.layout.locate(lba) {
+            None => return false,
+            Some(Location::Gap(kind)) => {
+                layout::write_gap(lba, kind, buffer);
+                return true;
+            }
+            Some(Location::Stored { file, position, sector_size, .. }) => (&mut self.files[file], position, sector_size)
+        };
+
+        let result = file.seek(SeekFrom::Start(position)).and_then(|_| match sector_size {
+            SECTOR_SIZE => file.read_exact(buffer),
+            _ => {
+                buffer.fill(0);
+                layout::write_header(lba, buffer);
+                file.read_exact(&mut buffer[24..24 + DATA_SIZE])
+            }
+        });
+
+        match result {
+            Ok(()) => true,
+            Err(error) => {
+                error!("[DISC] Failed to read sector {}: {}", Msf::from_lba(lba), error);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // (file name, sectors of 2352 bytes)
+    type Files<'a> = &'a [(&'a str, u32)];
+
+    fn open(name: &str, sheet: &str, files: Files) -> Image {
+        let directory = std::env::temp_dir().join(format!("shiranuhi-cue-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&directory).unwrap();
+        for &(file, sectors) in files {
+            fs::write(directory.join(file), vec![0; sectors as usize * SECTOR_SIZE]).unwrap();
+        }
+        let cue = directory.join("disc.cue");
+        fs::write(&cue, sheet).unwrap();
+
+        let image = Image::open_cue(&cue);
+        fs::remove_dir_all(&directory).unwrap();
+        image.unwrap_or_else(|error| panic!("{}: {}", name, error))
+    }
+
+    fn track(number: u8, kind: TrackKind, start: u32, pregap: u32, length: u32) -> Track {
+        Track { number, kind, start, pregap, length }
+    }
+
+    fn stored(kind: TrackKind, file: usize, sector: u64) -> Option<Location> {
+        Some(Location::Stored { kind, file, position: sector * SECTOR_SIZE as u64, sector_size: SECTOR_SIZE })
+    }
+
+    #[test]
+    fn first_track_starts_at_two_seconds() {
+        let image = open("single", "FILE a.bin BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:00:00\n", &[("a.bin", 10)]);
+
+        assert_eq!(image.tracks(), [track(1, TrackKind::Mode2, 150, 150, 10)]);
+        assert_eq!(image.lead_out(), 160);
+        assert_eq!(image.layout.locate(149), Some(Location::Gap(TrackKind::Mode2)));
+        assert_eq!(image.layout.locate(150), stored(TrackKind::Mode2, 0, 0));
+        assert_eq!(image.layout.locate(160), None);
+    }
+
+    #[test]
+    fn stored_first_pregap_is_not_doubled() {
+        let sheet = "FILE a.bin BINARY\nTRACK 01 MODE2/2352\nINDEX 00 00:00:00\nINDEX 01 00:02:00\n";
+        let image = open("stored-pregap", sheet, &[("a.bin", 160)]);
+
+        assert_eq!(image.tracks(), [track(1, TrackKind::Mode2, 150, 150, 10)]);
+        assert_eq!(image.lead_out(), 160);
+        assert_eq!(image.layout.locate(0), stored(TrackKind::Mode2, 0, 0));
+        assert_eq!(image.layout.locate(150), stored(TrackKind::Mode2, 0, 150));
+    }
+
+    #[test]
+    fn index0_is_stored_pregap() {
+        let sheet = "FILE a.bin BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:00:00\nTRACK 02 AUDIO\nINDEX 00 00:00:10\nINDEX 01 00:00:12\n";
+        let image = open("index0", sheet, &[("a.bin", 20)]);
+
+        assert_eq!(image.tracks(), [track(1, TrackKind::Mode2, 150, 150, 10), track(2, TrackKind::Audio, 162, 2, 8)]);
+        assert_eq!(image.lead_out(), 170);
+        assert_eq!(image.layout.locate(160), stored(TrackKind::Audio, 0, 10));
+    }
+
+    #[test]
+    fn pregap_and_multiple_files() {
+        let sheet = "FILE a.bin BINARY\nTRACK 01 MODE2/2352\nINDEX 01 00:00:00\n\
+                     FILE b.bin BINARY\nTRACK 02 AUDIO\nPREGAP 00:02:00\nINDEX 01 00:00:00\n\
+                     FILE c.bin BINARY\nTRACK 03 AUDIO\nINDEX 00 00:00:00\nINDEX 01 00:00:03\n";
+        let image = open("files", sheet, &[("a.bin", 10), ("b.bin", 5), ("c.bin", 7)]);
+
+        assert_eq!(image.tracks(), [
+            track(1, TrackKind::Mode2, 150, 150, 10),
+            track(2, TrackKind::Audio, 310, 150, 5),
+            track(3, TrackKind::Audio, 318, 3, 4)
+        ]);
+        assert_eq!(image.lead_out(), 322);
+        assert_eq!(image.layout.locate(160), Some(Location::Gap(TrackKind::Audio)));
+        assert_eq!(image.layout.locate(309), Some(Location::Gap(TrackKind::Audio)));
+        assert_eq!(image.layout.locate(310), stored(TrackKind::Audio, 1, 0));
+        assert_eq!(image.layout.locate(315), stored(TrackKind::Audio, 2, 0));
+        assert_eq!(image.layout.locate(321), stored(TrackKind::Audio, 2, 6));
+    }
+}
\ No newline at end of file
@@ -0,0 +1,128 @@
+use std::io;
+
+use spdlog::prelude::*;
+
+use super::{Msf, SECTOR_SIZE, Track, TrackKind};
+
+/// Where a sector of the disc lives in an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// Not stored anywhere, reads as an empty sector
+    Gap(TrackKind),
+    /// `position` is a byte offset into the image's `file`, which stores `sector_size` bytes
+    /// per sector
+    Stored { kind: TrackKind, file: usize, position: u64, sector_size: usize }
+}
+
+// A run of consecutive sectors stored the same way
+#[derive(Debug)]
+struct Extent {
+    start: u32,
+    length: u32,
+    kind: TrackKind,
+    // None for gaps which aren't stored in any file
+    source: Option<(usize, u64, usize)>
+}
+
+/// Maps absolute sectors onto the files of an image and keeps its TOC
+#[derive(Debug, Default)]
+pub struct Layout {
+    extents: Vec<Extent>,
+    tracks: Vec<Track>,
+    lead_out: u32
+}
+
+impl Layout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_gap(&mut self, start: u32, length: u32, kind: TrackKind) {
+        self.extents.push(Extent { start, length, kind, source: None });
+    }
+
+    /// `length` sectors from `start` are stored consecutively from byte `offset` of `file`
+    pub fn add_stored(&mut self, start: u32, length: u32, kind: TrackKind, file: usize, offset: u64, sector_size: usize) {
+        self.extents.push(Extent { start, length, kind, source: Some((file, offset, sector_size)) });
+    }
+
+    /// Only `start` and `pregap` matter, the length is worked out by `finish`
+    pub fn add_track(&mut self, number: u8, kind: TrackKind, start: u32, pregap: u32) {
+        self.tracks.push(Track { number, kind, start, pregap, length: 0 });
+    }
+
+    /// Fills in the lead-in gap and track lengths once every track is placed
+    pub fn finish(mut self, lead_out: u32) -> Result<Self, io::Error> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        if self.tracks.first().is_none_or(|track| track.number != 1) || self.tracks.len() > 99 {
+            return Err(invalid("tracks must be numbered 1 to 99".to_string()));
+        }
+        for (index, track) in self.tracks.iter().enumerate().skip(1) {
+            let previous = &self.tracks[index - 1];
+            if track.number != previous.number + 1 || track.start - track.pregap < previous.start {
+                return Err(invalid(format!("track {} is out of sequence", track.number)));
+            }
+        }
+
+        // Everything before the first stored sector is track 1's pregap
+        let first = self.extents.first().map_or(lead_out, |extent| extent.start);
+        if first > 0 {
+            self.extents.insert(0, Extent { start: 0, length: first, kind: self.tracks[0].kind, source: None });
+        }
+        self.tracks[0].pregap = self.tracks[0].start;
+
+        for index in 0..self.tracks.len() {
+            let end = match self.tracks.get(index + 1) {
+                Some(next) => next.start - next.pregap,
+                None => lead_out
+            };
+            self.tracks[index].length = end.saturating_sub(self.tracks[index].start);
+        }
+        self.lead_out = lead_out;
+
+        for track in &self.tracks {
+            debug!("[DISC] Track {:02} {:?} at {} for {} sectors, pregap {}", track.number, track.kind, Msf::from_lba(track.start), track.length, track.pregap);
+        }
+        Ok(self)
+    }
+
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    pub fn lead_out(&self) -> u32 {
+        self.lead_out
+    }
+
+    pub fn locate(&self, lba: u32) -> Option<Location> {
+        let extent = self.extents.iter().find(|extent| lba >= extent.start && lba - extent.start < extent.length)?;
+
+        Some(match extent.source {
+            None => Location::Gap(extent.kind),
+            Some((file, offset, sector_size)) => Location::Stored {
+                kind: extent.kind,
+                file,
+                position: offset + (lba - extent.start) as u64 * sector_size as u64,
+                sector_size
+            }
+        })
+    }
+}
+
+/// Empty sector as found in a gap, data tracks still get a header
+pub fn write_gap(lba: u32, kind: TrackKind, buffer: &mut [u8; SECTOR_SIZE]) {
+    buffer.fill(0);
+    if kind != TrackKind::Audio {
+        write_header(lba, buffer);
+    }
+}
+
+/// Sync, header and a Form 1 subheader, how cooked sectors look on a PlayStation disc
+pub fn write_header(lba: u32, buffer: &mut [u8; SECTOR_SIZE]) {
+    buffer[1..11].fill(0xFF);
+    buffer[12..15].copy_from_slice(&Msf::from_lba(lba).to_bcd());
+    buffer[15] = 2;
+    buffer[18] = 0x08;
+    buffer[22] = 0x08;
+}
\ No newline at end of file
@@ -0,0 +1,165 @@
+use std::{fmt, io, path::Path};
+
+use spdlog::prelude::*;
+
+use super::{super::region::Region, iso9660::Filesystem};
+
+#[cfg(any(feature = "chd", feature = "pbp"))]
+mod cache;
+#[cfg(feature = "chd")]
+mod chd;
+mod cue;
+mod image;
+mod layout;
+#[cfg(feature = "pbp")]
+mod pbp;
+mod subchannel;
+
+#[cfg(feature = "chd")]
+pub use chd::Chd;
+pub use image::Image;
+#[cfg(feature = "pbp")]
+pub use pbp::Pbp;
+pub use subchannel::{Patched, Q_SIZE, Subchannel};
+
+/// Raw sector size, sync and header included
+pub const SECTOR_SIZE: usize = 2352;
+/// Sectors per second at single speed
+pub const SECTORS_PER_SECOND: u32 = 75;
+/// Every disc starts with a two second pregap before track 1's data
+pub const PREGAP_SECTORS: u32 = 2 * SECTORS_PER_SECOND;
+
+/// A position on the disc in minutes, seconds and sectors, stored in binary
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct Msf {
+    pub minute: u8,
+    pub second: u8,
+    pub frame: u8
+}
+
+impl Msf {
+    /// `lba` counts sectors from 00:00:00, so the first data sector is at 150
+    pub fn from_lba(lba: u32) -> Self {
+        Self {
+            minute: (lba / (60 * SECTORS_PER_SECOND)) as u8,
+            second: ((lba / SECTORS_PER_SECOND) % 60) as u8,
+            frame: (lba % SECTORS_PER_SECOND) as u8
+        }
+    }
+
+    pub fn to_lba(self) -> u32 {
+        (self.minute as u32 * 60 + self.second as u32) * SECTORS_PER_SECOND + self.frame as u32
+    }
+
+    /// None if a value isn't valid BCD or is out of range
+    pub fn from_bcd(minute: u8, second: u8, frame: u8) -> Option<Self> {
+        let msf = Self { minute: from_bcd(minute)?, second: from_bcd(second)?, frame: from_bcd(frame)? };
+        (msf.second < 60 && msf.frame < SECTORS_PER_SECOND as u8).then_some(msf)
+    }
+
+    pub fn to_bcd(self) -> [u8; 3] {
+        [to_bcd(self.minute), to_bcd(self.second), to_bcd(self.frame)]
+    }
+}
+
+impl fmt::Display for Msf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}:{:02}", self.minute, self.second, self.frame)
+    }
+}
+
+pub fn from_bcd(value: u8) -> Option<u8> {
+    (value & 0xF < 10 && value >> 4 < 10).then_some((value >> 4) * 10 + (value & 0xF))
+}
+
+pub fn to_bcd(value: u8) -> u8 {
+    ((value / 10) << 4) | (value % 10)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackKind {
+    Mode1,
+    Mode2,
+    Audio
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Track {
+    pub number: u8,
+    pub kind: TrackKind,
+    /// Absolute sector of INDEX 01, where the track's data starts
+    pub start: u32,
+    /// Sectors of INDEX 00 before `start`
+    pub pregap: u32,
+    /// Sectors from `start` to the next track's pregap or the lead-out
+    pub length: u32
+}
+
+/// What the CD-ROM controller reads from
+pub trait Disc {
+    /// Tracks in ascending order, numbered from 1
+    fn tracks(&self) -> &[Track];
+
+    /// Absolute sector where the lead-out starts
+    fn lead_out(&self) -> u32;
+
+    /// Fills `buffer` with the raw sector at absolute sector `lba`, false if there's nothing
+    /// there or it couldn't be read
+    fn read_sector(&mut self, lba: u32, buffer: &mut [u8; SECTOR_SIZE]) -> bool;
+
+    /// The region of the SCEx string in the disc's wobble, None for discs without one or when
+    /// it couldn't be worked out
+    fn region(&self) -> Option<Region>;
+
+    /// Replaces parts of the subchannel Q of absolute sector `lba` where the disc differs from
+    /// what its TOC implies, only copy protected discs do
+    fn patch_subchannel_q(&self, _lba: u32, _q: &mut [u8; Q_SIZE]) {}
+
+    /// The track containing absolute sector `lba`, pregaps count as part of their track
+    fn track_at(&self, lba: u32) -> Option<&Track> {
+        self.tracks()
+            .iter()
+            .rev()
+            .find(|track| lba + track.pregap >= track.start)
+    }
+}
+
+/// Works out the region from the executable SYSTEM.CNF boots, which is named after the disc's
+/// serial. Images don't keep the wobble, so this is the closest we get to the SCEx string
+pub fn detect_region(disc: &mut dyn Disc) -> Option<Region> {
+    if disc.tracks().first()?.kind == TrackKind::Audio {
+        return None;
+    }
+
+    let system_cnf = Filesystem::new(disc).ok()?.system_cnf().ok()??;
+    Region::from_serial(system_cnf.executable())
+}
+
+/// Opens a disc image, picking the format from the extension
+pub fn open(path: impl AsRef<Path>) -> Result<Box<dyn Disc>, io::Error> {
+    let path = path.as_ref();
+    let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase();
+
+    let mut disc: Box<dyn Disc> = match extension.as_str() {
+        "cue" => Box::new(Image::open_cue(path)?),
+        "iso" => Box::new(Image::open_iso(path)?),
+        #[cfg(feature = "chd")]
+        "chd" => Box::new(Chd::open(path)?),
+        #[cfg(feature = "pbp")]
+        "pbp" => Box::new(Pbp::open(path)?),
+        #[cfg(not(feature = "chd"))]
+        "chd" => return Err(io::Error::new(io::ErrorKind::Unsupported, "CHD images need the chd feature")),
+        #[cfg(not(feature = "pbp"))]
+        "pbp" => return Err(io::Error::new(io::ErrorKind::Unsupported, "PBP images need the pbp feature")),
+        _ => return Err(io::Error::new(io::ErrorKind::Unsupported, format!("unknown disc image type .{}", extension)))
+    };
+    if let Some(subchannel) = Subchannel::find(path)? {
+        disc = Box::new(Patched::new(disc, subchannel));
+    }
+
+    match disc.region() {
+        Some(region) => info!("[DISC] Region: {}", region),
+        None => info!("[DISC] Unknown region")
+    }
+    Ok(disc)
+}
\ No newline at end of file
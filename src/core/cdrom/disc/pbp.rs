@@ -0,0 +1,255 @@
+use std::{fs::File, io::{self, Read, Seek, SeekFrom}, path::Path};
+
+use spdlog::prelude::*;
+
+use super::{
+    super::super::region::Region,
+    Disc, Msf, PREGAP_SECTORS, SECTOR_SIZE, Track, TrackKind, from_bcd,
+    cache::HunkCache,
+    layout::{self, Layout, Location}
+};
+
+const MAGIC: &[u8; 4] = b"\0PBP";
+const SINGLE_DISC: &[u8] = b"PSISOIMG0000";
+const MULTI_DISC: &[u8] = b"PSTITLEIMG000000";
+
+// Offsets from the start of a disc's image
+const SERIAL_OFFSET: u64 = 0x400;
+const TOC_OFFSET: u64 = 0x800;
+const INDEX_OFFSET: u64 = 0x4000;
+const DATA_OFFSET: u64 = 0x100000;
+
+const TOC_ENTRY_SIZE: usize = 10;
+const INDEX_ENTRY_SIZE: usize = 32;
+
+/// Images are split into blocks of 16 sectors, deflated unless that didn't make them smaller
+const BLOCK_SECTORS: usize = 16;
+const BLOCK_SIZE: usize = BLOCK_SECTORS * SECTOR_SIZE;
+
+const CACHE_BLOCKS: usize = 8;
+
+/// A PSP eboot holding a PlayStation disc, as made by popstation and similar tools. Only the
+/// first disc of multi-disc eboots is used and encrypted ones aren't supported
+#[derive(Debug)]
+pub struct Pbp {
+    file: File,
+    // (offset in the file, compressed length) of every block
+    blocks: Vec<(u64, u32)>,
+    cache: HunkCache,
+    layout: Layout,
+    region: Option<Region>
+}
+
+impl Pbp {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, io::Error> {
+        let mut file = File::open(path)?;
+
+        let mut header = [0; 0x28];
+        file.read_exact(&mut header)?;
+        if &header[0..4] != MAGIC {
+            return Err(invalid("not a PBP file"));
+        }
+        let psar = le32(&header[0x24..]) as u64;
+
+        let mut magic = [0; 16];
+        read_at(&mut file, psar, &mut magic)?;
+        let base = if magic.starts_with(SINGLE_DISC) {
+            psar
+        } else if magic.starts_with(MULTI_DISC) {
+            let mut offset = [0; 4];
+            read_at(&mut file, psar + 0x200, &mut offset)?;
+            warn!("[PBP] Multi-disc eboot, only the first disc is used");
+            psar + le32(&offset) as u64
+        } else {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "PBP image is encrypted or not a PlayStation disc"));
+        };
+
+        let mut disc_magic = [0; 12];
+        read_at(&mut file, base, &mut disc_magic)?;
+        if disc_magic != SINGLE_DISC {
+            return Err(invalid("missing disc image"));
+        }
+
+        let mut toc = [0; 102 * TOC_ENTRY_SIZE];
+        read_at(&mut file, base + TOC_OFFSET, &mut toc)?;
+        let layout = read_toc(&toc)?;
+
+        let mut index = vec![0; (DATA_OFFSET - INDEX_OFFSET) as usize];
+        read_at(&mut file, base + INDEX_OFFSET, &mut index)?;
+        let blocks = index
+            .chunks_exact(INDEX_ENTRY_SIZE)
+            .map(|entry| (le32(entry), u16::from_le_bytes([entry[4], entry[5]]) as u32))
+            .take_while(|&(_, length)| length != 0)
+            .map(|(offset, length)| (base + DATA_OFFSET + offset as u64, length))
+            .collect();
+
+        // The eboot also has the serial, for discs without a SYSTEM.CNF
+        let mut serial = [0; 16];
+        read_at(&mut file, base + SERIAL_OFFSET, &mut serial)?;
+        let serial = String::from_utf8_lossy(&serial).trim_start_matches('_').to_string();
+
+        let mut pbp = Self { file, blocks, cache: HunkCache::new(CACHE_BLOCKS), layout, region: None };
+        pbp.region = super::detect_region(&mut pbp).or_else(|| Region::from_serial(&serial));
+        Ok(pbp)
+    }
+
+    fn block(&mut self, index: u32) -> Result<&[u8], io::Error> {
+        if !self.cache.contains(index) {
+            let data = self.decompress_block(index)?;
+            return Ok(self.cache.insert(index, data));
+        }
+        Ok(self.cache.get(index).unwrap())
+    }
+
+    fn decompress_block(&mut self, index: u32) -> Result<Box<[u8]>, io::Error> {
+        let (offset, length) = *self.blocks.get(index as usize).ok_or_else(|| invalid("block past the end of the image"))?;
+        let mut data = vec![0; BLOCK_SIZE].into_boxed_slice();
+
+        if length as usize == BLOCK_SIZE {
+            read_at(&mut self.file, offset, &mut data)?;
+            return Ok(data);
+        }
+
+        let mut compressed = vec![0; length as usize];
+        read_at(&mut self.file, offset, &mut compressed)?;
+        // The last block may hold fewer sectors
+        match miniz_oxide::inflate::decompress_slice_iter_to_slice(&mut data, std::iter::once(&compressed[..]), false, true) {
+            Ok(length) if length.is_multiple_of(SECTOR_SIZE) => Ok(data),
+            Ok(_) => Err(invalid("block isn't made of whole sectors")),
+            Err(status) => Err(invalid(&format!("deflate error {:?}", status)))
+        }
+    }
+}
+
+impl Disc for Pbp {
+    fn tracks(&self) -> &[Track] {
+        self.layout.tracks()
+    }
+
+    fn lead_out(&self) -> u32 {
+        self.layout.lead_out()
+    }
+
+    fn region(&self) -> Option<Region> {
+        self.region
+    }
+
+    fn read_sector(&mut self, lba: u32, buffer: &mut [u8; SECTOR_SIZE]) -> bool {
+        let position = match self.layout.locate(lba) {
+            None => return false,
+            Some(Location::Gap(kind)) => {
+                layout::write_gap(lba, kind, buffer);
+                return true;
+            }
+            Some(Location::Stored { position, .. }) => position
+        };
+
+        let offset = (position % BLOCK_SIZE as u64) as usize;
+        match self.block((position / BLOCK_SIZE as u64) as u32) {
+            Ok(block) => {
+                buffer.copy_from_slice(&block[offset..offset + SECTOR_SIZE]);
+                true
+            }
+            Err(error) => {
+                error!("[PBP] Failed to read sector {}: {}", Msf::from_lba(lba), error);
+                false
+            }
+        }
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PBP: {}", message))
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn read_at(file: &mut File, offset: u64, buffer: &mut [u8]) -> Result<(), io::Error> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buffer)
+}
+
+// The TOC is a copy of the disc's lead-in, entries of control/ADR, track number, point,
+// absolute MSF, zero and the point's MSF, all in BCD. The image starts at 00:02:00 and holds
+// every track back to back
+fn read_toc(toc: &[u8]) -> Result<Layout, io::Error> {
+    let mut layout = Layout::new();
+    let mut lead_out = None;
+    let mut tracks = Vec::new();
+
+    for entry in toc.chunks_exact(TOC_ENTRY_SIZE).take_while(|entry| entry[2] != 0) {
+        let start = Msf::from_bcd(entry[7], entry[8], entry[9]).ok_or_else(|| invalid("invalid TOC position"))?.to_lba();
+        match entry[2] {
+            0xA0 | 0xA1 => {}
+            0xA2 => lead_out = Some(start),
+            point => {
+                let number = from_bcd(point).ok_or_else(|| invalid("invalid track number"))?;
+                let kind = if entry[0] & 0x40 != 0 { TrackKind::Mode2 } else { TrackKind::Audio };
+                tracks.push((number, kind, start));
+            }
+        }
+    }
+
+    let lead_out = lead_out.ok_or_else(|| invalid("TOC without a lead-out"))?;
+    for (index, &(number, kind, start)) in tracks.iter().enumerate() {
+        let end = tracks.get(index + 1).map_or(lead_out, |&(_, _, start)| start);
+        if start < PREGAP_SECTORS || end < start {
+            return Err(invalid("TOC is out of order"));
+        }
+
+        let offset = (start - PREGAP_SECTORS) as u64 * SECTOR_SIZE as u64;
+        layout.add_stored(start, end - start, kind, 0, offset, SECTOR_SIZE);
+        layout.add_track(number, kind, start, 0);
+    }
+
+    layout.finish(lead_out)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(control: u8, point: u8, msf: [u8; 3]) -> [u8; TOC_ENTRY_SIZE] {
+        [control, 0, point, 0, 0, 0, 0, msf[0], msf[1], msf[2]]
+    }
+
+    #[test]
+    fn reads_toc() {
+        let toc = [
+            entry(0x41, 0xA0, [0x01, 0x00, 0x00]),
+            entry(0x01, 0xA1, [0x02, 0x00, 0x00]),
+            entry(0x01, 0xA2, [0x00, 0x20, 0x00]),
+            entry(0x41, 0x01, [0x00, 0x02, 0x00]),
+            entry(0x01, 0x02, [0x00, 0x10, 0x00]),
+            [0; TOC_ENTRY_SIZE]
+        ]
+        .concat();
+
+        let layout = read_toc(&toc).unwrap();
+        assert_eq!(layout.tracks(), [
+            Track { number: 1, kind: TrackKind::Mode2, start: 150, pregap: 150, length: 600 },
+            Track { number: 2, kind: TrackKind::Audio, start: 750, pregap: 0, length: 750 }
+        ]);
+        assert_eq!(layout.lead_out(), 1500);
+        assert_eq!(layout.locate(750), Some(Location::Stored {
+            kind: TrackKind::Audio, file: 0, position: 600 * SECTOR_SIZE as u64, sector_size: SECTOR_SIZE
+        }));
+    }
+
+    #[test]
+    fn rejects_invalid_tocs() {
+        let tocs = [
+            [entry(0x41, 0x01, [0x00, 0x02, 0x00]), [0; TOC_ENTRY_SIZE]].concat(),
+            [entry(0x01, 0xA2, [0x00, 0x20, 0x00]), entry(0x41, 0x01, [0x00, 0x01, 0x00])].concat(),
+            [entry(0x01, 0xA2, [0x00, 0x20, 0x00]), entry(0x41, 0x01, [0x00, 0x02, 0x80])].concat(),
+            [entry(0x01, 0xA2, [0x00, 0x05, 0x00]), entry(0x41, 0x01, [0x00, 0x10, 0x00])].concat()
+        ];
+
+        for toc in tocs {
+            assert_eq!(read_toc(&toc).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:02X?}", toc);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,200 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use spdlog::prelude::*;
+
+use super::{super::super::region::Region, Disc, Msf, SECTOR_SIZE, Track};
+
+/// Subchannel Q of one sector without its CRC: control/ADR, track, index, relative MSF, zero
+/// and absolute MSF
+pub const Q_SIZE: usize = 10;
+
+const SBI_MAGIC: &[u8; 4] = b"SBI\0";
+// Absolute MSF followed by the whole Q including its CRC
+const LSD_ENTRY_SIZE: usize = 3 + Q_SIZE + 2;
+
+/// Bytes to write over part of a sector's subchannel Q
+#[derive(Debug, Clone)]
+struct Patch {
+    offset: usize,
+    data: Vec<u8>
+}
+
+/// Subchannel Q that differs from what the TOC implies, as dumped into .sbi and .lsd files.
+/// LibCrypt protected discs have sectors with deliberately broken Q that they look for
+#[derive(Debug, Default)]
+pub struct Subchannel {
+    patches: HashMap<u32, Patch>
+}
+
+impl Subchannel {
+    /// Looks for an .sbi or .lsd file named like the disc image
+    pub fn find(image: &Path) -> Result<Option<Self>, io::Error> {
+        let candidates = ["sbi", "SBI", "lsd", "LSD"].map(|extension| image.with_extension(extension));
+        let Some(path) = candidates.iter().find(|path| path.is_file()) else {
+            return Ok(None);
+        };
+
+        let subchannel = Self::open(path)?;
+        info!("[DISC] Replacing subchannel Q of {} sectors from {}", subchannel.patches.len(), path.display());
+        Ok(Some(subchannel))
+    }
+
+    /// Opens an .sbi or .lsd file, picking the format from the extension
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let data = fs::read(path)?;
+        let lsd = path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("lsd"));
+        if lsd { parse_lsd(&data) } else { parse_sbi(&data) }
+    }
+
+    /// Applies the replacement for absolute sector `lba`, if there is one
+    pub fn patch(&self, lba: u32, q: &mut [u8; Q_SIZE]) {
+        if let Some(patch) = self.patches.get(&lba) {
+            q[patch.offset..patch.offset + patch.data.len()].copy_from_slice(&patch.data);
+        }
+    }
+}
+
+/// A disc whose subchannel Q is partly replaced
+pub struct Patched {
+    disc: Box<dyn Disc>,
+    subchannel: Subchannel
+}
+
+impl Patched {
+    pub fn new(disc: Box<dyn Disc>, subchannel: Subchannel) -> Self {
+        Self { disc, subchannel }
+    }
+}
+
+impl Disc for Patched {
+    fn tracks(&self) -> &[Track] {
+        self.disc.tracks()
+    }
+
+    fn lead_out(&self) -> u32 {
+        self.disc.lead_out()
+    }
+
+    fn read_sector(&mut self, lba: u32, buffer: &mut [u8; SECTOR_SIZE]) -> bool {
+        self.disc.read_sector(lba, buffer)
+    }
+
+    fn region(&self) -> Option<Region> {
+        self.disc.region()
+    }
+
+    fn patch_subchannel_q(&self, lba: u32, q: &mut [u8; Q_SIZE]) {
+        self.subchannel.patch(lba, q);
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("subchannel: {}", message))
+}
+
+fn lba(msf: &[u8]) -> Result<u32, io::Error> {
+    Msf::from_bcd(msf[0], msf[1], msf[2]).map(Msf::to_lba).ok_or_else(|| invalid("invalid MSF"))
+}
+
+// A magic followed by entries of absolute MSF, a type and the data. Type 1 replaces the whole
+// Q, types 2 and 3 only the relative or absolute MSF
+fn parse_sbi(data: &[u8]) -> Result<Subchannel, io::Error> {
+    let mut entries = data.strip_prefix(SBI_MAGIC).ok_or_else(|| invalid("not an SBI file"))?;
+    let mut patches = HashMap::new();
+
+    while !entries.is_empty() {
+        let (offset, length) = match entries.get(3) {
+            Some(1) => (0, Q_SIZE),
+            Some(2) => (3, 3),
+            Some(3) => (7, 3),
+            Some(kind) => return Err(invalid(&format!("unknown SBI entry type {}", kind))),
+            None => return Err(invalid("truncated SBI entry"))
+        };
+        let data = entries.get(4..4 + length).ok_or_else(|| invalid("truncated SBI entry"))?;
+
+        patches.insert(lba(entries)?, Patch { offset, data: data.to_vec() });
+        entries = &entries[4 + length..];
+    }
+
+    Ok(Subchannel { patches })
+}
+
+fn parse_lsd(data: &[u8]) -> Result<Subchannel, io::Error> {
+    if !data.len().is_multiple_of(LSD_ENTRY_SIZE) {
+        return Err(invalid("truncated LSD entry"));
+    }
+
+    let patches = data
+        .chunks_exact(LSD_ENTRY_SIZE)
+        .map(|entry| Ok((lba(entry)?, Patch { offset: 0, data: entry[3..3 + Q_SIZE].to_vec() })))
+        .collect::<Result<_, io::Error>>()?;
+    Ok(Subchannel { patches })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const Q: [u8; Q_SIZE] = [0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x00];
+    const BROKEN: [u8; Q_SIZE] = [0x41, 0x01, 0x01, 0x03, 0x08, 0x05, 0x00, 0x03, 0x10, 0x05];
+
+    fn patched(subchannel: &Subchannel, lba: u32) -> [u8; Q_SIZE] {
+        let mut q = Q;
+        subchannel.patch(lba, &mut q);
+        q
+    }
+
+    #[test]
+    fn sbi_entry_types() {
+        let mut data = SBI_MAGIC.to_vec();
+        // 03:08:05 replaces everything, 03:08:06 the relative MSF and 03:08:07 the absolute one
+        data.extend([0x03, 0x08, 0x05, 0x01]);
+        data.extend(BROKEN);
+        data.extend([0x03, 0x08, 0x06, 0x02, 0x12, 0x34, 0x56]);
+        data.extend([0x03, 0x08, 0x07, 0x03, 0x65, 0x43, 0x21]);
+
+        let subchannel = parse_sbi(&data).unwrap();
+        assert_eq!(subchannel.patches.len(), 3);
+        assert_eq!(patched(&subchannel, 14105), BROKEN);
+        assert_eq!(patched(&subchannel, 14106), [0x41, 0x01, 0x01, 0x12, 0x34, 0x56, 0x00, 0x00, 0x02, 0x00]);
+        assert_eq!(patched(&subchannel, 14107), [0x41, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x65, 0x43, 0x21]);
+        assert_eq!(patched(&subchannel, 14108), Q);
+    }
+
+    #[test]
+    fn lsd_entries() {
+        let mut data = vec![0x00, 0x02, 0x00];
+        data.extend(BROKEN);
+        data.extend([0xAB, 0xCD]);
+        data.extend([0x03, 0x08, 0x05]);
+        data.extend(Q);
+        data.extend([0x00, 0x00]);
+
+        let subchannel = parse_lsd(&data).unwrap();
+        assert_eq!(subchannel.patches.len(), 2);
+        assert_eq!(patched(&subchannel, 150), BROKEN);
+        assert_eq!(patched(&subchannel, 14105), Q);
+        assert_eq!(patched(&subchannel, 151), Q);
+    }
+
+    #[test]
+    fn rejects_invalid_files() {
+        let sbi: [&[u8]; 5] = [
+            b"SBJ\0",
+            b"SBI\0\x00\x02\x00\x04\x00",
+            b"SBI\0\x00\x02\x00\x02\x00\x00",
+            b"SBI\0\x00\x02\x00",
+            b"SBI\0\x00\x0A\x00\x03\x00\x00\x00"
+        ];
+        for data in sbi {
+            assert_eq!(parse_sbi(data).unwrap_err().kind(), io::ErrorKind::InvalidData, "{:02X?}", data);
+        }
+
+        let mut lsd = vec![0x00, 0x02, 0x00];
+        lsd.extend(Q);
+        assert_eq!(parse_lsd(&lsd).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        lsd.extend([0x00, 0x00]);
+        lsd[1] = 0x60;
+        assert_eq!(parse_lsd(&lsd).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
\ No newline at end of file
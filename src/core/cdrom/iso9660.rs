@@ -0,0 +1,540 @@
+use std::{collections::HashSet, fmt, fs, io, path::{Component, Path}};
+
+use super::disc::{Disc, SECTOR_SIZE};
+
+/// Size of a logical block, the user data of a Mode 1 or Mode 2 Form 1 sector
+pub const BLOCK_SIZE: usize = 2048;
+/// Subheader, data and EDC of a Mode 2 Form 2 sector
+pub const FORM2_SECTOR_SIZE: usize = 2336;
+/// Blocks before the volume descriptors are reserved for the system area
+const SYSTEM_AREA_BLOCKS: u32 = 16;
+
+// XA attributes, stored big endian after the name of a directory record
+const XA_FORM2: u16 = 0x1000;
+const XA_INTERLEAVED: u16 = 0x2000;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The disc couldn't give us this absolute sector
+    ReadFailed(u32),
+    /// No ISO9660 volume on the first track
+    NotIso9660,
+    NotFound(String),
+    NotADirectory(String),
+    InvalidRecord,
+    /// Writing out extracted files failed
+    Io(io::Error)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::ReadFailed(lba) => write!(f, "failed to read sector {}", lba),
+            Error::NotIso9660 => write!(f, "not an ISO9660 filesystem"),
+            Error::NotFound(path) => write!(f, "{} doesn't exist", path),
+            Error::NotADirectory(path) => write!(f, "{} isn't a directory", path),
+            Error::InvalidRecord => write!(f, "invalid directory record"),
+            Error::Io(error) => write!(f, "{}", error)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// A file or directory as its directory record describes it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    /// Without the ;1 version suffix, empty for the root
+    pub name: String,
+    /// First logical block
+    pub block: u32,
+    /// Size in bytes, for Form 2 files this counts 2048 bytes per sector
+    pub size: u32,
+    pub directory: bool,
+    /// XA audio and video, stored in Mode 2 Form 2 sectors
+    pub form2: bool
+}
+
+impl Entry {
+    /// Number of logical blocks the entry spans
+    pub fn blocks(&self) -> u32 {
+        self.size.div_ceil(BLOCK_SIZE as u32)
+    }
+}
+
+/// An entry of the little endian path table, which lists every directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathTableEntry {
+    /// Empty for the root
+    pub name: String,
+    pub block: u32,
+    /// Position of the parent in the table counting from 1, the root is its own parent
+    pub parent: u16
+}
+
+/// What the primary volume descriptor says about the volume
+#[derive(Debug, Clone)]
+pub struct Volume {
+    pub system_id: String,
+    pub volume_id: String,
+    pub publisher_id: String,
+    pub application_id: String,
+    /// Size of the volume in logical blocks
+    pub blocks: u32,
+    path_table_block: u32,
+    path_table_size: u32,
+    root: Entry
+}
+
+/// The filesystem on a disc's first track, which may be made of Mode 1 or Mode 2 sectors
+pub struct Filesystem<'a> {
+    disc: &'a mut dyn Disc,
+    // Absolute sector of logical block 0
+    start: u32,
+    volume: Volume
+}
+
+impl<'a> Filesystem<'a> {
+    pub fn new(disc: &'a mut dyn Disc) -> Result<Self, Error> {
+        let start = disc.tracks().first().ok_or(Error::NotIso9660)?.start;
+        let mut sector = [0; SECTOR_SIZE];
+        let descriptor = read_block(disc, start + SYSTEM_AREA_BLOCKS, &mut sector)?;
+        if descriptor[0] != 1 || &descriptor[1..6] != b"CD001" {
+            return Err(Error::NotIso9660);
+        }
+
+        let text = |range: std::ops::Range<usize>| String::from_utf8_lossy(&descriptor[range]).trim_end().to_string();
+        let volume = Volume {
+            system_id: text(8..40),
+            volume_id: text(40..72),
+            publisher_id: text(318..446),
+            application_id: text(574..702),
+            blocks: le32(&descriptor[80..]),
+            path_table_size: le32(&descriptor[132..]),
+            path_table_block: le32(&descriptor[140..]),
+            root: parse_record(&descriptor[156..190]).ok_or(Error::InvalidRecord)?
+        };
+
+        Ok(Self { disc, start, volume })
+    }
+
+    pub fn volume(&self) -> &Volume {
+        &self.volume
+    }
+
+    /// Every directory on the volume, parents always come before their children
+    pub fn path_table(&mut self) -> Result<Vec<PathTableEntry>, Error> {
+        let table = self.read_blocks(self.volume.path_table_block, self.volume.path_table_size as usize)?;
+        let mut entries = Vec::new();
+        let mut offset = 0;
+
+        while offset + 8 <= table.len() {
+            let name_length = table[offset] as usize;
+            let name = table.get(offset + 8..offset + 8 + name_length).ok_or(Error::InvalidRecord)?;
+            entries.push(PathTableEntry {
+                // The root's name is a single zero byte
+                name: if name == [0] { String::new() } else { String::from_utf8_lossy(name).into_owned() },
+                block: le32(&table[offset + 2..]),
+                parent: u16::from_le_bytes([table[offset + 6], table[offset + 7]])
+            });
+            offset += 8 + name_length + name_length % 2;
+        }
+
+        Ok(entries)
+    }
+
+    /// Looks up `path`, which may be written like cdrom:\DIR\FILE.EXE;1
+    pub fn entry(&mut self, path: &str) -> Result<Entry, Error> {
+        let trimmed = path.strip_prefix("cdrom:").unwrap_or(path);
+        let mut entry = self.volume.root.clone();
+
+        for component in trimmed.split(['\\', '/']).filter(|component| !component.is_empty()) {
+            if !entry.directory {
+                return Err(Error::NotFound(path.to_string()));
+            }
+            let wanted = strip_version(component);
+            entry = self.list(&entry)?
+                .into_iter()
+                .find(|entry| entry.name.eq_ignore_ascii_case(wanted))
+                .ok_or_else(|| Error::NotFound(path.to_string()))?;
+        }
+
+        Ok(entry)
+    }
+
+    /// The files and directories in the directory at `path`
+    pub fn read_dir(&mut self, path: &str) -> Result<Vec<Entry>, Error> {
+        let entry = self.entry(path)?;
+        if !entry.directory {
+            return Err(Error::NotADirectory(path.to_string()));
+        }
+        self.list(&entry)
+    }
+
+    /// Every file on the volume along with its path, like DIR/FILE.EXE
+    pub fn files(&mut self) -> Result<Vec<(String, Entry)>, Error> {
+        let mut files = Vec::new();
+        let mut pending = vec![(String::new(), self.volume.root.clone())];
+        // A directory pointing back at itself or an ancestor would have us walk forever
+        let mut visited = HashSet::new();
+
+        while let Some((path, directory)) = pending.pop() {
+            if !visited.insert(directory.block) {
+                return Err(Error::InvalidRecord);
+            }
+            for entry in self.list(&directory)? {
+                let child = if path.is_empty() { entry.name.clone() } else { format!("{}/{}", path, entry.name) };
+                match entry.directory {
+                    true => pending.push((child, entry)),
+                    false => files.push((child, entry))
+                }
+            }
+        }
+
+        files.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(files)
+    }
+
+    /// Reads a whole file, see [`Filesystem::read_entry`]
+    pub fn read_file(&mut self, path: &str) -> Result<Vec<u8>, Error> {
+        let entry = self.entry(path)?;
+        if entry.directory {
+            return Err(Error::NotFound(path.to_string()));
+        }
+        self.read_entry(&entry)
+    }
+
+    /// Reads a file's contents. Form 2 files come out as whole 2336 byte sectors, subheaders
+    /// included, since their data doesn't fit in logical blocks
+    pub fn read_entry(&mut self, entry: &Entry) -> Result<Vec<u8>, Error> {
+        if !entry.form2 {
+            return self.read_blocks(entry.block, entry.size as usize);
+        }
+
+        let blocks = entry.blocks().min(self.max_blocks());
+        let mut data = Vec::with_capacity(blocks as usize * FORM2_SECTOR_SIZE);
+        let mut sector = [0; SECTOR_SIZE];
+        for block in entry.block..entry.block.saturating_add(blocks) {
+            let lba = self.start + block;
+            if !self.disc.read_sector(lba, &mut sector) {
+                return Err(Error::ReadFailed(lba));
+            }
+            data.extend_from_slice(&sector[16..16 + FORM2_SECTOR_SIZE]);
+        }
+        Ok(data)
+    }
+
+    /// Copies every file on the volume under `destination`, returns how many there were. Paths
+    /// that would end up outside of `destination` are refused
+    pub fn extract(&mut self, destination: &Path) -> Result<usize, Error> {
+        let files = self.files()?;
+        for (path, entry) in &files {
+            let relative = Path::new(path);
+            if !relative.components().all(|component| matches!(component, Component::Normal(_))) {
+                return Err(Error::InvalidRecord);
+            }
+            let target = destination.join(relative);
+            if !target.starts_with(destination) {
+                return Err(Error::InvalidRecord);
+            }
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(target, self.read_entry(entry)?)?;
+        }
+        Ok(files.len())
+    }
+
+    /// Parses SYSTEM.CNF, None if the volume doesn't have one
+    pub fn system_cnf(&mut self) -> Result<Option<SystemCnf>, Error> {
+        match self.read_file("SYSTEM.CNF") {
+            Ok(data) => Ok(SystemCnf::parse(&data)),
+            Err(Error::NotFound(_)) => Ok(None),
+            Err(error) => Err(error)
+        }
+    }
+
+    fn list(&mut self, directory: &Entry) -> Result<Vec<Entry>, Error> {
+        let data = self.read_blocks(directory.block, directory.size as usize)?;
+        records(&data)
+    }
+
+    // Sizes come from the disc, nothing can be bigger than the volume or the track holding it
+    fn max_blocks(&self) -> u32 {
+        self.volume.blocks.min(self.disc.lead_out().saturating_sub(self.start))
+    }
+
+    fn read_blocks(&mut self, first: u32, size: usize) -> Result<Vec<u8>, Error> {
+        let size = size.min(self.max_blocks() as usize * BLOCK_SIZE);
+        let mut data = Vec::with_capacity(size);
+        let mut sector = [0; SECTOR_SIZE];
+        let mut block = first;
+        while data.len() < size {
+            let remaining = size - data.len();
+            data.extend_from_slice(&read_block(self.disc, self.start + block, &mut sector)?[..remaining.min(BLOCK_SIZE)]);
+            block += 1;
+        }
+        Ok(data)
+    }
+}
+
+/// The boot configuration in SYSTEM.CNF, missing values get the BIOS defaults
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemCnf {
+    /// Path of the executable, like cdrom:\SLUS_000.01;1
+    pub boot: String,
+    /// Number of thread control blocks the kernel allocates
+    pub tcb: u32,
+    /// Number of event control blocks the kernel allocates
+    pub event: u32,
+    /// Initial stack pointer of the executable
+    pub stack: u32
+}
+
+/// What the BIOS boots from discs without a SYSTEM.CNF
+impl Default for SystemCnf {
+    fn default() -> Self {
+        Self {
+            boot: "cdrom:PSX.EXE;1".to_string(),
+            tcb: Self::DEFAULT_TCB,
+            event: Self::DEFAULT_EVENT,
+            stack: Self::DEFAULT_STACK
+        }
+    }
+}
+
+impl SystemCnf {
+    pub const DEFAULT_TCB: u32 = 4;
+    pub const DEFAULT_EVENT: u32 = 16;
+    pub const DEFAULT_STACK: u32 = 0x801FFFF0;
+
+    /// None if there's no BOOT line. Numbers are hexadecimal, like the BIOS reads them
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let text = String::from_utf8_lossy(data);
+        let value = |key: &str| {
+            text.lines()
+                .filter_map(|line| line.split_once('='))
+                .find(|(name, _)| name.trim().eq_ignore_ascii_case(key))
+                .and_then(|(_, value)| value.split_whitespace().next())
+        };
+        let number = |key: &str, default: u32| {
+            value(key)
+                .and_then(|value| u32::from_str_radix(value.trim_start_matches("0x").trim_start_matches("0X"), 16).ok())
+                .unwrap_or(default)
+        };
+
+        Some(Self {
+            boot: value("BOOT")?.to_string(),
+            tcb: number("TCB", Self::DEFAULT_TCB),
+            event: number("EVENT", Self::DEFAULT_EVENT),
+            stack: number("STACK", Self::DEFAULT_STACK)
+        })
+    }
+
+    /// Name of the executable without its directory and version, usually the disc's serial
+    pub fn executable(&self) -> &str {
+        let name = self.boot.rsplit(['\\', '/', ':']).next().unwrap_or(&self.boot);
+        strip_version(name)
+    }
+}
+
+fn read_block<'b>(disc: &mut dyn Disc, lba: u32, sector: &'b mut [u8; SECTOR_SIZE]) -> Result<&'b [u8], Error> {
+    if !disc.read_sector(lba, sector) {
+        return Err(Error::ReadFailed(lba));
+    }
+
+    // Mode 2 Form 1 has an 8 byte subheader before the data
+    let offset = if sector[15] == 2 { 24 } else { 16 };
+    Ok(&sector[offset..offset + BLOCK_SIZE])
+}
+
+fn le32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes(bytes[..4].try_into().unwrap())
+}
+
+fn strip_version(name: &str) -> &str {
+    let name = name.split(';').next().unwrap_or(name);
+    // Files without an extension are still recorded with the dot
+    name.strip_suffix('.').unwrap_or(name)
+}
+
+// Directory records never cross a block boundary, zero padding fills the rest of the block.
+// The first two records of every directory are itself and its parent
+fn records(directory: &[u8]) -> Result<Vec<Entry>, Error> {
+    let mut entries = Vec::new();
+
+    for block in directory.chunks(BLOCK_SIZE) {
+        let mut offset = 0;
+        while let Some(&length) = block.get(offset) && length != 0 {
+            let record = block.get(offset..offset + length as usize).ok_or(Error::InvalidRecord)?;
+            let entry = parse_record(record).ok_or(Error::InvalidRecord)?;
+            offset += length as usize;
+
+            match entry.name.as_str() {
+                "" | "\u{1}" => {}
+                name if valid_name(name) => entries.push(entry),
+                _ => return Err(Error::InvalidRecord)
+            }
+        }
+    }
+
+    Ok(entries)
+}
+
+// Names end up in host paths when extracting, they can't be allowed to climb out of it
+fn valid_name(name: &str) -> bool {
+    name != "." && name != ".." && !name.contains(['/', '\\', ':', '\0'])
+}
+
+fn parse_record(record: &[u8]) -> Option<Entry> {
+    if record.len() < 34 {
+        return None;
+    }
+    let name_length = record[32] as usize;
+    let name = record.get(33..33 + name_length)?;
+
+    // The XA extension follows the name, padded to an even length
+    let system_use = 33 + name_length + (name_length + 1) % 2;
+    let attributes = record
+        .get(system_use..system_use + 14)
+        .filter(|xa| &xa[6..8] == b"XA")
+        .map_or(0, |xa| u16::from_be_bytes([xa[4], xa[5]]));
+
+    Some(Entry {
+        // The root and the current directory are named with a zero byte
+        name: if name == [0] { String::new() } else { strip_version(&String::from_utf8_lossy(name)).to_string() },
+        block: le32(&record[2..]),
+        size: le32(&record[10..]),
+        directory: record[25] & 0x02 != 0,
+        form2: attributes & (XA_FORM2 | XA_INTERLEAVED) != 0
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(name: &[u8], block: u32, size: u32, flags: u8, xa: Option<u16>) -> Vec<u8> {
+        let mut record = vec![0; 33];
+        record[2..6].copy_from_slice(&block.to_le_bytes());
+        record[6..10].copy_from_slice(&block.to_be_bytes());
+        record[10..14].copy_from_slice(&size.to_le_bytes());
+        record[14..18].copy_from_slice(&size.to_be_bytes());
+        record[25] = flags;
+        record[32] = name.len() as u8;
+        record.extend(name);
+        if name.len().is_multiple_of(2) {
+            record.push(0);
+        }
+        if let Some(attributes) = xa {
+            record.extend([0, 0, 0, 0]);
+            record.extend(attributes.to_be_bytes());
+            record.extend(b"XA");
+            record.extend([0; 6]);
+        }
+        record[0] = record.len() as u8;
+        record
+    }
+
+    fn entry(name: &str, block: u32, size: u32, directory: bool, form2: bool) -> Entry {
+        Entry { name: name.to_string(), block, size, directory, form2 }
+    }
+
+    #[test]
+    fn parses_records() {
+        let records = [
+            (record(b"\0", 22, 2048, 0x02, None), entry("", 22, 2048, true, false)),
+            (record(b"SYSTEM.CNF;1", 24, 68, 0x00, Some(0x0D55)), entry("SYSTEM.CNF", 24, 68, false, false)),
+            (record(b"MOVIE.STR;1", 30, 0x10000, 0x00, Some(0x1555)), entry("MOVIE.STR", 30, 0x10000, false, true)),
+            (record(b"MUSIC.XA;1", 62, 0x8000, 0x00, Some(0x2555)), entry("MUSIC.XA", 62, 0x8000, false, true)),
+            (record(b"DATA", 80, 0, 0x02, Some(0x8D55)), entry("DATA", 80, 0, true, false)),
+            (record(b"README.;1", 81, 10, 0x00, None), entry("README", 81, 10, false, false))
+        ];
+
+        for (record, expected) in records {
+            assert_eq!(parse_record(&record), Some(expected));
+        }
+
+        let mut truncated = record(b"SLES_123.45;1", 25, 0x800, 0x00, None);
+        assert_eq!(parse_record(&truncated[..33]), None);
+        truncated[32] = 0x40;
+        assert_eq!(parse_record(&truncated), None);
+    }
+
+    #[test]
+    fn directory_records() {
+        let mut directory = [record(b"\0", 22, 2048, 0x02, None), record(b"\x01", 18, 2048, 0x02, None)].concat();
+        directory.extend(record(b"A.BIN;1", 30, 100, 0x00, None));
+        directory.resize(BLOCK_SIZE, 0);
+        directory.extend(record(b"B.BIN;1", 31, 200, 0x00, None));
+        directory.resize(2 * BLOCK_SIZE, 0);
+
+        let entries = records(&directory).unwrap();
+        assert_eq!(entries, [entry("A.BIN", 30, 100, false, false), entry("B.BIN", 31, 200, false, false)]);
+
+        for name in [&b".."[..], b"../ESCAPE.BIN;1", b"C:\\BOOT.EXE"] {
+            let directory = record(name, 40, 1, 0x00, None);
+            assert!(matches!(records(&directory), Err(Error::InvalidRecord)), "{}", String::from_utf8_lossy(name));
+        }
+
+        // A record which runs past the end of its block
+        let mut directory = record(b"A.BIN;1", 30, 100, 0x00, None);
+        directory[0] = 0xFF;
+        assert!(matches!(records(&directory), Err(Error::InvalidRecord)));
+    }
+
+    #[test]
+    fn parses_system_cnf() {
+        let files: [(&[u8], Option<SystemCnf>); 5] = [
+            (
+                b"BOOT = cdrom:\\SLUS_012.34;1\r\nTCB = 4\r\nEVENT = 10\r\nSTACK = 801FFFF0\r\n",
+                Some(SystemCnf { boot: "cdrom:\\SLUS_012.34;1".to_string(), tcb: 4, event: 0x10, stack: 0x801FFFF0 })
+            ),
+            (
+                b"BOOT=cdrom:\\GAME\\MAIN.EXE;1 arg\nTCB=0x8\nEVENT=oops\n",
+                Some(SystemCnf {
+                    boot: "cdrom:\\GAME\\MAIN.EXE;1".to_string(),
+                    tcb: 8,
+                    event: SystemCnf::DEFAULT_EVENT,
+                    stack: SystemCnf::DEFAULT_STACK
+                })
+            ),
+            (
+                b"boot\t= cdrom:PSX.EXE;1\n",
+                Some(SystemCnf {
+                    boot: "cdrom:PSX.EXE;1".to_string(),
+                    tcb: SystemCnf::DEFAULT_TCB,
+                    event: SystemCnf::DEFAULT_EVENT,
+                    stack: SystemCnf::DEFAULT_STACK
+                })
+            ),
+            (b"TCB = 4\nEVENT = 10\n", None),
+            (b"BOOT =\n", None)
+        ];
+
+        for (data, expected) in files {
+            assert_eq!(SystemCnf::parse(data), expected, "{}", String::from_utf8_lossy(data));
+        }
+    }
+
+    #[test]
+    fn executable_names() {
+        let names = [
+            ("cdrom:\\SLUS_012.34;1", "SLUS_012.34"),
+            ("cdrom:\\GAME\\MAIN.EXE;1", "MAIN.EXE"),
+            ("cdrom:PSX.EXE;1", "PSX.EXE"),
+            ("cdrom0:/DATA/BOOT.;1", "BOOT")
+        ];
+
+        for (boot, name) in names {
+            let system_cnf = SystemCnf { boot: boot.to_string(), ..SystemCnf::default() };
+            assert_eq!(system_cnf.executable(), name, "{}", boot);
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,1027 @@
+pub mod audio;
+pub mod disc;
+pub mod iso9660;
+
+use std::collections::VecDeque;
+
+use bitfield_struct::bitfield;
+use spdlog::prelude::*;
+
+use super::{devices::dma::DmaDevice, region::Region};
+use audio::{Submode, XaDecoder};
+use disc::{to_bcd, Disc, Msf, TrackKind, Q_SIZE, SECTOR_SIZE, SECTORS_PER_SECOND};
+
+const CPU_CLOCK: u64 = 33_868_800;
+/// CPU cycles between two 44.1 kHz samples of audio output
+pub const AUDIO_SAMPLE_CYCLES: u64 = CPU_CLOCK / 44_100;
+
+// Delays in CPU cycles, mostly averages measured on real drives
+const FIRST_RESPONSE_DELAY: u64 = 0xC4E1;
+const FIRST_RESPONSE_DELAY_STOPPED: u64 = 0x5CF4;
+const INIT_DELAY: u64 = 0x13CCE;
+const GET_ID_DELAY: u64 = 0x4A00;
+const PAUSE_DELAY_PAUSED: u64 = 0x1DF2;
+const PAUSE_DELAY_SINGLE: u64 = 0x21181C;
+const PAUSE_DELAY_DOUBLE: u64 = 0x10BD93;
+const STOP_DELAY_STOPPED: u64 = 0x1D7B;
+const STOP_DELAY_SINGLE: u64 = 0xD38ACA;
+const STOP_DELAY_DOUBLE: u64 = 0x18A6076;
+const MOTOR_ON_DELAY: u64 = CPU_CLOCK / 4;
+const READ_TOC_DELAY: u64 = CPU_CLOCK / 2;
+const SEEK_BASE_DELAY: u64 = 20_000;
+const SEEK_SECTOR_DELAY: u64 = 16;
+// The next queued interrupt shows up shortly after the CPU acknowledged the previous one
+const DELIVERY_DELAY: u64 = 1_000;
+
+const PARAMETER_FIFO_SIZE: usize = 16;
+/// Half a second of 44.1 kHz audio. The drive delivers whole sectors while the output takes
+/// one sample at a time, anything more than this behind is dropped
+const AUDIO_BUFFER_SIZE: usize = 22_050;
+
+// Interrupt types as they show up in the flag register
+const INT_DATA_READY: u8 = 1;
+const INT_COMPLETE: u8 = 2;
+const INT_ACKNOWLEDGE: u8 = 3;
+const INT_DATA_END: u8 = 4;
+const INT_ERROR: u8 = 5;
+
+// Error codes which follow the status byte in INT5 responses
+const ERROR_INVALID_PARAMETER: u8 = 0x10;
+const ERROR_WRONG_PARAMETER_COUNT: u8 = 0x20;
+const ERROR_INVALID_COMMAND: u8 = 0x40;
+const ERROR_NOT_READY: u8 = 0x80;
+const ERROR_SEEK_FAILED: u8 = 0x04;
+const ERROR_LID_OPENED: u8 = 0x08;
+
+/// The controller's timers, the bus turns them into scheduler events
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Timer {
+    /// The command in progress is done and sends its first response
+    Command,
+    /// A command's second response, for the ones which have one
+    SecondResponse,
+    /// The drive finished seeking or the next sector passed under the head
+    Drive,
+    /// A queued response can take the place of the acknowledged one
+    Delivery
+}
+
+struct Response {
+    interrupt: u8,
+    bytes: Vec<u8>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum AfterSeek {
+    Idle,
+    Read,
+    Play
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Drive {
+    Idle,
+    Seeking { target: u32, then: AfterSeek },
+    Reading,
+    Playing
+}
+
+/// The CD-ROM controller at 0x1F801800
+pub struct Cdrom {
+    index: u8,
+    parameters: VecDeque<u8>,
+    response: VecDeque<u8>,
+    data: VecDeque<u8>,
+    interrupt_enable: u8,
+    interrupt_flag: u8,
+    queued: VecDeque<Response>,
+
+    command: Option<u8>,
+    second_response: Option<u8>,
+
+    disc: Option<Box<dyn Disc>>,
+    console_region: Option<Region>,
+    lid_open: bool,
+    // Stays set after the lid closes until GetStat reports it
+    shell_opened: bool,
+    drive: Drive,
+    motor_on: bool,
+    mode: Mode,
+    setloc: Option<u32>,
+    position: u32,
+    sector: Box<[u8; SECTOR_SIZE]>,
+    sector_read: bool,
+    filter_file: u8,
+    filter_channel: u8,
+    play_track: u8,
+
+    audio: VecDeque<(i16, i16)>,
+    xa_decoder: XaDecoder,
+    muted: bool,
+    xa_muted: bool,
+    // Left to left, left to right, right to right and right to left, 0x80 is full volume
+    volume: [u8; 4],
+    pending_volume: [u8; 4],
+
+    timer_requests: Vec<(Timer, Option<u64>)>,
+    interrupt: bool
+}
+
+impl Default for Cdrom {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Cdrom {
+    pub fn new() -> Self {
+        Self {
+            index: 0,
+            parameters: VecDeque::with_capacity(PARAMETER_FIFO_SIZE),
+            response: VecDeque::with_capacity(16),
+            data: VecDeque::with_capacity(SECTOR_SIZE),
+            interrupt_enable: 0,
+            interrupt_flag: 0,
+            queued: VecDeque::new(),
+
+            command: None,
+            second_response: None,
+
+            disc: None,
+            console_region: None,
+            lid_open: false,
+            shell_opened: false,
+            drive: Drive::Idle,
+            motor_on: false,
+            mode: Mode::new(),
+            setloc: None,
+            position: 0,
+            sector: Box::new([0; SECTOR_SIZE]),
+            sector_read: false,
+            filter_file: 0,
+            filter_channel: 0,
+            play_track: 0,
+
+            audio: VecDeque::with_capacity(AUDIO_BUFFER_SIZE),
+            xa_decoder: XaDecoder::new(),
+            muted: false,
+            xa_muted: false,
+            volume: [0x80, 0x00, 0x80, 0x00],
+            pending_volume: [0x80, 0x00, 0x80, 0x00],
+
+            timer_requests: Vec::new(),
+            interrupt: false
+        }
+    }
+
+    /// Puts a disc into the drive, or takes it out with None. Games notice the swap only if
+    /// it happens with the lid open
+    pub fn set_disc(&mut self, disc: Option<Box<dyn Disc>>) {
+        self.motor_on = disc.is_some() && !self.lid_open;
+        self.disc = disc;
+        self.drive = Drive::Idle;
+        self.position = 0;
+        self.setloc = None;
+        self.sector_read = false;
+        self.request_timer(Timer::Drive, None);
+    }
+
+    /// Stops the motor, a read, play or seek in progress fails with an INT5
+    pub fn open_lid(&mut self) {
+        if self.lid_open {
+            return;
+        }
+        debug!("[CDROM] Lid opened");
+
+        let busy = self.drive != Drive::Idle;
+        let seeking = matches!(self.drive, Drive::Seeking { .. });
+        self.lid_open = true;
+        self.shell_opened = true;
+        self.motor_on = false;
+        self.stop_drive();
+        self.sector_read = false;
+
+        if busy {
+            let stat = self.stat() | if seeking { 0x05 } else { 0x01 };
+            self.push_response(INT_ERROR, vec![stat, ERROR_LID_OPENED]);
+        }
+    }
+
+    /// Spins the disc back up if there is one, the shell open bit stays set until the next
+    /// GetStat
+    pub fn close_lid(&mut self) {
+        if !self.lid_open {
+            return;
+        }
+        debug!("[CDROM] Lid closed");
+
+        self.lid_open = false;
+        self.motor_on = self.disc.is_some();
+        self.position = 0;
+    }
+
+    pub fn is_lid_open(&self) -> bool {
+        self.lid_open
+    }
+
+    /// The region of the console, GetID only reports discs of the same region as licensed
+    pub fn set_console_region(&mut self, region: Option<Region>) {
+        self.console_region = region;
+    }
+
+    pub fn has_disc(&self) -> bool {
+        self.disc.is_some()
+    }
+
+    /// The disc in the drive, reading it here doesn't disturb the drive
+    pub fn disc_mut(&mut self) -> Option<&mut dyn Disc> {
+        self.disc.as_mut().map(|disc| disc.as_mut() as _)
+    }
+
+    /// Returns true once for every interrupt raised towards the CPU
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    /// Timers to (re)start after the given delay, or stop with None
+    pub fn take_timer_requests(&mut self) -> Vec<(Timer, Option<u64>)> {
+        std::mem::take(&mut self.timer_requests)
+    }
+
+    /// The next 44.1 kHz sample of CD audio after the volume matrix, silence if the drive isn't
+    /// producing any. The bus takes one every `AUDIO_SAMPLE_CYCLES` while audio is captured
+    pub fn audio_sample(&mut self) -> (i16, i16) {
+        let (left, right) = self.audio.pop_front().unwrap_or_default();
+        let [left_to_left, left_to_right, right_to_right, right_to_left] = self.volume.map(|volume| volume as i32);
+        let mix = |sample: i32| (sample >> 7).clamp(i16::MIN as i32, i16::MAX as i32) as i16;
+
+        (
+            mix(left as i32 * left_to_left + right as i32 * right_to_left),
+            mix(right as i32 * right_to_right + left as i32 * left_to_right)
+        )
+    }
+
+    pub fn load8(&mut self, offset: u32) -> u8 {
+        let value = match (offset, self.index) {
+            (0, _) => self.status(),
+            (1, _) => self.response.pop_front().unwrap_or(0),
+            (2, _) => self.data.pop_front().unwrap_or(0),
+            // Reading either register gives the upper three bits set
+            (3, 0 | 2) => self.interrupt_enable | 0xE0,
+            (3, 1 | 3) => self.interrupt_flag | 0xE0,
+            _ => unreachable!()
+        };
+
+        trace!("[CDROM] Load8 at offset {}.{}: 0x{:02X}", offset, self.index, value);
+        value
+    }
+
+    /// 16-bit reads of the data FIFO take two bytes at once
+    pub fn load16(&mut self, offset: u32) -> u16 {
+        match offset {
+            2 => {
+                let lo = self.load8(2) as u16;
+                let hi = self.load8(2) as u16;
+                (hi << 8) | lo
+            }
+            _ => self.load8(offset) as u16
+        }
+    }
+
+    pub fn store8(&mut self, offset: u32, value: u8) {
+        trace!("[CDROM] Store8 at offset {}.{}: 0x{:02X}", offset, self.index, value);
+
+        match (offset, self.index) {
+            (0, _) => self.index = value & 0x3,
+            (1, 0) => self.write_command(value),
+            (2, 0) => {
+                if self.parameters.len() < PARAMETER_FIFO_SIZE {
+                    self.parameters.push_back(value);
+                }
+            }
+            (2, 1) => {
+                self.interrupt_enable = value & 0x1F;
+                self.update_interrupt();
+            }
+            (3, 0) => self.write_request(value),
+            (3, 1) => self.acknowledge(value),
+            (2, 2) => self.pending_volume[0] = value,
+            (3, 2) => self.pending_volume[1] = value,
+            (1, 3) => self.pending_volume[2] = value,
+            (2, 3) => self.pending_volume[3] = value,
+            (3, 3) => {
+                self.xa_muted = value & 0x01 != 0;
+                if value & 0x20 != 0 {
+                    self.volume = self.pending_volume;
+                }
+            }
+            // TODO: The sound map, XA-ADPCM written by the CPU instead of read from the disc
+            _ => debug!("[CDROM] Ignored store8 at offset {}.{}: 0x{:02X}", offset, self.index, value)
+        }
+    }
+
+    pub fn handle_timer(&mut self, timer: Timer) {
+        match timer {
+            Timer::Command => self.execute_command(),
+            Timer::SecondResponse => self.execute_second_response(),
+            Timer::Drive => self.drive_event(),
+            Timer::Delivery => {
+                if self.interrupt_flag == 0 && let Some(response) = self.queued.pop_front() {
+                    self.deliver(response);
+                }
+            }
+        }
+    }
+
+    fn status(&self) -> u8 {
+        self.index
+            | ((self.parameters.is_empty() as u8) << 3)
+            | (((self.parameters.len() < PARAMETER_FIFO_SIZE) as u8) << 4)
+            | ((!self.response.is_empty() as u8) << 5)
+            | ((!self.data.is_empty() as u8) << 6)
+            | ((self.command.is_some() as u8) << 7)
+    }
+
+    /// The status byte most responses start with
+    fn media_ready(&self) -> bool {
+        self.disc.is_some() && !self.lid_open
+    }
+
+    fn stat(&self) -> u8 {
+        let mut stat = Stat::new().with_motor_on(self.motor_on).with_shell_open(self.shell_opened);
+
+        match self.drive {
+            Drive::Idle => {}
+            Drive::Seeking { .. } => stat.set_seeking(true),
+            Drive::Reading => stat.set_reading(true),
+            Drive::Playing => stat.set_playing(true)
+        }
+
+        stat.into()
+    }
+
+    fn write_command(&mut self, command: u8) {
+        if let Some(previous) = self.command {
+            warn!("[CDROM] Command 0x{:02X} sent while 0x{:02X} is still busy", command, previous);
+        }
+        self.command = Some(command);
+
+        let delay = match command {
+            0x0A => INIT_DELAY,
+            _ if self.motor_on => FIRST_RESPONSE_DELAY,
+            _ => FIRST_RESPONSE_DELAY_STOPPED
+        };
+        self.request_timer(Timer::Command, Some(delay));
+    }
+
+    fn write_request(&mut self, value: u8) {
+        // BFRD loads the sector buffer into the data FIFO, clearing it drops whatever is left
+        if value & 0x80 == 0 {
+            self.data.clear();
+            return;
+        }
+        if !self.data.is_empty() || !self.sector_read {
+            return;
+        }
+
+        let data = if self.mode.whole_sector() {
+            &self.sector[12..12 + 0x924]
+        } else {
+            &self.sector[24..24 + 0x800]
+        };
+        self.data.extend(data.iter().copied());
+    }
+
+    fn acknowledge(&mut self, value: u8) {
+        self.interrupt_flag &= !(value & 0x1F);
+        if value & 0x40 != 0 {
+            self.parameters.clear();
+        }
+
+        if self.interrupt_flag == 0 && !self.queued.is_empty() {
+            self.request_timer(Timer::Delivery, Some(DELIVERY_DELAY));
+        }
+    }
+
+    fn push_response(&mut self, interrupt: u8, bytes: Vec<u8>) {
+        let response = Response { interrupt, bytes };
+
+        if self.interrupt_flag == 0 && self.queued.is_empty() {
+            self.deliver(response);
+            return;
+        }
+
+        // A new sector replaces one the CPU didn't get to yet
+        if interrupt == INT_DATA_READY {
+            self.queued.retain(|queued| queued.interrupt != INT_DATA_READY);
+        }
+        self.queued.push_back(response);
+    }
+
+    fn deliver(&mut self, response: Response) {
+        trace!("[CDROM] INT{} {:02X?}", response.interrupt, response.bytes);
+
+        self.response.clear();
+        self.response.extend(response.bytes);
+        self.interrupt_flag = response.interrupt;
+        self.update_interrupt();
+    }
+
+    fn update_interrupt(&mut self) {
+        if self.interrupt_flag & self.interrupt_enable != 0 {
+            self.interrupt = true;
+        }
+    }
+
+    fn request_timer(&mut self, timer: Timer, delay: Option<u64>) {
+        self.timer_requests.push((timer, delay));
+    }
+
+    fn acknowledge_with_stat(&mut self) {
+        let stat = self.stat();
+        self.push_response(INT_ACKNOWLEDGE, vec![stat]);
+    }
+
+    fn error(&mut self, code: u8) {
+        let stat = self.stat() | 0x01;
+        self.push_response(INT_ERROR, vec![stat, code]);
+    }
+
+    fn execute_command(&mut self) {
+        let Some(command) = self.command.take() else {
+            return;
+        };
+        let parameters: Vec<u8> = self.parameters.drain(..).collect();
+        debug!("[CDROM] Command 0x{:02X} {:02X?}", command, parameters);
+
+        let expected = match command {
+            0x02 => 3,
+            0x0D => 2,
+            0x0E | 0x12 | 0x14 | 0x19 => 1,
+            _ => 0
+        };
+        // Play and Test take a variable number of parameters
+        if parameters.len() != expected && !matches!(command, 0x03 | 0x19) {
+            self.error(ERROR_WRONG_PARAMETER_COUNT);
+            return;
+        }
+
+        let needs_disc = matches!(command, 0x03 | 0x06 | 0x07 | 0x10 | 0x11 | 0x12 | 0x13 | 0x14 | 0x15 | 0x16 | 0x1B | 0x1E);
+        // GetID only needs the disc for its second response, but fails right away with the lid open
+        if (needs_disc && !self.media_ready()) || (command == 0x1A && self.lid_open) {
+            self.error(ERROR_NOT_READY);
+            return;
+        }
+
+        match command {
+            // GetStat
+            0x01 => {
+                self.acknowledge_with_stat();
+                self.shell_opened = self.lid_open;
+            }
+            // Setloc
+            0x02 => match Msf::from_bcd(parameters[0], parameters[1], parameters[2]) {
+                Some(msf) => {
+                    self.setloc = Some(msf.to_lba());
+                    self.acknowledge_with_stat();
+                }
+                None => self.error(ERROR_INVALID_PARAMETER)
+            },
+            // Play, from the start of the given track or else from Setloc
+            0x03 => {
+                let track = parameters.first().and_then(|&track| disc::from_bcd(track)).filter(|&track| track != 0);
+                let target = match track {
+                    Some(number) => match self.disc.as_ref().unwrap().tracks().iter().find(|track| track.number == number) {
+                        Some(track) => Some(track.start),
+                        None => {
+                            self.error(ERROR_INVALID_PARAMETER);
+                            return;
+                        }
+                    },
+                    None => self.setloc.take()
+                };
+
+                self.acknowledge_with_stat();
+                match target {
+                    Some(target) if target != self.position => self.seek(target, AfterSeek::Play),
+                    _ => self.start_playing()
+                }
+            }
+            // ReadN and ReadS
+            0x06 | 0x1B => {
+                self.acknowledge_with_stat();
+                self.start_read();
+            }
+            // MotorOn
+            0x07 => {
+                self.acknowledge_with_stat();
+                self.schedule_second_response(command, if self.motor_on { PAUSE_DELAY_PAUSED } else { MOTOR_ON_DELAY });
+                self.motor_on = true;
+            }
+            // Stop
+            0x08 => {
+                let delay = match (self.motor_on, self.mode.double_speed()) {
+                    (false, _) => STOP_DELAY_STOPPED,
+                    (true, false) => STOP_DELAY_SINGLE,
+                    (true, true) => STOP_DELAY_DOUBLE
+                };
+
+                self.acknowledge_with_stat();
+                self.stop_drive();
+                self.motor_on = false;
+                self.schedule_second_response(command, delay);
+            }
+            // Pause
+            0x09 => {
+                let delay = match (self.drive, self.mode.double_speed()) {
+                    (Drive::Idle, _) => PAUSE_DELAY_PAUSED,
+                    (_, false) => PAUSE_DELAY_SINGLE,
+                    (_, true) => PAUSE_DELAY_DOUBLE
+                };
+
+                self.acknowledge_with_stat();
+                self.stop_drive();
+                self.schedule_second_response(command, delay);
+            }
+            // Init
+            0x0A => {
+                self.acknowledge_with_stat();
+                self.stop_drive();
+                self.mode = Mode(0x20);
+                self.motor_on = self.media_ready();
+                self.schedule_second_response(command, INIT_DELAY);
+            }
+            // Mute and Demute
+            0x0B | 0x0C => {
+                self.muted = command == 0x0B;
+                self.acknowledge_with_stat();
+            }
+            // Setfilter
+            0x0D => {
+                self.filter_file = parameters[0];
+                self.filter_channel = parameters[1];
+                self.acknowledge_with_stat();
+            }
+            // Setmode
+            0x0E => {
+                self.mode = Mode(parameters[0]);
+                self.acknowledge_with_stat();
+            }
+            // Getparam
+            0x0F => {
+                let response = vec![self.stat(), self.mode.into(), 0x00, self.filter_file, self.filter_channel];
+                self.push_response(INT_ACKNOWLEDGE, response);
+            }
+            // GetlocL, the header and subheader of the last data sector
+            0x10 => {
+                if !self.sector_read {
+                    self.error(ERROR_NOT_READY);
+                    return;
+                }
+                let response = self.sector[12..20].to_vec();
+                self.push_response(INT_ACKNOWLEDGE, response);
+            }
+            // GetlocP
+            0x11 => {
+                let response = self.position_report();
+                self.push_response(INT_ACKNOWLEDGE, response);
+            }
+            // SetSession, only single session discs exist here
+            0x12 => {
+                if parameters[0] != 1 {
+                    self.error(ERROR_INVALID_PARAMETER);
+                    return;
+                }
+                self.acknowledge_with_stat();
+                self.schedule_second_response(command, SEEK_BASE_DELAY);
+            }
+            // GetTN
+            0x13 => {
+                let tracks = self.disc.as_ref().unwrap().tracks();
+                let first = tracks.first().map_or(1, |track| track.number);
+                let last = tracks.last().map_or(1, |track| track.number);
+
+                let response = vec![self.stat(), to_bcd(first), to_bcd(last)];
+                self.push_response(INT_ACKNOWLEDGE, response);
+            }
+            // GetTD, track 0 is the lead-out
+            0x14 => {
+                let disc = self.disc.as_ref().unwrap();
+                let start = match disc::from_bcd(parameters[0]) {
+                    Some(0) => Some(disc.lead_out()),
+                    Some(number) => disc.tracks().iter().find(|track| track.number == number).map(|track| track.start),
+                    None => None
+                };
+
+                match start {
+                    Some(start) => {
+                        let [minute, second, _] = Msf::from_lba(start).to_bcd();
+                        let response = vec![self.stat(), minute, second];
+                        self.push_response(INT_ACKNOWLEDGE, response);
+                    }
+                    None => self.error(ERROR_INVALID_PARAMETER)
+                }
+            }
+            // SeekL and SeekP
+            0x15 | 0x16 => {
+                self.acknowledge_with_stat();
+
+                let target = self.setloc.take().unwrap_or(self.position);
+                self.seek(target, AfterSeek::Idle);
+            }
+            // Test
+            0x19 => match parameters.first() {
+                // BIOS date and version of the controller, a late SCPH-1001 one
+                Some(0x20) => self.push_response(INT_ACKNOWLEDGE, vec![0x94, 0x09, 0x19, 0xC0]),
+                _ => {
+                    warn!("[CDROM] Unhandled Test subcommand {:02X?}", parameters);
+                    self.error(ERROR_INVALID_PARAMETER);
+                }
+            },
+            // GetID
+            0x1A => {
+                self.acknowledge_with_stat();
+                self.schedule_second_response(command, GET_ID_DELAY);
+            }
+            // ReadTOC
+            0x1E => {
+                self.acknowledge_with_stat();
+                self.schedule_second_response(command, READ_TOC_DELAY);
+            }
+            _ => {
+                warn!("[CDROM] Unhandled command 0x{:02X}", command);
+                self.error(ERROR_INVALID_COMMAND);
+            }
+        }
+    }
+
+    fn schedule_second_response(&mut self, command: u8, delay: u64) {
+        self.second_response = Some(command);
+        self.request_timer(Timer::SecondResponse, Some(delay));
+    }
+
+    fn execute_second_response(&mut self) {
+        let Some(command) = self.second_response.take() else {
+            return;
+        };
+
+        match command {
+            // GetID
+            0x1A => self.identify(),
+            _ => {
+                let stat = self.stat();
+                self.push_response(INT_COMPLETE, vec![stat]);
+            }
+        }
+    }
+
+    // The second response of GetID, discs without the licence string or of another region are
+    // refused by the BIOS. The response is stat, flags, disc type, ATIP and the licence string
+    fn identify(&mut self) {
+        if self.lid_open {
+            self.error(ERROR_NOT_READY);
+            return;
+        }
+        let stat = self.stat();
+        let Some(disc) = &self.disc else {
+            self.push_response(INT_ERROR, vec![0x08, 0x40, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+            return;
+        };
+
+        let tracks = disc.tracks();
+        let has_audio = tracks.iter().any(|track| track.kind == TrackKind::Audio);
+        let unlicensed = if has_audio { 0x90 } else { 0x80 };
+
+        let response = match tracks.first().map(|track| track.kind) {
+            None | Some(TrackKind::Audio) => Err([0x90, 0x00]),
+            Some(TrackKind::Mode1) => Err([unlicensed, 0x00]),
+            // Discs we couldn't find a region for are assumed to match the console, and to be
+            // American if we don't know the console's either
+            Some(TrackKind::Mode2) => match (disc.region(), self.console_region) {
+                (Some(disc_region), Some(console)) if disc_region != console => {
+                    warn!("[CDROM] {} disc in a {} console", disc_region, console);
+                    Err([unlicensed, 0x20])
+                }
+                (Some(region), _) | (None, Some(region)) => Ok(region),
+                (None, None) => Ok(Region::NorthAmerica)
+            }
+        };
+
+        match response {
+            Ok(region) => self.push_response(INT_COMPLETE, vec![stat, 0x00, 0x20, 0x00, b'S', b'C', b'E', region.scex_letter()]),
+            Err([flags, kind]) => self.push_response(INT_ERROR, vec![stat | 0x08, flags, kind, 0x00, 0x00, 0x00, 0x00, 0x00])
+        }
+    }
+
+    /// Track, index and position within the track of `lba`, the lead-out is track 0xAA
+    fn track_position(&self, lba: u32) -> (u8, u8, u32) {
+        match self.disc.as_ref().and_then(|disc| disc.track_at(lba)) {
+            Some(track) if lba >= track.start + track.length => (0xAA, 1, lba - track.start - track.length),
+            Some(track) if lba < track.start => (track.number, 0, track.start - lba),
+            Some(track) => (track.number, 1, lba - track.start),
+            None => (0xAA, 1, 0)
+        }
+    }
+
+    /// The subchannel Q of `lba`: control/ADR, track, index, position within the track, zero
+    /// and absolute position, all BCD except the lead-out's track number
+    fn subchannel_q(&self, lba: u32) -> [u8; Q_SIZE] {
+        let (number, index, relative) = self.track_position(lba);
+        let audio = self.disc
+            .as_ref()
+            .and_then(|disc| disc.track_at(lba))
+            .is_some_and(|track| track.kind == TrackKind::Audio);
+        let number = if number == 0xAA { number } else { to_bcd(number) };
+
+        let [minute, second, frame] = Msf::from_lba(relative).to_bcd();
+        let [absolute_minute, absolute_second, absolute_frame] = Msf::from_lba(lba).to_bcd();
+        let mut q = [
+            if audio { 0x01 } else { 0x41 }, number, to_bcd(index),
+            minute, second, frame, 0x00,
+            absolute_minute, absolute_second, absolute_frame
+        ];
+
+        if let Some(disc) = &self.disc {
+            disc.patch_subchannel_q(lba, &mut q);
+        }
+        q
+    }
+
+    /// Track, index, position within the track and absolute position from subchannel Q
+    fn position_report(&self) -> Vec<u8> {
+        let q = self.subchannel_q(self.position);
+        [&q[1..6], &q[7..10]].concat()
+    }
+
+    fn sector_cycles(&self) -> u64 {
+        let speed = if self.mode.double_speed() { 2 } else { 1 };
+        CPU_CLOCK / (SECTORS_PER_SECOND as u64 * speed)
+    }
+
+    fn stop_drive(&mut self) {
+        self.drive = Drive::Idle;
+        self.request_timer(Timer::Drive, None);
+    }
+
+    fn start_read(&mut self) {
+        self.xa_decoder.reset();
+
+        match self.setloc.take() {
+            Some(target) if target != self.position => self.seek(target, AfterSeek::Read),
+            _ => self.start_reading()
+        }
+    }
+
+    // Reads with CD-DA enabled play audio tracks, auto pause needs to know which one it started on
+    fn start_reading(&mut self) {
+        self.drive = Drive::Reading;
+        self.play_track = self.track_position(self.position).0;
+
+        let delay = self.sector_cycles();
+        self.request_timer(Timer::Drive, Some(delay));
+    }
+
+    fn start_playing(&mut self) {
+        self.drive = Drive::Playing;
+        self.play_track = self.track_position(self.position).0;
+
+        let delay = self.sector_cycles();
+        self.request_timer(Timer::Drive, Some(delay));
+    }
+
+    fn seek(&mut self, target: u32, then: AfterSeek) {
+        self.motor_on = true;
+        self.drive = Drive::Seeking { target, then };
+
+        let distance = target.abs_diff(self.position) as u64;
+        self.request_timer(Timer::Drive, Some(SEEK_BASE_DELAY + distance * SEEK_SECTOR_DELAY));
+    }
+
+    fn drive_event(&mut self) {
+        match self.drive {
+            Drive::Idle => {}
+            Drive::Seeking { target, then } => {
+                let lead_out = self.disc.as_ref().map_or(0, |disc| disc.lead_out());
+                if target >= lead_out {
+                    warn!("[CDROM] Seek to {} past the end of the disc", Msf::from_lba(target));
+
+                    self.drive = Drive::Idle;
+                    let stat = self.stat() | 0x05;
+                    self.push_response(INT_ERROR, vec![stat, ERROR_SEEK_FAILED]);
+                    return;
+                }
+
+                self.position = target;
+                match then {
+                    AfterSeek::Idle => {
+                        self.drive = Drive::Idle;
+                        let stat = self.stat();
+                        self.push_response(INT_COMPLETE, vec![stat]);
+                    }
+                    AfterSeek::Read => self.start_reading(),
+                    AfterSeek::Play => self.start_playing()
+                }
+            }
+            Drive::Reading | Drive::Playing => {
+                let drive = self.drive;
+                let audio_track = self.disc
+                    .as_ref()
+                    .and_then(|disc| disc.track_at(self.position))
+                    .is_some_and(|track| track.kind == TrackKind::Audio);
+
+                // With CD-DA enabled, reading audio tracks plays them
+                if drive == Drive::Playing || (audio_track && self.mode.cdda()) {
+                    self.play_sector();
+                } else {
+                    self.read_sector();
+                }
+
+                if self.drive == drive {
+                    let delay = self.sector_cycles();
+                    self.request_timer(Timer::Drive, Some(delay));
+                }
+            }
+        }
+    }
+
+    fn play_sector(&mut self) {
+        let position = self.position;
+        let Some(lead_out) = self.disc.as_ref().map(|disc| disc.lead_out()) else {
+            return;
+        };
+
+        let track = self.track_position(position).0;
+        if position >= lead_out || (self.mode.auto_pause() && track != self.play_track) {
+            debug!("[CDROM] End of track {} at {}", self.play_track, Msf::from_lba(position));
+
+            self.stop_drive();
+            let stat = self.stat();
+            self.push_response(INT_DATA_END, vec![stat]);
+            return;
+        }
+        self.play_track = track;
+
+        let disc = self.disc.as_mut().unwrap();
+        if !disc.read_sector(position, &mut self.sector) {
+            warn!("[CDROM] Failed to read sector {}", Msf::from_lba(position));
+
+            self.stop_drive();
+            self.error(ERROR_NOT_READY);
+            return;
+        }
+        self.position += 1;
+
+        if !self.muted {
+            self.audio.extend(self.sector.chunks_exact(4).map(|sample| {
+                (i16::from_le_bytes([sample[0], sample[1]]), i16::from_le_bytes([sample[2], sample[3]]))
+            }));
+            self.trim_audio();
+        }
+
+        // Ten reports a second, alternating between absolute and relative positions
+        let frame = position % SECTORS_PER_SECOND;
+        if self.mode.report() && frame.is_multiple_of(10) {
+            let relative = (frame / 10) % 2 == 1;
+            let report = self.play_report(position, relative);
+            self.push_response(INT_DATA_READY, report);
+        }
+    }
+
+    /// Stat, track, index, either the absolute or the relative position (with bit 7 of the
+    /// seconds set) and the sector's peak level on the left or right channel
+    fn play_report(&self, position: u32, relative: bool) -> Vec<u8> {
+        let q = self.subchannel_q(position);
+        let [minute, second, frame] = match relative {
+            true => [q[3], q[4] | 0x80, q[5]],
+            false => [q[7], q[8], q[9]]
+        };
+
+        let channel = if relative { 2 } else { 0 };
+        let peak = self.sector
+            .chunks_exact(4)
+            .map(|sample| i16::from_le_bytes([sample[channel], sample[channel + 1]]).unsigned_abs())
+            .max()
+            .unwrap_or(0)
+            .min(0x7FFF)
+            | if relative { 0x8000 } else { 0 };
+
+        let [peak_low, peak_high] = peak.to_le_bytes();
+        vec![self.stat(), q[1], q[2], minute, second, frame, peak_low, peak_high]
+    }
+
+    fn trim_audio(&mut self) {
+        if self.audio.len() > AUDIO_BUFFER_SIZE {
+            let excess = self.audio.len() - AUDIO_BUFFER_SIZE;
+            self.audio.drain(..excess);
+        }
+    }
+
+    fn read_sector(&mut self) {
+        let position = self.position;
+        let Some(disc) = self.disc.as_mut() else {
+            return;
+        };
+
+        if !disc.read_sector(position, &mut self.sector) {
+            warn!("[CDROM] Failed to read sector {}", Msf::from_lba(position));
+
+            self.drive = Drive::Idle;
+            self.request_timer(Timer::Drive, None);
+            self.error(ERROR_NOT_READY);
+            return;
+        }
+        trace!("[CDROM] Read sector {}", Msf::from_lba(position));
+        self.position += 1;
+
+        // Real-time audio goes to the XA-ADPCM decoder instead of the CPU
+        let submode = Submode::from(self.sector[18]);
+        if self.mode.xa_adpcm() && self.sector[15] == 2 && submode.audio() && submode.real_time() {
+            let filtered = self.mode.xa_filter() && (self.sector[16] != self.filter_file || self.sector[17] != self.filter_channel);
+            if !filtered && !self.muted && !self.xa_muted {
+                self.xa_decoder.decode_sector(&self.sector, &mut self.audio);
+                self.trim_audio();
+            }
+            return;
+        }
+
+        self.sector_read = true;
+
+        let stat = self.stat();
+        self.push_response(INT_DATA_READY, vec![stat]);
+    }
+}
+
+impl DmaDevice for Cdrom {
+    fn dma_read(&mut self) -> u32 {
+        let mut word = 0;
+        for byte in 0..4 {
+            word |= (self.data.pop_front().unwrap_or(0) as u32) << (byte * 8);
+        }
+        word
+    }
+
+    fn dma_write(&mut self, value: u32) {
+        warn!("[CDROM] DMA write 0x{:08X}, the data FIFO is read only", value);
+    }
+}
+
+#[bitfield(u8)]
+struct Stat {
+    error: bool,
+    motor_on: bool,
+    seek_error: bool,
+    id_error: bool,
+    shell_open: bool,
+    reading: bool,
+    seeking: bool,
+    playing: bool
+}
+
+#[bitfield(u8)]
+struct Mode {
+    cdda: bool,
+    auto_pause: bool,
+    report: bool,
+    xa_filter: bool,
+    ignore_bit: bool,
+    whole_sector: bool,
+    xa_adpcm: bool,
+    double_speed: bool
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn audio_goes_through_the_volume_matrix() {
+        let mut cdrom = Cdrom::new();
+        assert_eq!(cdrom.audio_sample(), (0, 0));
+
+        cdrom.audio.extend([(1000, -2000), (1000, -2000), (30000, 30000)]);
+        assert_eq!(cdrom.audio_sample(), (1000, -2000));
+
+        // Half of each side to the other, only applied once bit 5 of 1F801803h.3 is written
+        cdrom.store8(0, 2);
+        cdrom.store8(2, 0x40);
+        cdrom.store8(3, 0x40);
+        cdrom.store8(0, 3);
+        cdrom.store8(1, 0x40);
+        cdrom.store8(2, 0x40);
+        cdrom.store8(3, 0x20);
+        assert_eq!(cdrom.audio_sample(), (-500, -500));
+        assert_eq!(cdrom.audio_sample(), (30000, 30000));
+
+        // Full volume both ways clips
+        cdrom.store8(0, 2);
+        cdrom.store8(2, 0x80);
+        cdrom.store8(3, 0x80);
+        cdrom.store8(0, 3);
+        cdrom.store8(1, 0x80);
+        cdrom.store8(2, 0x80);
+        cdrom.store8(3, 0x20);
+        cdrom.audio.push_back((30000, 30000));
+        assert_eq!(cdrom.audio_sample(), (i16::MAX, i16::MAX));
+    }
+}
\ No newline at end of file
@@ -18,6 +18,14 @@ impl Cop0 {
         self.status.isolate_cache()
     }
 
+    /// Hardware interrupt line from the interrupt controller, shows up in Cause bit 10
+    pub fn set_interrupt_line(&mut self, active: bool) {
+        let mut interrupt_pending = self.cause.interrupt_pending();
+        interrupt_pending &= !0x4;
+        interrupt_pending |= (active as u32) << 2;
+        self.cause.set_interrupt_pending(interrupt_pending);
+    }
+
     pub fn load(&self, register: usize) -> Option<u32> {
         trace!("Reading from COP0 register: {}", register);
         
@@ -0,0 +1,1015 @@
+use spdlog::prelude::*;
+
+use crate::core::precision::{PreciseVertex, PreciseWord};
+
+// Geometry Transformation Engine (COP2)
+// Register layout and command behaviour follow the nocash PSX specs
+
+const ROTATION: usize = 0;
+const LIGHT: usize = 1;
+const COLOR: usize = 2;
+
+const TRANSLATION: usize = 0;
+const BACKGROUND_COLOR: usize = 1;
+const FAR_COLOR: usize = 2;
+const ZERO: usize = 3;
+
+// FLAG register bits
+const IR0_SATURATED: u32 = 1 << 12;
+const SY2_SATURATED: u32 = 1 << 13;
+const SX2_SATURATED: u32 = 1 << 14;
+const MAC0_NEGATIVE_OVERFLOW: u32 = 1 << 15;
+const MAC0_POSITIVE_OVERFLOW: u32 = 1 << 16;
+const DIVIDE_OVERFLOW: u32 = 1 << 17;
+const SZ3_OTZ_SATURATED: u32 = 1 << 18;
+const COLOR_FIFO_B_SATURATED: u32 = 1 << 19;
+const COLOR_FIFO_G_SATURATED: u32 = 1 << 20;
+const COLOR_FIFO_R_SATURATED: u32 = 1 << 21;
+const IR3_SATURATED: u32 = 1 << 22;
+const IR2_SATURATED: u32 = 1 << 23;
+const IR1_SATURATED: u32 = 1 << 24;
+const MAC3_NEGATIVE_OVERFLOW: u32 = 1 << 25;
+const MAC2_NEGATIVE_OVERFLOW: u32 = 1 << 26;
+const MAC1_NEGATIVE_OVERFLOW: u32 = 1 << 27;
+const MAC3_POSITIVE_OVERFLOW: u32 = 1 << 28;
+const MAC2_POSITIVE_OVERFLOW: u32 = 1 << 29;
+const MAC1_POSITIVE_OVERFLOW: u32 = 1 << 30;
+const ERROR: u32 = 1 << 31;
+
+// Bits 30-23 and 18-13 are the ones which get summarized in bit 31
+const ERROR_MASK: u32 = 0x7F87E000;
+
+pub struct Gte {
+    // Control registers
+    matrices: [[[i16; 3]; 3]; 3],
+    control_vectors: [[i32; 3]; 4],
+    ofx: i32,
+    ofy: i32,
+    h: u16,
+    dqa: i16,
+    dqb: i32,
+    zsf3: i16,
+    zsf4: i16,
+    flag: u32,
+
+    // Data registers
+    vectors: [[i16; 3]; 3],
+    rgbc: [u8; 4],
+    otz: u16,
+    ir: [i16; 4],
+    xy_fifo: [(i16, i16); 3],
+    // Sub-pixel positions behind SXY0-2, for the entries RTPS/RTPT wrote
+    precise_fifo: [Option<PreciseVertex>; 3],
+    z_fifo: [u16; 4],
+    rgb_fifo: [[u8; 4]; 3],
+    res1: u32,
+    mac: [i32; 4],
+    lzcs: u32,
+    lzcr: u32
+}
+
+impl Gte {
+    pub fn new() -> Self {
+        Self {
+            matrices: [[[0; 3]; 3]; 3],
+            control_vectors: [[0; 3]; 4],
+            ofx: 0,
+            ofy: 0,
+            h: 0,
+            dqa: 0,
+            dqb: 0,
+            zsf3: 0,
+            zsf4: 0,
+            flag: 0,
+
+            vectors: [[0; 3]; 3],
+            rgbc: [0; 4],
+            otz: 0,
+            ir: [0; 4],
+            xy_fifo: [(0, 0); 3],
+            precise_fifo: [None; 3],
+            z_fifo: [0; 4],
+            rgb_fifo: [[0; 4]; 3],
+            res1: 0,
+            mac: [0; 4],
+            lzcs: 0,
+            lzcr: 32
+        }
+    }
+
+    pub fn load_data(&self, register: usize) -> u32 {
+        trace!("Reading from GTE data register: {}", register);
+
+        match register {
+            0 | 2 | 4 => pack_xy(self.vectors[register / 2][0], self.vectors[register / 2][1]),
+            1 | 3 | 5 => self.vectors[register / 2][2] as u32,
+            6 => u32::from_le_bytes(self.rgbc),
+            7 => self.otz as u32,
+            8..=11 => self.ir[register - 8] as u32,
+            12..=14 => {
+                let (x, y) = self.xy_fifo[register - 12];
+                pack_xy(x, y)
+            }
+            // SXYP is a mirror of SXY2 when read
+            15 => {
+                let (x, y) = self.xy_fifo[2];
+                pack_xy(x, y)
+            }
+            16..=19 => self.z_fifo[register - 16] as u32,
+            20..=22 => u32::from_le_bytes(self.rgb_fifo[register - 20]),
+            23 => self.res1,
+            24..=27 => self.mac[register - 24] as u32,
+            28 | 29 => {
+                let component = |ir: i16| ((ir >> 7).clamp(0, 0x1F)) as u32;
+
+                component(self.ir[1]) | (component(self.ir[2]) << 5) | (component(self.ir[3]) << 10)
+            }
+            30 => self.lzcs,
+            31 => self.lzcr,
+            _ => unreachable!()
+        }
+    }
+
+    /// The sub-pixel position behind SXY0-2 (or SXYP), if the GTE projected it
+    pub fn load_precise(&self, register: usize) -> Option<PreciseWord> {
+        let index = match register {
+            12..=14 => register - 12,
+            15 => 2,
+            _ => return None
+        };
+
+        self.precise_fifo[index].map(|vertex| PreciseWord { word: self.load_data(register), vertex })
+    }
+
+    pub fn store_data(&mut self, register: usize, value: u32) {
+        trace!("Writing to GTE data register: {}, value: 0x{:08X}", register, value);
+
+        match register {
+            0 | 2 | 4 => {
+                self.vectors[register / 2][0] = value as i16;
+                self.vectors[register / 2][1] = (value >> 16) as i16;
+            }
+            1 | 3 | 5 => self.vectors[register / 2][2] = value as i16,
+            6 => self.rgbc = value.to_le_bytes(),
+            7 => self.otz = value as u16,
+            8..=11 => self.ir[register - 8] = value as i16,
+            12..=14 => {
+                self.xy_fifo[register - 12] = (value as i16, (value >> 16) as i16);
+                self.precise_fifo[register - 12] = None;
+            }
+            15 => {
+                self.xy_fifo.copy_within(1.., 0);
+                self.xy_fifo[2] = (value as i16, (value >> 16) as i16);
+                self.precise_fifo.copy_within(1.., 0);
+                self.precise_fifo[2] = None;
+            }
+            16..=19 => self.z_fifo[register - 16] = value as u16,
+            20..=22 => self.rgb_fifo[register - 20] = value.to_le_bytes(),
+            23 => self.res1 = value,
+            24..=27 => self.mac[register - 24] = value as i32,
+            28 => {
+                self.ir[1] = ((value & 0x1F) << 7) as i16;
+                self.ir[2] = (((value >> 5) & 0x1F) << 7) as i16;
+                self.ir[3] = (((value >> 10) & 0x1F) << 7) as i16;
+            }
+            // ORGB and LZCR are read-only
+            29 | 31 => (),
+            30 => {
+                self.lzcs = value;
+                self.lzcr = if (value as i32) < 0 { value.leading_ones() } else { value.leading_zeros() };
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn load_control(&self, register: usize) -> u32 {
+        trace!("Reading from GTE control register: {}", register);
+
+        match register {
+            0..=4 | 8..=12 | 16..=20 => {
+                let matrix = self.matrices[register / 8];
+                let index = (register % 8) * 2;
+
+                // Matrices are stored as nine consecutive halfwords, the last register only holds one
+                let lo = matrix[index / 3][index % 3];
+                if index == 8 {
+                    lo as u32
+                } else {
+                    pack_xy(lo, matrix[(index + 1) / 3][(index + 1) % 3])
+                }
+            }
+            5..=7 | 13..=15 | 21..=23 => self.control_vectors[register / 8][(register % 8) - 5] as u32,
+            24 => self.ofx as u32,
+            25 => self.ofy as u32,
+            // H is unsigned but reads are sign-extended, a hardware bug
+            26 => self.h as i16 as u32,
+            27 => self.dqa as u32,
+            28 => self.dqb as u32,
+            29 => self.zsf3 as u32,
+            30 => self.zsf4 as u32,
+            31 => self.flag,
+            _ => unreachable!()
+        }
+    }
+
+    pub fn store_control(&mut self, register: usize, value: u32) {
+        trace!("Writing to GTE control register: {}, value: 0x{:08X}", register, value);
+
+        match register {
+            0..=4 | 8..=12 | 16..=20 => {
+                let matrix = &mut self.matrices[register / 8];
+                let index = (register % 8) * 2;
+
+                matrix[index / 3][index % 3] = value as i16;
+                if index != 8 {
+                    matrix[(index + 1) / 3][(index + 1) % 3] = (value >> 16) as i16;
+                }
+            }
+            5..=7 | 13..=15 | 21..=23 => self.control_vectors[register / 8][(register % 8) - 5] = value as i32,
+            24 => self.ofx = value as i32,
+            25 => self.ofy = value as i32,
+            26 => self.h = value as u16,
+            27 => self.dqa = value as i16,
+            28 => self.dqb = value as i32,
+            29 => self.zsf3 = value as i16,
+            30 => self.zsf4 = value as i16,
+            31 => {
+                self.flag = value & 0x7FFFF000;
+                if self.flag & ERROR_MASK != 0 {
+                    self.flag |= ERROR;
+                }
+            }
+            _ => unreachable!()
+        }
+    }
+
+    /// Runs a GTE command and returns how many cycles pass until its results can be read
+    pub fn execute(&mut self, command: u32) -> u64 {
+        let command = Command(command);
+        trace!("[GTE] Executing command 0x{:02X}: 0x{:08X}", command.opcode(), command.0);
+
+        self.flag = 0;
+
+        match command.opcode() {
+            0x01 => self.rtps(command, 0, true),
+            0x06 => self.nclip(),
+            0x0C => self.op(command),
+            0x10 => self.dpcs(command, false),
+            0x11 => self.intpl(command),
+            0x12 => self.mvmva(command),
+            0x13 => self.ncds(command, 0),
+            0x14 => self.cdp(command),
+            0x16 => (0..3).for_each(|vector| self.ncds(command, vector)),
+            0x1B => self.nccs(command, 0),
+            0x1C => self.cc(command),
+            0x1E => self.ncs(command, 0),
+            0x20 => (0..3).for_each(|vector| self.ncs(command, vector)),
+            0x28 => self.sqr(command),
+            0x29 => self.dcpl(command),
+            0x2A => (0..3).for_each(|_| self.dpcs(command, true)),
+            0x2D => self.avsz3(),
+            0x2E => self.avsz4(),
+            0x30 => {
+                self.rtps(command, 0, false);
+                self.rtps(command, 1, false);
+                self.rtps(command, 2, true);
+            }
+            0x3D => self.gpf(command),
+            0x3E => self.gpl(command),
+            0x3F => (0..3).for_each(|vector| self.nccs(command, vector)),
+            opcode => warn!("[GTE] Unknown command 0x{:02X}: 0x{:08X}", opcode, command.0)
+        }
+
+        if self.flag & ERROR_MASK != 0 {
+            self.flag |= ERROR;
+        }
+
+        command_cycles(command.opcode())
+    }
+
+    // Perspective transformation
+    fn rtps(&mut self, command: Command, vector: usize, depth_cue: bool) {
+        let shift = command.shift();
+        let lm = command.lm();
+        let v = self.vectors[vector];
+        let rt = self.matrices[ROTATION];
+        let tr = self.control_vectors[TRANSLATION];
+
+        let mut z = 0;
+        for row in 0..3 {
+            let mut value = (tr[row] as i64) << 12;
+            for column in 0..3 {
+                value = self.check_mac(row + 1, value + rt[row][column] as i64 * v[column] as i64);
+            }
+
+            self.mac[row + 1] = (value >> shift) as i32;
+            z = value;
+        }
+
+        self.ir[1] = self.saturate_ir(1, self.mac[1], lm);
+        self.ir[2] = self.saturate_ir(2, self.mac[2], lm);
+
+        // IR3 saturation is flagged against the unshifted value, regardless of sf
+        let z_shifted = (z >> 12) as i32;
+        if !(-0x8000..=0x7FFF).contains(&z_shifted) {
+            self.flag |= IR3_SATURATED;
+        }
+        let min = if lm { 0 } else { -0x8000 };
+        self.ir[3] = self.mac[3].clamp(min, 0x7FFF) as i16;
+
+        self.push_z(z_shifted as i64);
+
+        let projection_factor = self.divide() as i64;
+
+        let x = self.ofx as i64 + self.ir[1] as i64 * projection_factor;
+        let y = self.ofy as i64 + self.ir[2] as i64 * projection_factor;
+        self.check_mac0(x);
+        self.check_mac0(y);
+        self.push_xy(x >> 16, y >> 16);
+
+        // Saturated positions have nothing left to refine
+        if [x, y].iter().all(|value| (-0x400..=0x3FF).contains(&(value >> 16))) {
+            let fraction = |value: i64| (value as f64 / 65536.0) as f32;
+            let z = self.z_fifo[3] as f32;
+            self.precise_fifo[2] = Some(PreciseVertex { x: fraction(x), y: fraction(y), z });
+        }
+
+        if depth_cue {
+            let depth = self.dqb as i64 + self.dqa as i64 * projection_factor;
+            self.mac[0] = self.check_mac0(depth) as i32;
+            self.ir[0] = self.saturate_ir0(depth >> 12);
+        }
+    }
+
+    fn divide(&mut self) -> u32 {
+        let h = self.h as u32;
+        let sz3 = self.z_fifo[3] as u32;
+
+        if sz3 * 2 <= h {
+            self.flag |= DIVIDE_OVERFLOW;
+            return 0x1FFFF;
+        }
+
+        unr_divide(h, sz3)
+    }
+
+    // Normal clipping
+    fn nclip(&mut self) {
+        let [(x0, y0), (x1, y1), (x2, y2)] = self.xy_fifo.map(|(x, y)| (x as i64, y as i64));
+
+        let value = x0 * y1 + x1 * y2 + x2 * y0 - x0 * y2 - x1 * y0 - x2 * y1;
+        self.mac[0] = self.check_mac0(value) as i32;
+    }
+
+    // Outer product of two vectors
+    fn op(&mut self, command: Command) {
+        let rt = self.matrices[ROTATION];
+        let (d1, d2, d3) = (rt[0][0] as i64, rt[1][1] as i64, rt[2][2] as i64);
+        let [_, ir1, ir2, ir3] = self.ir.map(|ir| ir as i64);
+
+        self.set_mac_and_ir(command, [ir3 * d2 - ir2 * d3, ir1 * d3 - ir3 * d1, ir2 * d1 - ir1 * d2]);
+    }
+
+    // Depth cueing, single (or triple for DPCT, which always uses RGB0)
+    fn dpcs(&mut self, command: Command, fifo: bool) {
+        let [r, g, b, _] = if fifo { self.rgb_fifo[0] } else { self.rgbc };
+
+        self.interpolate_color(command, [(r as i64) << 16, (g as i64) << 16, (b as i64) << 16]);
+        self.push_color();
+    }
+
+    // Interpolation of a vector and far color
+    fn intpl(&mut self, command: Command) {
+        let [_, ir1, ir2, ir3] = self.ir.map(|ir| (ir as i64) << 12);
+
+        self.interpolate_color(command, [ir1, ir2, ir3]);
+        self.push_color();
+    }
+
+    // Multiply vector by matrix and add vector
+    fn mvmva(&mut self, command: Command) {
+        let matrix = match command.matrix() {
+            3 => {
+                // Selecting the reserved matrix gives a garbage mix of RGBC, IR0 and the rotation matrix
+                let r = (self.rgbc[0] as i16) << 4;
+                let rt = self.matrices[ROTATION];
+
+                [[-r, r, self.ir[0]], [rt[0][2]; 3], [rt[1][1]; 3]]
+            }
+            matrix => self.matrices[matrix]
+        };
+
+        let vector = match command.vector() {
+            3 => [self.ir[1], self.ir[2], self.ir[3]],
+            vector => self.vectors[vector]
+        };
+
+        let translation = self.control_vectors[command.translation()];
+
+        if command.translation() == FAR_COLOR {
+            self.multiply_far_color_bugged(command, matrix, vector, translation);
+        } else {
+            self.multiply_matrix_vector(command, matrix, vector, translation);
+        }
+    }
+
+    // Normal color depth cue, single vector (or triple for NCDT)
+    fn ncds(&mut self, command: Command, vector: usize) {
+        self.light_and_color(command, vector);
+        self.color_depth_cue(command);
+    }
+
+    // Color depth cue
+    fn cdp(&mut self, command: Command) {
+        let ir = [self.ir[1], self.ir[2], self.ir[3]];
+        self.multiply_matrix_vector(command, self.matrices[COLOR], ir, self.control_vectors[BACKGROUND_COLOR]);
+
+        self.color_depth_cue(command);
+    }
+
+    // Normal color color, single vector (or triple for NCCT)
+    fn nccs(&mut self, command: Command, vector: usize) {
+        self.light_and_color(command, vector);
+
+        let products = self.color_products();
+        self.set_mac_and_ir(command, products);
+        self.push_color();
+    }
+
+    // Color color
+    fn cc(&mut self, command: Command) {
+        let ir = [self.ir[1], self.ir[2], self.ir[3]];
+        self.multiply_matrix_vector(command, self.matrices[COLOR], ir, self.control_vectors[BACKGROUND_COLOR]);
+
+        let products = self.color_products();
+        self.set_mac_and_ir(command, products);
+        self.push_color();
+    }
+
+    // Normal color, single vector (or triple for NCT)
+    fn ncs(&mut self, command: Command, vector: usize) {
+        self.light_and_color(command, vector);
+        self.push_color();
+    }
+
+    // Square of vector IR
+    fn sqr(&mut self, command: Command) {
+        let [_, ir1, ir2, ir3] = self.ir.map(|ir| ir as i64);
+
+        self.set_mac_and_ir(command, [ir1 * ir1, ir2 * ir2, ir3 * ir3]);
+    }
+
+    // Depth cue color light
+    fn dcpl(&mut self, command: Command) {
+        let products = self.color_products();
+
+        self.interpolate_color(command, products);
+        self.push_color();
+    }
+
+    // Average of three Z values
+    fn avsz3(&mut self) {
+        let sum = self.z_fifo[1] as i64 + self.z_fifo[2] as i64 + self.z_fifo[3] as i64;
+        let value = self.zsf3 as i64 * sum;
+
+        self.mac[0] = self.check_mac0(value) as i32;
+        self.otz = self.saturate_otz(value >> 12);
+    }
+
+    // Average of four Z values
+    fn avsz4(&mut self) {
+        let sum = self.z_fifo.iter().map(|&z| z as i64).sum::<i64>();
+        let value = self.zsf4 as i64 * sum;
+
+        self.mac[0] = self.check_mac0(value) as i32;
+        self.otz = self.saturate_otz(value >> 12);
+    }
+
+    // General purpose interpolation
+    fn gpf(&mut self, command: Command) {
+        let ir0 = self.ir[0] as i64;
+        let [_, ir1, ir2, ir3] = self.ir.map(|ir| ir as i64);
+
+        self.set_mac_and_ir(command, [ir0 * ir1, ir0 * ir2, ir0 * ir3]);
+        self.push_color();
+    }
+
+    // General purpose interpolation with base
+    fn gpl(&mut self, command: Command) {
+        let shift = command.shift();
+        let ir0 = self.ir[0] as i64;
+        let [_, ir1, ir2, ir3] = self.ir.map(|ir| ir as i64);
+        let [_, mac1, mac2, mac3] = self.mac.map(|mac| (mac as i64) << shift);
+
+        self.set_mac_and_ir(command, [mac1 + ir0 * ir1, mac2 + ir0 * ir2, mac3 + ir0 * ir3]);
+        self.push_color();
+    }
+
+    // First two steps shared by the NCS/NCCS/NCDS family: light matrix then light color matrix
+    fn light_and_color(&mut self, command: Command, vector: usize) {
+        let v = self.vectors[vector];
+        self.multiply_matrix_vector(command, self.matrices[LIGHT], v, self.control_vectors[ZERO]);
+
+        let ir = [self.ir[1], self.ir[2], self.ir[3]];
+        self.multiply_matrix_vector(command, self.matrices[COLOR], ir, self.control_vectors[BACKGROUND_COLOR]);
+    }
+
+    fn color_depth_cue(&mut self, command: Command) {
+        let products = self.color_products();
+
+        self.interpolate_color(command, products);
+        self.push_color();
+    }
+
+    // [R*IR1, G*IR2, B*IR3] SHL 4, unshifted MAC values
+    fn color_products(&self) -> [i64; 3] {
+        let [r, g, b, _] = self.rgbc;
+
+        [
+            ((r as i64) * self.ir[1] as i64) << 4,
+            ((g as i64) * self.ir[2] as i64) << 4,
+            ((b as i64) * self.ir[3] as i64) << 4
+        ]
+    }
+
+    // Moves the unshifted MAC values towards the far color by IR0
+    fn interpolate_color(&mut self, command: Command, mac: [i64; 3]) {
+        let shift = command.shift();
+        let far_color = self.control_vectors[FAR_COLOR];
+
+        for index in 0..3 {
+            let value = self.check_mac(index + 1, ((far_color[index] as i64) << 12) - mac[index]);
+            self.mac[index + 1] = (value >> shift) as i32;
+            self.ir[index + 1] = self.saturate_ir(index + 1, self.mac[index + 1], false);
+        }
+
+        let ir0 = self.ir[0] as i64;
+        let values = [0, 1, 2].map(|index| self.ir[index + 1] as i64 * ir0 + mac[index]);
+        self.set_mac_and_ir(command, values);
+    }
+
+    fn multiply_matrix_vector(&mut self, command: Command, matrix: [[i16; 3]; 3], vector: [i16; 3], translation: [i32; 3]) {
+        let shift = command.shift();
+        let lm = command.lm();
+
+        for row in 0..3 {
+            let mut value = (translation[row] as i64) << 12;
+            for column in 0..3 {
+                value = self.check_mac(row + 1, value + matrix[row][column] as i64 * vector[column] as i64);
+            }
+
+            self.mac[row + 1] = (value >> shift) as i32;
+            self.ir[row + 1] = self.saturate_ir(row + 1, self.mac[row + 1], lm);
+        }
+    }
+
+    // MVMVA with the far color as translation only sets flags for the first column, the result ignores it
+    fn multiply_far_color_bugged(&mut self, command: Command, matrix: [[i16; 3]; 3], vector: [i16; 3], translation: [i32; 3]) {
+        let shift = command.shift();
+        let lm = command.lm();
+
+        for row in 0..3 {
+            let value = (translation[row] as i64) << 12;
+            let value = self.check_mac(row + 1, value + matrix[row][0] as i64 * vector[0] as i64);
+            self.saturate_ir(row + 1, (value >> shift) as i32, false);
+
+            let mut value = self.check_mac(row + 1, matrix[row][1] as i64 * vector[1] as i64);
+            value = self.check_mac(row + 1, value + matrix[row][2] as i64 * vector[2] as i64);
+
+            self.mac[row + 1] = (value >> shift) as i32;
+            self.ir[row + 1] = self.saturate_ir(row + 1, self.mac[row + 1], lm);
+        }
+    }
+
+    // Checks, shifts and stores MAC1-3, then saturates them into IR1-3
+    fn set_mac_and_ir(&mut self, command: Command, values: [i64; 3]) {
+        let shift = command.shift();
+        let lm = command.lm();
+
+        for (index, value) in values.into_iter().enumerate() {
+            let value = self.check_mac(index + 1, value);
+            self.mac[index + 1] = (value >> shift) as i32;
+            self.ir[index + 1] = self.saturate_ir(index + 1, self.mac[index + 1], lm);
+        }
+    }
+
+    // MAC1-3 are 44-bit wide, overflows get flagged and the value wraps around
+    fn check_mac(&mut self, index: usize, value: i64) -> i64 {
+        const POSITIVE: [u32; 3] = [MAC1_POSITIVE_OVERFLOW, MAC2_POSITIVE_OVERFLOW, MAC3_POSITIVE_OVERFLOW];
+        const NEGATIVE: [u32; 3] = [MAC1_NEGATIVE_OVERFLOW, MAC2_NEGATIVE_OVERFLOW, MAC3_NEGATIVE_OVERFLOW];
+
+        if value > 0x7FF_FFFF_FFFF {
+            self.flag |= POSITIVE[index - 1];
+        } else if value < -0x800_0000_0000 {
+            self.flag |= NEGATIVE[index - 1];
+        }
+
+        (value << 20) >> 20
+    }
+
+    // MAC0 is 32-bit wide
+    fn check_mac0(&mut self, value: i64) -> i64 {
+        if value > i32::MAX as i64 {
+            self.flag |= MAC0_POSITIVE_OVERFLOW;
+        } else if value < i32::MIN as i64 {
+            self.flag |= MAC0_NEGATIVE_OVERFLOW;
+        }
+
+        value
+    }
+
+    fn saturate_ir(&mut self, index: usize, value: i32, lm: bool) -> i16 {
+        const SATURATED: [u32; 3] = [IR1_SATURATED, IR2_SATURATED, IR3_SATURATED];
+
+        let min = if lm { 0 } else { -0x8000 };
+        if value < min || value > 0x7FFF {
+            self.flag |= SATURATED[index - 1];
+        }
+
+        value.clamp(min, 0x7FFF) as i16
+    }
+
+    fn saturate_ir0(&mut self, value: i64) -> i16 {
+        if !(0..=0x1000).contains(&value) {
+            self.flag |= IR0_SATURATED;
+        }
+
+        value.clamp(0, 0x1000) as i16
+    }
+
+    fn saturate_otz(&mut self, value: i64) -> u16 {
+        if !(0..=0xFFFF).contains(&value) {
+            self.flag |= SZ3_OTZ_SATURATED;
+        }
+
+        value.clamp(0, 0xFFFF) as u16
+    }
+
+    fn push_z(&mut self, value: i64) {
+        let z = self.saturate_otz(value);
+
+        self.z_fifo.copy_within(1.., 0);
+        self.z_fifo[3] = z;
+    }
+
+    fn push_xy(&mut self, x: i64, y: i64) {
+        if !(-0x400..=0x3FF).contains(&x) {
+            self.flag |= SX2_SATURATED;
+        }
+        if !(-0x400..=0x3FF).contains(&y) {
+            self.flag |= SY2_SATURATED;
+        }
+
+        self.xy_fifo.copy_within(1.., 0);
+        self.xy_fifo[2] = (x.clamp(-0x400, 0x3FF) as i16, y.clamp(-0x400, 0x3FF) as i16);
+        self.precise_fifo.copy_within(1.., 0);
+        self.precise_fifo[2] = None;
+    }
+
+    // Pushes MAC1-3 SAR 4 into the color FIFO, keeping the code byte of RGBC
+    fn push_color(&mut self) {
+        const SATURATED: [u32; 3] = [COLOR_FIFO_R_SATURATED, COLOR_FIFO_G_SATURATED, COLOR_FIFO_B_SATURATED];
+
+        let mut color = [0u8; 4];
+        for index in 0..3 {
+            let value = self.mac[index + 1] >> 4;
+            if !(0..=0xFF).contains(&value) {
+                self.flag |= SATURATED[index];
+            }
+            color[index] = value.clamp(0, 0xFF) as u8;
+        }
+        color[3] = self.rgbc[3];
+
+        self.rgb_fifo.copy_within(1.., 0);
+        self.rgb_fifo[2] = color;
+    }
+}
+
+// Cycles each command keeps the GTE busy for, including the issuing COP2 instruction
+fn command_cycles(opcode: u32) -> u64 {
+    match opcode {
+        0x01 => 15,
+        0x06 => 8,
+        0x0C => 6,
+        0x10 => 8,
+        0x11 => 8,
+        0x12 => 8,
+        0x13 => 19,
+        0x14 => 13,
+        0x16 => 44,
+        0x1B => 17,
+        0x1C => 11,
+        0x1E => 14,
+        0x20 => 30,
+        0x28 => 5,
+        0x29 => 8,
+        0x2A => 17,
+        0x2D => 5,
+        0x2E => 6,
+        0x30 => 23,
+        0x3D => 5,
+        0x3E => 5,
+        0x3F => 39,
+        _ => 1
+    }
+}
+
+// Reciprocal seeds used by the division, unr_table[i] = max(0, (0x40000 / (i + 0x100) + 1) / 2 - 0x101)
+const UNR_TABLE: [u8; 257] = {
+    let mut table = [0u8; 257];
+
+    let mut i = 0;
+    while i < table.len() {
+        let value = (0x40000 / (i as i32 + 0x100) + 1) / 2 - 0x101;
+        table[i] = if value > 0 { value as u8 } else { 0 };
+        i += 1;
+    }
+
+    table
+};
+
+// H / SZ3 as a 1.16 fixed point number, computed the way the hardware does it:
+// normalize the divisor, look up a seed and refine it with one Newton-Raphson step.
+// The caller has to make sure that SZ3 * 2 > H, so the result can't overflow
+fn unr_divide(h: u32, sz3: u32) -> u32 {
+    let shift = (sz3 as u16).leading_zeros();
+    let dividend = (h << shift) as u64;
+    let divisor = sz3 << shift;
+
+    let seed = UNR_TABLE[((divisor - 0x7FC0) >> 7) as usize] as u32 + 0x101;
+    let reciprocal = (0x2000080 - divisor * seed) >> 8;
+    let reciprocal = (0x80 + reciprocal * seed) >> 8;
+
+    ((dividend * reciprocal as u64 + 0x8000) >> 16).min(0x1FFFF) as u32
+}
+
+fn pack_xy(x: i16, y: i16) -> u32 {
+    (x as u16 as u32) | ((y as u16 as u32) << 16)
+}
+
+#[derive(Clone, Copy)]
+struct Command(u32);
+
+impl Command {
+    fn opcode(&self) -> u32 {
+        self.0 & 0x3F
+    }
+
+    fn lm(&self) -> bool {
+        self.0 & (1 << 10) != 0
+    }
+
+    fn translation(&self) -> usize {
+        ((self.0 >> 13) & 3) as usize
+    }
+
+    fn vector(&self) -> usize {
+        ((self.0 >> 15) & 3) as usize
+    }
+
+    fn matrix(&self) -> usize {
+        ((self.0 >> 17) & 3) as usize
+    }
+
+    fn shift(&self) -> u32 {
+        if self.0 & (1 << 19) != 0 { 12 } else { 0 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RTPS: u32 = 0x0000001 | (1 << 19);
+    const RTPS_NO_SHIFT: u32 = 0x0000001;
+    const GPF: u32 = 0x000003D | (1 << 19);
+
+    const IDENTITY: [[i16; 3]; 3] = [[0x1000, 0, 0], [0, 0x1000, 0], [0, 0, 0x1000]];
+    const ROTATED: [[i16; 3]; 3] = [[0x0F8A, -0x0321, 0x0100], [0x0300, 0x0FA0, -0x0200], [-0x0150, 0x0250, 0x0FC0]];
+
+    struct RtpsVector {
+        name: &'static str,
+        rotation: [[i16; 3]; 3],
+        translation: [i32; 3],
+        vertex: [i16; 3],
+        h: u16,
+        offset: (i32, i32),
+        dqa: i16,
+        dqb: i32,
+        command: u32,
+
+        sxy2: (i16, i16),
+        sz3: u16,
+        ir: [i16; 3],
+        mac: [i32; 3],
+        mac0: i32,
+        ir0: i16,
+        flag: u32
+    }
+
+    const RTPS_VECTORS: [RtpsVector; 5] = [
+        RtpsVector {
+            name: "identity",
+            rotation: IDENTITY, translation: [0, 0, 1000], vertex: [100, 50, 0],
+            h: 1000, offset: (0, 0), dqa: 0, dqb: 0, command: RTPS,
+            sxy2: (100, 50), sz3: 1000, ir: [100, 50, 1000], mac: [100, 50, 1000], mac0: 0, ir0: 0, flag: 0
+        },
+        RtpsVector {
+            name: "centered",
+            rotation: ROTATED, translation: [-120, 80, 0x500], vertex: [-300, 200, 800],
+            h: 0x155, offset: (160 << 16, 120 << 16), dqa: -0x1E3, dqb: 0x1400000, command: RTPS,
+            sxy2: (95, 139), sz3: 2121, ir: [-401, 119, 2121], mac: [-401, 119, 2121], mac0: 15882632, ir0: 3877, flag: 0
+        },
+        RtpsVector {
+            name: "divide overflow",
+            rotation: IDENTITY, translation: [0, 0, 200], vertex: [5000, -5000, 0],
+            h: 1000, offset: (0, 0), dqa: 0, dqb: 0, command: RTPS,
+            sxy2: (1023, -1024), sz3: 200, ir: [5000, -5000, 200], mac: [5000, -5000, 200], mac0: 0, ir0: 0,
+            flag: ERROR | DIVIDE_OVERFLOW | SX2_SATURATED | SY2_SATURATED
+        },
+        RtpsVector {
+            name: "behind camera",
+            rotation: ROTATED, translation: [0, 0, -0x800], vertex: [0x100, -0x80, 0x40],
+            h: 0x200, offset: (160 << 16, 120 << 16), dqa: -0x100, dqb: 0x1000000, command: RTPS,
+            sxy2: (713, -50), sz3: 0, ir: [277, -85, -2025], mac: [277, -85, -2025], mac0: -16776960, ir0: 0,
+            flag: ERROR | SZ3_OTZ_SATURATED | DIVIDE_OVERFLOW | IR0_SATURATED
+        },
+        RtpsVector {
+            name: "no shift",
+            rotation: IDENTITY, translation: [0, 0, 0], vertex: [0x10, 0x20, 0x40],
+            h: 0x40, offset: (0, 0), dqa: 0, dqb: 0, command: RTPS_NO_SHIFT,
+            sxy2: (1023, 1023), sz3: 64, ir: [0x7FFF, 0x7FFF, 0x7FFF], mac: [65536, 131072, 262144], mac0: 0, ir0: 0,
+            // IR3 gets clamped, but its flag only looks at MAC3 SAR 12 which is in range
+            flag: ERROR | IR1_SATURATED | IR2_SATURATED | SX2_SATURATED | SY2_SATURATED
+        }
+    ];
+
+    // (H, SZ3, H / SZ3 in 1.16 fixed point, divide overflow)
+    const DIVISION_VECTORS: [(u16, u16, u32, bool); 17] = [
+        (0x03E8, 0x03E8, 0x10000, false),
+        (0x0001, 0x0001, 0x10000, false),
+        (0x0000, 0x0000, 0x1FFFF, true),
+        (0x0000, 0x0001, 0x00000, false),
+        (0x0064, 0x0033, 0x1F5F6, false),
+        (0x0064, 0x0032, 0x1FFFF, true),
+        (0xFFFF, 0x8000, 0x1FFFE, false),
+        (0xFFFF, 0xFFFF, 0x0FFFF, false),
+        (0x0155, 0x03E8, 0x0574C, false),
+        (0x0155, 0x0123, 0x12BFC, false),
+        (0x00F0, 0x0001, 0x1FFFF, true),
+        (0x0001, 0xFFFF, 0x00001, false),
+        (0x7FFF, 0x4000, 0x1FFFC, false),
+        (0xFFFE, 0x7FFF, 0x1FFFF, true),
+        (0x3039, 0xD431, 0x03A2E, false),
+        (0x012C, 0x0097, 0x1FC9C, false),
+        (0x1234, 0x0ABC, 0x1B21D, false)
+    ];
+
+    fn store_matrix(gte: &mut Gte, base: usize, matrix: [[i16; 3]; 3]) {
+        let m = matrix.as_flattened();
+
+        for register in 0..4 {
+            gte.store_control(base + register, pack_xy(m[register * 2], m[register * 2 + 1]));
+        }
+        gte.store_control(base + 4, m[8] as u16 as u32);
+    }
+
+    #[test]
+    fn unr_table_matches_hardware() {
+        let head = [0xFF, 0xFD, 0xFB, 0xF9, 0xF7, 0xF5, 0xF3, 0xF1, 0xEF, 0xEE, 0xEC, 0xEA, 0xE8, 0xE6, 0xE4, 0xE3];
+
+        assert_eq!(UNR_TABLE[..16], head);
+        assert_eq!(UNR_TABLE[253..], [0x01, 0x00, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn division_vectors() {
+        for (h, sz3, expected, overflow) in DIVISION_VECTORS {
+            let mut gte = Gte::new();
+            gte.store_control(26, h as u32);
+            gte.store_data(19, sz3 as u32);
+
+            assert_eq!(gte.divide(), expected, "H = 0x{:04X}, SZ3 = 0x{:04X}", h, sz3);
+            assert_eq!(gte.flag & DIVIDE_OVERFLOW != 0, overflow, "H = 0x{:04X}, SZ3 = 0x{:04X}", h, sz3);
+        }
+    }
+
+    #[test]
+    fn rtps_vectors() {
+        for vector in RTPS_VECTORS {
+            let mut gte = Gte::new();
+            store_matrix(&mut gte, 0, vector.rotation);
+            for (index, &value) in vector.translation.iter().enumerate() {
+                gte.store_control(5 + index, value as u32);
+            }
+            gte.store_control(24, vector.offset.0 as u32);
+            gte.store_control(25, vector.offset.1 as u32);
+            gte.store_control(26, vector.h as u32);
+            gte.store_control(27, vector.dqa as u32);
+            gte.store_control(28, vector.dqb as u32);
+
+            gte.store_data(0, pack_xy(vector.vertex[0], vector.vertex[1]));
+            gte.store_data(1, vector.vertex[2] as u32);
+
+            gte.execute(vector.command);
+
+            let name = vector.name;
+            assert_eq!(gte.load_data(14), pack_xy(vector.sxy2.0, vector.sxy2.1), "{}: SXY2", name);
+            assert_eq!(gte.load_data(19), vector.sz3 as u32, "{}: SZ3", name);
+            for index in 0..3 {
+                assert_eq!(gte.load_data(9 + index), vector.ir[index] as u32, "{}: IR{}", name, index + 1);
+                assert_eq!(gte.load_data(25 + index), vector.mac[index] as u32, "{}: MAC{}", name, index + 1);
+            }
+            assert_eq!(gte.load_data(24), vector.mac0 as u32, "{}: MAC0", name);
+            assert_eq!(gte.load_data(8), vector.ir0 as u32, "{}: IR0", name);
+            assert_eq!(gte.load_control(31), vector.flag, "{}: FLAG = 0x{:08X}", name, gte.load_control(31));
+        }
+    }
+
+    #[test]
+    fn command_timings() {
+        let mut gte = Gte::new();
+
+        assert_eq!(gte.execute(RTPS), 15);
+        assert_eq!(gte.execute(0x0180030), 23);
+        assert_eq!(gte.execute(0x108041B), 17);
+        assert_eq!(gte.execute(0x118043F), 39);
+    }
+
+    #[test]
+    fn flag_writes_recompute_error_bit() {
+        // (written value, value read back)
+        let vectors = [
+            (0xFFFFFFFF, 0xFFFFF000),
+            (0x00000FFF, 0x00000000),
+            (0x00001000, 0x00001000),
+            (0x00040000, 0x80040000),
+            (0x00780000, 0x00780000),
+            (0x00800000, 0x80800000),
+            (0x80000000, 0x00000000)
+        ];
+
+        for (value, expected) in vectors {
+            let mut gte = Gte::new();
+            gte.store_control(31, value);
+
+            assert_eq!(gte.load_control(31), expected, "FLAG write 0x{:08X}", value);
+        }
+    }
+
+    #[test]
+    fn color_saturation_is_not_an_error() {
+        let mut gte = Gte::new();
+        gte.store_data(8, 0x1000);
+        gte.store_data(9, 0x7FFF);
+        gte.store_data(10, 0x7FFF);
+        gte.store_data(11, 0x7FFF);
+
+        gte.execute(GPF);
+
+        assert_eq!(gte.load_control(31), COLOR_FIFO_R_SATURATED | COLOR_FIFO_G_SATURATED | COLOR_FIFO_B_SATURATED);
+        assert_eq!(gte.load_data(22), 0x00FFFFFF);
+    }
+
+    #[test]
+    fn commands_clear_previous_flags() {
+        let mut gte = Gte::new();
+        gte.store_control(31, 0x7FFFF000);
+
+        gte.execute(RTPS);
+
+        // SZ3 is zero, so only the division overflow (and its summary bit) remain
+        assert_eq!(gte.load_control(31), ERROR | DIVIDE_OVERFLOW);
+    }
+
+    #[test]
+    fn keeps_sub_pixel_positions() {
+        let mut gte = Gte::new();
+        store_matrix(&mut gte, 0, [[0x1000, 0, 0], [0, 0x1000, 0], [0, 0, 0x1000]]);
+        gte.store_control(26, 200);
+        gte.store_data(0, pack_xy(101, 34));
+        gte.store_data(1, 600);
+
+        gte.execute(RTPS);
+
+        // 101 * 200 / 600 = 33.67 and 34 * 200 / 600 = 11.33
+        let precise = gte.load_precise(14).unwrap();
+        assert_eq!(precise.word, pack_xy(33, 11));
+        assert_eq!((precise.vertex.x.floor(), precise.vertex.y.floor(), precise.vertex.z), (33.0, 11.0, 600.0));
+        assert!((precise.vertex.x - 33.67).abs() < 0.01);
+        assert_eq!(gte.load_precise(15), Some(precise));
+        assert_eq!(gte.load_precise(13), None);
+
+        // They move along the FIFO, and are dropped when software writes the entries
+        gte.store_data(15, pack_xy(1, 2));
+        assert_eq!(gte.load_precise(13), Some(precise));
+        assert_eq!(gte.load_precise(14), None);
+        gte.store_data(13, pack_xy(33, 11));
+        assert_eq!(gte.load_precise(13), None);
+
+        // Saturated positions aren't refined
+        gte.store_data(0, pack_xy(0x7FFF, 34));
+        gte.execute(RTPS);
+        assert_eq!(gte.load_precise(14), None);
+    }
+}
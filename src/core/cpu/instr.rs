@@ -101,7 +101,43 @@ pub static CPU_INSTRUCTIONS: [fn(&mut Cpu, Instruction); 0x40] = [
         }
     },
     |cpu, instr| { unimplemented!("COP1") },
-    |cpu, instr| { unimplemented!("COP2") },
+    |cpu, instr| {
+        // Bit 25 set means the rest of the word is a GTE command
+        if instr.0 & (1 << 25) != 0 {
+            cpu.execute_gte_command(instr.0 & 0x1FFFFFF);
+            return;
+        }
+
+        match instr.rs() {
+            0 => {
+                cpu.wait_for_gte();
+                let value = cpu.gte.load_data(instr.rd());
+                cpu.load_delay_slot(instr.rt(), value);
+                cpu.set_precise_reg(instr.rt(), cpu.gte.load_precise(instr.rd()));
+            }
+            2 => {
+                cpu.wait_for_gte();
+                let value = cpu.gte.load_control(instr.rd());
+                cpu.load_delay_slot(instr.rt(), value);
+            }
+            4 => {
+                cpu.wait_for_gte();
+                cpu.gte.store_data(instr.rd(), cpu.regs[instr.rt()]);
+            }
+            6 => {
+                cpu.wait_for_gte();
+                cpu.gte.store_control(instr.rd(), cpu.regs[instr.rt()]);
+            }
+            8 => {
+                // BC2F/BC2T, the GTE has no condition line so it always reads as false
+                cpu.branch_delay = true;
+                if instr.rt() & 1 == 0 {
+                    branch(cpu, instr);
+                }
+            }
+            _ => op_illegal(cpu, instr)
+        }
+    },
     |cpu, instr| { unimplemented!("COP3") },
     op_illegal,
     op_illegal,
@@ -129,6 +165,7 @@ pub static CPU_INSTRUCTIONS: [fn(&mut Cpu, Instruction); 0x40] = [
         let value = cpu.load32(address);
 
         cpu.load_delay_slot(instr.rt(), value);
+        cpu.set_precise_reg(instr.rt(), cpu.bus.load_precise(address));
     },
     |cpu, instr| { unimplemented!("LBU") },
     |cpu, instr| { unimplemented!("LHU") },
@@ -149,7 +186,7 @@ pub static CPU_INSTRUCTIONS: [fn(&mut Cpu, Instruction); 0x40] = [
         let address = cpu.regs[instr.rs()].wrapping_add(instr.imm_signed());
         // TODO: Handle misalignments
 
-        cpu.store32(address, cpu.regs[instr.rt()]);
+        cpu.store32_precise(address, cpu.regs[instr.rt()], cpu.precise_regs[instr.rt()]);
     },
     op_illegal,
     op_illegal,
@@ -157,7 +194,14 @@ pub static CPU_INSTRUCTIONS: [fn(&mut Cpu, Instruction); 0x40] = [
     op_illegal,
     |cpu, instr| { unimplemented!("LWC0") },
     |cpu, instr| { unimplemented!("LWC1") },
-    |cpu, instr| { unimplemented!("LWC2") },
+    |cpu, instr| {
+        let address = cpu.regs[instr.rs()].wrapping_add(instr.imm_signed());
+        // TODO: Handle misalignments
+        let value = cpu.load32(address);
+
+        cpu.wait_for_gte();
+        cpu.gte.store_data(instr.rt(), value);
+    },
     |cpu, instr| { unimplemented!("LWC3") },
     op_illegal,
     op_illegal,
@@ -165,7 +209,13 @@ pub static CPU_INSTRUCTIONS: [fn(&mut Cpu, Instruction); 0x40] = [
     op_illegal,
     |cpu, instr| { unimplemented!("SWC0") },
     |cpu, instr| { unimplemented!("SWC1") },
-    |cpu, instr| { unimplemented!("SWC2") },
+    |cpu, instr| {
+        let address = cpu.regs[instr.rs()].wrapping_add(instr.imm_signed());
+        // TODO: Handle misalignments
+
+        cpu.wait_for_gte();
+        cpu.store32_precise(address, cpu.gte.load_data(instr.rt()), cpu.gte.load_precise(instr.rt()));
+    },
     |cpu, instr| { unimplemented!("SWC3") },
     op_illegal,
     op_illegal,
@@ -1,10 +1,12 @@
 mod cop0;
+mod gte;
 mod instr;
 
 use cop0::Cop0;
+use gte::Gte;
 use instr::{Instruction, CPU_INSTRUCTIONS};
 
-use super::bus::Bus;
+use super::{bus::Bus, precision::PreciseWord};
 
 use spdlog::prelude::*;
 
@@ -16,6 +18,8 @@ struct DelaySlot {
 
 pub struct Cpu {
     regs: [u32; 32],
+    // Sub-pixel vertices behind register values, only trusted while the value still matches
+    precise_regs: [Option<PreciseWord>; 32],
     program_counter: u32,
     program_counter_predictor: u32,
 
@@ -28,7 +32,12 @@ pub struct Cpu {
     ex_branch_delay: bool,
     ex_branch_taken: bool,
 
+    cycles: u64,
+    stall_cycles: u64,
+    gte_busy_until: u64,
+
     cop0: Cop0,
+    gte: Gte,
     bus: Bus
 }
 
@@ -39,6 +48,7 @@ impl Cpu {
         
         Self {
             regs,
+            precise_regs: [None; 32],
             program_counter: 0xBFC00000,
             program_counter_predictor: 0xBFC00004,
             branch_delay: false,
@@ -49,7 +59,13 @@ impl Cpu {
             ex_branch_taken: false,
 
             delay_slots: [None; 2],
+
+            cycles: 0,
+            stall_cycles: 0,
+            gte_busy_until: 0,
+
             cop0: Cop0::new(),
+            gte: Gte::new(),
             bus
         }
     }
@@ -63,10 +79,8 @@ impl Cpu {
         }
         self.regs[register] = value;
 
-        if let Some(slot) = self.delay_slots[0] {
-            if slot.register == register {
-                self.delay_slots[0] = None;
-            }
+        if let Some(slot) = self.delay_slots[0] && slot.register == register {
+            self.delay_slots[0] = None;
         }
     }
 
@@ -75,15 +89,20 @@ impl Cpu {
             return
         }
 
-        if let Some(slot) = self.delay_slots[0] {
-            if register == slot.register {
-                self.delay_slots[0] = None;
-            }
+        if let Some(slot) = self.delay_slots[0] && register == slot.register {
+            self.delay_slots[0] = None;
         }
 
         self.delay_slots[1] = Some(DelaySlot { register, value })
     }
 
+    // Tags a register with the precise vertex behind the value loaded into it
+    fn set_precise_reg(&mut self, register: usize, precise: Option<PreciseWord>) {
+        if register != 0 {
+            self.precise_regs[register] = precise;
+        }
+    }
+
     fn move_delay_slots(&mut self) {
         if let Some(slot0) = self.delay_slots[0] {
             self.regs[slot0.register] = slot0.value;
@@ -92,9 +111,10 @@ impl Cpu {
         self.delay_slots[0] = self.delay_slots[1].take();
     }
 
-    // TODO: Return ticks
-    pub fn clock(&mut self) {
+    /// Executes a single instruction, returns the number of cycles it took including interlock stalls
+    pub fn clock(&mut self) -> u64 {
         let instr = self.fetch_instruction(self.program_counter);
+        self.stall_cycles = 0;
 
         self.ex_program_counter = self.program_counter;
 
@@ -109,6 +129,65 @@ impl Cpu {
 
         CPU_INSTRUCTIONS[instr.opcode()](self, instr);
         self.move_delay_slots();
+
+        // TODO: Enter the exception handler when interrupts are enabled
+        self.cop0.set_interrupt_line(self.bus.interrupt_pending());
+
+        // TODO: Account for memory access times and the instruction cache
+        self.stall_cycles += self.bus.take_stall_cycles();
+
+        let ticks = 1 + self.stall_cycles;
+        self.cycles += ticks;
+        self.bus.tick(ticks);
+
+        ticks
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Address of the next instruction to execute
+    pub fn program_counter(&self) -> u32 {
+        self.program_counter
+    }
+
+    /// Continues execution at `address`, dropping any branch or load in flight
+    pub fn jump(&mut self, address: u32) {
+        self.program_counter = address;
+        self.program_counter_predictor = address.wrapping_add(4);
+        self.branch_delay = false;
+        self.branch_taken = false;
+        self.delay_slots = [None; 2];
+    }
+
+    pub fn set_register(&mut self, register: usize, value: u32) {
+        self.set_reg(register, value);
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    // Reading GTE results or issuing another command stalls until the running command finishes
+    fn wait_for_gte(&mut self) {
+        let now = self.cycles + self.stall_cycles;
+
+        if self.gte_busy_until > now {
+            trace!("[CPU] Stalling {} cycles for the GTE", self.gte_busy_until - now);
+            self.stall_cycles += self.gte_busy_until - now;
+        }
+    }
+
+    fn execute_gte_command(&mut self, command: u32) {
+        self.wait_for_gte();
+
+        let busy_cycles = self.gte.execute(command);
+        self.gte_busy_until = self.cycles + self.stall_cycles + busy_cycles;
     }
 
     fn fetch_instruction(&mut self, address: u32) -> Instruction {
@@ -116,15 +195,16 @@ impl Cpu {
         Instruction(self.load32(address))
     }
 
-    fn load32(&self, address: u32) -> u32 {
+    fn load32(&mut self, address: u32) -> u32 {
         self.bus.load32(address)
     }
 
-    fn load16(&self, address: u32) -> u16 {
+    #[allow(dead_code)]
+    fn load16(&mut self, address: u32) -> u16 {
         self.bus.load16(address)
     }
 
-    fn load8(&self, address: u32) -> u8 {
+    fn load8(&mut self, address: u32) -> u8 {
         self.bus.load8(address)
     }
 
@@ -137,6 +217,14 @@ impl Cpu {
         self.bus.store32(address, value);
     }
 
+    /// Stores a word along with the precise vertex behind it, if it's still the same word
+    fn store32_precise(&mut self, address: u32, value: u32, precise: Option<PreciseWord>) {
+        if !self.cop0.is_cache_isolated() {
+            self.bus.store_precise(address, precise.and_then(|precise| precise.matching(value)));
+        }
+        self.store32(address, value);
+    }
+
     fn store16(&mut self, address: u32, value: u16) {
         if self.cop0.is_cache_isolated() {
             trace!("Cache is isolated, ignoring store16 for now");
@@ -0,0 +1,428 @@
+use bitfield_struct::bitfield;
+use spdlog::prelude::*;
+
+use super::ram::Ram;
+use crate::core::precision::PreciseVertex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    MdecIn = 0,
+    MdecOut = 1,
+    Gpu = 2,
+    CdRom = 3,
+    Spu = 4,
+    Pio = 5,
+    Otc = 6
+}
+
+impl Port {
+    pub const ALL: [Port; 7] = [Port::MdecIn, Port::MdecOut, Port::Gpu, Port::CdRom, Port::Spu, Port::Pio, Port::Otc];
+}
+
+/// The device side of a DMA channel
+pub trait DmaDevice {
+    /// Word going from the device into RAM
+    fn dma_read(&mut self) -> u32;
+    /// Word going from RAM into the device
+    fn dma_write(&mut self, value: u32);
+
+    /// Same, along with the sub-pixel vertex RAM keeps for it
+    fn dma_write_precise(&mut self, value: u32, _precise: Option<PreciseVertex>) {
+        self.dma_write(value);
+    }
+}
+
+/// Stands in for devices which aren't emulated yet
+pub struct Disconnected(pub Port);
+
+impl DmaDevice for Disconnected {
+    fn dma_read(&mut self) -> u32 {
+        warn!("[DMA] Read from disconnected port {:?}", self.0);
+        0
+    }
+
+    fn dma_write(&mut self, value: u32) {
+        warn!("[DMA] Write to disconnected port {:?}: 0x{:08X}", self.0, value);
+    }
+}
+
+// Words moved per cycle is roughly one, linked list headers cost a little extra to fetch
+const WORD_CYCLES: u64 = 1;
+const HEADER_CYCLES: u64 = 2;
+
+// No real list can have more nodes than there are words in RAM, anything longer is a loop
+const MAX_LINKED_LIST_NODES: usize = 0x80000;
+
+pub struct Dma {
+    control: u32,
+    interrupt: InterruptControl,
+    channels: [Channel; 7],
+    interrupt_edge: bool
+}
+
+impl Default for Dma {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Self {
+            control: 0x07654321,
+            interrupt: InterruptControl::new(),
+            channels: [Channel::new(); 7],
+            interrupt_edge: false
+        }
+    }
+
+    pub fn load32(&self, offset: u32) -> u32 {
+        let index = (offset >> 4) as usize;
+
+        match (index, offset & 0xF) {
+            (0..=6, 0x0) => self.channels[index].base_address,
+            (0..=6, 0x4) => self.channels[index].block_control,
+            (0..=6, 0x8) => self.channels[index].control.into(),
+            (7, 0x0) => self.control,
+            (7, 0x4) => self.interrupt.into(),
+            _ => {
+                warn!("[DMA] Unhandled load32 at offset 0x{:02X}", offset);
+                0
+            }
+        }
+    }
+
+    pub fn store32(&mut self, offset: u32, value: u32) {
+        let index = (offset >> 4) as usize;
+        trace!("[DMA] Store32 at offset 0x{:02X}: 0x{:08X}", offset, value);
+
+        match (index, offset & 0xF) {
+            (0..=6, 0x0) => self.channels[index].base_address = value & 0xFFFFFF,
+            (0..=6, 0x4) => self.channels[index].block_control = value,
+            (0..=6, 0x8) => {
+                let channel = &mut self.channels[index];
+
+                channel.control = if index == 6 {
+                    // OTC only lets software start it, it always clears backwards into RAM
+                    ChannelControl(value & 0x51000000).with_decrement(true)
+                } else {
+                    ChannelControl(value & 0x71770703)
+                };
+
+                // Clearing the busy bit stops a transfer midway
+                if channel.running && !channel.control.busy() {
+                    debug!("[DMA] Channel {} stopped by software", index);
+                    channel.running = false;
+                }
+            }
+            (7, 0x0) => self.control = value,
+            (7, 0x4) => {
+                let acknowledged = (value >> 24) & 0x7F;
+                let flags = self.interrupt.flags() & !acknowledged;
+
+                self.interrupt = InterruptControl(value & 0x00FF803F).with_flags(flags);
+                self.update_master_flag();
+            }
+            _ => warn!("[DMA] Unhandled store32 at offset 0x{:02X}: 0x{:08X}", offset, value)
+        }
+    }
+
+    /// Returns true once for every rising edge of the DICR master flag
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt_edge)
+    }
+
+    /// The enabled channel which is ready to go with the highest priority, if any
+    pub fn active_channel(&self) -> Option<Port> {
+        Port::ALL
+            .into_iter()
+            .filter(|&port| self.is_enabled(port) && self.channels[port as usize].is_ready())
+            // Lower values win, on a tie the higher channel number does
+            .min_by_key(|&port| (self.priority(port), std::cmp::Reverse(port as usize)))
+    }
+
+    pub fn is_running(&self, port: Port) -> bool {
+        self.channels[port as usize].running
+    }
+
+    /// Moves the next chunk of an active channel's transfer. Burst transfers without chopping,
+    /// linked lists and OTC go in one chunk, chopped and sliced ones leave windows for the CPU
+    pub fn step(&mut self, port: Port, ram: &mut Ram, device: &mut dyn DmaDevice) -> Step {
+        let channel = &mut self.channels[port as usize];
+        if !channel.running {
+            trace!("[DMA] Starting {:?} transfer, CHCR: 0x{:08X}", port, u32::from(channel.control));
+
+            channel.running = true;
+            channel.address = channel.base_address;
+            channel.remaining = channel.words();
+        }
+
+        let control = channel.control;
+        let remaining = channel.remaining;
+        let cpu_window = 1 << control.chopping_cpu_window();
+
+        let (cycles, chunk) = match control.sync_mode() {
+            _ if port == Port::Otc => (self.clear_ordering_table(ram), None),
+            SyncMode::Manual if control.chopping() => {
+                let words = 1 << control.chopping_dma_window();
+                (self.transfer_words(port, ram, device, words), Some(words))
+            }
+            SyncMode::Manual => (self.transfer_words(port, ram, device, remaining), None),
+            SyncMode::Request => {
+                let words = match channel.block_control & 0xFFFF {
+                    0 => 0x10000,
+                    block_size => block_size
+                };
+                (self.transfer_words(port, ram, device, words), Some(words))
+            }
+            SyncMode::LinkedList => (self.transfer_linked_list(port, ram, device), None),
+            SyncMode::Reserved => {
+                warn!("[DMA] {:?} started with the reserved sync mode", port);
+                (0, None)
+            }
+        };
+
+        match chunk {
+            Some(_) if self.channels[port as usize].remaining > 0 => Step::Continue { cycles, cpu_window },
+            _ => Step::Done { cycles }
+        }
+    }
+
+    /// Ends the transfer, clearing the busy bit and raising the channel's interrupt flag
+    pub fn finish(&mut self, port: Port) {
+        let channel = &mut self.channels[port as usize];
+        channel.running = false;
+        channel.control.set_busy(false);
+        channel.control.set_trigger(false);
+
+        let bit = 1 << port as u32;
+        if self.interrupt.enable() & bit != 0 {
+            let flags = self.interrupt.flags() | bit;
+            self.interrupt.set_flags(flags);
+        }
+        self.update_master_flag();
+    }
+
+    // Builds an empty ordering table backwards from MADR, every entry links to the one before it
+    fn clear_ordering_table(&mut self, ram: &mut Ram) -> u64 {
+        let channel = &mut self.channels[Port::Otc as usize];
+        let words = channel.remaining;
+
+        let mut address = channel.address & 0x1FFFFC;
+        for remaining in (0..words).rev() {
+            let value = if remaining == 0 { 0x00FFFFFF } else { address.wrapping_sub(4) & 0x1FFFFC };
+            ram.store32(address, value);
+
+            address = address.wrapping_sub(4) & 0x1FFFFC;
+        }
+        channel.remaining = 0;
+
+        words as u64 * WORD_CYCLES
+    }
+
+    fn transfer_words(&mut self, port: Port, ram: &mut Ram, device: &mut dyn DmaDevice, words: u32) -> u64 {
+        let channel = &mut self.channels[port as usize];
+        let step = if channel.control.decrement() { 4u32.wrapping_neg() } else { 4 };
+        let words = words.min(channel.remaining);
+
+        for _ in 0..words {
+            let current = channel.address & 0x1FFFFC;
+
+            if channel.control.from_ram() {
+                device.dma_write_precise(ram.load32(current), ram.load_precise(current).map(|precise| precise.vertex));
+            } else {
+                ram.store32(current, device.dma_read());
+            }
+
+            channel.address = channel.address.wrapping_add(step);
+        }
+        channel.remaining -= words;
+
+        // Sliced transfers keep MADR and BA updated after every block
+        if channel.control.sync_mode() == SyncMode::Request {
+            let block_size = match channel.block_control & 0xFFFF {
+                0 => 0x10000,
+                block_size => block_size
+            };
+
+            channel.base_address = channel.address & 0xFFFFFF;
+            channel.block_control = (channel.block_control & 0xFFFF) | ((channel.remaining / block_size) << 16);
+        }
+
+        words as u64 * WORD_CYCLES
+    }
+
+    fn transfer_linked_list(&mut self, port: Port, ram: &mut Ram, device: &mut dyn DmaDevice) -> u64 {
+        let channel = &mut self.channels[port as usize];
+        if !channel.control.from_ram() {
+            warn!("[DMA] Linked list mode towards RAM on {:?}, ignoring", port);
+            return 0;
+        }
+
+        let mut cycles = 0;
+        let mut address = channel.base_address & 0x1FFFFC;
+        for _ in 0..MAX_LINKED_LIST_NODES {
+            let header = ram.load32(address);
+            let words = header >> 24;
+
+            for index in 1..=words {
+                let current = (address + index * 4) & 0x1FFFFC;
+                device.dma_write_precise(ram.load32(current), ram.load_precise(current).map(|precise| precise.vertex));
+            }
+            cycles += HEADER_CYCLES + words as u64 * WORD_CYCLES;
+
+            // The end marker is usually 0x00FFFFFF, but hardware only looks at bit 23
+            channel.base_address = header & 0xFFFFFF;
+            if header & 0x800000 != 0 {
+                return cycles;
+            }
+            address = header & 0x1FFFFC;
+        }
+
+        error!("[DMA] {:?} linked list at 0x{:08X} never ends, aborting the transfer", port, address);
+        cycles
+    }
+
+    fn update_master_flag(&mut self) {
+        let previous = self.interrupt.master_flag();
+        let master_flag = self.interrupt.force()
+            || (self.interrupt.master_enable() && self.interrupt.enable() & self.interrupt.flags() != 0);
+
+        self.interrupt.set_master_flag(master_flag);
+        if master_flag && !previous {
+            self.interrupt_edge = true;
+        }
+    }
+
+    fn is_enabled(&self, port: Port) -> bool {
+        self.control & (0x8 << (port as u32 * 4)) != 0
+    }
+
+    fn priority(&self, port: Port) -> u32 {
+        (self.control >> (port as u32 * 4)) & 0x7
+    }
+}
+
+/// Result of moving one chunk of a transfer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// The chunk took `cycles`, the next one comes after the CPU had `cpu_window` cycles of its own
+    Continue { cycles: u64, cpu_window: u64 },
+    /// The last chunk took `cycles`, the channel should be finished once they pass
+    Done { cycles: u64 }
+}
+
+#[derive(Clone, Copy)]
+struct Channel {
+    base_address: u32,
+    block_control: u32,
+    control: ChannelControl,
+
+    running: bool,
+    address: u32,
+    remaining: u32
+}
+
+impl Channel {
+    fn new() -> Self {
+        Self {
+            base_address: 0,
+            block_control: 0,
+            control: ChannelControl::new(),
+
+            running: false,
+            address: 0,
+            remaining: 0
+        }
+    }
+
+    /// Started by software but not picked up by the controller yet
+    fn is_ready(&self) -> bool {
+        if self.running {
+            return false;
+        }
+
+        // Manual transfers need the extra start trigger
+        match self.control.sync_mode() {
+            SyncMode::Manual => self.control.busy() && self.control.trigger(),
+            _ => self.control.busy()
+        }
+    }
+
+    fn words(&self) -> u32 {
+        let block_size = self.block_control & 0xFFFF;
+        let block_count = self.block_control >> 16;
+
+        match self.control.sync_mode() {
+            SyncMode::Manual => if block_size == 0 { 0x10000 } else { block_size },
+            SyncMode::Request => {
+                let block_size = if block_size == 0 { 0x10000 } else { block_size };
+                let block_count = if block_count == 0 { 0x10000 } else { block_count };
+                block_size * block_count
+            }
+            _ => 0
+        }
+    }
+}
+
+#[repr(u32)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    Manual = 0,
+    Request = 1,
+    LinkedList = 2,
+    Reserved = 3
+}
+impl SyncMode {
+    const fn into_bits(self) -> u32 { self as _ }
+    const fn from_bits(value: u32) -> Self {
+        match value {
+            0 => Self::Manual,
+            1 => Self::Request,
+            2 => Self::LinkedList,
+            3 => Self::Reserved,
+            _ => unreachable!()
+        }
+    }
+}
+
+#[bitfield(u32)]
+pub struct ChannelControl {
+    from_ram: bool,
+    decrement: bool,
+    #[bits(6)]
+    _pad1: u32,
+    chopping: bool,
+    #[bits(2)]
+    sync_mode: SyncMode,
+    #[bits(5)]
+    _pad2: u32,
+    #[bits(3)]
+    chopping_dma_window: u32,
+    _pad3: bool,
+    #[bits(3)]
+    chopping_cpu_window: u32,
+    _pad4: bool,
+    busy: bool,
+    #[bits(3)]
+    _pad5: u32,
+    trigger: bool,
+    #[bits(2)]
+    unknown: u32,
+    _pad6: bool
+}
+
+#[bitfield(u32)]
+pub struct InterruptControl {
+    #[bits(6)]
+    unknown: u32,
+    #[bits(9)]
+    _pad1: u32,
+    force: bool,
+    #[bits(7)]
+    enable: u32,
+    master_enable: bool,
+    #[bits(7)]
+    flags: u32,
+    master_flag: bool
+}
\ No newline at end of file
@@ -0,0 +1,61 @@
+use spdlog::prelude::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    Gpu = 1,
+    CdRom = 2,
+    Dma = 3,
+    Timer0 = 4,
+    Timer1 = 5,
+    Timer2 = 6,
+    Controller = 7,
+    Sio = 8,
+    Spu = 9,
+    Lightpen = 10
+}
+
+// I_STAT and I_MASK, only the lower 11 bits are used
+pub struct InterruptController {
+    status: u32,
+    mask: u32
+}
+
+impl Default for InterruptController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InterruptController {
+    pub fn new() -> Self {
+        Self { status: 0, mask: 0 }
+    }
+
+    pub fn request(&mut self, interrupt: Interrupt) {
+        trace!("[IRQ] Requested {:?}", interrupt);
+        self.status |= 1 << interrupt as u32;
+    }
+
+    /// State of the line going into COP0 Cause bit 10
+    pub fn pending(&self) -> bool {
+        self.status & self.mask != 0
+    }
+
+    pub fn load32(&self, offset: u32) -> u32 {
+        match offset {
+            0 => self.status,
+            4 => self.mask,
+            _ => unreachable!()
+        }
+    }
+
+    pub fn store32(&mut self, offset: u32, value: u32) {
+        match offset {
+            // Writing zeroes acknowledges, ones leave the bits untouched
+            0 => self.status &= value & 0x7FF,
+            4 => self.mask = value & 0x7FF,
+            _ => unreachable!()
+        }
+    }
+}
\ No newline at end of file
@@ -1 +1,5 @@
-pub mod ram;
\ No newline at end of file
+pub mod dma;
+pub mod interrupts;
+pub mod ram;
+pub mod sio;
+pub mod timers;
\ No newline at end of file
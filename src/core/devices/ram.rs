@@ -1,6 +1,16 @@
+use crate::core::precision::{PrecisionMemory, PreciseWord};
+
 pub struct Ram {
     // TODO: Maybe replace with Vec<u8> instead
-    data: Box<[u8; 2 * 1024 * 1024]>
+    data: Box<[u8; 2 * 1024 * 1024]>,
+    // Sub-pixel vertices stored along with words, None unless precise geometry is on
+    precision: Option<PrecisionMemory>
+}
+
+impl Default for Ram {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Ram {
@@ -11,7 +21,26 @@ impl Ram {
             .try_into()
             .unwrap();
 
-        Self { data }
+        Self { data, precision: None }
+    }
+
+    pub fn set_precision_tracking(&mut self, enabled: bool) {
+        if enabled != self.precision.is_some() {
+            self.precision = enabled.then(PrecisionMemory::new);
+        }
+    }
+
+    /// Records the precise vertex of the word about to be stored at `address`
+    pub fn store_precise(&mut self, address: u32, precise: Option<PreciseWord>) {
+        if let Some(precision) = &mut self.precision {
+            precision.store(address & 0x1FFFFF, precise);
+        }
+    }
+
+    /// The precise vertex behind the word at `address`, if it's still there
+    pub fn load_precise(&self, address: u32) -> Option<PreciseWord> {
+        let precision = self.precision.as_ref()?;
+        precision.load(address & 0x1FFFFF, self.load32(address))
     }
 
     pub fn load32(&self, address: u32) -> u32 {
@@ -0,0 +1,448 @@
+use std::{fs::{self, File}, io::{self, Write}, path::{Path, PathBuf}};
+
+use spdlog::prelude::*;
+
+use super::SioDevice;
+
+pub const CARD_SIZE: usize = 128 * 1024;
+pub const SECTOR_SIZE: usize = 128;
+pub const SECTOR_COUNT: u16 = (CARD_SIZE / SECTOR_SIZE) as u16;
+
+// FLAG bits: a fresh card reports that it was never written to, failed writes set the error bit
+const FLAG_WRITE_ERROR: u8 = 0x04;
+const FLAG_FRESH: u8 = 0x08;
+
+const END_GOOD: u8 = 0x47;
+const END_BAD_CHECKSUM: u8 = 0x4E;
+const END_BAD_SECTOR: u8 = 0xFF;
+
+/// Frames without a write before a changed card is saved, saving a block takes 64 sector writes
+/// and each one would otherwise rewrite the whole image
+const FLUSH_DELAY_FRAMES: u32 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    Read,
+    Write,
+    Id
+}
+
+/// A 128KB memory card, optionally backed by a raw .mcr image which is rewritten once the game
+/// stops writing for a while, on `flush` and when the card is dropped
+pub struct MemoryCard {
+    data: Box<[u8; CARD_SIZE]>,
+    path: Option<PathBuf>,
+    flag: u8,
+    dirty: bool,
+    idle_frames: u32,
+
+    position: Option<usize>,
+    command: Command,
+    sector: u16,
+    checksum: u8,
+    buffer: [u8; SECTOR_SIZE],
+    previous: u8,
+    status: u8
+}
+
+impl Default for MemoryCard {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryCard {
+    /// An unbacked, freshly formatted card
+    pub fn new() -> Self {
+        Self::with_data(formatted(), None)
+    }
+
+    /// Opens a .mcr image, a missing one is created as a formatted card
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let path = path.as_ref();
+
+        let data = match fs::read(path) {
+            Ok(data) => data.into_boxed_slice().try_into().map_err(|data: Box<[u8]>| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("memory card image is {} bytes, expected {}", data.len(), CARD_SIZE))
+            })?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                info!("[MEMCARD] Creating a new card at {}", path.display());
+
+                let data = formatted();
+                write_atomically(path, &data[..])?;
+                data
+            }
+            Err(error) => return Err(error)
+        };
+
+        Ok(Self::with_data(data, Some(path.to_path_buf())))
+    }
+
+    fn with_data(data: Box<[u8; CARD_SIZE]>, path: Option<PathBuf>) -> Self {
+        Self {
+            data,
+            path,
+            flag: FLAG_FRESH,
+            dirty: false,
+            idle_frames: 0,
+
+            position: Some(0),
+            command: Command::Read,
+            sector: 0,
+            checksum: 0,
+            buffer: [0; SECTOR_SIZE],
+            previous: 0,
+            status: END_GOOD
+        }
+    }
+
+    pub fn data(&self) -> &[u8; CARD_SIZE] {
+        &self.data
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    /// Whether there are writes which haven't been saved to the image yet
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// Saves pending writes to the image right away
+    pub fn flush(&mut self) -> Result<(), io::Error> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        if let Some(path) = &self.path {
+            write_atomically(path, &self.data[..])?;
+            debug!("[MEMCARD] Saved {}", path.display());
+        }
+        self.dirty = false;
+        Ok(())
+    }
+
+    // Saves from vblank and drop have nobody to hand the error to
+    fn flush_or_log(&mut self) {
+        if let Err(error) = self.flush() && let Some(path) = &self.path {
+            error!("[MEMCARD] Failed to save {}: {}", path.display(), error);
+        }
+    }
+
+    fn sector_valid(&self) -> bool {
+        self.sector < SECTOR_COUNT
+    }
+
+    fn read(&mut self, index: usize, value: u8) -> (u8, bool) {
+        let reply = match index {
+            2 => 0x5A,
+            3 => 0x5D,
+            4 => {
+                self.sector = (value as u16) << 8;
+                0x00
+            }
+            5 => {
+                self.sector |= value as u16;
+                self.checksum = (self.sector >> 8) as u8 ^ self.sector as u8;
+                (self.sector >> 8) as u8
+            }
+            6 => 0x5C,
+            7 => 0x5D,
+            // Out of range sectors answer with FFFFh and end the command there
+            8 if !self.sector_valid() => 0xFF,
+            9 if !self.sector_valid() => {
+                self.position = None;
+                return (0xFF, false);
+            }
+            8 => (self.sector >> 8) as u8,
+            9 => self.sector as u8,
+            10..=137 => {
+                let byte = self.data[self.sector as usize * SECTOR_SIZE + index - 10];
+                self.checksum ^= byte;
+                byte
+            }
+            138 => self.checksum,
+            _ => {
+                self.position = None;
+                return (END_GOOD, false);
+            }
+        };
+
+        (reply, true)
+    }
+
+    fn write(&mut self, index: usize, value: u8) -> (u8, bool) {
+        // During the address and data phase the card echoes the previous byte back
+        let reply = match index {
+            2 => 0x5A,
+            3 => 0x5D,
+            4 => {
+                self.sector = (value as u16) << 8;
+                self.previous
+            }
+            5 => {
+                self.sector |= value as u16;
+                self.checksum = (self.sector >> 8) as u8 ^ self.sector as u8;
+                self.previous
+            }
+            6..=133 => {
+                self.buffer[index - 6] = value;
+                self.checksum ^= value;
+                self.previous
+            }
+            134 => {
+                self.status = match (self.sector_valid(), self.checksum == value) {
+                    (false, _) => END_BAD_SECTOR,
+                    (true, false) => END_BAD_CHECKSUM,
+                    (true, true) => END_GOOD
+                };
+                self.previous
+            }
+            135 => 0x5C,
+            136 => 0x5D,
+            _ => {
+                self.finish_write();
+                self.position = None;
+                return (self.status, false);
+            }
+        };
+        self.previous = value;
+
+        (reply, true)
+    }
+
+    fn finish_write(&mut self) {
+        if self.status != END_GOOD {
+            warn!("[MEMCARD] Write to sector 0x{:04X} failed with 0x{:02X}", self.sector, self.status);
+            self.flag |= FLAG_WRITE_ERROR;
+            return;
+        }
+
+        let start = self.sector as usize * SECTOR_SIZE;
+        self.data[start..start + SECTOR_SIZE].copy_from_slice(&self.buffer);
+        self.flag = 0;
+        self.dirty = true;
+        self.idle_frames = 0;
+    }
+
+    fn id(&mut self, index: usize) -> (u8, bool) {
+        match index {
+            2 => (0x5A, true),
+            3 => (0x5D, true),
+            4 => (0x5C, true),
+            5 => (0x5D, true),
+            6 => (0x04, true),
+            7 => (0x00, true),
+            8 => (0x00, true),
+            _ => {
+                self.position = None;
+                (0x80, false)
+            }
+        }
+    }
+}
+
+impl SioDevice for MemoryCard {
+    fn transfer(&mut self, value: u8) -> (u8, bool) {
+        let Some(position) = self.position else {
+            return (0xFF, false);
+        };
+        self.position = Some(position + 1);
+
+        match position {
+            // Commands starting with anything else are for the controller
+            0 if value == 0x81 => (0xFF, true),
+            0 => {
+                self.position = None;
+                (0xFF, false)
+            }
+            1 => {
+                let command = match value {
+                    0x52 => Command::Read,
+                    0x57 => Command::Write,
+                    0x53 => Command::Id,
+                    _ => {
+                        trace!("[MEMCARD] Unsupported command 0x{:02X}", value);
+                        self.position = None;
+                        return (self.flag, false);
+                    }
+                };
+                self.command = command;
+                self.previous = 0;
+
+                (self.flag, true)
+            }
+            _ => match self.command {
+                Command::Read => self.read(position, value),
+                Command::Write => self.write(position, value),
+                Command::Id => self.id(position)
+            }
+        }
+    }
+
+    fn deselect(&mut self) {
+        self.position = Some(0);
+    }
+
+    fn vblank(&mut self) {
+        if !self.dirty {
+            return;
+        }
+
+        self.idle_frames += 1;
+        if self.idle_frames >= FLUSH_DELAY_FRAMES {
+            // Failures are retried after another delay
+            self.idle_frames = 0;
+            self.flush_or_log();
+        }
+    }
+}
+
+impl Drop for MemoryCard {
+    fn drop(&mut self) {
+        self.flush_or_log();
+    }
+}
+
+/// An empty card the way the BIOS formats it
+pub fn formatted() -> Box<[u8; CARD_SIZE]> {
+    let mut data: Box<[u8; CARD_SIZE]> = vec![0; CARD_SIZE].into_boxed_slice().try_into().unwrap();
+
+    let mut set_frame = |frame: usize, contents: &[u8]| {
+        let sector = &mut data[frame * SECTOR_SIZE..(frame + 1) * SECTOR_SIZE];
+        sector[..contents.len()].copy_from_slice(contents);
+        sector[SECTOR_SIZE - 1] = sector[..SECTOR_SIZE - 1].iter().fold(0, |checksum, byte| checksum ^ byte);
+    };
+
+    set_frame(0, b"MC");
+    for frame in 1..16 {
+        // Free block, no next block in the chain
+        set_frame(frame, &[0xA0, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    for frame in 16..36 {
+        // Unused entries of the broken sector list
+        set_frame(frame, &[0xFF, 0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    set_frame(63, b"MC");
+
+    data
+}
+
+// Writes next to the target and renames over it, so a crash never leaves half an image behind.
+// The data has to reach the disk before the rename does, or a power loss can still leave an
+// empty file under the card's name
+fn write_atomically(path: &Path, data: &[u8]) -> Result<(), io::Error> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+
+    let mut file = File::create(&temporary)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&temporary, path)?;
+
+    // The rename itself only sticks once the directory is synced
+    #[cfg(unix)]
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+        File::open(parent)?.sync_all()?;
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempCard(PathBuf);
+
+    impl TempCard {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("shiranuhi-memory-card-{}-{}.mcr", std::process::id(), name));
+            let _ = fs::remove_file(&path);
+            Self(path)
+        }
+
+        fn read(&self) -> Vec<u8> {
+            fs::read(&self.0).unwrap()
+        }
+    }
+
+    impl Drop for TempCard {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // Returns the end status byte
+    fn write_sector(card: &mut MemoryCard, sector: u16, data: &[u8; SECTOR_SIZE]) -> u8 {
+        let checksum = data.iter().fold((sector >> 8) as u8 ^ sector as u8, |checksum, byte| checksum ^ byte);
+
+        let mut command = vec![0x81, 0x57, 0x00, 0x00, (sector >> 8) as u8, sector as u8];
+        command.extend(data);
+        command.extend([checksum, 0x00, 0x00, 0x00]);
+
+        let replies: Vec<u8> = command.into_iter().map(|value| card.transfer(value).0).collect();
+        card.deselect();
+        *replies.last().unwrap()
+    }
+
+    #[test]
+    fn saves_once_writes_stop() {
+        let temp = TempCard::new("delay");
+        let mut card = MemoryCard::open(&temp.0).unwrap();
+        let original = temp.read();
+
+        // A save spread over several frames
+        for sector in 64..128 {
+            card.vblank();
+            assert_eq!(write_sector(&mut card, sector, &[sector as u8; SECTOR_SIZE]), END_GOOD);
+        }
+        assert!(card.is_dirty());
+        assert!(temp.read() == original);
+
+        for _ in 1..FLUSH_DELAY_FRAMES {
+            card.vblank();
+        }
+        assert!(temp.read() == original);
+        card.vblank();
+
+        assert!(!card.is_dirty());
+        assert!(temp.read() == card.data()[..]);
+        assert_eq!(temp.read()[127 * SECTOR_SIZE], 127);
+    }
+
+    #[test]
+    fn saves_on_flush_and_drop() {
+        let temp = TempCard::new("drop");
+        let mut card = MemoryCard::open(&temp.0).unwrap();
+
+        write_sector(&mut card, 100, &[0x11; SECTOR_SIZE]);
+        card.flush().unwrap();
+        assert!(!card.is_dirty());
+        assert_eq!(temp.read()[100 * SECTOR_SIZE], 0x11);
+
+        write_sector(&mut card, 101, &[0x22; SECTOR_SIZE]);
+        drop(card);
+        assert_eq!(temp.read()[101 * SECTOR_SIZE], 0x22);
+    }
+
+    #[test]
+    fn failed_writes_change_nothing() {
+        let mut card = MemoryCard::new();
+        let mut command = vec![0x81, 0x57, 0x00, 0x00, 0x00, 0x05];
+        command.extend([0xAA; SECTOR_SIZE]);
+        command.extend([0x00, 0x00, 0x00, 0x00]);
+
+        let replies: Vec<u8> = command.into_iter().map(|value| card.transfer(value).0).collect();
+        assert_eq!(replies.last(), Some(&END_BAD_CHECKSUM));
+        assert!(!card.is_dirty());
+        assert_eq!(card.data()[5 * SECTOR_SIZE..6 * SECTOR_SIZE], formatted()[5 * SECTOR_SIZE..6 * SECTOR_SIZE]);
+
+        card.deselect();
+        assert_eq!(card.transfer(0x81), (0xFF, true));
+        assert_eq!(card.transfer(0x53).0, FLAG_FRESH | FLAG_WRITE_ERROR);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,313 @@
+pub mod memory_card;
+pub mod pad;
+
+use std::{any::Any, collections::VecDeque};
+
+use bitfield_struct::bitfield;
+use spdlog::prelude::*;
+
+// The device pulls /ACK low a while after the last bit, and holds it for a few microseconds
+pub const ACK_DELAY: u64 = 338;
+pub const ACK_LENGTH: u64 = 100;
+
+const RX_FIFO_SIZE: usize = 8;
+
+/// Something plugged into a controller or memory card slot
+pub trait SioDevice: Any {
+    /// Exchanges one byte, returns the reply and whether the device pulls /ACK to ask for the next one
+    fn transfer(&mut self, value: u8) -> (u8, bool);
+    /// /JOY went high, the device drops whatever command it was in the middle of
+    fn deselect(&mut self);
+    /// Called at the start of every vblank, for work that happens between commands
+    fn vblank(&mut self) {}
+}
+
+/// A controller and a memory card share the lines of each port, the first byte of a command
+/// tells them apart and the other one stays quiet
+#[derive(Default)]
+struct Slot {
+    controller: Option<Box<dyn SioDevice>>,
+    memory_card: Option<Box<dyn SioDevice>>
+}
+
+impl Slot {
+    fn devices(&mut self) -> impl Iterator<Item = &mut Box<dyn SioDevice>> {
+        self.controller.iter_mut().chain(self.memory_card.iter_mut())
+    }
+}
+
+/// SIO0, the serial interface for controllers and memory cards at 0x1F801040
+pub struct Sio {
+    slots: [Slot; 2],
+
+    rx_fifo: VecDeque<u8>,
+    tx_pending: Option<u8>,
+    transferring: Option<u8>,
+    transfer_started: Option<u64>,
+
+    status: Status,
+    mode: u16,
+    control: Control,
+    baud: u16,
+
+    interrupt: bool
+}
+
+impl Default for Sio {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sio {
+    pub fn new() -> Self {
+        Self {
+            slots: [Slot::default(), Slot::default()],
+
+            rx_fifo: VecDeque::with_capacity(RX_FIFO_SIZE),
+            tx_pending: None,
+            transferring: None,
+            transfer_started: None,
+
+            status: Status::new(),
+            mode: 0,
+            control: Control::new(),
+            baud: 0,
+
+            interrupt: false
+        }
+    }
+
+    /// Plugs a controller into port 0 or 1, or unplugs it with None
+    pub fn connect_controller(&mut self, port: usize, device: Option<Box<dyn SioDevice>>) {
+        self.slots[port].controller = device;
+    }
+
+    /// Plugs a memory card into port 0 or 1, or unplugs it with None
+    pub fn connect_memory_card(&mut self, port: usize, device: Option<Box<dyn SioDevice>>) {
+        self.slots[port].memory_card = device;
+    }
+
+    /// The controller in port 0 or 1, if it's a `T`
+    pub fn controller_mut<T: SioDevice>(&mut self, port: usize) -> Option<&mut T> {
+        let device: &mut dyn Any = self.slots[port].controller.as_deref_mut()?;
+        device.downcast_mut()
+    }
+
+    /// The memory card in port 0 or 1, if it's a `T`
+    pub fn memory_card_mut<T: SioDevice>(&mut self, port: usize) -> Option<&mut T> {
+        let device: &mut dyn Any = self.slots[port].memory_card.as_deref_mut()?;
+        device.downcast_mut()
+    }
+
+    /// Lets the devices in both ports know a frame went by
+    pub fn vblank(&mut self) {
+        for slot in &mut self.slots {
+            for device in slot.devices() {
+                device.vblank();
+            }
+        }
+    }
+
+    pub fn load32(&mut self, offset: u32) -> u32 {
+        match offset {
+            0x0 => self.rx_fifo.pop_front().unwrap_or(0xFF) as u32,
+            0x4 => self.status().into(),
+            0x8 => self.mode as u32,
+            0xA => u16::from(self.control) as u32,
+            0xE => self.baud as u32,
+            _ => {
+                warn!("[SIO0] Unhandled load at offset 0x{:02X}", offset);
+                0
+            }
+        }
+    }
+
+    pub fn store32(&mut self, offset: u32, value: u32) {
+        trace!("[SIO0] Store at offset 0x{:02X}: 0x{:08X}", offset, value);
+
+        match offset {
+            0x0 => {
+                // The TX buffer is a single byte, a second write before it started replaces it
+                self.tx_pending = Some(value as u8);
+                self.start_transfer();
+            }
+            0x8 => self.mode = value as u16,
+            0xA => self.store_control(Control::from(value as u16)),
+            0xE => self.baud = value as u16,
+            _ => warn!("[SIO0] Unhandled store at offset 0x{:02X}: 0x{:08X}", offset, value)
+        }
+    }
+
+    /// Returns the length of a transfer that just started, once
+    pub fn take_transfer_start(&mut self) -> Option<u64> {
+        self.transfer_started.take()
+    }
+
+    pub fn is_transferring(&self) -> bool {
+        self.transferring.is_some()
+    }
+
+    /// Returns true once for every rising edge of the IRQ bit
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt)
+    }
+
+    /// The byte finished shifting out, returns whether the selected device acknowledged it
+    pub fn finish_transfer(&mut self) -> bool {
+        let Some(value) = self.transferring.take() else {
+            return false;
+        };
+
+        // The data line is open collector, so the devices' replies are ANDed together
+        let mut response = 0xFF;
+        let mut ack = false;
+        if self.control.select() {
+            for device in self.slots[self.control.port() as usize].devices() {
+                let (reply, acknowledged) = device.transfer(value);
+                response &= reply;
+                ack |= acknowledged;
+            }
+        }
+        trace!("[SIO0] Sent 0x{:02X}, received 0x{:02X}, ack: {}", value, response, ack);
+
+        if self.rx_fifo.len() == RX_FIFO_SIZE {
+            // Overruns replace the newest byte
+            self.rx_fifo.pop_back();
+        }
+        self.rx_fifo.push_back(response);
+
+        let threshold = 1 << self.control.rx_interrupt_mode();
+        if self.control.rx_interrupt() && self.rx_fifo.len() >= threshold {
+            self.raise_interrupt();
+        }
+
+        self.start_transfer();
+        if self.control.tx_interrupt() && self.tx_pending.is_none() {
+            self.raise_interrupt();
+        }
+
+        ack
+    }
+
+    /// Drives the /ACK input, its falling edge interrupts if enabled
+    pub fn set_ack(&mut self, active: bool) {
+        self.status.set_ack(active);
+
+        if active && self.control.ack_interrupt() {
+            self.raise_interrupt();
+        }
+    }
+
+    fn status(&self) -> Status {
+        self.status
+            .with_tx_ready(self.tx_pending.is_none())
+            .with_rx_not_empty(!self.rx_fifo.is_empty())
+            .with_tx_finished(self.tx_pending.is_none() && self.transferring.is_none())
+    }
+
+    fn store_control(&mut self, control: Control) {
+        let previous = self.control;
+
+        if control.reset() {
+            debug!("[SIO0] Reset");
+            self.deselect(previous);
+
+            self.rx_fifo.clear();
+            self.tx_pending = None;
+            self.transferring = None;
+            self.transfer_started = None;
+            self.status = Status::new();
+            self.mode = 0;
+            self.control = Control::new();
+            return;
+        }
+
+        if control.acknowledge() {
+            self.status.set_interrupt(false);
+            self.status.set_rx_parity_error(false);
+        }
+
+        // Writing the acknowledge and reset bits doesn't latch them
+        self.control = control.with_acknowledge(false).with_reset(false);
+
+        if previous.select() && (!control.select() || previous.port() != control.port()) {
+            self.deselect(previous);
+        }
+
+        self.start_transfer();
+    }
+
+    fn deselect(&mut self, control: Control) {
+        if control.select() {
+            for device in self.slots[control.port() as usize].devices() {
+                device.deselect();
+            }
+        }
+    }
+
+    fn start_transfer(&mut self) {
+        if self.transferring.is_some() || !self.control.tx_enable() {
+            return;
+        }
+
+        if let Some(value) = self.tx_pending.take() {
+            self.transferring = Some(value);
+            self.transfer_started = Some(self.transfer_cycles());
+        }
+    }
+
+    // Eight bits at the rate set by the reload value and the MODE factor
+    fn transfer_cycles(&self) -> u64 {
+        let factor = match self.mode & 0x3 {
+            2 => 16,
+            3 => 64,
+            _ => 1
+        };
+
+        (self.baud as u64 * factor * 8).max(1)
+    }
+
+    fn raise_interrupt(&mut self) {
+        if !self.status.interrupt() {
+            self.status.set_interrupt(true);
+            self.interrupt = true;
+        }
+    }
+}
+
+#[bitfield(u32)]
+struct Status {
+    tx_ready: bool,
+    rx_not_empty: bool,
+    tx_finished: bool,
+    rx_parity_error: bool,
+    #[bits(3)]
+    __: u32,
+    ack: bool,
+    __: bool,
+    interrupt: bool,
+    #[bits(22)]
+    __: u32
+}
+
+#[bitfield(u16)]
+struct Control {
+    tx_enable: bool,
+    select: bool,
+    rx_enable: bool,
+    __: bool,
+    acknowledge: bool,
+    __: bool,
+    reset: bool,
+    __: bool,
+    #[bits(2)]
+    rx_interrupt_mode: usize,
+    tx_interrupt: bool,
+    rx_interrupt: bool,
+    ack_interrupt: bool,
+    #[bits(1)]
+    port: u8,
+    #[bits(2)]
+    __: u8
+}
\ No newline at end of file
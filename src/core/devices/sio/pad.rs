@@ -0,0 +1,253 @@
+use bitfield_struct::bitfield;
+use spdlog::prelude::*;
+
+use super::SioDevice;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PadKind {
+    /// SCPH-1080, buttons only
+    Digital,
+    /// SCPH-1200, two sticks, two motors and the config mode to control them
+    DualShock
+}
+
+/// Pressed buttons, a set bit means the button is held down
+#[bitfield(u16)]
+#[derive(PartialEq, Eq)]
+pub struct Buttons {
+    pub select: bool,
+    pub l3: bool,
+    pub r3: bool,
+    pub start: bool,
+    pub up: bool,
+    pub right: bool,
+    pub down: bool,
+    pub left: bool,
+    pub l2: bool,
+    pub r2: bool,
+    pub l1: bool,
+    pub r1: bool,
+    pub triangle: bool,
+    pub circle: bool,
+    pub cross: bool,
+    pub square: bool
+}
+
+/// Stick positions, 0x00 is fully left/up and 0xFF fully right/down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Sticks {
+    pub right_x: u8,
+    pub right_y: u8,
+    pub left_x: u8,
+    pub left_y: u8
+}
+
+impl Sticks {
+    pub const CENTERED: Sticks = Sticks { right_x: 0x80, right_y: 0x80, left_x: 0x80, left_y: 0x80 };
+}
+
+impl Default for Sticks {
+    fn default() -> Self {
+        Self::CENTERED
+    }
+}
+
+pub struct Pad {
+    kind: PadKind,
+    buttons: Buttons,
+    sticks: Sticks,
+
+    analog: bool,
+    analog_locked: bool,
+    config: bool,
+    rumble_mapping: [u8; 6],
+    small_motor: bool,
+    large_motor: u8,
+
+    // Where we are in the current command, None once it's addressed to someone else or over
+    position: Option<usize>,
+    command: u8,
+    length: usize,
+    payload: [u8; 6]
+}
+
+impl Pad {
+    pub fn new(kind: PadKind) -> Self {
+        Self {
+            kind,
+            buttons: Buttons::new(),
+            sticks: Sticks::CENTERED,
+
+            analog: false,
+            analog_locked: false,
+            config: false,
+            rumble_mapping: [0xFF; 6],
+            small_motor: false,
+            large_motor: 0,
+
+            position: Some(0),
+            command: 0,
+            length: 0,
+            payload: [0; 6]
+        }
+    }
+
+    pub fn kind(&self) -> PadKind {
+        self.kind
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+    }
+
+    pub fn set_sticks(&mut self, sticks: Sticks) {
+        self.sticks = sticks;
+    }
+
+    pub fn is_analog(&self) -> bool {
+        self.analog
+    }
+
+    /// The ANALOG button, ignored while software has the mode locked
+    pub fn press_analog_button(&mut self) {
+        if self.kind == PadKind::DualShock && !self.analog_locked {
+            self.analog = !self.analog;
+            debug!("[PAD] Analog mode {}", if self.analog { "on" } else { "off" });
+        }
+    }
+
+    /// Small motor on/off and large motor speed
+    pub fn motors(&self) -> (bool, u8) {
+        (self.small_motor, self.large_motor)
+    }
+
+    fn id(&self) -> u8 {
+        match (self.config, self.analog) {
+            (true, _) => 0xF3,
+            (false, true) => 0x73,
+            (false, false) => 0x41
+        }
+    }
+
+    fn supports(&self, command: u8) -> bool {
+        match self.kind {
+            PadKind::Digital => command == 0x42,
+            PadKind::DualShock if self.config => matches!(command, 0x42..=0x47 | 0x4C | 0x4D),
+            PadKind::DualShock => matches!(command, 0x42 | 0x43)
+        }
+    }
+
+    fn input_state(&self, index: usize) -> u8 {
+        let mut buttons = self.buttons;
+        if self.kind == PadKind::Digital || !self.analog {
+            // L3 and R3 only exist on the sticks
+            buttons = buttons.with_l3(false).with_r3(false);
+        }
+        let buttons = !u16::from(buttons);
+
+        match index {
+            0 => buttons as u8,
+            1 => (buttons >> 8) as u8,
+            2 => self.sticks.right_x,
+            3 => self.sticks.right_y,
+            4 => self.sticks.left_x,
+            5 => self.sticks.left_y,
+            _ => 0
+        }
+    }
+
+    // The reply to the index-th byte after the header, while the host's byte goes the other way
+    fn exchange(&mut self, index: usize, value: u8) -> u8 {
+        self.payload[index] = value;
+
+        match self.command {
+            0x42 => {
+                match self.rumble_mapping[index] {
+                    0x00 => self.small_motor = value & 0x01 != 0,
+                    0x01 => self.large_motor = value,
+                    _ => {}
+                }
+                self.input_state(index)
+            }
+            0x43 if !self.config => self.input_state(index),
+            0x45 => [0x01, 0x02, self.analog as u8, 0x02, 0x01, 0x00][index],
+            0x46 => match (self.payload[0], index) {
+                (_, 0..=1) => 0x00,
+                (0x00, _) => [0x01, 0x02, 0x00, 0x0A][index - 2],
+                (0x01, _) => [0x01, 0x01, 0x01, 0x14][index - 2],
+                _ => 0x00
+            },
+            0x47 => [0x00, 0x00, 0x02, 0x00, 0x01, 0x00][index],
+            0x4C => match (self.payload[0], index) {
+                (0x00, 3) => 0x04,
+                (0x01, 3) => 0x07,
+                _ => 0x00
+            },
+            0x4D => std::mem::replace(&mut self.rumble_mapping[index], value),
+            _ => 0x00
+        }
+    }
+
+    fn complete(&mut self) {
+        match self.command {
+            0x43 => {
+                self.config = self.payload[0] == 0x01;
+                debug!("[PAD] {} config mode", if self.config { "Entered" } else { "Left" });
+            }
+            0x44 => {
+                if self.payload[0] <= 0x01 {
+                    self.analog = self.payload[0] == 0x01;
+                }
+                self.analog_locked = self.payload[1] == 0x03;
+                debug!("[PAD] Analog mode {}, locked: {}", self.analog, self.analog_locked);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl SioDevice for Pad {
+    fn transfer(&mut self, value: u8) -> (u8, bool) {
+        let Some(position) = self.position else {
+            return (0xFF, false);
+        };
+        self.position = Some(position + 1);
+
+        match position {
+            // Commands starting with anything else are for the memory card
+            0 if value == 0x01 => (0xFF, true),
+            0 => {
+                self.position = None;
+                (0xFF, false)
+            }
+            1 => {
+                let id = self.id();
+                self.command = value;
+                self.length = (id & 0xF) as usize * 2;
+
+                if !self.supports(value) {
+                    trace!("[PAD] Unsupported command 0x{:02X}", value);
+                    self.position = None;
+                    return (id, false);
+                }
+                (id, true)
+            }
+            2 => (0x5A, true),
+            _ => {
+                let index = position - 3;
+                let reply = self.exchange(index, value);
+
+                let last = index + 1 == self.length;
+                if last {
+                    self.complete();
+                    self.position = None;
+                }
+                (reply, !last)
+            }
+        }
+    }
+
+    fn deselect(&mut self) {
+        self.position = Some(0);
+    }
+}
\ No newline at end of file
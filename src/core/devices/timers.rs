@@ -0,0 +1,325 @@
+use bitfield_struct::bitfield;
+use spdlog::prelude::*;
+
+/// The three root counters. Nothing is counted per CPU step, the counters are brought up to date
+/// from the elapsed cycles whenever they're accessed, a blanking edge arrives, or the bus wakes
+/// them up right before one of them reaches its target or overflows
+pub struct Timers {
+    timers: [Timer; 3],
+    last_sync: u64,
+
+    hblank: bool,
+    vblank: bool,
+    dot_divider: u64
+}
+
+impl Default for Timers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            timers: [Timer::new(0), Timer::new(1), Timer::new(2)],
+            last_sync: 0,
+
+            hblank: false,
+            vblank: false,
+            dot_divider: 10
+        }
+    }
+
+    pub fn load32(&mut self, offset: u32, now: u64) -> u32 {
+        self.sync(now);
+
+        let timer = &mut self.timers[(offset >> 4) as usize];
+        match offset & 0xF {
+            0x0 => timer.counter,
+            0x4 => {
+                // The reached flags clear once they're read
+                let mode = timer.mode;
+                timer.mode = mode.with_reached_target(false).with_reached_overflow(false);
+                mode.into()
+            }
+            0x8 => timer.target,
+            _ => {
+                warn!("[TIMERS] Unhandled load32 at offset 0x{:02X}", offset);
+                0
+            }
+        }
+    }
+
+    pub fn store32(&mut self, offset: u32, value: u32, now: u64) {
+        self.sync(now);
+        trace!("[TIMERS] Store32 at offset 0x{:02X}: 0x{:08X}", offset, value);
+
+        let timer = &mut self.timers[(offset >> 4) as usize];
+        match offset & 0xF {
+            0x0 => timer.counter = value & 0xFFFF,
+            0x4 => {
+                // Writing the mode restarts the counter and rearms one-shot interrupts
+                timer.mode = Mode(value & 0x3FF).with_interrupt_n(true);
+                timer.counter = 0;
+                timer.remainder = 0;
+                timer.irq_fired = false;
+                timer.released = false;
+            }
+            0x8 => timer.target = value & 0xFFFF,
+            _ => warn!("[TIMERS] Unhandled store32 at offset 0x{:02X}: 0x{:08X}", offset, value)
+        }
+    }
+
+    /// The registers are 16 bits wide, the upper halves read as zero
+    pub fn load16(&mut self, offset: u32, now: u64) -> u16 {
+        match offset & 2 {
+            0 => self.load32(offset, now) as u16,
+            _ => 0
+        }
+    }
+
+    /// Writes to the upper halves are dropped, merging them into the word would rewrite the
+    /// mode and restart the counter
+    pub fn store16(&mut self, offset: u32, value: u16, now: u64) {
+        match offset & 2 {
+            0 => self.store32(offset, value as u32, now),
+            _ => trace!("[TIMERS] Ignored store16 at offset 0x{:02X}: 0x{:04X}", offset, value)
+        }
+    }
+
+    /// Returns true once for every interrupt the timer raised
+    pub fn take_interrupt(&mut self, index: usize) -> bool {
+        std::mem::take(&mut self.timers[index].interrupt)
+    }
+
+    /// Counts everything which happened between the last sync and `now`
+    pub fn sync(&mut self, now: u64) {
+        let elapsed = now.saturating_sub(self.last_sync);
+        self.last_sync = now;
+
+        if elapsed == 0 {
+            return;
+        }
+
+        for index in 0..3 {
+            if !self.is_running(index) {
+                continue;
+            }
+
+            if let Some((numerator, denominator)) = self.rate(index) {
+                let timer = &mut self.timers[index];
+                let total = timer.remainder + elapsed * numerator;
+                timer.remainder = total % denominator;
+                timer.increment(total / denominator);
+            }
+        }
+    }
+
+    /// CPU cycles until one of the counters reaches its target or overflows, if any are counting
+    pub fn next_event(&self) -> Option<u64> {
+        (0..3)
+            .filter(|&index| self.is_running(index))
+            .filter_map(|index| {
+                let (numerator, denominator) = self.rate(index)?;
+                let timer = &self.timers[index];
+                let ticks = timer.distance_to_target().min(timer.distance_to_overflow());
+
+                Some((ticks * denominator - timer.remainder).div_ceil(numerator))
+            })
+            .min()
+    }
+
+    pub fn set_hblank(&mut self, active: bool, now: u64) {
+        self.sync(now);
+        self.hblank = active;
+
+        if active {
+            self.blank_started(0);
+
+            // Timer 1 can count hblanks instead of cycles
+            if self.timers[1].mode.clock_source() & 1 != 0 && self.is_running(1) {
+                self.timers[1].increment(1);
+            }
+        }
+    }
+
+    pub fn set_vblank(&mut self, active: bool, now: u64) {
+        self.sync(now);
+        self.vblank = active;
+
+        if active {
+            self.blank_started(1);
+        }
+    }
+
+    /// Follows the GPU's horizontal resolution, which sets the dot clock timer 0 can count
+    pub fn set_dot_divider(&mut self, divider: u64, now: u64) {
+        if divider != self.dot_divider {
+            self.sync(now);
+            self.dot_divider = divider;
+        }
+    }
+
+    fn blank_started(&mut self, index: usize) {
+        let timer = &mut self.timers[index];
+        if !timer.mode.sync_enable() {
+            return;
+        }
+
+        match timer.mode.sync_mode() {
+            1 | 2 => timer.counter = 0,
+            3 => timer.released = true,
+            _ => {}
+        }
+    }
+
+    fn is_running(&self, index: usize) -> bool {
+        let timer = &self.timers[index];
+        if !timer.mode.sync_enable() {
+            return true;
+        }
+
+        let blank = match index {
+            0 => self.hblank,
+            1 => self.vblank,
+            // Timer 2 has no blank to sync to, modes 0 and 3 just stop it
+            _ => return matches!(timer.mode.sync_mode(), 1 | 2)
+        };
+
+        match timer.mode.sync_mode() {
+            0 => !blank,
+            1 => true,
+            2 => blank,
+            3 => timer.released,
+            _ => unreachable!()
+        }
+    }
+
+    // Counter ticks per CPU cycle as a fraction, None when the counter follows hblanks
+    fn rate(&self, index: usize) -> Option<(u64, u64)> {
+        let source = self.timers[index].mode.clock_source();
+
+        match index {
+            0 if source & 1 != 0 => Some((11, 7 * self.dot_divider)),
+            1 if source & 1 != 0 => None,
+            2 if source & 2 != 0 => Some((1, 8)),
+            _ => Some((1, 1))
+        }
+    }
+}
+
+struct Timer {
+    index: usize,
+    counter: u32,
+    mode: Mode,
+    target: u32,
+
+    // Fraction of a tick carried over between syncs, in units of the denominator of the rate
+    remainder: u64,
+    // One-shot interrupts only fire once until the mode is written again
+    irq_fired: bool,
+    // Sync mode 3 waits for one blank and then runs freely
+    released: bool,
+    interrupt: bool
+}
+
+impl Timer {
+    fn new(index: usize) -> Self {
+        Self {
+            index,
+            counter: 0,
+            mode: Mode::new().with_interrupt_n(true),
+            target: 0,
+
+            remainder: 0,
+            irq_fired: false,
+            released: false,
+            interrupt: false
+        }
+    }
+
+    fn distance_to_target(&self) -> u64 {
+        if self.target > self.counter {
+            (self.target - self.counter) as u64
+        } else {
+            (self.target + 0x10000 - self.counter) as u64
+        }
+    }
+
+    fn distance_to_overflow(&self) -> u64 {
+        match self.counter {
+            0xFFFF => 0x10000,
+            counter => (0xFFFF - counter) as u64
+        }
+    }
+
+    // Jumps from one target or overflow hit to the next instead of counting one tick at a time
+    fn increment(&mut self, mut ticks: u64) {
+        while ticks > 0 {
+            let to_target = self.distance_to_target();
+            let to_overflow = self.distance_to_overflow();
+            let step = ticks.min(to_target).min(to_overflow);
+
+            self.counter = ((self.counter as u64 + step) & 0xFFFF) as u32;
+            ticks -= step;
+
+            if step == to_target {
+                self.mode.set_reached_target(true);
+                if self.mode.irq_on_target() {
+                    self.raise_interrupt();
+                }
+                if self.mode.reset_on_target() {
+                    self.counter = 0;
+                }
+            }
+
+            if step == to_overflow {
+                self.mode.set_reached_overflow(true);
+                if self.mode.irq_on_overflow() {
+                    self.raise_interrupt();
+                }
+            }
+        }
+    }
+
+    fn raise_interrupt(&mut self) {
+        if self.irq_fired && !self.mode.irq_repeat() {
+            return;
+        }
+        self.irq_fired = true;
+
+        // Bit 10 is active low. Pulse mode only drops it for a few cycles, toggle mode flips it
+        // and only the falling edge counts as an interrupt
+        if self.mode.irq_toggle() {
+            let interrupt_n = !self.mode.interrupt_n();
+            self.mode.set_interrupt_n(interrupt_n);
+
+            if interrupt_n {
+                return;
+            }
+        }
+
+        trace!("[TIMERS] Timer {} interrupt at 0x{:04X}", self.index, self.counter);
+        self.interrupt = true;
+    }
+}
+
+#[bitfield(u32)]
+struct Mode {
+    sync_enable: bool,
+    #[bits(2)]
+    sync_mode: u32,
+    reset_on_target: bool,
+    irq_on_target: bool,
+    irq_on_overflow: bool,
+    irq_repeat: bool,
+    irq_toggle: bool,
+    #[bits(2)]
+    clock_source: u32,
+    interrupt_n: bool,
+    reached_target: bool,
+    reached_overflow: bool,
+    #[bits(19)]
+    __: u32
+}
\ No newline at end of file
@@ -0,0 +1,64 @@
+/// PS-X EXE headers are padded to a whole CD sector, the text follows
+const HEADER_SIZE: usize = 0x800;
+const MAGIC: &[u8; 8] = b"PS-X EXE";
+/// RAM past the kernel's first 64KB, where executables have to stay
+const USER_RAM_START: u32 = 0x10000;
+const USER_RAM_END: u32 = 0x200000;
+
+/// A PS-X EXE, the executables the BIOS loads from discs
+#[derive(Debug, Clone)]
+pub struct Executable {
+    /// Entry point
+    pub pc: u32,
+    pub gp: u32,
+    /// Where the text gets copied, it holds both code and data
+    pub load_address: u32,
+    pub text: Vec<u8>,
+    /// Zero filled before starting, most executables leave it to their own startup code
+    pub bss_address: u32,
+    pub bss_size: u32,
+    /// The stack pointer is base plus offset, a base of 0 keeps the caller's stack. The BIOS
+    /// replaces both with the STACK of SYSTEM.CNF when booting a disc
+    pub stack_base: u32,
+    pub stack_offset: u32
+}
+
+impl Executable {
+    /// None if `data` isn't a PS-X EXE or is shorter than its header claims
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_SIZE || &data[0..8] != MAGIC {
+            return None;
+        }
+        let word = |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+
+        let text_size = word(0x1C) as usize;
+        Some(Self {
+            pc: word(0x10),
+            gp: word(0x14),
+            load_address: word(0x18),
+            text: data.get(HEADER_SIZE..HEADER_SIZE.checked_add(text_size)?)?.to_vec(),
+            bss_address: word(0x28),
+            bss_size: word(0x2C),
+            stack_base: word(0x30),
+            stack_offset: word(0x34)
+        })
+    }
+
+    /// Whether the text and BSS both land in RAM without touching the kernel, loading the
+    /// executable would otherwise wrap around RAM or overwrite it
+    pub fn fits_in_ram(&self) -> bool {
+        in_user_ram(self.load_address, self.text.len() as u32) && in_user_ram(self.bss_address, self.bss_size)
+    }
+}
+
+// KUSEG, KSEG0 and KSEG1 all mirror RAM
+fn in_user_ram(address: u32, size: u32) -> bool {
+    if size == 0 {
+        return true;
+    }
+    let segment = address >> 29;
+    let physical = address & 0x1FFFFFFF;
+    matches!(segment, 0 | 4 | 5)
+        && physical >= USER_RAM_START
+        && physical.checked_add(size).is_some_and(|end| end <= USER_RAM_END)
+}
\ No newline at end of file
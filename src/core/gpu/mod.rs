@@ -0,0 +1,1074 @@
+pub mod png;
+mod rasterizer;
+mod render_threads;
+pub mod trace;
+pub mod vram_view;
+
+use std::{fs::File, io::{self, BufWriter}, path::Path};
+
+use bitfield_struct::bitfield;
+use spdlog::prelude::*;
+
+use super::{devices::dma::DmaDevice, precision::PreciseVertex};
+use rasterizer::{Area, Primitive, Shape, Target, Texture, Vertex};
+use render_threads::RenderThreads;
+use trace::{Port, TraceRecorder};
+use vram_view::VramView;
+
+pub const VRAM_WIDTH: usize = 1024;
+pub const VRAM_HEIGHT: usize = 512;
+/// 8x keeps the shadow VRAM at 64 MiB
+pub const MAX_RESOLUTION_SCALE: usize = 8;
+/// Queued draws are flushed to the render threads at the latest after this many
+const QUEUE_LIMIT: usize = 1024;
+
+// The video clock runs at 11/7 of the CPU clock, these are in video clock cycles
+const NTSC_LINE_CYCLES: u64 = 3413;
+const PAL_LINE_CYCLES: u64 = 3406;
+const NTSC_LINES: u16 = 263;
+const PAL_LINES: u16 = 314;
+
+pub struct Gpu {
+    vram: Box<[u16; VRAM_WIDTH * VRAM_HEIGHT]>,
+    status: Status,
+
+    // Draws are repeated at `scale` times the native resolution into the shadow VRAM,
+    // which only feeds upscaled screenshots. The native VRAM stays authoritative
+    scale: usize,
+    shadow: Vec<u16>,
+    shadow_stale: bool,
+
+    // With render threads, draws are queued until something needs to see VRAM
+    render_threads: Option<RenderThreads>,
+    queue: Vec<Primitive>,
+    queued_rows: Option<(i32, i32)>,
+
+    command: Vec<u32>,
+    command_words: usize,
+    polyline: bool,
+
+    // Sub-pixel vertices which came along with the command words, see `core::precision`
+    precise_geometry: bool,
+    command_precise: Vec<Option<PreciseVertex>>,
+    next_precise: Option<PreciseVertex>,
+
+    write_transfer: Option<VramTransfer>,
+    read_transfer: Option<VramTransfer>,
+    gpuread: u32,
+
+    texture_rect_flip_x: bool,
+    texture_rect_flip_y: bool,
+    texture_window: u32,
+    drawing_area_top_left: u32,
+    drawing_area_bottom_right: u32,
+    drawing_offset: u32,
+
+    display_start: u32,
+    horizontal_range: u32,
+    vertical_range: u32,
+
+    scanline: u16,
+    odd_line: bool,
+    video_remainder: u64,
+
+    cycles: u64,
+    recorder: Option<TraceRecorder>
+}
+
+impl Default for Gpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Gpu {
+    pub fn new() -> Self {
+        let vram = vec![0x0000; VRAM_WIDTH * VRAM_HEIGHT]
+            .into_boxed_slice()
+            .try_into()
+            .unwrap();
+
+        Self {
+            vram,
+            status: Status(0x14802000),
+
+            scale: 1,
+            shadow: Vec::new(),
+            shadow_stale: false,
+
+            render_threads: None,
+            queue: Vec::new(),
+            queued_rows: None,
+
+            command: Vec::with_capacity(16),
+            command_words: 0,
+            polyline: false,
+
+            precise_geometry: false,
+            command_precise: Vec::with_capacity(16),
+            next_precise: None,
+
+            write_transfer: None,
+            read_transfer: None,
+            gpuread: 0,
+
+            texture_rect_flip_x: false,
+            texture_rect_flip_y: false,
+            texture_window: 0,
+            drawing_area_top_left: 0,
+            drawing_area_bottom_right: 0,
+            drawing_offset: 0,
+
+            display_start: 0,
+            horizontal_range: 0xC60260,
+            vertical_range: 0x40010,
+
+            scanline: 0,
+            odd_line: false,
+            video_remainder: 0,
+
+            cycles: 0,
+            recorder: None
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    /// Video clock cycles per dot for the current horizontal resolution
+    pub fn dot_divider(&self) -> u64 {
+        if self.status.horizontal_resolution_2() {
+            return 7;
+        }
+
+        match self.status.horizontal_resolution_1() {
+            0 => 10,
+            1 => 8,
+            2 => 5,
+            3 => 4,
+            _ => unreachable!()
+        }
+    }
+
+    pub fn in_vblank(&self) -> bool {
+        let start = (self.vertical_range & 0x3FF) as u16;
+        let end = ((self.vertical_range >> 10) & 0x3FF) as u16;
+
+        !(start..end).contains(&self.scanline)
+    }
+
+    /// CPU cycles from the start of the current line until its hblank begins
+    pub fn cycles_to_hblank(&mut self) -> u64 {
+        self.video_to_cpu(self.hblank_start())
+    }
+
+    /// Called when the beam leaves the display area, returns the CPU cycles until the line ends
+    pub fn start_hblank(&mut self) -> u64 {
+        let (line_cycles, _) = self.video_timing();
+        self.video_to_cpu(line_cycles - self.hblank_start())
+    }
+
+    /// Moves the beam to the start of the next line
+    pub fn next_scanline(&mut self) {
+        let (_, lines) = self.video_timing();
+        self.scanline = (self.scanline + 1) % lines;
+
+        // 480-line interlaced output alternates fields every frame, everything else every line
+        let interlaced = self.status.vertical_interlace() && self.status.vertical_resolution();
+        if !interlaced || self.scanline == 0 {
+            self.odd_line = !self.odd_line;
+        }
+        if self.scanline == 0 {
+            self.status.set_interlace_field(interlaced && self.odd_line);
+        }
+    }
+
+    fn video_timing(&self) -> (u64, u16) {
+        if self.status.video_mode() {
+            (PAL_LINE_CYCLES, PAL_LINES)
+        } else {
+            (NTSC_LINE_CYCLES, NTSC_LINES)
+        }
+    }
+
+    // Hblank starts where the horizontal display range ends
+    fn hblank_start(&self) -> u64 {
+        let (line_cycles, _) = self.video_timing();
+        let end = ((self.horizontal_range >> 12) & 0xFFF) as u64;
+
+        end.clamp(1, line_cycles - 1)
+    }
+
+    fn video_to_cpu(&mut self, cycles: u64) -> u64 {
+        let total = cycles * 7 + self.video_remainder;
+        self.video_remainder = total % 11;
+        total / 11
+    }
+
+    pub fn load32(&mut self, offset: u32) -> u32 {
+        match offset {
+            0 => self.gpuread(),
+            4 => {
+                self.sync();
+                self.gpustat()
+            }
+            _ => unreachable!()
+        }
+    }
+
+    pub fn store32(&mut self, offset: u32, value: u32) {
+        match offset {
+            0 => self.gp0(value),
+            4 => self.gp1(value),
+            _ => unreachable!()
+        }
+    }
+
+    pub fn gpustat(&self) -> u32 {
+        let mut status = self.status;
+
+        // Always reads as even during vblank
+        status.set_even_odd_line(self.odd_line && !self.in_vblank());
+        status.set_ready_vram_send(self.read_transfer.is_some());
+
+        // Bit 25 mirrors one of the other ready flags depending on the DMA direction
+        let dma_request = match status.dma_direction() {
+            0 => false,
+            1 => true,
+            2 => status.ready_dma_block(),
+            3 => status.ready_vram_send(),
+            _ => unreachable!()
+        };
+        status.set_dma_request(dma_request);
+
+        status.into()
+    }
+
+    pub fn gpuread(&mut self) -> u32 {
+        self.sync();
+        if self.read_transfer.is_some() {
+            let lo = self.read_vram_transfer() as u32;
+            let hi = self.read_vram_transfer() as u32;
+            self.gpuread = (hi << 16) | lo;
+        }
+
+        self.gpuread
+    }
+
+    pub fn gp0(&mut self, value: u32) {
+        let precise = self.next_precise.take();
+        self.gp0_precise(value, precise);
+    }
+
+    /// GP0 write of a word the GTE might have projected more precisely
+    pub fn gp0_precise(&mut self, value: u32, precise: Option<PreciseVertex>) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(self.cycles, Port::Gp0, value);
+        }
+
+        if self.write_transfer.is_some() {
+            self.write_vram_transfer(value as u16);
+            self.write_vram_transfer((value >> 16) as u16);
+            return;
+        }
+
+        if self.command.is_empty() {
+            let opcode = value >> 24;
+            self.command_words = gp0_command_words(opcode);
+            self.polyline = (0x48..=0x5F).contains(&opcode) && opcode & 0x08 != 0;
+        }
+
+        self.command_precise.push(precise.filter(|_| self.precise_geometry));
+        if self.polyline && self.command.len() >= 3 && value & 0xF000F000 == 0x50005000 {
+            self.command.push(value);
+            self.execute_gp0();
+            return;
+        }
+
+        self.command.push(value);
+
+        if !self.polyline && self.command.len() >= self.command_words {
+            self.execute_gp0();
+        }
+    }
+
+    pub fn gp1(&mut self, value: u32) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(self.cycles, Port::Gp1, value);
+        }
+
+        let opcode = (value >> 24) & 0x3F;
+        trace!("[GPU] GP1 command 0x{:02X}: 0x{:08X}", opcode, value);
+
+        match opcode {
+            0x00 => self.reset(),
+            0x01 => self.reset_command_buffer(),
+            0x02 => self.status.set_interrupt_request(false),
+            0x03 => self.status.set_display_disabled(value & 1 != 0),
+            0x04 => self.status.set_dma_direction(value & 3),
+            0x05 => {
+                // A new frame is shown, get it drawn
+                self.sync();
+                self.display_start = value & 0x7FFFE;
+            }
+            0x06 => self.horizontal_range = value & 0xFFFFFF,
+            0x07 => self.vertical_range = value & 0xFFFFF,
+            0x08 => {
+                self.status.set_horizontal_resolution_1(value & 3);
+                self.status.set_vertical_resolution(value & 0x04 != 0);
+                self.status.set_video_mode(value & 0x08 != 0);
+                self.status.set_display_24bit(value & 0x10 != 0);
+                self.status.set_vertical_interlace(value & 0x20 != 0);
+                self.status.set_horizontal_resolution_2(value & 0x40 != 0);
+                self.status.set_reverse_flag(value & 0x80 != 0);
+            }
+            0x10..=0x1F => {
+                self.gpuread = match value & 0x7 {
+                    2 => self.texture_window,
+                    3 => self.drawing_area_top_left,
+                    4 => self.drawing_area_bottom_right,
+                    5 => self.drawing_offset,
+                    7 => 2,
+                    _ => self.gpuread
+                };
+            }
+            _ => warn!("[GPU] Unhandled GP1 command 0x{:02X}: 0x{:08X}", opcode, value)
+        }
+    }
+
+    /// Doesn't include draws still queued for the render threads, see `sync`
+    pub fn vram(&self) -> &[u16; VRAM_WIDTH * VRAM_HEIGHT] {
+        &self.vram
+    }
+
+    /// Writes made through this are upscaled into the shadow VRAM before the next draw
+    pub fn vram_mut(&mut self) -> &mut [u16; VRAM_WIDTH * VRAM_HEIGHT] {
+        self.sync();
+        self.shadow_stale = self.scale > 1;
+        &mut self.vram
+    }
+
+    pub fn resolution_scale(&self) -> usize {
+        self.scale
+    }
+
+    /// Renders at an integer multiple of the native resolution, 1 turns upscaling off.
+    /// The shadow VRAM starts out as a nearest neighbour copy of the current one
+    pub fn set_resolution_scale(&mut self, scale: usize) {
+        self.sync();
+        self.scale = scale.clamp(1, MAX_RESOLUTION_SCALE);
+        self.shadow = Vec::new();
+        self.shadow_stale = false;
+
+        if self.scale > 1 {
+            self.shadow = vec![0; VRAM_WIDTH * VRAM_HEIGHT * self.scale * self.scale];
+            self.upscale_shadow();
+        }
+    }
+
+    pub fn export_vram_png<P: AsRef<Path>>(&self, path: P, view: VramView) -> Result<(), io::Error> {
+        vram_view::export_png(&self.vram[..], view, path)
+    }
+
+    pub fn start_recording(&mut self, mut recorder: TraceRecorder) {
+        self.sync();
+        if !self.command.is_empty() || self.write_transfer.is_some() {
+            warn!("[GPU] Trace recording started in the middle of a GP0 command, replay might desync");
+        }
+
+        recorder.record_initial_state(self.cycles, &self.vram[..], &self.state_commands());
+        self.recorder = Some(recorder);
+    }
+
+    pub fn stop_recording(&mut self) -> Option<TraceRecorder> {
+        self.recorder.take()
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn precise_geometry(&self) -> bool {
+        self.precise_geometry
+    }
+
+    /// Draws polygons from the sub-pixel vertices given with their words, texturing them
+    /// with perspective correction when their depth is known too
+    pub fn set_precise_geometry(&mut self, enabled: bool) {
+        self.precise_geometry = enabled;
+    }
+
+    /// The precise vertex behind the next GP0 word written through the bus
+    pub fn set_next_precise(&mut self, precise: Option<PreciseVertex>) {
+        self.next_precise = precise;
+    }
+
+    pub fn render_threads(&self) -> usize {
+        self.render_threads.as_ref().map_or(1, RenderThreads::count)
+    }
+
+    /// Draws on worker threads, each taking interleaved bands of scanlines. 1 draws on the
+    /// emulation thread as soon as a command arrives
+    pub fn set_render_threads(&mut self, threads: usize) {
+        self.sync();
+        self.render_threads = (threads > 1).then(|| RenderThreads::new(threads));
+    }
+
+    /// Waits for the draws queued for the render threads. GPUREAD, GPUSTAT, VRAM transfers
+    /// and display flips do this on their own, call it before looking at VRAM from outside
+    pub fn sync(&mut self) {
+        let Some(render_threads) = &self.render_threads else {
+            return
+        };
+        if self.queue.is_empty() {
+            return;
+        }
+
+        let shadow = (self.scale > 1).then_some((&mut self.shadow[..], self.scale));
+        render_threads.draw(&self.queue, &mut self.vram[..], shadow);
+        self.queue.clear();
+        self.queued_rows = None;
+    }
+
+    /// Saves the visible part of VRAM at the resolution scale
+    pub fn export_screenshot_png<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        let (width, height, pixels) = self.display_frame_scaled();
+        let writer = BufWriter::new(File::create(path)?);
+        png::encode_rgb(writer, width, height, &pixels)
+    }
+
+    /// Returns the visible part of VRAM as RGB888 pixels, along with its dimensions
+    pub fn display_frame(&self) -> (usize, usize, Vec<u8>) {
+        self.render_display(1)
+    }
+
+    /// Like `display_frame`, but from the shadow VRAM at the resolution scale.
+    /// 24-bit output (FMVs) is only ever uploaded natively, so it gets blown up instead
+    pub fn display_frame_scaled(&self) -> (usize, usize, Vec<u8>) {
+        self.render_display(self.scale)
+    }
+
+    fn render_display(&self, scale: usize) -> (usize, usize, Vec<u8>) {
+        let width = match (self.status.horizontal_resolution_2(), self.status.horizontal_resolution_1()) {
+            (true, _) => 368,
+            (false, 0) => 256,
+            (false, 1) => 320,
+            (false, 2) => 512,
+            (false, _) => 640
+        };
+
+        let y1 = self.vertical_range & 0x3FF;
+        let y2 = (self.vertical_range >> 10) & 0x3FF;
+        let mut height = match y2.saturating_sub(y1) {
+            0 => 240,
+            lines => lines as usize
+        };
+        if self.status.vertical_interlace() && self.status.vertical_resolution() {
+            height *= 2;
+        }
+        let height = height.min(VRAM_HEIGHT);
+
+        let start_x = (self.display_start & 0x3FE) as usize;
+        let start_y = ((self.display_start >> 10) & 0x1FF) as usize;
+
+        let shadow = (scale > 1 && !self.shadow_stale && !self.status.display_24bit()).then_some(&self.shadow[..]);
+        let (scaled_width, scaled_height) = (width * scale, height * scale);
+
+        let mut pixels = Vec::with_capacity(scaled_width * scaled_height * 3);
+        for y in 0..scaled_height {
+            let row = ((start_y + y / scale) % VRAM_HEIGHT) * VRAM_WIDTH;
+
+            for x in 0..scaled_width {
+                if let Some(shadow) = shadow {
+                    let shadow_y = (start_y * scale + y) % (VRAM_HEIGHT * scale);
+                    let shadow_x = (start_x * scale + x) % (VRAM_WIDTH * scale);
+                    pixels.extend_from_slice(&rgb555_to_rgb888(shadow[shadow_y * VRAM_WIDTH * scale + shadow_x]));
+                } else if self.status.display_24bit() {
+                    // Pixels are packed as bytes, three per 24-bit pixel
+                    for byte in 0..3 {
+                        let index = x / scale * 3 + byte;
+                        let halfword = self.vram[row + (start_x + index / 2) % VRAM_WIDTH];
+                        pixels.push((halfword >> ((index & 1) * 8)) as u8);
+                    }
+                } else {
+                    let pixel = self.vram[row + (start_x + x / scale) % VRAM_WIDTH];
+                    pixels.extend_from_slice(&rgb555_to_rgb888(pixel));
+                }
+            }
+        }
+
+        (scaled_width, scaled_height, pixels)
+    }
+
+    fn reset(&mut self) {
+        self.status = Status(0x14802000);
+        self.reset_command_buffer();
+
+        self.texture_rect_flip_x = false;
+        self.texture_rect_flip_y = false;
+        self.texture_window = 0;
+        self.drawing_area_top_left = 0;
+        self.drawing_area_bottom_right = 0;
+        self.drawing_offset = 0;
+
+        self.display_start = 0;
+        self.horizontal_range = 0xC60260;
+        self.vertical_range = 0x40010;
+    }
+
+    fn reset_command_buffer(&mut self) {
+        self.command.clear();
+        self.command_precise.clear();
+        self.command_words = 0;
+        self.polyline = false;
+        self.write_transfer = None;
+        self.read_transfer = None;
+    }
+
+    /// GP0/GP1 writes which bring a freshly reset GPU into the current drawing and display state
+    fn state_commands(&self) -> Vec<(Port, u32)> {
+        let status: u32 = self.status.into();
+        let draw_mode = (status & 0x7FF)
+            | ((self.status.texture_disable() as u32) << 11)
+            | ((self.texture_rect_flip_x as u32) << 12)
+            | ((self.texture_rect_flip_y as u32) << 13);
+        let mask = (self.status.set_mask_bit() as u32) | ((self.status.check_mask_bit() as u32) << 1);
+        let display_mode = ((status >> 17) & 0x3)
+            | (((status >> 19) & 0x1) << 2)
+            | (((status >> 20) & 0x1) << 3)
+            | (((status >> 21) & 0x1) << 4)
+            | (((status >> 22) & 0x1) << 5)
+            | (((status >> 16) & 0x1) << 6)
+            | (((status >> 14) & 0x1) << 7);
+
+        vec![
+            (Port::Gp0, 0xE1000000 | draw_mode),
+            (Port::Gp0, 0xE2000000 | self.texture_window),
+            (Port::Gp0, 0xE3000000 | self.drawing_area_top_left),
+            (Port::Gp0, 0xE4000000 | self.drawing_area_bottom_right),
+            (Port::Gp0, 0xE5000000 | self.drawing_offset),
+            (Port::Gp0, 0xE6000000 | mask),
+            (Port::Gp1, 0x03000000 | self.status.display_disabled() as u32),
+            (Port::Gp1, 0x04000000 | self.status.dma_direction()),
+            (Port::Gp1, 0x05000000 | self.display_start),
+            (Port::Gp1, 0x06000000 | self.horizontal_range),
+            (Port::Gp1, 0x07000000 | self.vertical_range),
+            (Port::Gp1, 0x08000000 | display_mode),
+        ]
+    }
+
+    fn execute_gp0(&mut self) {
+        let command = std::mem::take(&mut self.command);
+        let precise = std::mem::take(&mut self.command_precise);
+        let opcode = command[0] >> 24;
+        trace!("[GPU] GP0 command 0x{:02X}: {:08X?}", opcode, command);
+
+        match opcode {
+            0x00 | 0x03..=0x1E => (),
+            0x01 => trace!("[GPU] Clear texture cache"),
+            0x02 => {
+                self.sync();
+                self.fill_rectangle(&command);
+            }
+            0x1F => self.status.set_interrupt_request(true),
+            0x20..=0x3F => self.draw_polygon(&command, &precise),
+            0x40..=0x5F => self.draw_line(&command),
+            0x60..=0x7F => self.draw_rectangle(&command),
+            0x80..=0x9F => {
+                self.sync();
+                self.copy_rectangle(&command);
+            }
+            0xA0..=0xBF => {
+                self.sync();
+                self.write_transfer = Some(VramTransfer::new(command[1], command[2]));
+            }
+            0xC0..=0xDF => {
+                self.sync();
+                self.read_transfer = Some(VramTransfer::new(command[1], command[2]));
+            }
+            0xE1 => {
+                let value = command[0];
+                let status = (u32::from(self.status) & !0x87FF) | (value & 0x7FF) | ((value & 0x800) << 4);
+                self.status = Status(status);
+                self.texture_rect_flip_x = value & 0x1000 != 0;
+                self.texture_rect_flip_y = value & 0x2000 != 0;
+            }
+            0xE2 => self.texture_window = command[0] & 0xFFFFF,
+            0xE3 => self.drawing_area_top_left = command[0] & 0xFFFFF,
+            0xE4 => self.drawing_area_bottom_right = command[0] & 0xFFFFF,
+            0xE5 => self.drawing_offset = command[0] & 0x3FFFFF,
+            0xE6 => {
+                self.status.set_set_mask_bit(command[0] & 1 != 0);
+                self.status.set_check_mask_bit(command[0] & 2 != 0);
+            }
+            _ => warn!("[GPU] Unhandled GP0 command 0x{:02X}: 0x{:08X}", opcode, command[0])
+        }
+
+        self.command_words = 0;
+        self.polyline = false;
+    }
+
+    fn fill_rectangle(&mut self, command: &[u32]) {
+        let color = rgb888_to_rgb555(command[0]);
+        let x = (command[1] & 0x3F0) as usize;
+        let y = ((command[1] >> 16) & 0x1FF) as usize;
+        let width = (((command[2] & 0x3FF) + 0xF) & !0xF) as usize;
+        let height = ((command[2] >> 16) & 0x1FF) as usize;
+
+        // Fills ignore both the drawing area and the mask bit settings
+        for dy in 0..height {
+            for dx in 0..width {
+                let index = ((y + dy) % VRAM_HEIGHT) * VRAM_WIDTH + (x + dx) % VRAM_WIDTH;
+                self.vram[index] = color;
+                self.upscale_pixel(index);
+            }
+        }
+    }
+
+    fn draw_polygon(&mut self, command: &[u32], precise: &[Option<PreciseVertex>]) {
+        let opcode = command[0] >> 24;
+        let quad = opcode & 0x08 != 0;
+        let textured = opcode & 0x04 != 0;
+        let gouraud = opcode & 0x10 != 0;
+        let raw = opcode & 0x01 != 0;
+
+        let mut words = command[1..].iter().copied();
+        let mut vertices = [Vertex::default(); 4];
+        let mut color = command[0];
+        let (mut clut, mut page) = (0, 0);
+
+        let count = if quad { 4 } else { 3 };
+        for (index, vertex) in vertices[..count].iter_mut().enumerate() {
+            if gouraud && index > 0 {
+                color = words.next().unwrap();
+            }
+            let position = command.len() - words.len();
+            *vertex = self.vertex(words.next().unwrap(), color);
+            vertex.precise = precise[position].map(|precise| self.offset_precise(precise));
+
+            if textured {
+                let uv = words.next().unwrap();
+                vertex.u = (uv & 0xFF) as i32;
+                vertex.v = ((uv >> 8) & 0xFF) as i32;
+                match index {
+                    0 => clut = uv >> 16,
+                    1 => page = uv >> 16,
+                    _ => ()
+                }
+            }
+        }
+
+        // Textured polygons carry their own texture page, which sticks for later rectangles
+        if textured {
+            let status = (u32::from(self.status) & !0x81FF) | (page & 0x1FF) | ((page & 0x800) << 4);
+            self.status = Status(status);
+        }
+
+        let texture = textured.then(|| self.texture(clut, raw));
+        let dither = self.status.dither() && (gouraud || (textured && !raw));
+
+        // Quads are drawn as two triangles sharing the 1-2 edge
+        for triangle in [[0, 1, 2], [1, 2, 3]].iter().take(count - 2) {
+            let shape = Shape::Triangle(triangle.map(|index| vertices[index]));
+            self.draw(shape, texture, opcode, dither);
+        }
+    }
+
+    fn draw_line(&mut self, command: &[u32]) {
+        let opcode = command[0] >> 24;
+        let gouraud = opcode & 0x10 != 0;
+        let dither = self.status.dither() && gouraud;
+
+        // Polylines end with a terminator word, Gouraud ones have a colour before every vertex
+        let words = if self.polyline { &command[1..command.len() - 1] } else { &command[1..] };
+        let mut vertices = vec![self.vertex(words[0], command[0])];
+        if gouraud {
+            vertices.extend(words[1..].chunks_exact(2).map(|pair| self.vertex(pair[1], pair[0])));
+        } else {
+            vertices.extend(words[1..].iter().map(|&position| self.vertex(position, command[0])));
+        }
+
+        for pair in vertices.windows(2) {
+            self.draw(Shape::Line([pair[0], pair[1]]), None, opcode, dither);
+        }
+    }
+
+    fn draw_rectangle(&mut self, command: &[u32]) {
+        let opcode = command[0] >> 24;
+        let textured = opcode & 0x04 != 0;
+
+        let mut words = command[1..].iter().copied();
+        let mut vertex = self.vertex(words.next().unwrap(), command[0]);
+        let mut clut = 0;
+        if textured {
+            let uv = words.next().unwrap();
+            vertex.u = (uv & 0xFF) as i32;
+            vertex.v = ((uv >> 8) & 0xFF) as i32;
+            clut = uv >> 16;
+        }
+
+        let (width, height) = match (opcode >> 3) & 0x3 {
+            0 => {
+                let size = words.next().unwrap();
+                ((size & 0x3FF) as i32, ((size >> 16) & 0x1FF) as i32)
+            }
+            1 => (1, 1),
+            2 => (8, 8),
+            3 => (16, 16),
+            _ => unreachable!()
+        };
+
+        // Rectangles use the current texture page and are never dithered
+        let texture = textured.then(|| self.texture(clut, opcode & 0x01 != 0));
+        let shape = Shape::Rectangle {
+            vertex,
+            width,
+            height,
+            flip_x: self.texture_rect_flip_x,
+            flip_y: self.texture_rect_flip_y
+        };
+        self.draw(shape, texture, opcode, false);
+    }
+
+    fn draw(&mut self, shape: Shape, texture: Option<Texture>, opcode: u32, dither: bool) {
+        let top_left = self.drawing_area_top_left;
+        let bottom_right = self.drawing_area_bottom_right;
+
+        let primitive = Primitive {
+            shape,
+            texture,
+            semi_transparency: (opcode & 0x02 != 0).then(|| self.status.semi_transparency()),
+            dither,
+            set_mask: self.status.set_mask_bit(),
+            check_mask: self.status.check_mask_bit(),
+            area: Area {
+                left: (top_left & 0x3FF) as i32,
+                top: ((top_left >> 10) & 0x1FF) as i32,
+                right: (bottom_right & 0x3FF) as i32,
+                bottom: ((bottom_right >> 10) & 0x1FF) as i32
+            }
+        };
+
+        if self.shadow_stale {
+            self.upscale_shadow();
+        }
+
+        if self.render_threads.is_some() {
+            if !primitive.samples_own_rows() {
+                self.queue_draw(primitive);
+                return;
+            }
+
+            // Drawing into its own texture depends on the pixel order, which only one thread keeps
+            self.sync();
+        }
+
+        // The shadow goes first so it samples textures from before the draw, like the native one
+        let vram = rasterizer::atomic_pixels(&mut self.vram[..]);
+        if self.scale > 1 {
+            let shadow = rasterizer::atomic_pixels(&mut self.shadow);
+            rasterizer::draw(&Target::scaled(shadow, vram, self.scale), &primitive);
+        }
+        rasterizer::draw(&Target::native(vram), &primitive);
+    }
+
+    fn queue_draw(&mut self, primitive: Primitive) {
+        // Render threads only see their own bands, so a texture drawn by queued primitives has to land first
+        if let (Some(texture), Some((top, bottom))) = (&primitive.texture, self.queued_rows)
+            && texture.rows().iter().any(|&(first, last)| first <= bottom && top <= last)
+        {
+            self.sync();
+        }
+
+        let (top, bottom) = primitive.rows();
+        if top > bottom {
+            return;
+        }
+
+        self.queued_rows = Some(match self.queued_rows {
+            Some((queued_top, queued_bottom)) => (queued_top.min(top), queued_bottom.max(bottom)),
+            None => (top, bottom)
+        });
+        self.queue.push(primitive);
+
+        if self.queue.len() >= QUEUE_LIMIT {
+            self.sync();
+        }
+    }
+
+    /// Applies the drawing offset to a GP0 vertex word
+    fn vertex(&self, position: u32, color: u32) -> Vertex {
+        Vertex {
+            x: sign_extend_11(position) + sign_extend_11(self.drawing_offset),
+            y: sign_extend_11(position >> 16) + sign_extend_11(self.drawing_offset >> 11),
+            color: [color & 0xFF, (color >> 8) & 0xFF, (color >> 16) & 0xFF].map(|channel| channel as i32),
+            u: 0,
+            v: 0,
+            precise: None
+        }
+    }
+
+    fn offset_precise(&self, precise: PreciseVertex) -> PreciseVertex {
+        PreciseVertex {
+            x: precise.x + sign_extend_11(self.drawing_offset) as f32,
+            y: precise.y + sign_extend_11(self.drawing_offset >> 11) as f32,
+            z: precise.z
+        }
+    }
+
+    fn texture(&self, clut: u32, raw: bool) -> Texture {
+        Texture {
+            page_x: self.status.texture_page_x() as usize * 64,
+            page_y: self.status.texture_page_y() as usize * 256,
+            depth: self.status.texture_page_colors(),
+            clut_x: (clut & 0x3F) as usize * 16,
+            clut_y: ((clut >> 6) & 0x1FF) as usize,
+            window: self.texture_window,
+            raw
+        }
+    }
+
+    fn copy_rectangle(&mut self, command: &[u32]) {
+        let src_x = (command[1] & 0x3FF) as usize;
+        let src_y = ((command[1] >> 16) & 0x1FF) as usize;
+        let dst_x = (command[2] & 0x3FF) as usize;
+        let dst_y = ((command[2] >> 16) & 0x1FF) as usize;
+        let width = ((command[3] & 0x3FF).wrapping_sub(1) & 0x3FF) as usize + 1;
+        let height = (((command[3] >> 16) & 0x1FF).wrapping_sub(1) & 0x1FF) as usize + 1;
+
+        for dy in 0..height {
+            for dx in 0..width {
+                let src = ((src_y + dy) % VRAM_HEIGHT) * VRAM_WIDTH + (src_x + dx) % VRAM_WIDTH;
+                let dst = ((dst_y + dy) % VRAM_HEIGHT) * VRAM_WIDTH + (dst_x + dx) % VRAM_WIDTH;
+                if self.write_vram_masked(dst, self.vram[src]) {
+                    self.copy_shadow_pixel(src, dst);
+                }
+            }
+        }
+    }
+
+    fn write_vram_transfer(&mut self, value: u16) {
+        let Some(transfer) = &mut self.write_transfer else {
+            return
+        };
+
+        let index = transfer.index();
+        let done = transfer.advance();
+        if self.write_vram_masked(index, value) {
+            self.upscale_pixel(index);
+        }
+
+        if done {
+            self.write_transfer = None;
+        }
+    }
+
+    fn read_vram_transfer(&mut self) -> u16 {
+        let Some(transfer) = &mut self.read_transfer else {
+            return 0
+        };
+
+        let index = transfer.index();
+        if transfer.advance() {
+            self.read_transfer = None;
+        }
+
+        self.vram[index]
+    }
+
+    /// Returns false if the mask bit kept the pixel
+    fn write_vram_masked(&mut self, index: usize, value: u16) -> bool {
+        if self.status.check_mask_bit() && self.vram[index] & 0x8000 != 0 {
+            return false;
+        }
+
+        let mask = if self.status.set_mask_bit() { 0x8000 } else { 0 };
+        self.vram[index] = value | mask;
+        true
+    }
+
+    fn upscale_shadow(&mut self) {
+        self.shadow_stale = false;
+        for index in 0..VRAM_WIDTH * VRAM_HEIGHT {
+            self.upscale_pixel(index);
+        }
+    }
+
+    /// Fills the shadow block of a native pixel with its value
+    fn upscale_pixel(&mut self, index: usize) {
+        if self.shadow_stale {
+            return;
+        }
+
+        let value = self.vram[index];
+        for offset in self.shadow_block(index) {
+            self.shadow[offset..offset + self.scale].fill(value);
+        }
+    }
+
+    // VRAM to VRAM copies keep the upscaled detail
+    fn copy_shadow_pixel(&mut self, src: usize, dst: usize) {
+        if self.shadow_stale {
+            return;
+        }
+
+        for (src, dst) in self.shadow_block(src).zip(self.shadow_block(dst)) {
+            self.shadow.copy_within(src..src + self.scale, dst);
+        }
+    }
+
+    /// Start of every shadow row covered by a native pixel, empty when not upscaling
+    fn shadow_block(&self, index: usize) -> impl Iterator<Item = usize> + use<> {
+        let scale = if self.shadow.is_empty() { 0 } else { self.scale };
+        let (x, y) = (index % VRAM_WIDTH, index / VRAM_WIDTH);
+        let width = VRAM_WIDTH * scale;
+
+        (0..scale).map(move |row| (y * scale + row) * width + x * scale)
+    }
+}
+
+impl DmaDevice for Gpu {
+    fn dma_read(&mut self) -> u32 {
+        self.gpuread()
+    }
+
+    fn dma_write(&mut self, value: u32) {
+        self.gp0(value);
+    }
+
+    fn dma_write_precise(&mut self, value: u32, precise: Option<PreciseVertex>) {
+        self.gp0_precise(value, precise);
+    }
+}
+
+/// Number of words a GP0 command takes, polylines are terminated separately
+fn gp0_command_words(opcode: u32) -> usize {
+    match opcode {
+        0x02 => 3,
+        0x20..=0x3F => {
+            let vertices = if opcode & 0x08 != 0 { 4 } else { 3 };
+            let textured = opcode & 0x04 != 0;
+            let gouraud = opcode & 0x10 != 0;
+
+            let mut words = 1 + vertices;
+            if textured {
+                words += vertices;
+            }
+            if gouraud {
+                words += vertices - 1;
+            }
+            words
+        }
+        0x40..=0x5F => if opcode & 0x10 != 0 { 4 } else { 3 },
+        0x60..=0x7F => {
+            let mut words = 2;
+            if opcode & 0x04 != 0 {
+                words += 1;
+            }
+            if opcode & 0x18 == 0 {
+                words += 1;
+            }
+            words
+        }
+        0x80..=0x9F => 4,
+        0xA0..=0xDF => 3,
+        _ => 1
+    }
+}
+
+struct VramTransfer {
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    current_x: usize,
+    current_y: usize
+}
+
+impl VramTransfer {
+    fn new(position: u32, size: u32) -> Self {
+        let width = ((size & 0x3FF).wrapping_sub(1) & 0x3FF) as usize + 1;
+        let height = (((size >> 16) & 0x1FF).wrapping_sub(1) & 0x1FF) as usize + 1;
+
+        Self {
+            x: (position & 0x3FF) as usize,
+            y: ((position >> 16) & 0x1FF) as usize,
+            width,
+            height,
+            current_x: 0,
+            current_y: 0
+        }
+    }
+
+    fn index(&self) -> usize {
+        let x = (self.x + self.current_x) % VRAM_WIDTH;
+        let y = (self.y + self.current_y) % VRAM_HEIGHT;
+        y * VRAM_WIDTH + x
+    }
+
+    /// Moves to the next pixel, returns true once the whole rectangle is done
+    fn advance(&mut self) -> bool {
+        self.current_x += 1;
+        if self.current_x == self.width {
+            self.current_x = 0;
+            self.current_y += 1;
+        }
+
+        self.current_y == self.height
+    }
+}
+
+fn sign_extend_11(value: u32) -> i32 {
+    ((value << 21) as i32) >> 21
+}
+
+pub fn rgb555_to_rgb888(pixel: u16) -> [u8; 3] {
+    let r = (pixel & 0x1F) as u8;
+    let g = ((pixel >> 5) & 0x1F) as u8;
+    let b = ((pixel >> 10) & 0x1F) as u8;
+
+    [(r << 3) | (r >> 2), (g << 3) | (g >> 2), (b << 3) | (b >> 2)]
+}
+
+fn rgb888_to_rgb555(color: u32) -> u16 {
+    let r = (color >> 3) & 0x1F;
+    let g = (color >> 11) & 0x1F;
+    let b = (color >> 19) & 0x1F;
+
+    (r | (g << 5) | (b << 10)) as u16
+}
+
+#[bitfield(u32)]
+pub struct Status {
+    #[bits(4)]
+    texture_page_x: u32,
+    texture_page_y: bool,
+    #[bits(2)]
+    semi_transparency: u32,
+    #[bits(2)]
+    texture_page_colors: u32,
+    dither: bool,
+    draw_to_display: bool,
+    set_mask_bit: bool,
+    check_mask_bit: bool,
+    interlace_field: bool,
+    reverse_flag: bool,
+    texture_disable: bool,
+    horizontal_resolution_2: bool,
+    #[bits(2)]
+    horizontal_resolution_1: u32,
+    vertical_resolution: bool,
+    video_mode: bool,
+    display_24bit: bool,
+    vertical_interlace: bool,
+    display_disabled: bool,
+    interrupt_request: bool,
+    dma_request: bool,
+    ready_command: bool,
+    ready_vram_send: bool,
+    ready_dma_block: bool,
+    #[bits(2)]
+    dma_direction: u32,
+    even_odd_line: bool
+}
\ No newline at end of file
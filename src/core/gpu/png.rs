@@ -0,0 +1,108 @@
+use std::io::{self, Write};
+
+// Minimal PNG writer: 8-bit RGB, no filtering, and the zlib stream only uses stored deflate blocks.
+// Files come out big but there's nothing to depend on
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+/// Writes `pixels` (RGB888, row-major) as a PNG image
+pub fn encode_rgb<W: Write>(mut writer: W, width: usize, height: usize, pixels: &[u8]) -> Result<(), io::Error> {
+    assert_eq!(pixels.len(), width * height * 3, "pixel buffer doesn't match image dimensions");
+
+    writer.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, color type 2 (RGB), default compression, filter and interlace methods
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_chunk(&mut writer, b"IHDR", &header)?;
+
+    // Every scanline is prefixed by its filter type, which is always 0 (None) here
+    let mut scanlines = Vec::with_capacity((width * 3 + 1) * height);
+    for row in pixels.chunks_exact(width * 3) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+    write_chunk(&mut writer, b"IDAT", &zlib_stored(&scanlines))?;
+
+    write_chunk(&mut writer, b"IEND", &[])?;
+    writer.flush()
+}
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> Result<(), io::Error> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+
+    let crc = crc32(crc32(0xFFFFFFFF, kind), data) ^ 0xFFFFFFFF;
+    writer.write_all(&crc.to_be_bytes())
+}
+
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let blocks = data.len().div_ceil(MAX_STORED_BLOCK).max(1);
+    let mut output = Vec::with_capacity(data.len() + blocks * 5 + 6);
+
+    // CM = 8 (deflate), CINFO = 7 (32K window), FCHECK makes the header a multiple of 31
+    output.extend_from_slice(&[0x78, 0x01]);
+
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        output.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let length = chunk.len() as u16;
+
+        output.push(last as u8);
+        output.extend_from_slice(&length.to_le_bytes());
+        output.extend_from_slice(&(!length).to_le_bytes());
+        output.extend_from_slice(chunk);
+    }
+
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+
+    let mut n = 0;
+    while n < 256 {
+        let mut crc = n as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { 0xEDB88320 ^ (crc >> 1) } else { crc >> 1 };
+            bit += 1;
+        }
+        table[n] = crc;
+        n += 1;
+    }
+
+    table
+};
+
+fn crc32(mut crc: u32, data: &[u8]) -> u32 {
+    for &byte in data {
+        crc = CRC32_TABLE[((crc ^ byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+
+    // 5552 is the largest run which can't overflow before the modulo
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+
+    (b << 16) | a
+}
\ No newline at end of file
@@ -0,0 +1,873 @@
+use std::sync::atomic::{AtomicU16, Ordering};
+
+use super::{VRAM_HEIGHT, VRAM_WIDTH};
+use crate::core::precision::PreciseVertex;
+
+// Software rasterizer for the GP0 polygon, line and rectangle commands.
+// Attributes are interpolated in 16.16 fixed point, colours stay 8-bit until dithering
+const FRACTION_BITS: u32 = 16;
+
+// Polygons and lines with a longer edge are skipped by the GPU
+const MAX_EDGE_X: i32 = 1023;
+const MAX_EDGE_Y: i32 = 511;
+
+/// Native rows per band, bands are handed out to the render threads round robin
+pub(super) const BAND_HEIGHT: usize = 16;
+
+const DITHER: [[i32; 4]; 4] = [
+    [-4, 0, -3, 1],
+    [2, -2, 3, -1],
+    [-3, 1, -4, 0],
+    [3, -1, 2, -2]
+];
+
+/// Inclusive pixel bounds, set with GP0(E3h) and GP0(E4h)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Area {
+    pub left: i32,
+    pub top: i32,
+    pub right: i32,
+    pub bottom: i32
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub(super) struct Vertex {
+    pub x: i32,
+    pub y: i32,
+    pub color: [i32; 3],
+    pub u: i32,
+    pub v: i32,
+    /// Sub-pixel position and depth from the GTE, drawing offset included
+    pub precise: Option<PreciseVertex>
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Texture {
+    pub page_x: usize,
+    pub page_y: usize,
+    /// 0 = 4-bit CLUT, 1 = 8-bit CLUT, 2 = 15-bit direct
+    pub depth: u32,
+    pub clut_x: usize,
+    pub clut_y: usize,
+    /// GP0(E2h) mask and offset, in 8 texel units
+    pub window: u32,
+    /// Texels are used as is instead of being modulated by the vertex colour
+    pub raw: bool
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) enum Shape {
+    Triangle([Vertex; 3]),
+    Line([Vertex; 2]),
+    Rectangle { vertex: Vertex, width: i32, height: i32, flip_x: bool, flip_y: bool }
+}
+
+/// A draw command with every piece of GPU state it depends on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(super) struct Primitive {
+    pub shape: Shape,
+    pub texture: Option<Texture>,
+    /// Blending mode from the texture page, None for opaque commands
+    pub semi_transparency: Option<u32>,
+    pub dither: bool,
+    pub set_mask: bool,
+    pub check_mask: bool,
+    pub area: Area
+}
+
+impl Primitive {
+    /// Native rows the primitive can touch
+    pub fn rows(&self) -> (i32, i32) {
+        let (top, bottom) = match self.shape {
+            Shape::Triangle(vertices) => vertical_extent(&vertices),
+            Shape::Line(vertices) => vertical_extent(&vertices),
+            Shape::Rectangle { vertex, height, .. } => (vertex.y, vertex.y + height - 1)
+        };
+
+        (top.max(self.area.top), bottom.min(self.area.bottom))
+    }
+
+    /// Whether the primitive might sample texels it draws itself
+    pub fn samples_own_rows(&self) -> bool {
+        let (top, bottom) = self.rows();
+        self.texture.is_some_and(|texture| texture.rows().iter().any(|&(first, last)| first <= bottom && top <= last))
+    }
+}
+
+fn vertical_extent(vertices: &[Vertex]) -> (i32, i32) {
+    let ys = vertices.iter().map(|vertex| vertex.y);
+    (ys.clone().min().unwrap(), ys.max().unwrap())
+}
+
+impl Texture {
+    /// Rows of the texture page and the CLUT
+    pub fn rows(&self) -> [(i32, i32); 2] {
+        let (page, clut) = (self.page_y as i32, self.clut_y as i32);
+        [(page, page + 255), (clut, clut)]
+    }
+}
+
+/// Which of the interleaved bands of rows a render thread draws
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Band {
+    pub index: usize,
+    pub count: usize
+}
+
+/// Where primitives are drawn, either VRAM itself or its upscaled shadow.
+/// Pixels are atomics so render threads can share them, each one writing its own band
+pub(super) struct Target<'a> {
+    pixels: &'a [AtomicU16],
+    /// Native VRAM to sample textures from when drawing into the shadow
+    texels: Option<&'a [AtomicU16]>,
+    scale: i32,
+    band: Option<Band>
+}
+
+impl<'a> Target<'a> {
+    pub fn native(vram: &'a [AtomicU16]) -> Self {
+        Self { pixels: vram, texels: None, scale: 1, band: None }
+    }
+
+    /// A shadow VRAM `scale` times wider and taller than the native one
+    pub fn scaled(shadow: &'a [AtomicU16], vram: &'a [AtomicU16], scale: usize) -> Self {
+        Self { pixels: shadow, texels: Some(vram), scale: scale as i32, band: None }
+    }
+
+    /// Limits drawing to one band of rows
+    pub fn with_band(self, band: Band) -> Self {
+        Self { band: Some(band), ..self }
+    }
+
+    fn width(&self) -> usize {
+        VRAM_WIDTH * self.scale as usize
+    }
+
+    fn owns_row(&self, y: i32) -> bool {
+        self.band.is_none_or(|band| (y / self.scale) as usize / BAND_HEIGHT % band.count == band.index)
+    }
+}
+
+/// Shares pixels between render threads for as long as they're borrowed
+pub(super) fn atomic_pixels(pixels: &mut [u16]) -> &[AtomicU16] {
+    // SAFETY: AtomicU16 has the size and alignment of u16, and the exclusive borrow
+    // keeps everything else away from the pixels while the atomic view lives
+    unsafe { &*(pixels as *mut [u16] as *const [AtomicU16]) }
+}
+
+pub(super) fn draw(target: &Target, primitive: &Primitive) {
+    let Area { left, top, right, bottom } = primitive.area;
+    if left > right || top > bottom {
+        return;
+    }
+
+    // Everything is positioned in native pixels, the target scales it up
+    let scale = target.scale;
+    let area = Area { left: left * scale, top: top * scale, right: (right + 1) * scale - 1, bottom: (bottom + 1) * scale - 1 };
+    let scaled = |vertex: Vertex| Vertex {
+        x: vertex.x * scale,
+        y: vertex.y * scale,
+        precise: vertex.precise.map(|precise| PreciseVertex { x: precise.x * scale as f32, y: precise.y * scale as f32, ..precise }),
+        ..vertex
+    };
+
+    match primitive.shape {
+        Shape::Triangle(vertices) if !is_oversized(&vertices) => {
+            let vertices = vertices.map(scaled);
+            match vertices.map(|vertex| vertex.precise) {
+                [Some(p0), Some(p1), Some(p2)] => draw_precise_triangle(target, primitive, &area, vertices, [p0, p1, p2]),
+                _ => draw_triangle(target, primitive, &area, vertices)
+            }
+        }
+        Shape::Line(vertices) if !is_oversized(&vertices) => draw_line(target, primitive, &area, vertices),
+        Shape::Rectangle { vertex, width, height, flip_x, flip_y } => {
+            draw_rectangle(target, primitive, &area, vertex, (width, height), (flip_x, flip_y))
+        }
+        _ => ()
+    }
+}
+
+fn is_oversized(vertices: &[Vertex]) -> bool {
+    vertices.iter().enumerate().any(|(index, a)| {
+        vertices[index + 1..].iter().any(|b| (a.x - b.x).abs() > MAX_EDGE_X || (a.y - b.y).abs() > MAX_EDGE_Y)
+    })
+}
+
+fn draw_triangle(target: &Target, primitive: &Primitive, clip: &Area, mut vertices: [Vertex; 3]) {
+    // Wind every triangle the same way so the edge functions are positive inside
+    let mut area = edge(&vertices[0], &vertices[1], vertices[2].x, vertices[2].y);
+    if area < 0 {
+        vertices.swap(1, 2);
+        area = -area;
+    }
+    if area == 0 {
+        return;
+    }
+
+    let [v0, v1, v2] = vertices;
+    let left = v0.x.min(v1.x).min(v2.x).max(clip.left);
+    let right = v0.x.max(v1.x).max(v2.x).min(clip.right);
+    let top = v0.y.min(v1.y).min(v2.y).max(clip.top);
+    let bottom = v0.y.max(v1.y).max(v2.y).min(clip.bottom);
+
+    let attributes = |vertex: &Vertex| [vertex.color[0], vertex.color[1], vertex.color[2], vertex.u, vertex.v];
+    let gradients = Gradients::new(&vertices, area, attributes);
+
+    let edges = [(v1, v2), (v2, v0), (v0, v1)];
+    for y in (top..=bottom).filter(|&y| target.owns_row(y)) {
+        let mut weights = edges.map(|(a, b)| edge(&a, &b, left, y));
+        let mut values = gradients.at(left, y);
+
+        for x in left..=right {
+            let inside = weights.iter().zip(&edges).all(|(&weight, (a, b))| weight > 0 || (weight == 0 && is_top_left(a, b)));
+            if inside {
+                let [r, g, b, u, v] = values.map(|value| (value + (1 << (FRACTION_BITS - 1))) >> FRACTION_BITS);
+                let color = [r, g, b].map(|channel| channel as i32);
+                shade(target, primitive, x, y, color, (u.clamp(0, 255) as u8, v.clamp(0, 255) as u8));
+            }
+
+            for (weight, (a, b)) in weights.iter_mut().zip(&edges) {
+                *weight -= (b.y - a.y) as i64;
+            }
+            for (value, step) in values.iter_mut().zip(&gradients.x) {
+                *value += step;
+            }
+        }
+    }
+}
+
+/// Twice the signed area of (a, b, p), positive when p is on the inner side of a -> b
+fn edge(a: &Vertex, b: &Vertex, x: i32, y: i32) -> i64 {
+    (b.x - a.x) as i64 * (y - a.y) as i64 - (b.y - a.y) as i64 * (x - a.x) as i64
+}
+
+// Pixels exactly on an edge belong to the triangle only for top and left edges,
+// so polygons sharing an edge never draw it twice
+fn is_top_left(a: &Vertex, b: &Vertex) -> bool {
+    b.y < a.y || (b.y == a.y && b.x > a.x)
+}
+
+// Triangles whose vertices all came from the GTE are set up from their sub-pixel positions,
+// and textured with perspective correction when every depth is known
+fn draw_precise_triangle(
+    target: &Target,
+    primitive: &Primitive,
+    clip: &Area,
+    mut vertices: [Vertex; 3],
+    mut points: [PreciseVertex; 3]
+) {
+    let mut area = precise_edge(&points[0], &points[1], points[2].x as f64, points[2].y as f64);
+    if area < 0.0 {
+        vertices.swap(1, 2);
+        points.swap(1, 2);
+        area = -area;
+    }
+    if area == 0.0 {
+        return;
+    }
+
+    let xs = points.map(|point| point.x);
+    let ys = points.map(|point| point.y);
+    let left = (xs.iter().copied().fold(f32::MAX, f32::min).ceil() as i32).max(clip.left);
+    let right = (xs.iter().copied().fold(f32::MIN, f32::max).floor() as i32).min(clip.right);
+    let top = (ys.iter().copied().fold(f32::MAX, f32::min).ceil() as i32).max(clip.top);
+    let bottom = (ys.iter().copied().fold(f32::MIN, f32::max).floor() as i32).min(clip.bottom);
+
+    let perspective = primitive.texture.is_some() && points.iter().all(|point| point.z > 0.0);
+    let edges = [(1, 2), (2, 0), (0, 1)];
+    for y in (top..=bottom).filter(|&y| target.owns_row(y)) {
+        for x in left..=right {
+            let weights = edges.map(|(a, b)| precise_edge(&points[a], &points[b], x as f64, y as f64));
+            let inside = weights.iter().zip(&edges).all(|(&weight, &(a, b))| {
+                weight > 0.0 || (weight == 0.0 && is_precise_top_left(&points[a], &points[b]))
+            });
+            if !inside {
+                continue;
+            }
+
+            let weights = weights.map(|weight| weight / area);
+            let color = std::array::from_fn(|i| interpolate(weights, vertices.map(|vertex| vertex.color[i])).round() as i32);
+
+            // Interpolating U/Z and V/Z linearly in screen space is what keeps textures straight
+            let weights = match perspective {
+                true => {
+                    let weights: [f64; 3] = std::array::from_fn(|i| weights[i] / points[i].z as f64);
+                    let sum: f64 = weights.iter().sum();
+                    weights.map(|weight| weight / sum)
+                }
+                false => weights
+            };
+            let u = interpolate(weights, vertices.map(|vertex| vertex.u));
+            let v = interpolate(weights, vertices.map(|vertex| vertex.v));
+            let texel = |value: f64| value.round().clamp(0.0, 255.0) as u8;
+            shade(target, primitive, x, y, color, (texel(u), texel(v)));
+        }
+    }
+}
+
+fn interpolate(weights: [f64; 3], values: [i32; 3]) -> f64 {
+    weights.iter().zip(values).map(|(weight, value)| weight * value as f64).sum()
+}
+
+fn precise_edge(a: &PreciseVertex, b: &PreciseVertex, x: f64, y: f64) -> f64 {
+    let (ax, ay) = (a.x as f64, a.y as f64);
+    (b.x as f64 - ax) * (y - ay) - (b.y as f64 - ay) * (x - ax)
+}
+
+fn is_precise_top_left(a: &PreciseVertex, b: &PreciseVertex) -> bool {
+    b.y < a.y || (b.y == a.y && b.x > a.x)
+}
+
+/// Per-pixel steps for the colour and texture coordinates of a triangle
+struct Gradients {
+    origin: (i32, i32),
+    base: [i64; 5],
+    x: [i64; 5],
+    y: [i64; 5]
+}
+
+impl Gradients {
+    fn new(vertices: &[Vertex; 3], area: i64, attributes: impl Fn(&Vertex) -> [i32; 5]) -> Self {
+        let [v0, v1, v2] = vertices;
+        let (a0, a1, a2) = (attributes(v0), attributes(v1), attributes(v2));
+        let (dx1, dy1) = ((v1.x - v0.x) as i64, (v1.y - v0.y) as i64);
+        let (dx2, dy2) = ((v2.x - v0.x) as i64, (v2.y - v0.y) as i64);
+
+        let deltas: [(i64, i64); 5] = std::array::from_fn(|i| ((a1[i] - a0[i]) as i64, (a2[i] - a0[i]) as i64));
+        let x = deltas.map(|(d1, d2)| ((d1 * dy2 - d2 * dy1) << FRACTION_BITS) / area);
+        let y = deltas.map(|(d1, d2)| ((d2 * dx1 - d1 * dx2) << FRACTION_BITS) / area);
+
+        Self { origin: (v0.x, v0.y), base: a0.map(|value| (value as i64) << FRACTION_BITS), x, y }
+    }
+
+    fn at(&self, x: i32, y: i32) -> [i64; 5] {
+        let (dx, dy) = ((x - self.origin.0) as i64, (y - self.origin.1) as i64);
+        std::array::from_fn(|i| self.base[i] + self.x[i] * dx + self.y[i] * dy)
+    }
+}
+
+fn draw_line(target: &Target, primitive: &Primitive, clip: &Area, [start, end]: [Vertex; 2]) {
+    // Both ends are drawn, colours are interpolated along the major axis.
+    // Lines are stepped in native pixels and keep their thickness when upscaled
+    let scale = target.scale;
+    let steps = (end.x - start.x).abs().max((end.y - start.y).abs());
+    for step in 0..=steps {
+        let lerp = |from: i32, to: i32| match steps {
+            0 => from,
+            _ => from + div_round((to - from) * step, steps)
+        };
+
+        let (x, y) = (lerp(start.x, end.x) * scale, lerp(start.y, end.y) * scale);
+        let color = std::array::from_fn(|i| lerp(start.color[i], end.color[i]));
+        for y in (y.max(clip.top)..(y + scale).min(clip.bottom + 1)).filter(|&y| target.owns_row(y)) {
+            for x in x.max(clip.left)..(x + scale).min(clip.right + 1) {
+                shade(target, primitive, x, y, color, (0, 0));
+            }
+        }
+    }
+}
+
+fn div_round(numerator: i32, denominator: i32) -> i32 {
+    let half = denominator / 2;
+    if numerator >= 0 {
+        (numerator + half) / denominator
+    } else {
+        (numerator - half) / denominator
+    }
+}
+
+fn draw_rectangle(
+    target: &Target,
+    primitive: &Primitive,
+    clip: &Area,
+    vertex: Vertex,
+    (width, height): (i32, i32),
+    (flip_x, flip_y): (bool, bool)
+) {
+    let scale = target.scale;
+    let (x, y) = (vertex.x * scale, vertex.y * scale);
+    let left = x.max(clip.left);
+    let right = (x + width * scale - 1).min(clip.right);
+    let top = y.max(clip.top);
+    let bottom = (y + height * scale - 1).min(clip.bottom);
+
+    // Texture coordinates wrap within the page, flipped rectangles walk them backwards
+    let step_x = if flip_x { -1 } else { 1 };
+    let step_y = if flip_y { -1 } else { 1 };
+
+    for row in (top..=bottom).filter(|&row| target.owns_row(row)) {
+        let v = (vertex.v + (row - y) / scale * step_y) as u8;
+        for column in left..=right {
+            let u = (vertex.u + (column - x) / scale * step_x) as u8;
+            shade(target, primitive, column, row, vertex.color, (u, v));
+        }
+    }
+}
+
+/// Colours, blends and writes one pixel
+fn shade(target: &Target, primitive: &Primitive, x: i32, y: i32, color: [i32; 3], (u, v): (u8, u8)) {
+    let index = y as usize * target.width() + x as usize;
+    let background = target.pixels[index].load(Ordering::Relaxed);
+    if primitive.check_mask && background & 0x8000 != 0 {
+        return;
+    }
+
+    let (mut color, semi_transparent, mask) = match &primitive.texture {
+        Some(texture) => {
+            let texel = sample(target.texels.unwrap_or(target.pixels), texture, u, v);
+            if texel == 0 {
+                return;
+            }
+
+            let texel_color = [texel & 0x1F, (texel >> 5) & 0x1F, (texel >> 10) & 0x1F].map(|channel| channel as i32);
+            let color = if texture.raw {
+                texel_color.map(|channel| channel << 3)
+            } else {
+                std::array::from_fn(|i| (texel_color[i] * color[i]) >> 4)
+            };
+
+            // Only texels with bit 15 set are blended
+            (color, texel & 0x8000 != 0, texel & 0x8000)
+        }
+        None => (color, true, 0)
+    };
+
+    // The dither pattern keeps its native size when upscaled
+    if primitive.dither {
+        let offset = DITHER[(y / target.scale) as usize & 3][(x / target.scale) as usize & 3];
+        color = color.map(|channel| channel + offset);
+    }
+    let mut color = color.map(|channel| channel.clamp(0, 255) >> 3);
+
+    if let Some(mode) = primitive.semi_transparency
+        && semi_transparent
+    {
+        let background = [background & 0x1F, (background >> 5) & 0x1F, (background >> 10) & 0x1F].map(|channel| channel as i32);
+        color = std::array::from_fn(|i| blend(mode, background[i], color[i]));
+    }
+
+    let mask = if primitive.set_mask { 0x8000 } else { mask };
+    target.pixels[index].store((color[0] | (color[1] << 5) | (color[2] << 10)) as u16 | mask, Ordering::Relaxed);
+}
+
+fn blend(mode: u32, background: i32, foreground: i32) -> i32 {
+    let color = match mode {
+        0 => (background + foreground) >> 1,
+        1 => background + foreground,
+        2 => background - foreground,
+        3 => background + (foreground >> 2),
+        _ => unreachable!()
+    };
+
+    color.clamp(0, 31)
+}
+
+fn sample(vram: &[AtomicU16], texture: &Texture, u: u8, v: u8) -> u16 {
+    let (u, v) = apply_window(texture.window, u as usize, v as usize);
+    let read = |x: usize, y: usize| vram[(y % VRAM_HEIGHT) * VRAM_WIDTH + x % VRAM_WIDTH].load(Ordering::Relaxed);
+
+    match texture.depth {
+        0 => {
+            let index = (read(texture.page_x + u / 4, texture.page_y + v) >> ((u & 3) * 4)) & 0xF;
+            read(texture.clut_x + index as usize, texture.clut_y)
+        }
+        1 => {
+            let index = (read(texture.page_x + u / 2, texture.page_y + v) >> ((u & 1) * 8)) & 0xFF;
+            read(texture.clut_x + index as usize, texture.clut_y)
+        }
+        _ => read(texture.page_x + u, texture.page_y + v)
+    }
+}
+
+// Texture coordinates inside the window mask are replaced by the window offset
+fn apply_window(window: u32, u: usize, v: usize) -> (usize, usize) {
+    let mask_x = (window & 0x1F) as usize * 8;
+    let mask_y = ((window >> 5) & 0x1F) as usize * 8;
+    let offset_x = ((window >> 10) & 0x1F) as usize * 8;
+    let offset_y = ((window >> 15) & 0x1F) as usize * 8;
+
+    ((u & !mask_x) | (offset_x & mask_x), (v & !mask_y) | (offset_y & mask_y))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Gpu, rgb555_to_rgb888};
+    use super::*;
+
+    const RED: u16 = 0x001F;
+
+    fn gpu() -> Gpu {
+        let mut gpu = Gpu::new();
+        run(&mut gpu, &[0xE3000000, 0xE4000000 | (511 << 10) | 1023, 0xE5000000]);
+        gpu
+    }
+
+    fn run(gpu: &mut Gpu, words: &[u32]) {
+        for &word in words {
+            gpu.gp0(word);
+        }
+    }
+
+    fn position(x: i32, y: i32) -> u32 {
+        ((y as u32 & 0x7FF) << 16) | (x as u32 & 0x7FF)
+    }
+
+    fn pixel(gpu: &Gpu, x: usize, y: usize) -> u16 {
+        gpu.vram()[y * VRAM_WIDTH + x]
+    }
+
+    fn drawn(gpu: &Gpu) -> usize {
+        gpu.vram().iter().filter(|&&pixel| pixel != 0).count()
+    }
+
+    fn upload(gpu: &mut Gpu, (x, y): (u32, u32), width: u32, pixels: &[u16]) {
+        let height = pixels.len() as u32 / width;
+        run(gpu, &[0xA0000000, (y << 16) | x, (height << 16) | width]);
+        for pair in pixels.chunks(2) {
+            gpu.gp0(pair[0] as u32 | ((*pair.get(1).unwrap_or(&0) as u32) << 16));
+        }
+    }
+
+    #[test]
+    fn flat_quads_cover_their_pixels_once() {
+        let mut gpu = gpu();
+        run(&mut gpu, &[0x280000FF, position(10, 10), position(26, 10), position(10, 26), position(26, 26)]);
+
+        assert_eq!(drawn(&gpu), 16 * 16, "right and bottom edges are not drawn");
+        assert_eq!(pixel(&gpu, 10, 10), RED);
+        assert_eq!(pixel(&gpu, 25, 25), RED);
+
+        // Additive blending shows any pixel drawn by both triangles
+        let mut gpu = self::gpu();
+        run(&mut gpu, &[0xE1000020, 0x2A080808, position(0, 0), position(7, 3), position(2, 9), position(11, 11)]);
+        let pixels: Vec<u16> = gpu.vram().iter().copied().filter(|&pixel| pixel != 0).collect();
+        assert!(!pixels.is_empty());
+        assert!(pixels.iter().all(|&pixel| pixel == 0x0421), "shared edge drawn twice");
+    }
+
+    #[test]
+    fn skips_oversized_polygons() {
+        let mut gpu = gpu();
+        run(&mut gpu, &[0x200000FF, position(-512, 0), position(512, 1), position(0, 8)]);
+        assert_eq!(drawn(&gpu), 0);
+
+        run(&mut gpu, &[0x200000FF, position(0, 0), position(8, 512), position(0, 8)]);
+        assert_eq!(drawn(&gpu), 0);
+
+        run(&mut gpu, &[0x200000FF, position(-511, 0), position(512, 1), position(0, 8)]);
+        assert_ne!(drawn(&gpu), 0);
+    }
+
+    #[test]
+    fn gouraud_interpolates_colors() {
+        let mut gpu = gpu();
+        run(&mut gpu, &[
+            0x38000000, position(0, 0),
+            0x000000F8, position(31, 0),
+            0x00F80000, position(0, 4),
+            0x00F800F8, position(31, 4)
+        ]);
+
+        for x in 0..31 {
+            assert_eq!(pixel(&gpu, x, 0), x as u16, "x = {}", x);
+        }
+        assert_eq!(pixel(&gpu, 0, 3), 23 << 10);
+        assert_eq!(pixel(&gpu, 31, 0), 0);
+    }
+
+    #[test]
+    fn dithers_shaded_polygons() {
+        let quad = |command: u32| [command | 0x808080, position(0, 0), 0x808080, position(4, 0), 0x808080, position(0, 4), 0x808080, position(4, 4)];
+
+        let mut gpu = gpu();
+        run(&mut gpu, &[0xE1000200]);
+        run(&mut gpu, &quad(0x38000000));
+        for (y, row) in DITHER.iter().enumerate() {
+            for (x, &offset) in row.iter().enumerate() {
+                let channel = if offset < 0 { 15 } else { 16 };
+                assert_eq!(pixel(&gpu, x, y), channel * 0x421, "({}, {})", x, y);
+            }
+        }
+
+        // Flat polygons and rectangles are left alone
+        let mut gpu = self::gpu();
+        run(&mut gpu, &[0xE1000200]);
+        run(&mut gpu, &quad(0x28000000)[..5]);
+        run(&mut gpu, &[0x68808080, position(8, 0)]);
+        assert!((0..4).all(|x| pixel(&gpu, x, 0) == 16 * 0x421));
+        assert_eq!(pixel(&gpu, 8, 0), 16 * 0x421);
+    }
+
+    struct TextureVector {
+        name: &'static str,
+        depth: u32,
+        texels: [u16; 2],
+        expected: [u16; 4]
+    }
+
+    const CLUT: [u16; 4] = [0x0000, 0x001F, 0x03E0, 0x7C00];
+    const TEXTURE_VECTORS: [TextureVector; 3] = [
+        // Texel 0 points at the transparent CLUT entry
+        TextureVector { name: "4-bit", depth: 0, texels: [0x0123, 0], expected: [0x7C00, 0x03E0, 0x001F, 0x1234] },
+        TextureVector { name: "8-bit", depth: 1, texels: [0x0302, 0x0001], expected: [0x03E0, 0x7C00, 0x001F, 0x1234] },
+        TextureVector { name: "15-bit", depth: 2, texels: [0x8001, 0x0000], expected: [0x8001, 0x1234, 0x1234, 0x1234] }
+    ];
+
+    #[test]
+    fn textured_rectangles() {
+        for vector in &TEXTURE_VECTORS {
+            let mut gpu = gpu();
+            run(&mut gpu, &[0x022088A0, position(96, 100), (1 << 16) | 16]);
+            assert_eq!(pixel(&gpu, 100, 100), 0x1234, "{}", vector.name);
+
+            // Page at (640, 256), CLUT at (32, 480)
+            upload(&mut gpu, (32, 480), 4, &CLUT);
+            upload(&mut gpu, (640, 256), 2, &vector.texels);
+            run(&mut gpu, &[0xE100001A | (vector.depth << 7)]);
+            run(&mut gpu, &[0x65000000, position(100, 100), (((480 << 6) | 2) << 16), (1 << 16) | 4]);
+
+            let pixels: Vec<u16> = (100..104).map(|x| pixel(&gpu, x, 100)).collect();
+            assert_eq!(pixels, vector.expected, "{}", vector.name);
+        }
+    }
+
+    #[test]
+    fn textured_polygons_modulate() {
+        let mut gpu = gpu();
+        let texels: Vec<u16> = (0..16).map(texel).collect();
+        upload(&mut gpu, (128, 0), 16, &[texels.clone(), texels].concat());
+
+        // Texture page comes from the second vertex, 15-bit at (128, 0)
+        let page = (2 << 7) | 2;
+        let polygon = |color: u32| [
+            0x2C000000 | color,
+            position(0, 0), 0,
+            position(16, 0), (page << 16) | 16,
+            position(0, 2), 2 << 8,
+            position(16, 2), (2 << 8) | 16
+        ];
+
+        run(&mut gpu, &polygon(0x808080));
+        assert_eq!(gpu.gpustat() & 0x1FF, page);
+        let row: Vec<u16> = (0..16).map(|x| pixel(&gpu, x, 0)).collect();
+        assert_eq!(row, (0..16).map(texel).collect::<Vec<_>>());
+
+        run(&mut gpu, &polygon(0x804040));
+        assert_eq!(pixel(&gpu, 8, 1), (4 << 5) | 0x08, "halved green and red");
+
+        // Raw textures ignore the colour, rectangles reuse the page set by the polygon
+        run(&mut gpu, &[0x7D000000, position(0, 4), 8]);
+        assert_eq!(pixel(&gpu, 0, 4), texel(8));
+    }
+
+    // Green follows U, red is constant
+    fn texel(u: u16) -> u16 {
+        (u << 5) | 0x10
+    }
+
+    #[test]
+    fn texture_window() {
+        let mut gpu = gpu();
+        let texels: Vec<u16> = (1..=32).collect();
+        upload(&mut gpu, (128, 0), 32, &texels);
+
+        // Mask the 16s bit of U and force it on
+        run(&mut gpu, &[0xE1000102, 0xE2000000 | (2 << 10) | 2]);
+        run(&mut gpu, &[0x7D000000, position(0, 0), 0]);
+        assert_eq!(pixel(&gpu, 0, 0), 17);
+        run(&mut gpu, &[0x7D000000, position(1, 0), 20]);
+        assert_eq!(pixel(&gpu, 1, 0), 21);
+    }
+
+    #[test]
+    fn semi_transparency_modes() {
+        // 16 in the background, 8 in the foreground
+        let expected = [12, 24, 8, 18];
+
+        for (mode, expected) in expected.into_iter().enumerate() {
+            let mut gpu = gpu();
+            run(&mut gpu, &[0x02808080, 0, (1 << 16) | 16]);
+            run(&mut gpu, &[0xE1000000 | ((mode as u32) << 5), 0x6A404040, position(0, 0)]);
+            assert_eq!(pixel(&gpu, 0, 0), expected * 0x421, "mode {}", mode);
+        }
+
+        // Textured pixels only blend when the texel has bit 15 set
+        let mut gpu = gpu();
+        run(&mut gpu, &[0x02808080, 0, (1 << 16) | 16]);
+        upload(&mut gpu, (128, 0), 2, &[0x2108, 0xA108]);
+        run(&mut gpu, &[0xE1000122, 0x6F000000, position(0, 0), 0]);
+        run(&mut gpu, &[0x6F000000, position(1, 0), 1]);
+        assert_eq!(pixel(&gpu, 0, 0), 0x2108);
+        assert_eq!(pixel(&gpu, 1, 0), 0x8000 | (24 * 0x421));
+    }
+
+    #[test]
+    fn lines() {
+        let mut gpu = gpu();
+        run(&mut gpu, &[0x400000FF, position(2, 0), position(6, 0)]);
+        run(&mut gpu, &[0x400000FF, position(0, 2), position(3, 5)]);
+
+        let row: Vec<u16> = (0..8).map(|x| pixel(&gpu, x, 0)).collect();
+        assert_eq!(row, [0, 0, RED, RED, RED, RED, RED, 0], "both ends are drawn");
+        assert!((0..4).all(|step| pixel(&gpu, step, 2 + step) == RED));
+        assert_eq!(drawn(&gpu), 9);
+
+        // Gouraud polyline, the terminator ends the command
+        let mut gpu = self::gpu();
+        run(&mut gpu, &[0x58000000, position(0, 0), 0x0000F8, position(31, 0), 0x00F800, position(31, 31), 0x55555555]);
+        assert_eq!(pixel(&gpu, 8, 0), 8);
+        assert_eq!(pixel(&gpu, 31, 0), 31);
+        assert_eq!(pixel(&gpu, 31, 31), 31 << 5);
+        assert_eq!(drawn(&gpu), 31 + 31, "the first pixel is black");
+    }
+
+    #[test]
+    fn clips_to_the_drawing_area() {
+        let mut gpu = gpu();
+        run(&mut gpu, &[0xE3000000 | (4 << 10) | 4, 0xE4000000 | (7 << 10) | 7]);
+        run(&mut gpu, &[0x680000FF | (2 << 27), position(0, 0)]);
+        run(&mut gpu, &[0x200000FF, position(0, 0), position(16, 0), position(0, 16)]);
+        run(&mut gpu, &[0x400000FF, position(0, 0), position(15, 15)]);
+
+        assert_eq!(drawn(&gpu), 16);
+        assert!((4..8).all(|y| (4..8).all(|x| pixel(&gpu, x, y) == RED)));
+    }
+
+    #[test]
+    fn drawing_offset() {
+        let mut gpu = gpu();
+        let offset = ((-2i32 as u32 & 0x7FF) << 11) | 100;
+        run(&mut gpu, &[0xE5000000 | offset, 0x680000FF, position(3, 5)]);
+
+        assert_eq!(pixel(&gpu, 103, 3), RED);
+        assert_eq!(drawn(&gpu), 1);
+    }
+
+    #[test]
+    fn mask_bits() {
+        let mut gpu = gpu();
+        run(&mut gpu, &[0xE6000001, 0x68FF0000, position(0, 0), 0x680000FF, position(1, 0)]);
+        assert_eq!(pixel(&gpu, 0, 0), 0xFC00);
+
+        // Pixels with bit 15 set are kept
+        run(&mut gpu, &[0xE6000002, 0x6800FF00 | (1 << 27), position(0, 0)]);
+        assert_eq!(pixel(&gpu, 0, 0), 0xFC00);
+        run(&mut gpu, &[0xE6000000, 0x6800FF00, position(0, 0)]);
+        assert_eq!(pixel(&gpu, 0, 0), 0x03E0);
+    }
+
+    fn shadow_pixel(gpu: &Gpu, x: usize, y: usize) -> u16 {
+        gpu.shadow[y * VRAM_WIDTH * gpu.scale + x]
+    }
+
+    fn precise(x: f32, y: f32, z: f32) -> Option<PreciseVertex> {
+        Some(PreciseVertex { x, y, z })
+    }
+
+    #[test]
+    fn precise_polygons() {
+        let words = [0x200000FF, position(0, 0), position(8, 0), position(0, 8)];
+        let vertices = [None, precise(0.9, 0.9, 0.0), precise(8.9, 0.9, 0.0), precise(0.9, 8.9, 0.0)];
+
+        for enabled in [false, true] {
+            let mut gpu = gpu();
+            gpu.set_precise_geometry(enabled);
+            for (&word, &vertex) in words.iter().zip(&vertices) {
+                gpu.gp0_precise(word, vertex);
+            }
+            assert_eq!(pixel(&gpu, 0, 0) == RED, !enabled, "enabled: {}", enabled);
+            assert_eq!(pixel(&gpu, 1, 1), RED);
+        }
+
+        // The right edge is four times further away, so U advances slower towards it
+        let mut gpu = gpu();
+        gpu.set_precise_geometry(true);
+        let texels: Vec<u16> = (0..64).map(texel).collect();
+        upload(&mut gpu, (128, 0), 64, &texels);
+
+        let page = (2 << 7) | 2;
+        let quad = |near: f32, far: f32| [
+            (0x2D000000, None),
+            (position(0, 0), precise(0.0, 0.0, near)), (0, None),
+            (position(64, 0), precise(64.0, 0.0, far)), ((page << 16) | 64, None),
+            (position(0, 4), precise(0.0, 4.0, near)), (0, None),
+            (position(64, 4), precise(64.0, 4.0, far)), (64, None)
+        ];
+
+        for (word, vertex) in quad(100.0, 400.0) {
+            gpu.gp0_precise(word, vertex);
+        }
+        assert_eq!(pixel(&gpu, 0, 1), texel(0));
+        assert_eq!(pixel(&gpu, 32, 1), texel(13), "0.5 * 64 / 400 / (0.25 / 100 + 0.5 / 400 + 0.25 / 100)");
+
+        // Without depth it stays affine
+        for (word, vertex) in quad(0.0, 0.0) {
+            gpu.gp0_precise(word, vertex);
+        }
+        assert_eq!(pixel(&gpu, 32, 1), texel(32));
+    }
+
+    #[test]
+    fn upscaled_draws() {
+        let triangle = [0x200000FF, position(0, 0), position(16, 0), position(0, 16)];
+        let mut native = gpu();
+        run(&mut native, &triangle);
+
+        let mut gpu = gpu();
+        gpu.set_resolution_scale(2);
+        run(&mut gpu, &triangle);
+        assert!(gpu.vram()[..] == native.vram()[..], "native VRAM is unaffected");
+
+        // The diagonal is stepped at twice the resolution
+        let drawn = gpu.shadow.iter().filter(|&&pixel| pixel != 0).count();
+        assert_eq!(drawn, 32 * 33 / 2);
+        assert_eq!((shadow_pixel(&gpu, 30, 1), shadow_pixel(&gpu, 31, 1)), (RED, 0));
+
+        let (width, height, pixels) = gpu.display_frame_scaled();
+        assert_eq!((width, height), (512, 480));
+        assert_eq!(pixels.chunks(3).filter(|pixel| pixel != &[0, 0, 0]).count(), drawn);
+    }
+
+    #[test]
+    fn upscaled_textures_are_magnified() {
+        let mut gpu = gpu();
+        gpu.set_resolution_scale(3);
+        upload(&mut gpu, (128, 0), 2, &[0x001F, 0x03E0, 0x7C00, 0x7FFF]);
+        run(&mut gpu, &[0xE1000102, 0x7D000000, position(0, 0), 0, 0x7D000000, position(1, 0), 1]);
+        run(&mut gpu, &[0x7D000000, position(0, 1), 1 << 8, 0x7D000000, position(1, 1), (1 << 8) | 1]);
+
+        for (y, row) in [[0x001F, 0x03E0], [0x7C00, 0x7FFF]].iter().enumerate() {
+            for (x, &texel) in row.iter().enumerate() {
+                assert!((0..9).all(|i| shadow_pixel(&gpu, x * 3 + i % 3, y * 3 + i / 3) == texel), "texel ({}, {})", x, y);
+            }
+        }
+
+        // Flipped rectangles walk whole texels backwards
+        run(&mut gpu, &[0xE1001102, 0x64000000 | 0x808080, position(4, 0), 1, (1 << 16) | 2]);
+        let row: Vec<u16> = (12..18).map(|x| shadow_pixel(&gpu, x, 0)).collect();
+        assert_eq!(row, [0x03E0, 0x03E0, 0x03E0, 0x001F, 0x001F, 0x001F]);
+    }
+
+    #[test]
+    fn shadow_follows_vram_writes() {
+        let mut gpu = gpu();
+        gpu.set_resolution_scale(2);
+
+        upload(&mut gpu, (10, 10), 1, &[0x1234]);
+        run(&mut gpu, &[0x80000000, (10 << 16) | 10, (20 << 16) | 20, (1 << 16) | 1]);
+        run(&mut gpu, &[0x02FFFFFF, (30 << 16) | 16, (1 << 16) | 1]);
+        for (x, y, value) in [(10, 10, 0x1234), (20, 20, 0x1234), (16, 30, 0x7FFF)] {
+            assert!((0..4).all(|i| shadow_pixel(&gpu, x * 2 + i % 2, y * 2 + i / 2) == value), "({}, {})", x, y);
+        }
+
+        // Direct VRAM writes are picked up before the next draw
+        gpu.vram_mut()[5] = 0x4321;
+        assert_eq!(gpu.display_frame_scaled().2[10 * 3..11 * 3], rgb555_to_rgb888(0x4321));
+        run(&mut gpu, &[0x680000FF, position(0, 0)]);
+        assert_eq!((shadow_pixel(&gpu, 11, 1), shadow_pixel(&gpu, 1, 1)), (0x4321, RED));
+
+        gpu.set_resolution_scale(1);
+        assert!(gpu.shadow.is_empty());
+        assert_eq!(gpu.display_frame_scaled().0, 256);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,223 @@
+use std::{
+    sync::{Arc, atomic::AtomicU16, mpsc::{self, Receiver, Sender}},
+    thread::{self, JoinHandle}
+};
+
+use super::rasterizer::{self, Band, Primitive, Target};
+
+/// Pixels lent to a render thread, valid until `RenderThreads::draw` returns
+struct SharedPixels {
+    pointer: *const AtomicU16,
+    len: usize
+}
+
+// SAFETY: atomics can be shared between threads, and `draw` outlives every job holding these
+unsafe impl Send for SharedPixels {}
+
+impl SharedPixels {
+    fn new(pixels: &[AtomicU16]) -> Self {
+        Self { pointer: pixels.as_ptr(), len: pixels.len() }
+    }
+
+    /// SAFETY: only while the job holding these is alive
+    unsafe fn get<'a>(&self) -> &'a [AtomicU16] {
+        unsafe { std::slice::from_raw_parts(self.pointer, self.len) }
+    }
+}
+
+struct Job {
+    primitives: Arc<[Primitive]>,
+    vram: SharedPixels,
+    shadow: Option<(SharedPixels, usize)>,
+    band: Band,
+    /// Dropped with the job, even when drawing panics
+    done: Sender<()>
+}
+
+/// Worker threads which draw queued primitives, each one into its own bands of rows
+pub(super) struct RenderThreads {
+    jobs: Vec<Sender<Job>>,
+    handles: Vec<JoinHandle<()>>
+}
+
+impl RenderThreads {
+    pub fn new(count: usize) -> Self {
+        let (jobs, handles) = (0..count)
+            .map(|index| {
+                let (sender, receiver) = mpsc::channel();
+                let handle = thread::Builder::new()
+                    .name(format!("gpu-render-{}", index))
+                    .spawn(move || work(receiver))
+                    .unwrap();
+                (sender, handle)
+            })
+            .unzip();
+
+        Self { jobs, handles }
+    }
+
+    pub fn count(&self) -> usize {
+        self.jobs.len()
+    }
+
+    /// Draws the primitives in order into VRAM and the shadow VRAM, if there is one
+    pub fn draw(&self, primitives: &[Primitive], vram: &mut [u16], shadow: Option<(&mut [u16], usize)>) {
+        let primitives: Arc<[Primitive]> = primitives.into();
+        let vram = rasterizer::atomic_pixels(vram);
+        let shadow = shadow.map(|(pixels, scale)| (rasterizer::atomic_pixels(pixels), scale));
+        let (done, finished) = mpsc::channel();
+
+        let mut sent = 0;
+        for (index, jobs) in self.jobs.iter().enumerate() {
+            let job = Job {
+                primitives: primitives.clone(),
+                vram: SharedPixels::new(vram),
+                shadow: shadow.as_ref().map(|(pixels, scale)| (SharedPixels::new(pixels), *scale)),
+                band: Band { index, count: self.count() },
+                done: done.clone()
+            };
+            sent += jobs.send(job).is_ok() as usize;
+        }
+        drop(done);
+
+        // Every job holds a sender, so this only ends once no thread can touch the pixels,
+        // don't bail out before that
+        let completed = finished.iter().count();
+        assert!(sent == self.count() && completed == sent, "GPU render thread died");
+    }
+}
+
+impl Drop for RenderThreads {
+    fn drop(&mut self) {
+        self.jobs.clear();
+        for handle in self.handles.drain(..) {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn work(jobs: Receiver<Job>) {
+    for job in jobs {
+        // SAFETY: `RenderThreads::draw` waits for the job to be dropped
+        let vram = unsafe { job.vram.get() };
+        let native = Target::native(vram).with_band(job.band);
+        let shadow = job.shadow.as_ref().map(|(pixels, scale)| {
+            Target::scaled(unsafe { pixels.get() }, vram, *scale).with_band(job.band)
+        });
+
+        for primitive in job.primitives.iter() {
+            if let Some(shadow) = &shadow {
+                rasterizer::draw(shadow, primitive);
+            }
+            rasterizer::draw(&native, primitive);
+        }
+
+        let _ = job.done.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{Gpu, VRAM_WIDTH};
+
+    struct Random(u32);
+
+    impl Random {
+        fn next(&mut self, range: u32) -> u32 {
+            self.0 = self.0.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (self.0 >> 8) % range
+        }
+
+        /// Somewhere within 32 pixels of `center`
+        fn near(&mut self, (x, y): (u32, u32)) -> u32 {
+            let y = y.wrapping_add(self.next(64)) & 0x7FF;
+            (y << 16) | (x.wrapping_add(self.next(64)) & 0x7FF)
+        }
+
+        fn color(&mut self) -> u32 {
+            self.next(0x1000000)
+        }
+    }
+
+    // Random draws over a 320x240 screen which also serves as the texture page,
+    // so textured draws keep sampling what earlier ones drew
+    fn draws(count: usize) -> Vec<u32> {
+        let mut random = Random(0x12345678);
+        let mut words = vec![0xE3000000, 0xE4000000 | (239 << 10) | 319, 0xE5000000];
+
+        for index in 0..count {
+            let r = &mut random;
+            let c = (r.next(320).wrapping_sub(32), r.next(240).wrapping_sub(32));
+            let draw = match index % 5 {
+                0 => vec![0x38000000 | r.color(), r.near(c), r.color(), r.near(c), r.color(), r.near(c), r.color(), r.near(c)],
+                1 => vec![
+                    0x2E808080,
+                    r.near(c), r.next(0x10000),
+                    r.near(c), (0x100 << 16) | r.next(0x10000),
+                    r.near(c), r.next(0x10000),
+                    r.near(c), r.next(0x10000)
+                ],
+                2 => vec![0xE1000200 | (r.next(4) << 5), 0x62000000 | r.color(), r.near(c), (r.next(64) << 16) | r.next(64)],
+                3 => vec![0x58000000 | r.color(), r.near(c), r.color(), r.near(c), r.color(), r.near(c), 0x55555555],
+                _ => vec![0x7C808080, r.near(c), r.next(0x10000)]
+            };
+            words.extend(draw);
+        }
+        words
+    }
+
+    fn render(threads: usize, words: &[u32]) -> Gpu {
+        let mut gpu = Gpu::new();
+        gpu.set_resolution_scale(2);
+        gpu.set_render_threads(threads);
+        for &word in words {
+            gpu.gp0(word);
+        }
+        gpu.sync();
+        gpu
+    }
+
+    #[test]
+    fn matches_the_emulation_thread() {
+        let words = draws(400);
+        let expected = render(1, &words);
+        assert!(expected.vram().iter().filter(|&&pixel| pixel != 0).count() > 320 * 240 / 2, "draws cover the screen");
+
+        for threads in [2, 3, 8] {
+            let gpu = render(threads, &words);
+            assert_eq!(gpu.render_threads(), threads);
+            assert!(gpu.vram()[..] == expected.vram()[..], "{} threads", threads);
+            assert!(gpu.shadow == expected.shadow, "{} threads, upscaled", threads);
+        }
+    }
+
+    #[test]
+    fn syncs_before_vram_is_read() {
+        let mut gpu = Gpu::new();
+        gpu.set_render_threads(4);
+        for word in [0xE3000000, 0xE4000000 | (511 << 10) | 1023, 0x680000FF, (20 << 16) | 10] {
+            gpu.gp0(word);
+        }
+        assert_eq!((gpu.queue.len(), gpu.vram()[20 * VRAM_WIDTH + 10]), (1, 0));
+
+        gpu.load32(4);
+        assert_eq!((gpu.queue.len(), gpu.vram()[20 * VRAM_WIDTH + 10]), (0, 0x001F));
+
+        // Reading back a rectangle sees the draw before it
+        for word in [0x68FF0000, (20 << 16) | 11, 0xC0000000, (20 << 16) | 10, (1 << 16) | 2] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.gpuread(), 0x7C00_001F);
+
+        // So do textures drawn by queued primitives, even in another band
+        for word in [0xE1000110, 0x680000FF, (300 << 16) | 12, 0x7D000000, 1 << 16, (44 << 8) | 12] {
+            gpu.gp0(word);
+        }
+        assert_eq!(gpu.queue.len(), 1);
+        gpu.sync();
+        assert_eq!(gpu.vram()[VRAM_WIDTH], 0x001F);
+
+        gpu.set_render_threads(1);
+        assert_eq!(gpu.render_threads(), 1);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,365 @@
+use std::{fs::File, io::{self, BufReader, BufWriter, Read, Write}, path::Path};
+
+use super::{Gpu, VRAM_HEIGHT, VRAM_WIDTH};
+use spdlog::prelude::*;
+
+// File layout:
+//   magic "SHGT", version (u8), start cycle (u64 LE)
+//   initial VRAM as run-length encoded pixels: (run length varint, pixel u16 LE) pairs
+//   events until EOF: port (u8), cycles since previous event (varint), value (u32 LE)
+const MAGIC: &[u8; 4] = b"SHGT";
+const VERSION: u8 = 1;
+/// Events between flushes, for games which never flip buffers
+const FLUSH_INTERVAL: u32 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Port {
+    Gp0 = 0,
+    Gp1 = 1
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEvent {
+    pub cycle: u64,
+    pub port: Port,
+    pub value: u32
+}
+
+pub struct TraceRecorder {
+    writer: BufWriter<File>,
+    last_cycle: u64,
+    unflushed: u32,
+    failed: bool
+}
+
+impl TraceRecorder {
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        let writer = BufWriter::new(File::create(path)?);
+        Ok(Self { writer, last_cycle: 0, unflushed: 0, failed: false })
+    }
+
+    /// Flushes everything recorded so far, call after `Gpu::stop_recording`
+    pub fn finish(mut self) -> Result<(), io::Error> {
+        self.writer.flush()
+    }
+
+    pub(super) fn record_initial_state(&mut self, cycle: u64, vram: &[u16], state: &[(Port, u32)]) {
+        self.last_cycle = cycle;
+
+        let result = self.write_header(cycle, vram).and_then(|_| self.writer.flush());
+        self.check(result);
+
+        for &(port, value) in state {
+            self.record(cycle, port, value);
+        }
+    }
+
+    pub(super) fn record(&mut self, cycle: u64, port: Port, value: u32) {
+        if self.failed {
+            return;
+        }
+
+        let delta = cycle.saturating_sub(self.last_cycle);
+        self.last_cycle = cycle;
+
+        let result = self.write_event(delta, port, value);
+        self.check(result);
+
+        // Recording usually ends with the process getting killed, flush on every buffer flip
+        // (a new display start) so at most a frame is lost
+        self.unflushed += 1;
+        let flip = port == Port::Gp1 && value >> 24 == 0x05;
+        if flip || self.unflushed >= FLUSH_INTERVAL {
+            self.unflushed = 0;
+            let result = self.writer.flush();
+            self.check(result);
+        }
+    }
+
+    fn write_header(&mut self, cycle: u64, vram: &[u16]) -> Result<(), io::Error> {
+        self.writer.write_all(MAGIC)?;
+        self.writer.write_all(&[VERSION])?;
+        self.writer.write_all(&cycle.to_le_bytes())?;
+
+        let mut pixels = vram.iter().peekable();
+        while let Some(&pixel) = pixels.next() {
+            let mut run = 1u64;
+            while pixels.next_if_eq(&&pixel).is_some() {
+                run += 1;
+            }
+
+            write_varint(&mut self.writer, run)?;
+            self.writer.write_all(&pixel.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    fn write_event(&mut self, delta: u64, port: Port, value: u32) -> Result<(), io::Error> {
+        self.writer.write_all(&[port as u8])?;
+        write_varint(&mut self.writer, delta)?;
+        self.writer.write_all(&value.to_le_bytes())
+    }
+
+    fn check(&mut self, result: Result<(), io::Error>) {
+        if let Err(error) = result {
+            error!("[GPU] Trace recording failed, further writes are dropped: {}", error);
+            self.failed = true;
+        }
+    }
+}
+
+pub struct Trace {
+    pub start_cycle: u64,
+    pub vram: Box<[u16; VRAM_WIDTH * VRAM_HEIGHT]>,
+    pub events: Vec<TraceEvent>
+}
+
+impl Trace {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, io::Error> {
+        Self::read(BufReader::new(File::open(path)?))
+    }
+
+    pub fn read<R: Read>(mut reader: R) -> Result<Self, io::Error> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid_data("not a GPU trace file"));
+        }
+
+        let version = read_u8(&mut reader)?;
+        if version != VERSION {
+            return Err(invalid_data("unsupported GPU trace version"));
+        }
+
+        let mut start_cycle = [0u8; 8];
+        reader.read_exact(&mut start_cycle)?;
+        let start_cycle = u64::from_le_bytes(start_cycle);
+
+        let mut vram = Vec::with_capacity(VRAM_WIDTH * VRAM_HEIGHT);
+        while vram.len() < VRAM_WIDTH * VRAM_HEIGHT {
+            let run = read_varint(&mut reader)? as usize;
+            let mut pixel = [0u8; 2];
+            reader.read_exact(&mut pixel)?;
+
+            if run == 0 || vram.len() + run > VRAM_WIDTH * VRAM_HEIGHT {
+                return Err(invalid_data("corrupted VRAM snapshot"));
+            }
+            vram.resize(vram.len() + run, u16::from_le_bytes(pixel));
+        }
+        let vram = vram.into_boxed_slice().try_into().unwrap();
+
+        let mut events = Vec::new();
+        let mut cycle = start_cycle;
+        loop {
+            let mut port = [0u8; 1];
+            if reader.read(&mut port)? == 0 {
+                break;
+            }
+
+            let port = match port[0] {
+                0 => Port::Gp0,
+                1 => Port::Gp1,
+                _ => return Err(invalid_data("unknown GPU port in trace event"))
+            };
+
+            // The emulator might get killed mid-write, keep everything before the torn event
+            let event = read_varint(&mut reader).and_then(|delta| {
+                let mut value = [0u8; 4];
+                reader.read_exact(&mut value)?;
+                Ok((delta, u32::from_le_bytes(value)))
+            });
+
+            match event {
+                Ok((delta, value)) => {
+                    cycle = cycle.checked_add(delta).ok_or_else(|| invalid_data("trace event cycle overflows"))?;
+                    events.push(TraceEvent { cycle, port, value });
+                }
+                Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+                    warn!("[GPU] Trace ends with a truncated event, ignoring it");
+                    break;
+                }
+                Err(error) => return Err(error)
+            }
+        }
+
+        Ok(Self { start_cycle, vram, events })
+    }
+}
+
+/// A displayed frame, upscaled when the replayed `Gpu` has a resolution scale
+pub struct Frame {
+    pub cycle: u64,
+    pub width: usize,
+    pub height: usize,
+    /// RGB888 pixels
+    pub pixels: Vec<u8>
+}
+
+/// Feeds a recorded trace into a standalone `Gpu`, no CPU or BIOS involved
+pub struct Replayer {
+    gpu: Gpu,
+    events: Vec<TraceEvent>,
+    position: usize,
+    last_cycle: u64,
+    dirty: bool
+}
+
+impl Replayer {
+    pub fn new(trace: Trace) -> Self {
+        let mut gpu = Gpu::new();
+        gpu.vram_mut().copy_from_slice(&trace.vram[..]);
+        gpu.tick(trace.start_cycle);
+
+        Self {
+            gpu,
+            events: trace.events,
+            position: 0,
+            last_cycle: trace.start_cycle,
+            dirty: true
+        }
+    }
+
+    pub fn gpu(&self) -> &Gpu {
+        &self.gpu
+    }
+
+    pub fn gpu_mut(&mut self) -> &mut Gpu {
+        &mut self.gpu
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position == self.events.len()
+    }
+
+    /// Applies the next recorded write, returns None once the trace is exhausted
+    pub fn step(&mut self) -> Option<TraceEvent> {
+        let event = *self.events.get(self.position)?;
+        self.position += 1;
+
+        self.gpu.tick(event.cycle - self.last_cycle);
+        self.last_cycle = event.cycle;
+
+        match event.port {
+            Port::Gp0 => self.gpu.gp0(event.value),
+            Port::Gp1 => self.gpu.gp1(event.value)
+        }
+        self.dirty = true;
+
+        Some(event)
+    }
+
+    /// Replays until the game flips the display buffer (GP1(05h)) and returns what's shown afterwards.
+    /// The end of the trace yields one last frame if anything was written since the previous one
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        while let Some(event) = self.step() {
+            if event.port == Port::Gp1 && event.value >> 24 == 0x05 {
+                return Some(self.frame());
+            }
+        }
+
+        if self.dirty {
+            return Some(self.frame());
+        }
+
+        None
+    }
+
+    fn frame(&mut self) -> Frame {
+        self.dirty = false;
+        self.gpu.sync();
+
+        let (width, height, pixels) = self.gpu.display_frame_scaled();
+        Frame { cycle: self.last_cycle, width, height, pixels }
+    }
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) -> Result<(), io::Error> {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            return writer.write_all(&[byte]);
+        }
+        writer.write_all(&[byte | 0x80])?;
+    }
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Result<u64, io::Error> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let byte = read_u8(reader)?;
+        value |= ((byte & 0x7F) as u64) << shift;
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(invalid_data("varint is too long"))
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Result<u8, io::Error> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Clears the screen, then draws a Gouraud triangle, a 4-bit textured sprite and a line
+    const DRAWS: [u32; 29] = [
+        0xE3000000, 0xE4000000 | (239 << 10) | 255, 0xE5000000,
+        0x02000000, 0x00000000, (240 << 16) | 256,
+        // CLUT at (0, 256) and a 4x1 texture at (64, 0)
+        0xA0000000, 256 << 16, (1 << 16) | 4, 0x001F0000, 0x7C0003E0,
+        0xA0000000, 64, (1 << 16) | 1, 0x00003210,
+        0x30FF0000, 0x00000000, 0x0000FF00, 0x00000010, 0x000000FF, 0x00100000,
+        0xE1000001, 0x65000000, (8 << 16) | 32, 256 << 22, (1 << 16) | 4,
+        0x4000FFFF, 0x00140000, 0x00140010
+    ];
+
+    #[test]
+    fn replays_draws() {
+        let path = std::env::temp_dir().join(format!("shiranuhi-trace-{}.bin", std::process::id()));
+
+        let mut gpu = Gpu::new();
+        gpu.start_recording(TraceRecorder::create(&path).unwrap());
+        for (cycle, &word) in DRAWS.iter().enumerate() {
+            gpu.tick(cycle as u64);
+            gpu.gp0(word);
+        }
+        gpu.gp1(0x05000000);
+        gpu.stop_recording().unwrap().finish().unwrap();
+
+        let trace = Trace::open(&path);
+        std::fs::remove_file(&path).unwrap();
+        let mut replayer = Replayer::new(trace.unwrap());
+
+        // The recorded initial state sets the display start too
+        let blank = replayer.next_frame().unwrap();
+        assert!(blank.pixels.iter().all(|&byte| byte == 0));
+        let frame = replayer.next_frame().unwrap();
+        assert!(replayer.next_frame().is_none());
+        assert!(replayer.gpu().vram()[..] == gpu.vram()[..], "replayed VRAM differs from the recording");
+
+        let vram = replayer.gpu().vram();
+        let pixel = |x: usize, y: usize| vram[y * VRAM_WIDTH + x];
+        assert_eq!([pixel(0, 0), pixel(15, 0), pixel(0, 15), pixel(16, 0)], [0x7C00, 0x0BA0, 0x081D, 0], "triangle");
+        assert_eq!([pixel(32, 8), pixel(33, 8), pixel(34, 8), pixel(35, 8)], [0, 0x001F, 0x03E0, 0x7C00], "sprite");
+        assert!((0..=16).all(|x| pixel(x, 20) == 0x03FF), "line");
+
+        // 136 for the triangle, 3 sprite, 17 line, 3 CLUT and 1 texture pixel
+        assert_eq!(vram.iter().filter(|&&pixel| pixel != 0).count(), 136 + 3 + 17 + 3 + 1);
+
+        assert_eq!((frame.width, frame.height), (256, 240));
+        assert_eq!(frame.pixels[..3], [0, 0, 0xFF]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,68 @@
+use std::{fs::File, io::{self, BufWriter}, path::Path};
+
+use super::{png, rgb555_to_rgb888, VRAM_HEIGHT, VRAM_WIDTH};
+
+/// How VRAM halfwords get turned into pixels when dumping the whole 1024x512 area.
+/// CLUT coordinates are in VRAM pixels, the palette is read from there on every line
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VramView {
+    /// One 15-bit color per halfword, 1024x512
+    Direct15,
+    /// Four 4-bit palette indices per halfword, 4096x512
+    Indexed4 { clut_x: usize, clut_y: usize },
+    /// Two 8-bit palette indices per halfword, 2048x512
+    Indexed8 { clut_x: usize, clut_y: usize }
+}
+
+impl VramView {
+    pub fn width(&self) -> usize {
+        match self {
+            VramView::Direct15 => VRAM_WIDTH,
+            VramView::Indexed4 { .. } => VRAM_WIDTH * 4,
+            VramView::Indexed8 { .. } => VRAM_WIDTH * 2
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        VRAM_HEIGHT
+    }
+}
+
+/// Converts the entire VRAM into RGB888 pixels according to `view`
+pub fn render(vram: &[u16], view: VramView) -> Vec<u8> {
+    assert_eq!(vram.len(), VRAM_WIDTH * VRAM_HEIGHT);
+
+    let clut = |clut_x: usize, clut_y: usize, index: usize| {
+        let x = (clut_x + index) % VRAM_WIDTH;
+        let y = clut_y % VRAM_HEIGHT;
+        vram[y * VRAM_WIDTH + x]
+    };
+
+    let mut pixels = Vec::with_capacity(view.width() * view.height() * 3);
+    for &halfword in vram {
+        match view {
+            VramView::Direct15 => pixels.extend_from_slice(&rgb555_to_rgb888(halfword)),
+            VramView::Indexed4 { clut_x, clut_y } => {
+                for nibble in 0..4 {
+                    let index = ((halfword >> (nibble * 4)) & 0xF) as usize;
+                    pixels.extend_from_slice(&rgb555_to_rgb888(clut(clut_x, clut_y, index)));
+                }
+            }
+            VramView::Indexed8 { clut_x, clut_y } => {
+                for byte in 0..2 {
+                    let index = ((halfword >> (byte * 8)) & 0xFF) as usize;
+                    pixels.extend_from_slice(&rgb555_to_rgb888(clut(clut_x, clut_y, index)));
+                }
+            }
+        }
+    }
+
+    pixels
+}
+
+pub fn export_png<P: AsRef<Path>>(vram: &[u16], view: VramView, path: P) -> Result<(), io::Error> {
+    let pixels = render(vram, view);
+    let writer = BufWriter::new(File::create(path)?);
+
+    png::encode_rgb(writer, view.width(), view.height(), &pixels)
+}
\ No newline at end of file
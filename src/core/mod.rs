@@ -1,4 +1,11 @@
 pub mod cpu;
 pub mod bus;
 pub mod bios;
+pub mod cdrom;
 pub mod devices;
+pub mod exe;
+pub mod gpu;
+pub mod precision;
+pub mod region;
+pub mod scheduler;
+pub mod system;
\ No newline at end of file
@@ -0,0 +1,51 @@
+use std::collections::HashMap;
+
+// Optional precision-preserving geometry. The GTE only hands out whole screen coordinates,
+// which makes PS1 geometry wobble as it moves. The sub-pixel positions it computed are kept
+// on the side and follow the integer words through registers, RAM and DMA to the GPU
+
+/// Screen position with its fraction, and the depth it was projected from
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreciseVertex {
+    pub x: f32,
+    pub y: f32,
+    pub z: f32
+}
+
+/// A precise vertex along with the packed SXY word it refines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PreciseWord {
+    pub word: u32,
+    pub vertex: PreciseVertex
+}
+
+impl PreciseWord {
+    /// Only trusted while the word it was made for is still the one being moved around
+    pub fn matching(self, word: u32) -> Option<PreciseWord> {
+        (self.word == word).then_some(self)
+    }
+}
+
+/// Precise vertices stored to RAM, keyed by word address. Entries are checked against the
+/// word in RAM when read, so plain stores over them don't need to clear anything
+#[derive(Default)]
+pub struct PrecisionMemory {
+    words: HashMap<u32, PreciseWord>
+}
+
+impl PrecisionMemory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn store(&mut self, address: u32, precise: Option<PreciseWord>) {
+        match precise {
+            Some(precise) => self.words.insert(address & !3, precise),
+            None => self.words.remove(&(address & !3))
+        };
+    }
+
+    pub fn load(&self, address: u32, word: u32) -> Option<PreciseWord> {
+        self.words.get(&(address & !3)).and_then(|precise| precise.matching(word))
+    }
+}
\ No newline at end of file
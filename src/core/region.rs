@@ -0,0 +1,62 @@
+use std::fmt;
+
+/// Which consoles a disc is licensed for, or which console a BIOS belongs to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// NTSC-J, also covers the rest of Asia
+    Japan,
+    /// NTSC-U
+    NorthAmerica,
+    /// PAL
+    Europe
+}
+
+// Serial prefixes and the region their discs were released in
+const SERIAL_PREFIXES: [(&str, Region); 15] = [
+    ("SCPS", Region::Japan),
+    ("SLPS", Region::Japan),
+    ("SLPM", Region::Japan),
+    ("SCPM", Region::Japan),
+    ("SIPS", Region::Japan),
+    ("PAPX", Region::Japan),
+    ("PCPX", Region::Japan),
+    ("SCAJ", Region::Japan),
+    ("SLKA", Region::Japan),
+    ("SCUS", Region::NorthAmerica),
+    ("SLUS", Region::NorthAmerica),
+    ("SCES", Region::Europe),
+    ("SLES", Region::Europe),
+    ("SCED", Region::Europe),
+    ("SLED", Region::Europe)
+];
+
+impl Region {
+    /// Looks up a product code such as SLUS_007.56 or SCES-00344
+    pub fn from_serial(serial: &str) -> Option<Self> {
+        let prefix = serial.get(..4)?;
+        SERIAL_PREFIXES
+            .iter()
+            .find(|(known, _)| known.eq_ignore_ascii_case(prefix))
+            .map(|&(_, region)| region)
+    }
+
+    /// Last letter of the SCEx string pressed into the disc's wobble
+    pub fn scex_letter(self) -> u8 {
+        match self {
+            Region::Japan => b'I',
+            Region::NorthAmerica => b'A',
+            Region::Europe => b'E'
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Region::Japan => "NTSC-J",
+            Region::NorthAmerica => "NTSC-U",
+            Region::Europe => "PAL"
+        };
+        f.write_str(name)
+    }
+}
\ No newline at end of file
@@ -0,0 +1,76 @@
+use super::{cdrom, devices::dma::Port};
+
+/// Things that happen a fixed number of cycles after something else
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    /// The CPU window of a chopped or sliced transfer is over, the next chunk goes
+    DmaChunk(Port),
+    /// The last chunk of a transfer went through, busy clears and the IRQ fires
+    DmaComplete(Port),
+    /// The beam reached the end of the display area on its line
+    HBlankStart,
+    /// The beam wrapped around to the next line
+    HBlankEnd,
+    /// A root counter is about to reach its target or overflow
+    Timers,
+    /// SIO0 finished shifting a byte out and in
+    SioTransfer,
+    /// The selected SIO0 device pulls /ACK low
+    SioAck,
+    /// ...and lets go of it again
+    SioAckEnd,
+    Cdrom(cdrom::Timer),
+    /// The audio output is due for its next 44.1 kHz sample
+    AudioSample
+}
+
+/// Keeps track of the system clock and the events waiting on it. There are only a handful
+/// pending at any time, so a plain list beats anything fancier
+pub struct Scheduler {
+    now: u64,
+    events: Vec<(u64, Event)>
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self { now: 0, events: Vec::new() }
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Queues `event` to happen `delay` cycles from now
+    pub fn schedule(&mut self, event: Event, delay: u64) {
+        self.events.push((self.now + delay, event));
+    }
+
+    /// Drops every pending occurrence of `event`
+    pub fn cancel(&mut self, event: Event) {
+        self.events.retain(|&(_, pending)| pending != event);
+    }
+
+    /// Takes the earliest event due no later than `time` and moves the clock up to it, events due
+    /// at the same time come out in the order they were scheduled
+    pub fn pop_until(&mut self, time: u64) -> Option<Event> {
+        let (index, &(due, _)) = self.events
+            .iter()
+            .enumerate()
+            .filter(|&(_, &(due, _))| due <= time)
+            .min_by_key(|&(index, &(due, _))| (due, index))?;
+
+        self.now = self.now.max(due);
+        Some(self.events.remove(index).1)
+    }
+
+    /// Moves the clock to `time` once every event up to it was handled
+    pub fn advance_to(&mut self, time: u64) {
+        self.now = self.now.max(time);
+    }
+}
\ No newline at end of file
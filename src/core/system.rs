@@ -0,0 +1,217 @@
+use std::{io, path::Path};
+
+use spdlog::prelude::*;
+
+use super::{
+    bios::Bios,
+    bus::Bus,
+    cdrom::{disc, iso9660::{Filesystem, SystemCnf}},
+    cpu::Cpu,
+    devices::sio::{
+        memory_card::MemoryCard,
+        pad::{Buttons, Pad, PadKind, Sticks}
+    },
+    exe::Executable
+};
+
+/// The BIOS copies the shell here and jumps to it once the kernel is set up
+const SHELL_ENTRY: u32 = 0x80030000;
+
+// Registers set up for the executable
+const GP: usize = 28;
+const SP: usize = 29;
+const FP: usize = 30;
+
+/// The whole console, this is what frontends and test scripts talk to
+pub struct System {
+    cpu: Cpu,
+    fast_boot: bool
+}
+
+impl System {
+    pub fn new(bios: Bios) -> Self {
+        Self { cpu: Cpu::new(Bus::new(bios)), fast_boot: false }
+    }
+
+    /// Skips the shell, and with it the logo, and boots the disc's executable as soon as the
+    /// BIOS is done setting up the kernel. Only has an effect before the shell starts
+    pub fn set_fast_boot(&mut self, enabled: bool) {
+        self.fast_boot = enabled;
+    }
+
+    /// Runs a single instruction, returns the cycles it took
+    pub fn step(&mut self) -> u64 {
+        let cycles = self.cpu.clock();
+        if self.fast_boot && self.cpu.program_counter() == SHELL_ENTRY {
+            self.fast_boot = false;
+            self.boot_disc();
+        }
+        cycles
+    }
+
+    /// Runs instructions until at least `cycles` went by
+    pub fn run_for(&mut self, cycles: u64) {
+        let target = self.cpu.cycles() + cycles;
+        while self.cpu.cycles() < target {
+            self.step();
+        }
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cpu.cycles()
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus {
+        self.cpu.bus()
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        self.cpu.bus_mut()
+    }
+
+    /// Plugs a fresh pad into port 0 or 1, or unplugs whatever is there with None
+    pub fn connect_pad(&mut self, port: usize, kind: Option<PadKind>) {
+        let pad = kind.map(|kind| Box::new(Pad::new(kind)) as _);
+        self.bus_mut().sio_mut().connect_controller(port, pad);
+    }
+
+    pub fn pad(&mut self, port: usize) -> Option<&mut Pad> {
+        self.bus_mut().sio_mut().controller_mut(port)
+    }
+
+    /// Sets which buttons are held on the pad in `port`, does nothing if there's no pad
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        if let Some(pad) = self.pad(port) {
+            pad.set_buttons(buttons);
+        }
+    }
+
+    /// Moves the sticks of the pad in `port`, only DualShocks in analog mode report them
+    pub fn set_sticks(&mut self, port: usize, sticks: Sticks) {
+        if let Some(pad) = self.pad(port) {
+            pad.set_sticks(sticks);
+        }
+    }
+
+    pub fn press_analog_button(&mut self, port: usize) {
+        if let Some(pad) = self.pad(port) {
+            pad.press_analog_button();
+        }
+    }
+
+    /// Small motor on/off and large motor speed of the pad in `port`
+    pub fn rumble(&mut self, port: usize) -> Option<(bool, u8)> {
+        self.pad(port).map(|pad| pad.motors())
+    }
+
+    /// Inserts a card into port 0 or 1, or pulls it out with None
+    pub fn connect_memory_card(&mut self, port: usize, card: Option<MemoryCard>) {
+        let card = card.map(|card| Box::new(card) as _);
+        self.bus_mut().sio_mut().connect_memory_card(port, card);
+    }
+
+    pub fn memory_card(&mut self, port: usize) -> Option<&mut MemoryCard> {
+        self.bus_mut().sio_mut().memory_card_mut(port)
+    }
+
+    /// Starts or stops keeping the 44.1 kHz stereo audio output around for `take_audio`. It has
+    /// to be taken regularly, while capture is on nothing is dropped
+    pub fn set_audio_capture(&mut self, enabled: bool) {
+        self.bus_mut().set_audio_capture(enabled);
+    }
+
+    /// Starts or stops tracking the sub-pixel vertex positions the GTE computes through to the
+    /// GPU, which draws polygons from them with perspective-correct texturing. Off by default
+    pub fn set_precise_geometry(&mut self, enabled: bool) {
+        self.bus_mut().set_precise_geometry(enabled);
+    }
+
+    /// Audio produced since the last call, one sample per 1/44100th of a second of emulated
+    /// time. For now that's the CD audio, CD-DA and XA-ADPCM after the volume matrix
+    pub fn take_audio(&mut self) -> Vec<(i16, i16)> {
+        self.bus_mut().take_audio()
+    }
+
+    /// Puts a disc image (.cue, .iso, .chd or .pbp) in the drive, replacing whatever was there.
+    /// To swap discs while a game runs, open the lid first
+    pub fn load_disc(&mut self, path: impl AsRef<Path>) -> Result<(), io::Error> {
+        let disc = disc::open(path)?;
+        self.bus_mut().set_disc(Some(disc));
+        Ok(())
+    }
+
+    pub fn remove_disc(&mut self) {
+        self.bus_mut().set_disc(None);
+    }
+
+    /// Opening the lid stops the drive and sets the shell open bit of its status, closing it
+    /// spins the disc in the drive back up
+    pub fn open_lid(&mut self) {
+        self.bus_mut().set_cdrom_lid(true);
+    }
+
+    pub fn close_lid(&mut self) {
+        self.bus_mut().set_cdrom_lid(false);
+    }
+
+    pub fn is_lid_open(&self) -> bool {
+        self.bus().cdrom().is_lid_open()
+    }
+
+    // Falls back to the shell if there's nothing to boot
+    fn boot_disc(&mut self) {
+        let (system_cnf, executable) = match self.read_boot_executable() {
+            Ok(boot) => boot,
+            Err(error) => {
+                warn!("[SYSTEM] Fast boot failed, starting the shell: {}", error);
+                return;
+            }
+        };
+        info!("[SYSTEM] Fast booting, {} bytes at 0x{:08X}, entry 0x{:08X}", executable.text.len(), executable.load_address, executable.pc);
+
+        let bus = self.cpu.bus_mut();
+        bus.write_ram(executable.load_address, &executable.text);
+        bus.write_ram(executable.bss_address, &vec![0; executable.bss_size as usize]);
+
+        // The shell would also have the kernel reallocate its tables
+        if system_cnf.tcb != SystemCnf::DEFAULT_TCB || system_cnf.event != SystemCnf::DEFAULT_EVENT {
+            warn!("[SYSTEM] Keeping the kernel's default TCB and EVENT counts instead of {} and {}", system_cnf.tcb, system_cnf.event);
+        }
+
+        // Like the BIOS, the stack from SYSTEM.CNF replaces the executable's
+        self.cpu.set_register(GP, executable.gp);
+        self.cpu.set_register(SP, system_cnf.stack);
+        self.cpu.set_register(FP, system_cnf.stack);
+        self.cpu.jump(executable.pc);
+    }
+
+    fn read_boot_executable(&mut self) -> Result<(SystemCnf, Executable), String> {
+        let cdrom = self.cpu.bus_mut().cdrom_mut();
+        if cdrom.is_lid_open() {
+            return Err("the lid is open".to_string());
+        }
+        let disc = cdrom.disc_mut().ok_or("no disc in the drive")?;
+        let mut filesystem = Filesystem::new(disc).map_err(|error| error.to_string())?;
+
+        let system_cnf = match filesystem.system_cnf().map_err(|error| error.to_string())? {
+            Some(system_cnf) => system_cnf,
+            None if filesystem.entry("SYSTEM.CNF").is_ok() => return Err("SYSTEM.CNF has no BOOT line".to_string()),
+            None => SystemCnf::default()
+        };
+
+        let data = filesystem.read_file(&system_cnf.boot).map_err(|error| error.to_string())?;
+        let executable = Executable::parse(&data).ok_or_else(|| format!("{} isn't a PS-X EXE", system_cnf.boot))?;
+        if !executable.fits_in_ram() {
+            return Err(format!("{} doesn't fit in RAM above the kernel", system_cnf.boot));
+        }
+        Ok((system_cnf, executable))
+    }
+}
\ No newline at end of file
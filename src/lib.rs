@@ -1 +1,2 @@
-pub mod core;
\ No newline at end of file
+pub mod core;
+pub mod memcard;
\ No newline at end of file
@@ -1,4 +1,9 @@
-use shiranuhi::core::{bios::Bios, bus::Bus, cpu::Cpu};
+use shiranuhi::core::{
+    bios::Bios,
+    devices::sio::{memory_card::MemoryCard, pad::PadKind},
+    gpu::trace::TraceRecorder,
+    system::System
+};
 use spdlog::prelude::*;
 
 fn main() {
@@ -11,11 +16,31 @@ fn main() {
     let bios = Bios::new("SCPH1001.BIN").unwrap();
     assert_eq!(bios.load32(0x00000000), first_lui_instruction);
 
-    let bus = Bus::new(bios);
-    assert_eq!(bus.load32(0xBFC00000), first_lui_instruction);
+    let mut system = System::new(bios);
+    assert_eq!(system.bus_mut().load32(0xBFC00000), first_lui_instruction);
+
+    system.connect_pad(0, Some(PadKind::Digital));
+
+    if let Some(path) = std::env::args().skip_while(|arg| arg != "--memory-card").nth(1) {
+        info!("Using memory card {}", path);
+        system.connect_memory_card(0, Some(MemoryCard::open(path).unwrap()));
+    }
+
+    if let Some(path) = std::env::args().skip_while(|arg| arg != "--disc").nth(1) {
+        info!("Using disc {}", path);
+        system.load_disc(path).unwrap();
+    }
+
+    if std::env::args().any(|arg| arg == "--fast-boot") {
+        system.set_fast_boot(true);
+    }
+
+    if let Some(path) = std::env::args().skip_while(|arg| arg != "--gpu-trace").nth(1) {
+        info!("Recording GPU trace to {}", path);
+        system.bus_mut().gpu_mut().start_recording(TraceRecorder::create(path).unwrap());
+    }
 
-    let mut cpu = Cpu::new(bus);
     loop {
-        cpu.clock();
+        system.step();
     }
-}
+}
\ No newline at end of file
@@ -0,0 +1,326 @@
+use std::{ops::Range, path::Path};
+
+use aes::{Aes128, cipher::{BlockDecrypt, BlockEncrypt, KeyInit}};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use crate::core::devices::sio::memory_card::CARD_SIZE;
+use super::{Card, Error, SaveFile, BLOCK_SIZE, FILENAME_LENGTH, FRAME_SIZE};
+
+// DexDrive header: magic, a few constant bytes, a copy of each directory entry's state and next
+// block, then a 256 byte comment per block
+const GME_MAGIC: &[u8] = b"123-456-STD";
+const GME_HEADER_SIZE: usize = 0xF40;
+
+// PS3 virtual memory cards. The header carries a salt seed and an HMAC-SHA1 over the whole file
+// which the PS3 checks, keyed by the seed run through AES with a key every PS3 shares
+const VMP_MAGIC: &[u8] = b"\0PMV";
+const VMP_HEADER_SIZE: usize = 0x80;
+const VMP_SEED: Range<usize> = 0x0C..0x20;
+const VMP_SIGNATURE: Range<usize> = 0x20..0x34;
+const VMP_KEY: [u8; 16] = [0xAB, 0x5A, 0xBC, 0x9F, 0xC1, 0xF4, 0x9D, 0xE6, 0xA0, 0x51, 0xDB, 0xAE, 0xFA, 0x51, 0x88, 0x59];
+const VMP_IV: [u8; 16] = [0xB3, 0x0F, 0xFE, 0xED, 0xB7, 0xDC, 0x5E, 0xB7, 0x13, 0x3D, 0xA6, 0x0D, 0x1B, 0x6B, 0x2C, 0xDC];
+
+// Action Replay/Caetla single saves, the filename padded to 21 bytes and the title to 33
+const PSX_HEADER_SIZE: usize = 54;
+
+/// Whole card image formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardFormat {
+    /// .mcr, .mcd, .mc and friends, nothing but the 128KB of card contents
+    Raw,
+    /// .gme, as written by DexDrive software
+    Gme,
+    /// .vmp, the PS3's memory card images
+    Vmp
+}
+
+impl CardFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "mcr" | "mcd" | "mc" | "mem" | "ddf" | "srm" | "bin" => Some(CardFormat::Raw),
+            "gme" => Some(CardFormat::Gme),
+            "vmp" => Some(CardFormat::Vmp),
+            _ => None
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<Card, Error> {
+        let (magic, header_size): (&[u8], usize) = match self {
+            CardFormat::Raw => (&[], 0),
+            CardFormat::Gme => (GME_MAGIC, GME_HEADER_SIZE),
+            CardFormat::Vmp => (VMP_MAGIC, VMP_HEADER_SIZE)
+        };
+
+        if !bytes.starts_with(magic) {
+            return Err(Error::InvalidImage("wrong magic for the card format"));
+        }
+        let data = bytes
+            .get(header_size..header_size + CARD_SIZE)
+            .ok_or(Error::InvalidImage("card image is truncated"))?;
+
+        Card::from_raw(data)
+    }
+
+    pub fn encode(self, card: &Card) -> Vec<u8> {
+        let mut output = match self {
+            CardFormat::Raw => Vec::with_capacity(CARD_SIZE),
+            CardFormat::Gme => gme_header(card),
+            CardFormat::Vmp => {
+                let mut header = vec![0; VMP_HEADER_SIZE];
+                header[..4].copy_from_slice(VMP_MAGIC);
+                header[4..8].copy_from_slice(&(VMP_HEADER_SIZE as u32).to_le_bytes());
+                header
+            }
+        };
+
+        output.extend_from_slice(&card.data()[..]);
+        // The seed stays zero, the PS3 derives the key from whatever seed is stored
+        if self == CardFormat::Vmp {
+            let signature = vmp_signature(&output);
+            output[VMP_SIGNATURE].copy_from_slice(&signature);
+        }
+        output
+    }
+}
+
+// The signature a .vmp file should carry, computed with its signature field taken as zero
+fn vmp_signature(image: &[u8]) -> [u8; 20] {
+    let seed = &image[VMP_SEED];
+
+    // The first 16 bytes of the key are the seed decrypted in CBC mode, the last 4 come from the
+    // seed encrypted and XORed with its own tail
+    let cipher = Aes128::new(&VMP_KEY.into());
+    let mut decrypted = <[u8; 16]>::try_from(&seed[..16]).unwrap().into();
+    let mut encrypted = decrypted;
+    cipher.decrypt_block(&mut decrypted);
+    cipher.encrypt_block(&mut encrypted);
+
+    let mut key = [0; 20];
+    for (index, byte) in key.iter_mut().enumerate() {
+        *byte = match index {
+            0..16 => decrypted[index] ^ VMP_IV[index],
+            _ => encrypted[index - 16] ^ seed[index]
+        };
+    }
+
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(&key).unwrap();
+    mac.update(&image[..VMP_SIGNATURE.start]);
+    mac.update(&[0; VMP_SIGNATURE.end - VMP_SIGNATURE.start]);
+    mac.update(&image[VMP_SIGNATURE.end..]);
+    mac.finalize().into_bytes().into()
+}
+
+fn gme_header(card: &Card) -> Vec<u8> {
+    let mut header = vec![0; GME_HEADER_SIZE];
+    header[..GME_MAGIC.len()].copy_from_slice(GME_MAGIC);
+    header[18] = 0x01;
+    header[20] = 0x01;
+    header[21] = b'M';
+
+    for block in 0..15 {
+        let entry = card.entry(block);
+        header[22 + block] = entry[0];
+        header[38 + block] = entry[8];
+    }
+
+    header
+}
+
+/// Single save formats
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SaveFormat {
+    /// .mcs, the directory entry followed by the blocks
+    Mcs,
+    /// .psx, a 54 byte header with the filename followed by the blocks
+    Psx
+}
+
+impl SaveFormat {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let extension = path.as_ref().extension()?.to_str()?.to_ascii_lowercase();
+
+        match extension.as_str() {
+            "mcs" => Some(SaveFormat::Mcs),
+            "psx" | "mcb" | "mcx" | "pda" => Some(SaveFormat::Psx),
+            _ => None
+        }
+    }
+
+    pub fn decode(self, bytes: &[u8]) -> Result<SaveFile, Error> {
+        let (filename, data) = match self {
+            SaveFormat::Mcs => {
+                if bytes.len() < FRAME_SIZE || bytes[0] != super::STATE_FIRST {
+                    return Err(Error::InvalidImage("missing directory entry"));
+                }
+                (&bytes[10..10 + FILENAME_LENGTH], &bytes[FRAME_SIZE..])
+            }
+            SaveFormat::Psx => {
+                if bytes.len() < PSX_HEADER_SIZE {
+                    return Err(Error::InvalidImage("header is truncated"));
+                }
+                (&bytes[..FILENAME_LENGTH], &bytes[PSX_HEADER_SIZE..])
+            }
+        };
+
+        if data.is_empty() || !data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(Error::InvalidImage("save data isn't made of whole blocks"));
+        }
+
+        let length = filename.iter().position(|&byte| byte == 0).unwrap_or(FILENAME_LENGTH);
+        Ok(SaveFile {
+            filename: String::from_utf8_lossy(&filename[..length]).into_owned(),
+            data: data.to_vec()
+        })
+    }
+
+    /// `entry` is the save's first directory entry on the card it came from
+    pub fn encode(self, save: &SaveFile, entry: &[u8; FRAME_SIZE]) -> Vec<u8> {
+        let mut output = match self {
+            SaveFormat::Mcs => {
+                // The chain only meant something on the old card
+                let mut entry = *entry;
+                entry[8..10].copy_from_slice(&super::NO_NEXT_BLOCK.to_le_bytes());
+                entry[FRAME_SIZE - 1] = entry[..FRAME_SIZE - 1].iter().fold(0, |checksum, byte| checksum ^ byte);
+                entry.to_vec()
+            }
+            SaveFormat::Psx => {
+                let mut header = vec![0; PSX_HEADER_SIZE];
+                let filename = save.filename.as_bytes();
+                let length = filename.len().min(FILENAME_LENGTH);
+                header[..length].copy_from_slice(&filename[..length]);
+                header
+            }
+        };
+
+        output.extend_from_slice(&save.data);
+        output
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use sha1::Digest;
+
+    use super::*;
+    use super::super::tests::{card_with_saves, save_file};
+
+    #[test]
+    fn cards_round_trip() {
+        let card = card_with_saves();
+
+        for format in [CardFormat::Raw, CardFormat::Gme, CardFormat::Vmp] {
+            let bytes = format.encode(&card);
+            let decoded = format.decode(&bytes).unwrap();
+            assert!(decoded.data() == card.data(), "{:?}", format);
+            assert_eq!(decoded.saves(), card.saves(), "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn gme_header_mirrors_the_directory() {
+        let card = card_with_saves();
+        let bytes = CardFormat::Gme.encode(&card);
+
+        assert_eq!(bytes.len(), GME_HEADER_SIZE + CARD_SIZE);
+        assert!(bytes.starts_with(GME_MAGIC));
+        for block in 0..15 {
+            assert_eq!(bytes[22 + block], card.entry(block)[0], "block {}", block);
+            assert_eq!(bytes[38 + block], card.entry(block)[8], "block {}", block);
+        }
+    }
+
+    #[test]
+    fn vmp_is_signed() {
+        let bytes = CardFormat::Vmp.encode(&card_with_saves());
+        assert_eq!(bytes.len(), VMP_HEADER_SIZE + CARD_SIZE);
+        assert_eq!(&bytes[..8], b"\0PMV\x80\0\0\0");
+        assert_eq!(bytes[VMP_SIGNATURE], vmp_signature(&bytes));
+
+        // The way PS3 tools spell it out: the key is the seed decrypted with AES-CBC, then four
+        // bytes of it encrypted and XORed with the rest of the seed, all padded into an HMAC
+        let cipher = Aes128::new(&VMP_KEY.into());
+        let mut salt = [0u8; 0x40];
+        let mut block = <[u8; 16]>::try_from(&bytes[0x0C..0x1C]).unwrap().into();
+        cipher.decrypt_block(&mut block);
+        salt[..16].copy_from_slice(&block);
+        let mut block = <[u8; 16]>::try_from(&bytes[0x0C..0x1C]).unwrap().into();
+        cipher.encrypt_block(&mut block);
+        salt[16..20].copy_from_slice(&block[..4]);
+        for index in 0..16 {
+            salt[index] ^= VMP_IV[index];
+        }
+        for index in 0..4 {
+            salt[16 + index] ^= bytes[0x1C + index];
+        }
+
+        let mut unsigned = bytes.clone();
+        unsigned[0x20..0x34].fill(0);
+        let inner = Sha1::new().chain_update(salt.map(|byte| byte ^ 0x36)).chain_update(&unsigned).finalize();
+        let outer = Sha1::new().chain_update(salt.map(|byte| byte ^ 0x5C)).chain_update(inner).finalize();
+        assert_eq!(bytes[0x20..0x34], outer[..]);
+
+        // A different seed or card gives a different signature
+        let mut reseeded = bytes.clone();
+        reseeded[0x0C] = 1;
+        assert_ne!(vmp_signature(&reseeded), vmp_signature(&bytes));
+        let mut changed = bytes.clone();
+        changed[VMP_HEADER_SIZE + 0x2000] ^= 1;
+        assert_ne!(vmp_signature(&changed), vmp_signature(&bytes));
+    }
+
+    #[test]
+    fn rejects_invalid_cards() {
+        let raw = CardFormat::Raw.encode(&Card::new());
+        assert!(matches!(CardFormat::Gme.decode(&raw), Err(Error::InvalidImage(_))));
+        assert!(matches!(CardFormat::Vmp.decode(&raw), Err(Error::InvalidImage(_))));
+        assert!(matches!(CardFormat::Raw.decode(&raw[..CARD_SIZE - 1]), Err(Error::InvalidImage(_))));
+        assert!(matches!(CardFormat::Raw.decode(&vec![0; CARD_SIZE]), Err(Error::InvalidImage(_))));
+
+        let gme = CardFormat::Gme.encode(&Card::new());
+        assert!(matches!(CardFormat::Gme.decode(&gme[..gme.len() - 1]), Err(Error::InvalidImage(_))));
+    }
+
+    #[test]
+    fn saves_round_trip() {
+        let card = card_with_saves();
+        let first_block = card.saves()[1].first_block;
+        let save = card.export(first_block).unwrap();
+
+        for format in [SaveFormat::Mcs, SaveFormat::Psx] {
+            let bytes = card.export_as(first_block, format).unwrap();
+            assert_eq!(format.decode(&bytes).unwrap(), save, "{:?}", format);
+
+            let mut other = Card::new();
+            let block = other.import_from(&bytes, format).unwrap();
+            assert_eq!(other.export(block).unwrap(), save, "{:?}", format);
+        }
+    }
+
+    #[test]
+    fn mcs_entry_drops_the_chain() {
+        let card = card_with_saves();
+        let first_block = card.saves()[1].first_block;
+        let bytes = card.export_as(first_block, SaveFormat::Mcs).unwrap();
+
+        assert_eq!(bytes.len(), FRAME_SIZE + 3 * BLOCK_SIZE);
+        assert_eq!(bytes[..8], card.entry(first_block)[..8]);
+        assert_eq!(bytes[8..10], [0xFF, 0xFF]);
+        assert_eq!(bytes[..FRAME_SIZE].iter().fold(0, |checksum, byte| checksum ^ byte), 0);
+    }
+
+    #[test]
+    fn rejects_invalid_saves() {
+        let save = save_file("BASLUS-00001GAME", 1, b"x");
+        let mcs = SaveFormat::Mcs.encode(&save, &[0; FRAME_SIZE]);
+        assert!(matches!(SaveFormat::Mcs.decode(&mcs), Err(Error::InvalidImage(_))));
+
+        let psx = SaveFormat::Psx.encode(&save, &[0; FRAME_SIZE]);
+        assert!(matches!(SaveFormat::Psx.decode(&psx[..PSX_HEADER_SIZE]), Err(Error::InvalidImage(_))));
+        assert!(matches!(SaveFormat::Psx.decode(&psx[..psx.len() - 1]), Err(Error::InvalidImage(_))));
+        assert!(matches!(SaveFormat::Psx.decode(&psx[..10]), Err(Error::InvalidImage(_))));
+    }
+}
\ No newline at end of file
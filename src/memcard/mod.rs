@@ -0,0 +1,508 @@
+pub mod format;
+pub mod shift_jis;
+
+use std::{fmt, fs, io, path::Path};
+
+use crate::core::{
+    devices::sio::memory_card::{self, CARD_SIZE},
+    gpu::rgb555_to_rgb888
+};
+use format::{CardFormat, SaveFormat};
+
+pub const FRAME_SIZE: usize = 128;
+pub const BLOCK_SIZE: usize = 8 * 1024;
+/// Block 0 holds the directory, the other 15 hold saves
+pub const BLOCK_COUNT: usize = 15;
+
+const FILENAME_LENGTH: usize = 20;
+
+// Directory entry states
+const STATE_FIRST: u8 = 0x51;
+const STATE_MIDDLE: u8 = 0x52;
+const STATE_LAST: u8 = 0x53;
+const STATE_FREE: u8 = 0xA0;
+const STATE_DELETED_FIRST: u8 = 0xA1;
+const STATE_DELETED_MIDDLE: u8 = 0xA2;
+const STATE_DELETED_LAST: u8 = 0xA3;
+const NO_NEXT_BLOCK: u16 = 0xFFFF;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file isn't a valid image in the format it claims to be
+    InvalidImage(&'static str),
+    /// No save starts at the given block
+    NoSuchSave(usize),
+    /// The save needs more free blocks than the card has
+    NotEnoughSpace { needed: usize, free: usize },
+    /// A save with the same filename is already on the card
+    AlreadyExists(String)
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(error) => write!(f, "{}", error),
+            Error::InvalidImage(reason) => write!(f, "invalid image: {}", reason),
+            Error::NoSuchSave(block) => write!(f, "no save starts at block {}", block),
+            Error::NotEnoughSpace { needed, free } => write!(f, "save needs {} blocks, only {} free", needed, free),
+            Error::AlreadyExists(filename) => write!(f, "{} is already on the card", filename)
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(error: io::Error) -> Self {
+        Error::Io(error)
+    }
+}
+
+/// The icon shown in the BIOS browser, up to three animation frames of 16x16 4-bit pixels
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Icon {
+    pub palette: [u16; 16],
+    pub frames: Vec<[u8; FRAME_SIZE]>
+}
+
+impl Icon {
+    pub const WIDTH: usize = 16;
+    pub const HEIGHT: usize = 16;
+
+    /// One frame as RGB888, transparent pixels come out black
+    pub fn frame_rgb(&self, frame: usize) -> Vec<u8> {
+        let mut pixels = Vec::with_capacity(Self::WIDTH * Self::HEIGHT * 3);
+
+        for &byte in &self.frames[frame] {
+            for index in [byte & 0xF, byte >> 4] {
+                pixels.extend_from_slice(&rgb555_to_rgb888(self.palette[index as usize]));
+            }
+        }
+
+        pixels
+    }
+}
+
+/// A save as listed in the directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Save {
+    /// Block the save starts at, 0-14, it's what identifies the save for export and delete
+    pub first_block: usize,
+    /// Every block of the chain in order, starting with `first_block`
+    pub blocks: Vec<usize>,
+    /// Size from the directory, in bytes
+    pub size: usize,
+    /// The whole filename, e.g. "BASLUS-00594FF7S01"
+    pub filename: String,
+    /// "BI" (Japan), "BA" (America) or "BE" (Europe)
+    pub region: String,
+    /// e.g. "SLUS-00594", or whatever the game put in place of one
+    pub product_code: String,
+    /// The game's own part of the filename
+    pub identifier: String,
+    /// Title from the save header, decoded from Shift-JIS
+    pub title: String,
+    pub icon: Icon
+}
+
+/// A save taken off a card, everything needed to put it back onto another
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SaveFile {
+    pub filename: String,
+    /// The save's blocks back to back, always a multiple of the block size
+    pub data: Vec<u8>
+}
+
+impl SaveFile {
+    pub fn blocks(&self) -> usize {
+        self.data.len() / BLOCK_SIZE
+    }
+}
+
+/// A memory card image
+#[derive(Clone)]
+pub struct Card {
+    data: Box<[u8; CARD_SIZE]>
+}
+
+impl Default for Card {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Card {
+    /// A freshly formatted card
+    pub fn new() -> Self {
+        Self { data: memory_card::formatted() }
+    }
+
+    /// Takes raw card contents, the way they're stored in .mcr files
+    pub fn from_raw(data: &[u8]) -> Result<Self, Error> {
+        let data: Box<[u8; CARD_SIZE]> = data
+            .to_vec()
+            .into_boxed_slice()
+            .try_into()
+            .map_err(|_| Error::InvalidImage("card image isn't 128KB"))?;
+
+        if &data[..2] != b"MC" {
+            return Err(Error::InvalidImage("missing MC header frame"));
+        }
+
+        Ok(Self { data })
+    }
+
+    /// Opens a card image, the format is picked from the file extension
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let format = CardFormat::from_path(path).unwrap_or(CardFormat::Raw);
+
+        format.decode(&fs::read(path)?)
+    }
+
+    /// Saves the card, the format is picked from the file extension
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = path.as_ref();
+        let format = CardFormat::from_path(path).unwrap_or(CardFormat::Raw);
+
+        fs::write(path, format.encode(self))?;
+        Ok(())
+    }
+
+    pub fn data(&self) -> &[u8; CARD_SIZE] {
+        &self.data
+    }
+
+    pub fn saves(&self) -> Vec<Save> {
+        (0..BLOCK_COUNT)
+            .filter(|&block| self.entry(block)[0] == STATE_FIRST)
+            .map(|block| self.describe(block))
+            .collect()
+    }
+
+    /// Blocks which are free or only hold deleted saves
+    pub fn free_blocks(&self) -> usize {
+        (0..BLOCK_COUNT).filter(|&block| self.is_free(block)).count()
+    }
+
+    pub fn export(&self, first_block: usize) -> Result<SaveFile, Error> {
+        let save = self.saves()
+            .into_iter()
+            .find(|save| save.first_block == first_block)
+            .ok_or(Error::NoSuchSave(first_block))?;
+
+        let data = save.blocks
+            .iter()
+            .flat_map(|&block| self.block(block).iter().copied())
+            .collect();
+
+        Ok(SaveFile { filename: save.filename, data })
+    }
+
+    /// Exports a save straight into one of the single save formats
+    pub fn export_as(&self, first_block: usize, format: SaveFormat) -> Result<Vec<u8>, Error> {
+        let save = self.export(first_block)?;
+        Ok(format.encode(&save, &self.entry(first_block)))
+    }
+
+    /// Writes the save into free blocks, returns the block it starts at
+    pub fn import(&mut self, save: &SaveFile) -> Result<usize, Error> {
+        if save.data.is_empty() || !save.data.len().is_multiple_of(BLOCK_SIZE) {
+            return Err(Error::InvalidImage("save data isn't made of whole blocks"));
+        }
+        if self.saves().iter().any(|existing| existing.filename == save.filename) {
+            return Err(Error::AlreadyExists(save.filename.clone()));
+        }
+
+        let free: Vec<usize> = (0..BLOCK_COUNT).filter(|&block| self.is_free(block)).collect();
+        if free.len() < save.blocks() {
+            return Err(Error::NotEnoughSpace { needed: save.blocks(), free: free.len() });
+        }
+
+        let blocks = &free[..save.blocks()];
+        for (index, (&block, data)) in blocks.iter().zip(save.data.chunks_exact(BLOCK_SIZE)).enumerate() {
+            let last = index + 1 == blocks.len();
+
+            let state = match index {
+                0 => STATE_FIRST,
+                _ if last => STATE_LAST,
+                _ => STATE_MIDDLE
+            };
+            let size = if index == 0 { save.data.len() as u32 } else { 0 };
+            let next = if last { NO_NEXT_BLOCK } else { blocks[index + 1] as u16 };
+
+            let mut entry = [0u8; FRAME_SIZE];
+            entry[0] = state;
+            entry[4..8].copy_from_slice(&size.to_le_bytes());
+            entry[8..10].copy_from_slice(&next.to_le_bytes());
+            if index == 0 {
+                let filename = save.filename.as_bytes();
+                let length = filename.len().min(FILENAME_LENGTH);
+                entry[10..10 + length].copy_from_slice(&filename[..length]);
+            }
+            self.set_entry(block, entry);
+
+            self.block_mut(block).copy_from_slice(data);
+        }
+
+        Ok(blocks[0])
+    }
+
+    /// Imports a save from one of the single save formats
+    pub fn import_from(&mut self, data: &[u8], format: SaveFormat) -> Result<usize, Error> {
+        self.import(&format.decode(data)?)
+    }
+
+    /// Marks the save's blocks as deleted the way the BIOS does, the data stays until overwritten
+    pub fn delete(&mut self, first_block: usize) -> Result<(), Error> {
+        let save = self.saves()
+            .into_iter()
+            .find(|save| save.first_block == first_block)
+            .ok_or(Error::NoSuchSave(first_block))?;
+
+        for &block in &save.blocks {
+            let mut entry = self.entry(block);
+            entry[0] = match entry[0] {
+                STATE_FIRST => STATE_DELETED_FIRST,
+                STATE_MIDDLE => STATE_DELETED_MIDDLE,
+                STATE_LAST => STATE_DELETED_LAST,
+                state => state
+            };
+            self.set_entry(block, entry);
+        }
+
+        Ok(())
+    }
+
+    fn describe(&self, first_block: usize) -> Save {
+        let entry = self.entry(first_block);
+        let filename_bytes = &entry[10..10 + FILENAME_LENGTH];
+        let filename_length = filename_bytes.iter().position(|&byte| byte == 0).unwrap_or(FILENAME_LENGTH);
+        let filename = String::from_utf8_lossy(&filename_bytes[..filename_length]).into_owned();
+
+        let part = |range: std::ops::Range<usize>| filename.get(range).unwrap_or("").to_string();
+        let header = &self.block(first_block)[..FRAME_SIZE];
+
+        // 11h, 12h and 13h for one, two and three animation frames
+        let frame_count = match header[2] {
+            flag @ 0x11..=0x13 => (flag - 0x10) as usize,
+            _ => 1
+        };
+        let mut palette = [0u16; 16];
+        for (index, color) in palette.iter_mut().enumerate() {
+            *color = u16::from_le_bytes([header[0x60 + index * 2], header[0x61 + index * 2]]);
+        }
+        let frames = (1..=frame_count)
+            .map(|frame| self.block(first_block)[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE].try_into().unwrap())
+            .collect();
+
+        Save {
+            first_block,
+            blocks: self.chain(first_block),
+            size: u32::from_le_bytes(entry[4..8].try_into().unwrap()) as usize,
+            region: part(0..2),
+            product_code: part(2..12),
+            identifier: filename.get(12..).unwrap_or("").to_string(),
+            filename,
+            title: shift_jis::decode(&header[4..0x44]).trim_end_matches(['\u{3000}', ' ']).to_string(),
+            icon: Icon { palette, frames }
+        }
+    }
+
+    fn chain(&self, first_block: usize) -> Vec<usize> {
+        let mut blocks = vec![first_block];
+
+        // A broken chain can't have more links than there are blocks
+        let mut block = first_block;
+        while blocks.len() < BLOCK_COUNT {
+            let entry = self.entry(block);
+            let next = u16::from_le_bytes([entry[8], entry[9]]) as usize;
+            if next >= BLOCK_COUNT || blocks.contains(&next) {
+                break;
+            }
+
+            blocks.push(next);
+            block = next;
+        }
+
+        blocks
+    }
+
+    fn is_free(&self, block: usize) -> bool {
+        matches!(self.entry(block)[0], STATE_FREE | STATE_DELETED_FIRST | STATE_DELETED_MIDDLE | STATE_DELETED_LAST)
+    }
+
+    // Directory frames 1-15 describe blocks 1-15, which is block 0-14 here
+    fn entry(&self, block: usize) -> [u8; FRAME_SIZE] {
+        let start = (block + 1) * FRAME_SIZE;
+        self.data[start..start + FRAME_SIZE].try_into().unwrap()
+    }
+
+    fn set_entry(&mut self, block: usize, mut entry: [u8; FRAME_SIZE]) {
+        entry[FRAME_SIZE - 1] = entry[..FRAME_SIZE - 1].iter().fold(0, |checksum, byte| checksum ^ byte);
+
+        let start = (block + 1) * FRAME_SIZE;
+        self.data[start..start + FRAME_SIZE].copy_from_slice(&entry);
+    }
+
+    fn block(&self, block: usize) -> &[u8] {
+        let start = (block + 1) * BLOCK_SIZE;
+        &self.data[start..start + BLOCK_SIZE]
+    }
+
+    fn block_mut(&mut self, block: usize) -> &mut [u8] {
+        let start = (block + 1) * BLOCK_SIZE;
+        &mut self.data[start..start + BLOCK_SIZE]
+    }
+}
+
+
+#[cfg(test)]
+pub(super) mod tests {
+    use super::*;
+
+    /// A save whose header has `title` and a single icon frame, every block filled with its index
+    pub(super) fn save_file(filename: &str, blocks: usize, title: &[u8]) -> SaveFile {
+        let mut data = vec![0; blocks * BLOCK_SIZE];
+        for (block, chunk) in data.chunks_exact_mut(BLOCK_SIZE).enumerate() {
+            chunk.fill(block as u8 + 1);
+        }
+
+        let header = &mut data[..FRAME_SIZE];
+        header.fill(0);
+        header[..4].copy_from_slice(&[b'S', b'C', 0x11, blocks as u8]);
+        header[4..4 + title.len()].copy_from_slice(title);
+        for (index, color) in header[0x60..0x80].chunks_exact_mut(2).enumerate() {
+            color.copy_from_slice(&(index as u16 * 0x421).to_le_bytes());
+        }
+        data[FRAME_SIZE..2 * FRAME_SIZE].fill(0x10);
+
+        SaveFile { filename: filename.to_string(), data }
+    }
+
+    // ＦＦ７ and ゲーム followed by full-width spaces
+    const TITLE_FF7: &[u8] = b"\x82\x65\x82\x65\x82\x56";
+    const TITLE_GAME: &[u8] = b"\x83\x51\x81\x5B\x83\x80\x81\x40\x81\x40";
+
+    /// A one block save at block 0 and a three block one at blocks 1-3
+    pub(super) fn card_with_saves() -> Card {
+        let mut card = Card::new();
+        assert_eq!(card.import(&save_file("BASLUS-00594FF7S01", 1, TITLE_FF7)).unwrap(), 0);
+        assert_eq!(card.import(&save_file("BISLPS-01234GAME", 3, TITLE_GAME)).unwrap(), 1);
+        card
+    }
+
+    fn assert_checksums(card: &Card) {
+        for frame in 0..16 {
+            let entry = &card.data()[frame * FRAME_SIZE..(frame + 1) * FRAME_SIZE];
+            assert_eq!(entry.iter().fold(0, |checksum, byte| checksum ^ byte), 0, "frame {}", frame);
+        }
+    }
+
+    #[test]
+    fn import_links_blocks() {
+        let card = card_with_saves();
+        assert_checksums(&card);
+        assert_eq!(card.free_blocks(), 11);
+
+        let states: Vec<u8> = (0..5).map(|block| card.entry(block)[0]).collect();
+        assert_eq!(states, [STATE_FIRST, STATE_FIRST, STATE_MIDDLE, STATE_LAST, STATE_FREE]);
+        let next: Vec<[u8; 2]> = (0..4).map(|block| [card.entry(block)[8], card.entry(block)[9]]).collect();
+        assert_eq!(next, [[0xFF, 0xFF], [2, 0], [3, 0], [0xFF, 0xFF]]);
+
+        // Only the first entry has the size and filename
+        assert_eq!(card.entry(1)[4..8], (3 * BLOCK_SIZE as u32).to_le_bytes());
+        assert_eq!(card.entry(2)[4..8], [0; 4]);
+        assert_eq!(&card.entry(1)[10..26], b"BISLPS-01234GAME");
+        assert!(card.entry(2)[10..30].iter().all(|&byte| byte == 0));
+    }
+
+    #[test]
+    fn lists_saves() {
+        let saves = card_with_saves().saves();
+        assert_eq!(saves.len(), 2);
+
+        let ff7 = &saves[0];
+        assert_eq!((ff7.first_block, &ff7.blocks[..], ff7.size), (0, &[0][..], BLOCK_SIZE));
+        assert_eq!((ff7.region.as_str(), ff7.product_code.as_str(), ff7.identifier.as_str()), ("BA", "SLUS-00594", "FF7S01"));
+        assert_eq!(ff7.title, "ＦＦ７");
+        assert_eq!(ff7.icon.frames, [[0x10; FRAME_SIZE]]);
+        assert_eq!(ff7.icon.palette[15], 15 * 0x421);
+        assert_eq!(ff7.icon.frame_rgb(0)[..6], [[0; 3], rgb555_to_rgb888(0x421)].concat());
+
+        let game = &saves[1];
+        assert_eq!((game.first_block, &game.blocks[..], game.size), (1, &[1, 2, 3][..], 3 * BLOCK_SIZE));
+        // Trailing full-width spaces are padding
+        assert_eq!(game.title, "ゲーム");
+        assert_eq!(game.identifier, "GAME");
+    }
+
+    #[test]
+    fn export_follows_the_chain() {
+        let mut card = card_with_saves();
+        card.delete(0).unwrap();
+
+        // The freed block comes first, so the new save is split around the existing one
+        let save = save_file("BESCES-00001SPLIT", 3, b"SPLIT");
+        assert_eq!(card.import(&save).unwrap(), 0);
+        assert_checksums(&card);
+
+        let split = card.saves().into_iter().find(|save| save.first_block == 0).unwrap();
+        assert_eq!(split.blocks, [0, 4, 5]);
+        assert_eq!(split.title, "SPLIT");
+        assert_eq!(card.export(0).unwrap(), save);
+        assert_eq!(card.export(1).unwrap(), save_file("BISLPS-01234GAME", 3, TITLE_GAME));
+    }
+
+    #[test]
+    fn delete_keeps_the_data() {
+        let mut card = card_with_saves();
+        let before = card.clone();
+        card.delete(1).unwrap();
+        assert_checksums(&card);
+
+        let states: Vec<u8> = (1..4).map(|block| card.entry(block)[0]).collect();
+        assert_eq!(states, [STATE_DELETED_FIRST, STATE_DELETED_MIDDLE, STATE_DELETED_LAST]);
+        assert_eq!(card.entry(1)[1..FRAME_SIZE - 1], before.entry(1)[1..FRAME_SIZE - 1]);
+        assert_eq!(card.data()[2 * BLOCK_SIZE..5 * BLOCK_SIZE], before.data()[2 * BLOCK_SIZE..5 * BLOCK_SIZE]);
+        assert_eq!(card.free_blocks(), 14);
+        assert_eq!(card.saves().len(), 1);
+
+        assert!(matches!(card.delete(1), Err(Error::NoSuchSave(1))));
+        assert!(matches!(card.export(2), Err(Error::NoSuchSave(2))));
+    }
+
+    #[test]
+    fn import_errors() {
+        let mut card = card_with_saves();
+
+        let duplicate = save_file("BASLUS-00594FF7S01", 1, b"");
+        assert!(matches!(card.import(&duplicate), Err(Error::AlreadyExists(_))));
+
+        let large = save_file("BASLUS-00001BIG", 12, b"");
+        assert!(matches!(card.import(&large), Err(Error::NotEnoughSpace { needed: 12, free: 11 })));
+
+        let mut partial = save_file("BASLUS-00001PART", 1, b"");
+        partial.data.pop();
+        assert!(matches!(card.import(&partial), Err(Error::InvalidImage(_))));
+
+        assert!(card.data() == card_with_saves().data());
+    }
+
+    #[test]
+    fn broken_chains_end() {
+        let mut card = card_with_saves();
+
+        // Point the last block back at the middle one
+        let mut entry = card.entry(3);
+        entry[8..10].copy_from_slice(&2u16.to_le_bytes());
+        card.set_entry(3, entry);
+        assert_eq!(card.saves()[1].blocks, [1, 2, 3]);
+
+        let mut entry = card.entry(1);
+        entry[8..10].copy_from_slice(&0x20u16.to_le_bytes());
+        card.set_entry(1, entry);
+        assert_eq!(card.saves()[1].blocks, [1]);
+    }
+}
\ No newline at end of file
@@ -0,0 +1,78 @@
+// Save titles are Shift-JIS, almost always full-width letters, digits, punctuation and kana.
+// Kanji would need the whole JIS X 0208 table, those come out as U+FFFD
+
+// JIS row 1 (81 40 - 81 9E) and the start of row 2 (81 9F - 81 AC)
+const SYMBOLS: &str = "\u{3000}、。，．・：；？！゛゜´｀¨＾￣＿ヽヾゝゞ〃仝々〆〇ー―‐／＼～∥｜…‥‘’“”（）〔〕［］｛｝〈〉《》「」『』【】＋－±×\
+                       ÷＝≠＜＞≦≧∞∴♂♀°′″℃￥＄￠￡％＃＆＊＠§☆★○●◎◇\
+                       ◆□■△▲▽▼※〒→←↑↓〓";
+const GREEK_UPPER: &str = "ΑΒΓΔΕΖΗΘΙΚΛΜΝΞΟΠΡΣΤΥΦΧΨΩ";
+const GREEK_LOWER: &str = "αβγδεζηθικλμνξοπρστυφχψω";
+
+/// Decodes Shift-JIS up to the first NUL
+pub fn decode(bytes: &[u8]) -> String {
+    let mut output = String::with_capacity(bytes.len());
+
+    let mut iter = bytes.iter().copied();
+    while let Some(lead) = iter.next() {
+        match lead {
+            0x00 => break,
+            0x20..=0x7E => output.push(lead as char),
+            // Half-width katakana
+            0xA1..=0xDF => output.push(char::from_u32(0xFF61 + (lead - 0xA1) as u32).unwrap()),
+            0x81..=0x9F | 0xE0..=0xFC => {
+                let Some(trail) = iter.next() else {
+                    output.push('\u{FFFD}');
+                    break;
+                };
+                output.push(decode_pair(lead, trail).unwrap_or('\u{FFFD}'));
+            }
+            _ => output.push('\u{FFFD}')
+        }
+    }
+
+    output
+}
+
+fn decode_pair(lead: u8, trail: u8) -> Option<char> {
+    let offset = |start: u32, base: u8| char::from_u32(start + (trail - base) as u32);
+
+    match (lead, trail) {
+        (0x81, 0x40..=0x7E) => SYMBOLS.chars().nth((trail - 0x40) as usize),
+        (0x81, 0x80..=0xAC) => SYMBOLS.chars().nth((trail - 0x80) as usize + 63),
+        (0x82, 0x4F..=0x58) => offset(0xFF10, 0x4F),
+        (0x82, 0x60..=0x79) => offset(0xFF21, 0x60),
+        (0x82, 0x81..=0x9A) => offset(0xFF41, 0x81),
+        (0x82, 0x9F..=0xF1) => offset(0x3041, 0x9F),
+        (0x83, 0x40..=0x7E) => offset(0x30A1, 0x40),
+        (0x83, 0x80..=0x96) => offset(0x30E0, 0x80),
+        (0x83, 0x9F..=0xB6) => GREEK_UPPER.chars().nth((trail - 0x9F) as usize),
+        (0x83, 0xBF..=0xD6) => GREEK_LOWER.chars().nth((trail - 0xBF) as usize),
+        _ => None
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_titles() {
+        let titles: [(&[u8], &str); 10] = [
+            (b"MEMORY CARD 1", "MEMORY CARD 1"),
+            (b"\x82\x60\x82\x61\x82\x79 \x82\x4F\x82\x58", "ＡＢＺ ０９"),
+            (b"\x82\x81\x82\x9A", "ａｚ"),
+            (b"\x82\xA0\x82\xF1", "あん"),
+            (b"\x83\x4A\x83\x5B\x83\x96", "カゼヶ"),
+            (b"\x81\x40\x81\x5B\x81\x75\x81\x76\x81\x99\x81\xAC", "\u{3000}ー「」☆〓"),
+            (b"\x83\x9F\x83\xB6\x83\xBF\x83\xD6", "ΑΩαω"),
+            (b"\xB1\xDF", "ｱﾟ"),
+            (b"AB\0CD", "AB"),
+            (b"\x88\x9F\x82", "\u{FFFD}\u{FFFD}")
+        ];
+
+        for (bytes, title) in titles {
+            assert_eq!(decode(bytes), title, "{:02X?}", bytes);
+        }
+    }
+}
\ No newline at end of file